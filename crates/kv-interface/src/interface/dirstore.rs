@@ -4,6 +4,7 @@ use bytes::Bytes;
 use kv::{
     batched::batched_write::{BatchedWrite, CreateBatch},
     definitions::types::KvBytes,
+    merge::auto::AutoMerge,
     store::store::Store,
 };
 use parking_lot::Mutex;
@@ -19,9 +20,13 @@ use super::{
 };
 
 pub struct DirStore {
-    pub(crate) store: Arc<Store>,
+    /// The store underneath, keys are wrapped by `KeyType`
+    pub store: Arc<Store>,
     pub(crate) depth: usize,
     pub(crate) batches: Arc<Mutex<HashMap<String, BatchedWrite>>>,
+    /// background merge if enabled in `[backend.merge]`,
+    ///     used for RAII management of the merge thread, not explicitly
+    pub(crate) _auto_merge: Option<AutoMerge>,
 }
 
 pub enum DirStoreMetadataType {
@@ -48,6 +53,7 @@ impl DirStore {
     /// Possible errors:
     ///     - depth mismatch error
     pub fn open(config: DirStoreConfig) -> anyhow::Result<Self> {
+        let merge_config = config.backend.merge;
         let store = Store::open(
             config.backend.store,
             config.backend.file,
@@ -87,10 +93,18 @@ impl DirStore {
             }
         }
 
+        let store = Arc::new(store);
+        let auto_merge = if merge_config.auto() {
+            Some(AutoMerge::start(&store, merge_config))
+        } else {
+            None
+        };
+
         Ok(Self {
-            store: Arc::new(store),
+            store,
             depth,
             batches: Arc::new(Mutex::new(HashMap::new())),
+            _auto_merge: auto_merge,
         })
     }
 }
@@ -141,9 +155,7 @@ impl DirStore {
                     emsg: format!("Error while merging: {}", e.to_string()),
                 })?;
 
-                Ok(ExecOutput::Info(
-                    "Merge ok, will clean up on next reboot.".into(),
-                ))
+                Ok(ExecOutput::Info("Merge ok.".into()))
             }
         }
     }
@@ -3,6 +3,7 @@ use axum::{
     Router,
 };
 use kv_interface::interface::config::start_dir_store;
+use service::store::AsyncDirStore;
 use std::sync::Arc;
 
 mod service;
@@ -13,7 +14,8 @@ async fn main() {
     pretty_env_logger::init();
 
     // build our application with a route
-    let ds = Arc::new(start_dir_store("config.toml"));
+    // store commands run on a blocking pool of their own
+    let ds = Arc::new(AsyncDirStore::new(start_dir_store("config.toml")).unwrap());
 
     let app = Router::new()
         .route(
@@ -5,12 +5,13 @@ use axum::{
     response::IntoResponse,
     Json,
 };
-use kv_interface::{interface::dirstore::DirStore, ksis::parse::commands::Command};
+use crate::service::store::AsyncDirStore;
+use kv_interface::ksis::parse::commands::Command;
 use log::{error, info};
 use std::{collections::BTreeMap, error, sync::Arc};
 
 pub async fn exec(
-    State(store): State<Arc<DirStore>>,
+    State(store): State<Arc<AsyncDirStore>>,
     Json(body): Json<BTreeMap<String, String>>,
 ) -> (StatusCode, String) {
     info!("Execute command...");
@@ -22,13 +23,13 @@ pub async fn exec(
     };
 
     match Command::try_parse(cmd_str) {
-        Ok(cmd) => store.exec_command(cmd).to_resp(),
+        Ok(cmd) => store.exec_command(cmd).await.to_resp(),
         Err(e) => (StatusCode::BAD_REQUEST, e.to_string()),
     }
 }
 
-pub async fn merge(State(store): State<Arc<DirStore>>) -> (StatusCode, String) {
-    match store.exec_command(Command::Merge) {
+pub async fn merge(State(store): State<Arc<AsyncDirStore>>) -> (StatusCode, String) {
+    match store.exec_command(Command::Merge).await {
         Ok(res) => (StatusCode::OK, res.to_string()),
         Err(e) => (StatusCode::BAD_REQUEST, e.to_string()),
     }
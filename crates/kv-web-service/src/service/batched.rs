@@ -5,39 +5,40 @@ use axum::{
     response::IntoResponse,
     Json,
 };
-use kv_interface::{interface::dirstore::DirStore, ksis::parse::commands::Command};
+use crate::service::store::AsyncDirStore;
+use kv_interface::ksis::parse::commands::Command;
 use log::{error, info};
 use std::{collections::BTreeMap, error, sync::Arc};
 
 #[axum::debug_handler]
 pub async fn new(
-    State(store): State<Arc<DirStore>>,
+    State(store): State<Arc<AsyncDirStore>>,
     Path(batchname): Path<String>,
 ) -> (StatusCode, String) {
     info!("New batch: {}", batchname);
     let cmd_str = format!("$bat {}", batchname);
     match Command::try_parse(cmd_str) {
-        Ok(cmd) => store.exec_command(cmd).to_resp(),
+        Ok(cmd) => store.exec_command(cmd).await.to_resp(),
         Err(e) => (StatusCode::BAD_REQUEST, e.to_string()),
     }
 }
 
 #[axum::debug_handler]
 pub async fn commit(
-    State(store): State<Arc<DirStore>>,
+    State(store): State<Arc<AsyncDirStore>>,
     Path(batchname): Path<String>,
 ) -> (StatusCode, String) {
     info!("Commit batch: {}", batchname);
     let cmd_str = format!("$cmt {}", batchname);
     match Command::try_parse(cmd_str) {
-        Ok(cmd) => store.exec_command(cmd).to_resp(),
+        Ok(cmd) => store.exec_command(cmd).await.to_resp(),
         Err(e) => (StatusCode::BAD_REQUEST, e.to_string()),
     }
 }
 
 #[axum::debug_handler]
 pub async fn delete(
-    State(store): State<Arc<DirStore>>,
+    State(store): State<Arc<AsyncDirStore>>,
     Path((batchname, path)): Path<(String, String)>,
 ) -> (StatusCode, String) {
     match parse_path(path) {
@@ -45,7 +46,7 @@ pub async fn delete(
             info!("DELETE dir: {}", dir);
             let cmd_str = format!("$bdel {} {}", batchname, dir);
             match Command::try_parse(cmd_str) {
-                Ok(cmd) => store.exec_command(cmd).to_resp(),
+                Ok(cmd) => store.exec_command(cmd).await.to_resp(),
                 Err(e) => (StatusCode::BAD_REQUEST, e.to_string()),
             }
         }
@@ -58,7 +59,7 @@ pub async fn delete(
 
 #[axum::debug_handler]
 pub async fn put(
-    State(store): State<Arc<DirStore>>,
+    State(store): State<Arc<AsyncDirStore>>,
     Path((batchname, path)): Path<(String, String)>,
     Json(body): Json<BTreeMap<String, String>>,
 ) -> (StatusCode, String) {
@@ -78,7 +79,7 @@ pub async fn put(
             let cmd_str = format!("$bput {} {} -{} {}", batchname, dir, value_type, value);
 
             match Command::try_parse(cmd_str) {
-                Ok(cmd) => store.exec_command(cmd).to_resp(),
+                Ok(cmd) => store.exec_command(cmd).await.to_resp(),
                 Err(e) => (StatusCode::BAD_REQUEST, e.to_string()),
             }
         }
@@ -5,13 +5,14 @@ use axum::{
     response::IntoResponse,
     Json,
 };
-use kv_interface::{interface::dirstore::DirStore, ksis::parse::commands::Command};
+use crate::service::store::AsyncDirStore;
+use kv_interface::ksis::parse::commands::Command;
 use log::{error, info};
 use std::{collections::BTreeMap, error, sync::Arc};
 
 /// ParseError and ExecError are two types of errors that should respond to client
 pub async fn get(
-    State(store): State<Arc<DirStore>>,
+    State(store): State<Arc<AsyncDirStore>>,
     Path(path): Path<String>,
 ) -> (StatusCode, String) {
     match parse_path(path) {
@@ -19,7 +20,7 @@ pub async fn get(
             info!("GET dir: {}", dir);
             let cmd_str = format!("$get {}", dir);
             match Command::try_parse(cmd_str) {
-                Ok(cmd) => store.exec_command(cmd).to_resp(),
+                Ok(cmd) => store.exec_command(cmd).await.to_resp(),
                 Err(e) => (StatusCode::BAD_REQUEST, e.to_string()),
             }
         }
@@ -30,17 +31,17 @@ pub async fn get(
     }
 }
 
-pub async fn list_root(State(store): State<Arc<DirStore>>) -> (StatusCode, String) {
+pub async fn list_root(State(store): State<Arc<AsyncDirStore>>) -> (StatusCode, String) {
     info!("List root");
     let cmd_str = format!("$ls .");
     match Command::try_parse(cmd_str) {
-        Ok(cmd) => store.exec_command(cmd).to_resp(),
+        Ok(cmd) => store.exec_command(cmd).await.to_resp(),
         Err(e) => (StatusCode::BAD_REQUEST, e.to_string()),
     }
 }
 
 pub async fn list(
-    State(store): State<Arc<DirStore>>,
+    State(store): State<Arc<AsyncDirStore>>,
     Path(path): Path<String>,
 ) -> (StatusCode, String) {
     match parse_path(path) {
@@ -48,7 +49,7 @@ pub async fn list(
             info!("List dir: {}", dir);
             let cmd_str = format!("$ls {}", dir);
             match Command::try_parse(cmd_str) {
-                Ok(cmd) => store.exec_command(cmd).to_resp(),
+                Ok(cmd) => store.exec_command(cmd).await.to_resp(),
                 Err(e) => (StatusCode::BAD_REQUEST, e.to_string()),
             }
         }
@@ -60,7 +61,7 @@ pub async fn list(
 }
 
 pub async fn delete(
-    State(store): State<Arc<DirStore>>,
+    State(store): State<Arc<AsyncDirStore>>,
     Path(path): Path<String>,
 ) -> (StatusCode, String) {
     match parse_path(path) {
@@ -68,7 +69,7 @@ pub async fn delete(
             info!("DELETE dir: {}", dir);
             let cmd_str = format!("$del {}", dir);
             match Command::try_parse(cmd_str) {
-                Ok(cmd) => store.exec_command(cmd).to_resp(),
+                Ok(cmd) => store.exec_command(cmd).await.to_resp(),
                 Err(e) => (StatusCode::BAD_REQUEST, e.to_string()),
             }
         }
@@ -80,7 +81,7 @@ pub async fn delete(
 }
 
 pub async fn put(
-    State(store): State<Arc<DirStore>>,
+    State(store): State<Arc<AsyncDirStore>>,
     Path(path): Path<String>,
     Json(body): Json<BTreeMap<String, String>>,
 ) -> (StatusCode, String) {
@@ -100,7 +101,7 @@ pub async fn put(
             let cmd_str = format!("$put {} -{} {}", dir, value_type, value);
 
             match Command::try_parse(cmd_str) {
-                Ok(cmd) => store.exec_command(cmd).to_resp(),
+                Ok(cmd) => store.exec_command(cmd).await.to_resp(),
                 Err(e) => (StatusCode::BAD_REQUEST, e.to_string()),
             }
         }
@@ -2,4 +2,5 @@ pub mod advanced;
 pub mod batched;
 pub mod crud;
 pub mod parse;
-pub mod response;
\ No newline at end of file
+pub mod response;
+pub mod store;
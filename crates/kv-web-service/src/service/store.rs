@@ -0,0 +1,31 @@
+use std::sync::Arc;
+
+use kv::store::async_store::AsyncStore;
+use kv_interface::{
+    interface::{dirstore::DirStore, errors::ExecReturn},
+    ksis::parse::commands::Command,
+};
+
+/// Directory store whose commands run on the blocking pool of an `AsyncStore`,
+///     handlers never block the runtime.
+pub struct AsyncDirStore {
+    dir_store: Arc<DirStore>,
+    store: AsyncStore,
+}
+
+impl AsyncDirStore {
+    pub fn new(dir_store: DirStore) -> std::io::Result<Self> {
+        let store = AsyncStore::new(Arc::clone(&dir_store.store))?;
+        Ok(Self {
+            dir_store: Arc::new(dir_store),
+            store,
+        })
+    }
+
+    pub async fn exec_command(&self, cmd: Command) -> ExecReturn {
+        let dir_store = Arc::clone(&self.dir_store);
+        self.store
+            .blocking(move || dir_store.exec_command(cmd))
+            .await
+    }
+}
@@ -1,6 +1,6 @@
-use std::sync::Arc;
+use std::{sync::Arc, thread};
 
-use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
 use kv::{batched::batched_write::CreateBatch, store::utils::TempStore};
 
 fn bench_put(c: &mut Criterion) {
@@ -62,9 +62,68 @@ fn bench_mixed(c: &mut Criterion) {
     });
 }
 
+/// `sync_every_write` puts from several writers, sharing syncs through group commit:
+///     throughput should grow with the number of writers.
+fn bench_concurrent_put(c: &mut Criterion) {
+    const PUTS: usize = 256;
+    let (_raii, store) = TempStore::init(202);
+
+    let mut group = c.benchmark_group("bench-concurrent-put");
+    group.throughput(Throughput::Elements(PUTS as u64));
+    for writers in [1, 2, 4, 8] {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(writers),
+            &writers,
+            |b, &writers| {
+                b.iter(|| {
+                    thread::scope(|s| {
+                        for w in 0..writers {
+                            let store = &store;
+                            s.spawn(move || {
+                                for i in (w..PUTS).step_by(writers) {
+                                    let key = format!("{}", i);
+                                    let val = english_numbers::convert_all_fmt(i as i64);
+                                    store.put(key.into(), val.into()).unwrap();
+                                }
+                            });
+                        }
+                    });
+                });
+            },
+        );
+    }
+    group.finish();
+}
+
+/// Writes wait for a snapshot to copy the index: the time grows with the number of keys.
+fn bench_snapshot(c: &mut Criterion) {
+    const BATCH: usize = 100;
+
+    let mut group = c.benchmark_group("bench-snapshot");
+    for keys in [1_000, 10_000, 100_000] {
+        let (_raii, store) = TempStore::init(203);
+        let store = Arc::new(store);
+        for start in (0..keys).step_by(BATCH) {
+            let batch = store.new_batched();
+            for i in start..start + BATCH {
+                let key = format!("{}", i);
+                let val = english_numbers::convert_all_fmt(i as i64);
+                batch.put(key.into(), val.into()).unwrap();
+            }
+            batch.commit().unwrap();
+        }
+
+        group.throughput(Throughput::Elements(keys as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(keys), &keys, |b, _| {
+            b.iter(|| black_box(store.snapshot()));
+        });
+    }
+    group.finish();
+}
+
 criterion_group! {
     name = benches;
     config = Criterion::default();
-    targets = bench_put, bench_batched_put, bench_mixed
+    targets = bench_put, bench_batched_put, bench_mixed, bench_concurrent_put, bench_snapshot
 }
 criterion_main!(benches);
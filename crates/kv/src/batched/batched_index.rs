@@ -2,7 +2,11 @@ use crate::{definitions::types::ByteVec, records::log_record::LogRecordPtr, stor
 use std::{collections::HashMap, sync::Arc};
 
 pub enum BatchedIndexPtr {
-    Put(LogRecordPtr),
+    Put {
+        ptr: LogRecordPtr,
+        size: u64,
+        expire_at: Option<u64>,
+    },
     Delete,
 }
 
@@ -19,8 +23,21 @@ impl BatchedIndex {
         }
     }
 
-    pub fn mark_put(&mut self, key: ByteVec, value: LogRecordPtr) {
-        self.ptr.insert(key, BatchedIndexPtr::Put(value));
+    pub fn mark_put(
+        &mut self,
+        key: ByteVec,
+        value: LogRecordPtr,
+        size: u64,
+        expire_at: Option<u64>,
+    ) {
+        self.ptr.insert(
+            key,
+            BatchedIndexPtr::Put {
+                ptr: value,
+                size,
+                expire_at,
+            },
+        );
     }
 
     pub fn mark_delete(&mut self, key: ByteVec) {
@@ -33,14 +50,19 @@ impl BatchedIndex {
 }
 
 impl BatchedIndex {
-    pub(crate) fn commit(&self, store: &Store) {
+    /// `sizes` as in `Store::replay_put`
+    pub(crate) fn commit(&self, store: &Store, mut sizes: Option<&mut HashMap<LogRecordPtr, u64>>) {
         for (key, val) in self.ptr.iter() {
             match val {
-                BatchedIndexPtr::Put(ptr) => {
-                    store.index.put(key.clone(), ptr.clone());
+                BatchedIndexPtr::Put {
+                    ptr,
+                    size,
+                    expire_at,
+                } => {
+                    store.replay_put(key.clone(), *ptr, *size, *expire_at, sizes.as_deref_mut());
                 }
                 BatchedIndexPtr::Delete => {
-                    store.index.delete(key.clone());
+                    store.replay_delete(key.clone(), sizes.as_deref_mut());
                 }
             }
         }
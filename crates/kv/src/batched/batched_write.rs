@@ -3,11 +3,14 @@ use crate::{
     definitions::types::ByteVec,
     errors::{Errors, Result},
     records::log_record::LogRecord,
-    store::store::Store,
+    store::{
+        expiry::{expire_at_after, now_millis},
+        store::Store,
+    },
 };
 use bytes::Bytes;
 use parking_lot::Mutex;
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, sync::Arc, time::Duration};
 
 use super::{batched_index::BatchedIndex, log_record::BatchedLogRecord};
 
@@ -52,6 +55,26 @@ impl BatchedWrite {
         Ok(())
     }
 
+    /// The ttl starts now, not at commit.
+    pub fn put_with_ttl(&self, key: Bytes, value: Bytes, ttl: Duration) -> Result<()> {
+        if key.is_empty() {
+            return Err(Errors::KeyIsEmpty);
+        }
+
+        let mut pending = self.pending.lock();
+        if pending.len() >= self.config.max_batch_size {
+            return Err(Errors::BatchOverflow);
+        }
+        let record = BatchedLogRecord::Expiring {
+            key: key.to_vec(),
+            value: value.to_vec(),
+            expire_at: expire_at_after(ttl),
+        };
+        pending.insert(key.to_vec(), record);
+
+        Ok(())
+    }
+
     pub fn delete(&self, key: Bytes) -> Result<()> {
         if key.is_empty() {
             return Err(Errors::KeyIsEmpty);
@@ -69,45 +92,94 @@ impl BatchedWrite {
         Ok(())
     }
 
+    /// The pending write of `key`, `Some(Err(KeyNotFound))` for a pending delete.
+    pub(crate) fn get_pending(&self, key: &[u8]) -> Option<Result<Bytes>> {
+        let pending = self.pending.lock();
+        match pending.get(key)? {
+            BatchedLogRecord::Data { key: _, value } => Some(Ok(Bytes::from(value.clone()))),
+            BatchedLogRecord::Tomb { key: _ } => Some(Err(Errors::KeyNotFound)),
+            BatchedLogRecord::Expiring {
+                key: _,
+                value,
+                expire_at,
+            } => {
+                if *expire_at <= now_millis() {
+                    Some(Err(Errors::KeyNotFound))
+                } else {
+                    Some(Ok(Bytes::from(value.clone())))
+                }
+            }
+        }
+    }
+
     pub fn commit(&self) -> Result<()> {
+        self.commit_checked(|| Ok(()))
+    }
+
+    /// Commit only if `check` passes;
+    ///     it runs after every other write to the store has been locked out.
+    pub(crate) fn commit_checked(&self, check: impl FnOnce() -> Result<()>) -> Result<()> {
+        self.store.check_writable()?;
         // Since every item in `pending` hashmap
         //      refers to different key (whose order need not to be maintained)
         // we simply read from hashmap without enforcing order.
         let mut pending = self.pending.lock();
 
-        let _commit_lock = self.store.batch_commit_lock.lock();
-        let batch_id = self
-            .store
-            .batch_id
-            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-
-        // no iter/adapter here since errors need to be propagated
-        let mut record_ptrs = Vec::new();
-        for record in pending.values_mut() {
-            let ptr = self.store.log(&mut record.into_batched(batch_id))?;
-            record_ptrs.push(ptr);
-        }
-
-        // println!("batch {} commit", batch_id);
-        self.store.log(&mut LogRecord::BatchDone { batch_id })?;
-
-        if self.config.sync_every_write {
-            self.store.sync()?;
-        }
+        let ticket = {
+            let _commit_lock = self.store.batch_commit_lock.lock();
+            let _write_lock = self.store.write_lock.lock();
+            check()?;
+            let batch_id = self
+                .store
+                .batch_id
+                .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+
+            // no iter/adapter here since errors need to be propagated
+            let mut record_ptrs = Vec::new();
+            for record in pending.values_mut() {
+                let ptr = self.store.log(&mut record.into_batched(batch_id))?;
+                record_ptrs.push(ptr);
+            }
 
-        // if all write succeeded, we should reach here
-        for (record, ptr) in pending.values_mut().zip(record_ptrs) {
-            match record {
-                BatchedLogRecord::Data { key, value: _ } => {
-                    self.store.index.put(key.to_vec(), ptr);
-                }
-                BatchedLogRecord::Tomb { key } => {
-                    self.store.index.delete(key.to_vec());
+            // println!("batch {} commit", batch_id);
+            self.store.log(&mut LogRecord::BatchDone { batch_id })?;
+
+            // if all write succeeded, we should reach here
+            for (record, ptr) in pending.values_mut().zip(record_ptrs) {
+                match record {
+                    BatchedLogRecord::Data { key, value: _ } => {
+                        self.store.expirations.set(key.to_vec(), None);
+                        if let Some(old_ptr) = self.store.index.put(key.to_vec(), ptr) {
+                            self.store.mark_stale(old_ptr);
+                        }
+                    }
+                    BatchedLogRecord::Tomb { key } => {
+                        self.store.expirations.set(key.to_vec(), None);
+                        if let Some(old_ptr) = self.store.index.delete(key.to_vec()) {
+                            self.store.mark_stale(old_ptr);
+                        }
+                    }
+                    BatchedLogRecord::Expiring {
+                        key,
+                        value: _,
+                        expire_at,
+                    } => {
+                        self.store.expirations.set(key.to_vec(), Some(*expire_at));
+                        if let Some(old_ptr) = self.store.index.put(key.to_vec(), ptr) {
+                            self.store.mark_stale(old_ptr);
+                        }
+                    }
                 }
             }
-        }
 
-        pending.clear();
+            pending.clear();
+            self.store.group_commit.ticket()
+        };
+
+        // one sync for the whole batch, shared with concurrent writers
+        if self.config.sync_every_write || self.store.store_config.sync_every_write {
+            self.store.sync_until(ticket)?;
+        }
 
         Ok(())
     }
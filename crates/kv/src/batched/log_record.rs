@@ -5,8 +5,19 @@ use crate::{
 };
 
 pub enum BatchedLogRecord {
-    Data { key: ByteVec, value: ByteVec },
-    Tomb { key: ByteVec },
+    Data {
+        key: ByteVec,
+        value: ByteVec,
+    },
+    Tomb {
+        key: ByteVec,
+    },
+    /// `expire_at` in milliseconds since UNIX epoch
+    Expiring {
+        key: ByteVec,
+        value: ByteVec,
+        expire_at: u64,
+    },
 }
 
 impl TryFrom<LogRecord> for BatchedLogRecord {
@@ -20,6 +31,16 @@ impl TryFrom<LogRecord> for BatchedLogRecord {
                 value,
             } => Ok(Self::Data { key, value }),
             LogRecord::TombInBatch { batch_id: _, key } => Ok(Self::Tomb { key }),
+            LogRecord::ExpiringInBatch {
+                batch_id: _,
+                key,
+                value,
+                expire_at,
+            } => Ok(Self::Expiring {
+                key,
+                value,
+                expire_at,
+            }),
             _ => Err(Errors::InvalidBatchedRecordType { record: value }),
         }
     }
@@ -37,6 +58,16 @@ impl BatchedLogRecord {
                 batch_id,
                 key: key.clone(),
             },
+            BatchedLogRecord::Expiring {
+                key,
+                value,
+                expire_at,
+            } => LogRecord::ExpiringInBatch {
+                batch_id,
+                key: key.clone(),
+                value: value.clone(),
+                expire_at: *expire_at,
+            },
         }
     }
 }
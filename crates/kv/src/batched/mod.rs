@@ -1,6 +1,7 @@
 pub mod batched_index;
 pub mod batched_write;
 pub mod log_record;
+pub mod transaction;
 
 #[cfg(test)]
 mod tests {
@@ -0,0 +1,182 @@
+/*
+    Optimistic transactions:
+    reads go to the store and remember the index pointer they saw,
+    writes are buffered in a `BatchedWrite`.
+    Commit re-checks every pointer with all other writes locked out,
+    and only then logs the batch, so the transaction is serializable.
+*/
+
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use bytes::Bytes;
+use parking_lot::Mutex;
+
+use crate::{
+    definitions::types::ByteVec,
+    errors::{Errors, Result},
+    records::log_record::LogRecordPtr,
+    store::store::Store,
+};
+
+use super::batched_write::{BatchedWrite, CreateBatch};
+
+pub struct Transaction {
+    batch: BatchedWrite,
+    /// Maps `key` to the pointer observed by its first read, `None` if it was absent.
+    reads: Arc<Mutex<HashMap<ByteVec, Option<LogRecordPtr>>>>,
+    store: Arc<Store>,
+}
+
+pub trait CreateTransaction {
+    fn new_transaction(&self) -> Transaction;
+}
+
+impl CreateTransaction for Arc<Store> {
+    fn new_transaction(&self) -> Transaction {
+        Transaction {
+            batch: self.new_batched(),
+            reads: Arc::new(Mutex::new(HashMap::new())),
+            store: Arc::clone(self),
+        }
+    }
+}
+
+impl Transaction {
+    /// Reads own pending writes first, then the store.
+    pub fn get(&self, key: Bytes) -> Result<Bytes> {
+        if key.is_empty() {
+            return Err(Errors::KeyIsEmpty);
+        }
+        if let Some(res) = self.batch.get_pending(&key) {
+            return res;
+        }
+
+        let (ptr, value) = self.store.get_versioned(&key)?;
+        // a later read of the same key must see the same record anyway
+        self.reads.lock().entry(key.to_vec()).or_insert(ptr);
+        value.ok_or(Errors::KeyNotFound)
+    }
+
+    pub fn put(&self, key: Bytes, value: Bytes) -> Result<()> {
+        self.batch.put(key, value)
+    }
+
+    pub fn put_with_ttl(&self, key: Bytes, value: Bytes, ttl: Duration) -> Result<()> {
+        self.batch.put_with_ttl(key, value, ttl)
+    }
+
+    pub fn delete(&self, key: Bytes) -> Result<()> {
+        self.batch.delete(key)
+    }
+
+    /// Fails with `TransactionConflict` if any key read has been written since,
+    ///     in which case nothing is written.
+    ///
+    /// A merge moving a key also counts as a change.
+    pub fn commit(&self) -> Result<()> {
+        let mut reads = self.reads.lock();
+        self.batch.commit_checked(|| {
+            for (key, ptr) in reads.iter() {
+                if self.store.index.get(key.clone()) != *ptr {
+                    return Err(Errors::TransactionConflict { key: key.clone() });
+                }
+            }
+            Ok(())
+        })?;
+        reads.clear();
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread};
+
+    use crate::{errors::Errors, store::utils::TempStore};
+
+    use super::CreateTransaction;
+
+    #[test]
+    fn test_transaction() {
+        let (_raii, store) = TempStore::init(41);
+        let store = Arc::new(store);
+        store.put("a".into(), "1".into()).unwrap();
+        store.put("b".into(), "2".into()).unwrap();
+
+        // reads its own writes, nothing is visible before commit
+        let txn = store.new_transaction();
+        assert_eq!(txn.get("a".into()).unwrap(), "1");
+        txn.put("a".into(), "10".into()).unwrap();
+        txn.delete("b".into()).unwrap();
+        assert_eq!(txn.get("a".into()).unwrap(), "10");
+        assert!(matches!(txn.get("b".into()), Err(Errors::KeyNotFound)));
+        assert_eq!(store.get("a".into()).unwrap(), "1");
+        txn.commit().unwrap();
+        assert_eq!(store.get("a".into()).unwrap(), "10");
+        assert!(store.get("b".into()).is_err());
+
+        // a write after the read conflicts, also on a key read as absent
+        let txn = store.new_transaction();
+        assert_eq!(txn.get("a".into()).unwrap(), "10");
+        assert!(txn.get("c".into()).is_err());
+        txn.put("d".into(), "4".into()).unwrap();
+        store.put("c".into(), "3".into()).unwrap();
+        assert_eq!(
+            txn.commit(),
+            Err(Errors::TransactionConflict { key: b"c".to_vec() })
+        );
+        assert!(store.get("d".into()).is_err());
+
+        // writes to keys not read do not conflict
+        let txn = store.new_transaction();
+        assert_eq!(txn.get("a".into()).unwrap(), "10");
+        store.put("c".into(), "30".into()).unwrap();
+        txn.put("a".into(), "11".into()).unwrap();
+        txn.commit().unwrap();
+        assert_eq!(store.get("a".into()).unwrap(), "11");
+    }
+
+    #[test]
+    fn test_concurrent_transactions() {
+        let (_raii, store) = TempStore::init(42);
+        let store = Arc::new(store);
+        store.put("from".into(), "800".into()).unwrap();
+        store.put("to".into(), "0".into()).unwrap();
+
+        // transfers between two keys, their sum never changes
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let store = Arc::clone(&store);
+                thread::spawn(move || {
+                    for _ in 0..100 {
+                        loop {
+                            let txn = store.new_transaction();
+                            let from: u64 =
+                                String::from_utf8_lossy(&txn.get("from".into()).unwrap())
+                                    .parse()
+                                    .unwrap();
+                            let to: u64 = String::from_utf8_lossy(&txn.get("to".into()).unwrap())
+                                .parse()
+                                .unwrap();
+                            txn.put("from".into(), format!("{}", from - 1).into())
+                                .unwrap();
+                            txn.put("to".into(), format!("{}", to + 1).into()).unwrap();
+                            match txn.commit() {
+                                Ok(_) => break,
+                                Err(Errors::TransactionConflict { .. }) => {}
+                                Err(e) => panic!("{}", e),
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(store.get("from".into()).unwrap(), "0");
+        assert_eq!(store.get("to".into()).unwrap(), "800");
+    }
+}
@@ -0,0 +1,355 @@
+/*
+    Key-value separation:
+    a value of at least `min_size` is appended to a blob file `<id>.blob`,
+    and the record in the store file holds a `BlobPtr` in its place,
+    marked by `BLOB_REF` on its type byte.
+    A blob file holds data records, key included, so garbage collection can tell
+    which blobs are still referred to by the index; compression and encryption
+    apply to them as to store files.
+
+    Merge copies the references only, blob files are rewritten by `Store::gc_blobs` alone.
+*/
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::PathBuf,
+    sync::{atomic::AtomicU32, Arc},
+};
+
+use log::error;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::config::FileConfig,
+    definitions::{constants::get_blob_numbers, types::ByteVec},
+    errors::{Errors, Result},
+    io::{cipher::RecordCipher, traits::IoType},
+    propagate_err,
+    records::{compression::CompressionConfig, log_record::LogRecord},
+    store::{
+        file_handle::FileHandle,
+        file_header::FileHeader,
+        utils::{format_blob_filename, sync_dir},
+    },
+};
+
+/// Set on the type byte of a record whose value is a `BlobPtr`
+pub const BLOB_REF: u8 = 0x40;
+
+/// `[store.blob]`, off by default.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct BlobConfig {
+    pub(crate) enabled: bool,
+    /// values shorter than this stay in the store files
+    pub(crate) min_size: usize,
+    pub(crate) max_file_size: u64,
+    /// `gc_blobs` rewrites a blob file once this share of it is garbage
+    pub(crate) gc_ratio: f64,
+}
+
+impl Default for BlobConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            min_size: 4096,
+            max_file_size: 256 << 20,
+            gc_ratio: 0.5,
+        }
+    }
+}
+
+/// Where a separated value lives.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BlobPtr {
+    pub(crate) file_id: u32,
+    pub(crate) offset: u64,
+    /// size of the value
+    pub(crate) size: u32,
+}
+
+impl BlobPtr {
+    pub fn encoded_len() -> usize {
+        4 /* file id */ + 8 /* offset */ + 4 /* size */
+    }
+
+    pub(crate) fn encode(&self) -> ByteVec {
+        let mut res = self.file_id.to_be_bytes().to_vec();
+        res.extend_from_slice(&self.offset.to_be_bytes());
+        res.extend_from_slice(&self.size.to_be_bytes());
+        res
+    }
+
+    pub(crate) fn decode(bin: &[u8]) -> Result<Self> {
+        if bin.len() != Self::encoded_len() {
+            error!("Blob reference of {} bytes", bin.len());
+            return Err(Errors::InvalidBlobRef);
+        }
+        let (file_id, rest) = bin.split_at(4);
+        let (offset, size) = rest.split_at(8);
+        Ok(Self {
+            file_id: u32::from_be_bytes(file_id.try_into().unwrap()),
+            offset: u64::from_be_bytes(offset.try_into().unwrap()),
+            size: u32::from_be_bytes(size.try_into().unwrap()),
+        })
+    }
+}
+
+/// A blob as found in its file, see `BlobFiles::scan`.
+pub(crate) struct BlobEntry {
+    pub(crate) key: ByteVec,
+    pub(crate) ptr: BlobPtr,
+    /// bytes taken in the blob file
+    pub(crate) disk_size: u64,
+}
+
+pub struct BlobFiles {
+    dir: PathBuf,
+    config: BlobConfig,
+    cipher: Option<Arc<RecordCipher>>,
+    /// (file id, handle) written to, created on the first blob
+    active: RwLock<Option<(u32, FileHandle)>>,
+    /// file id -> sealed blob file
+    sealed: RwLock<HashMap<u32, FileHandle>>,
+    /// ids only grow, a removed id is never reused
+    next_file_id: AtomicU32,
+}
+
+impl BlobFiles {
+    /// Opens the blob files in `dir`, the newest one is written to.
+    pub fn open(
+        dir: PathBuf,
+        config: BlobConfig,
+        cipher: Option<Arc<RecordCipher>>,
+    ) -> Result<Self> {
+        let file_ids = get_blob_numbers(dir.clone())?;
+        Self::open_files(dir, config, cipher, file_ids, IoType::File)
+    }
+
+    /// Same as `open`, for the blob files `file_ids` opened as `io_type`.
+    pub(crate) fn open_files(
+        dir: PathBuf,
+        config: BlobConfig,
+        cipher: Option<Arc<RecordCipher>>,
+        mut file_ids: Vec<u32>,
+        io_type: IoType,
+    ) -> Result<Self> {
+        let next_file_id = file_ids.last().map_or(0, |file_id| file_id + 1);
+        let file_config = Self::file_config(config);
+
+        let active = match file_ids.pop() {
+            Some(file_id) => {
+                let file = FileHandle::open_at(
+                    format_blob_filename(dir.clone(), file_id),
+                    file_id,
+                    file_config,
+                    io_type,
+                )?
+                .with_cipher(cipher.clone());
+                file.set_write_offset(file.size());
+                Some((file_id, file))
+            }
+            None => None,
+        };
+        let mut sealed = HashMap::new();
+        for file_id in file_ids {
+            let file = FileHandle::open_at(
+                format_blob_filename(dir.clone(), file_id),
+                file_id,
+                file_config,
+                io_type,
+            )?
+            .with_cipher(cipher.clone());
+            sealed.insert(file_id, file);
+        }
+
+        Ok(Self {
+            dir,
+            config,
+            cipher,
+            active: RwLock::new(active),
+            sealed: RwLock::new(sealed),
+            next_file_id: next_file_id.into(),
+        })
+    }
+
+    /// Take over the files of `other`, as a read-only store opens them again to follow its writer;
+    ///     `other` is left without any.
+    pub(crate) fn replace(&self, other: &BlobFiles) {
+        let mut active = self.active.write();
+        let mut sealed = self.sealed.write();
+        *active = other.active.write().take();
+        *sealed = std::mem::take(&mut *other.sealed.write());
+        self.next_file_id.store(
+            other
+                .next_file_id
+                .load(std::sync::atomic::Ordering::Relaxed),
+            std::sync::atomic::Ordering::Relaxed,
+        );
+    }
+
+    fn file_config(config: BlobConfig) -> FileConfig {
+        FileConfig {
+            max_file_size: config.max_file_size,
+        }
+    }
+
+    pub(crate) fn config(&self) -> BlobConfig {
+        self.config
+    }
+
+    /// Whether `value` is written to a blob file
+    pub(crate) fn separates(&self, value: &[u8]) -> bool {
+        self.config.enabled && value.len() >= self.config.min_size
+    }
+
+    pub(crate) fn write(
+        &self,
+        key: &[u8],
+        value: &[u8],
+        compression: &CompressionConfig,
+    ) -> Result<BlobPtr> {
+        let mut record = LogRecord::Data {
+            key: key.to_vec(),
+            value: value.to_vec(),
+        };
+        let mut active = self.active.write();
+        loop {
+            if let Some((file_id, file)) = active.as_ref() {
+                let offset = file.get_write_offset();
+                match file.try_append_with(&mut record, compression, None) {
+                    Ok(_) => {
+                        return Ok(BlobPtr {
+                            file_id: *file_id,
+                            offset,
+                            size: value.len() as u32,
+                        })
+                    }
+                    // does not fit even into an empty file
+                    Err(Errors::BufferOverflow) if offset == 0 => {
+                        return Err(Errors::BufferOverflow)
+                    }
+                    Err(Errors::BufferOverflow) => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            self.rotate(&mut active)?;
+        }
+    }
+
+    /// Seal the file written to, if any, and start a new one.
+    fn rotate(&self, active: &mut Option<(u32, FileHandle)>) -> Result<()> {
+        self.seal(active)?;
+        let file_id = self
+            .next_file_id
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let file = FileHandle::create_at(
+            format_blob_filename(self.dir.clone(), file_id),
+            FileHeader::new(file_id),
+            Self::file_config(self.config),
+        )?
+        .with_cipher(self.cipher.clone());
+        *active = Some((file_id, file));
+        Ok(())
+    }
+
+    fn seal(&self, active: &mut Option<(u32, FileHandle)>) -> Result<()> {
+        if let Some((file_id, file)) = active.take() {
+            file.sync()?;
+            self.sealed.write().insert(file_id, file);
+        }
+        Ok(())
+    }
+
+    /// Seal the file written to, the next blob starts a new one.
+    pub(crate) fn seal_active(&self) -> Result<()> {
+        self.seal(&mut self.active.write())
+    }
+
+    pub(crate) fn read(&self, ptr: BlobPtr) -> Result<ByteVec> {
+        let record = {
+            let active = self.active.read();
+            match active.as_ref() {
+                Some((file_id, file)) if *file_id == ptr.file_id => {
+                    file.read_at_offset(ptr.offset)?.0
+                }
+                _ => {
+                    drop(active);
+                    let sealed = self.sealed.read();
+                    let file = sealed.get(&ptr.file_id).ok_or(Errors::BlobFileNotFound {
+                        file_id: ptr.file_id,
+                    })?;
+                    file.read_at_offset(ptr.offset)?.0
+                }
+            }
+        };
+        match record {
+            LogRecord::Data { key: _, value } if value.len() == ptr.size as usize => Ok(value),
+            record => {
+                error!("Blob at {:?} is not a value: {:?}", ptr, record.type_id());
+                Err(Errors::InvalidBlobRef)
+            }
+        }
+    }
+
+    pub(crate) fn sync(&self) -> Result<()> {
+        match self.active.read().as_ref() {
+            Some((_, file)) => file.sync(),
+            None => Ok(()),
+        }
+    }
+
+    /// (file id, length written, header included) of the file written to, if any
+    pub(crate) fn active_end(&self) -> Option<(u32, u64)> {
+        self.active
+            .read()
+            .as_ref()
+            .map(|(file_id, file)| (*file_id, file.header().length() + file.get_write_offset()))
+    }
+
+    pub(crate) fn sealed_ids(&self) -> Vec<u32> {
+        let mut file_ids: Vec<u32> = self.sealed.read().keys().copied().collect();
+        file_ids.sort_unstable();
+        file_ids
+    }
+
+    /// Every blob in the sealed file `file_id`, in file order.
+    pub(crate) fn scan(&self, file_id: u32) -> Result<(Vec<BlobEntry>, u64)> {
+        let sealed = self.sealed.read();
+        let file = sealed
+            .get(&file_id)
+            .ok_or(Errors::BlobFileNotFound { file_id })?;
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        loop {
+            let (record, size) = match file.read_at_offset(offset) {
+                Ok(res) => res,
+                Err(Errors::Eof) => break,
+                Err(e) => return Err(e),
+            };
+            if let LogRecord::Data { key, value } = record {
+                entries.push(BlobEntry {
+                    key,
+                    ptr: BlobPtr {
+                        file_id,
+                        offset,
+                        size: value.len() as u32,
+                    },
+                    disk_size: size,
+                });
+            }
+            offset += size;
+        }
+        Ok((entries, file.size()))
+    }
+
+    /// Delete the sealed file `file_id`
+    pub(crate) fn remove(&self, file_id: u32) -> Result<()> {
+        self.sealed.write().remove(&file_id);
+        fs::remove_file(format_blob_filename(self.dir.clone(), file_id))
+            .map_err(propagate_err!(Errors::FileIoWriteError))?;
+        sync_dir(self.dir.clone())
+    }
+}
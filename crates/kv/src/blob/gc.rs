@@ -0,0 +1,235 @@
+/*
+    Blob garbage collection, behind `merge_lock`:
+    1. seal the blob file written to, so every file looked at is immutable;
+    2. a blob is live if the index points to a record referring to it;
+    3. a file with at least `gc_ratio` garbage has its live blobs copied to the new blob file,
+        each referred to by a new record appended for its key, then it is removed.
+    Store files are left to merge, the records written here are small.
+
+    A blob file is kept while a snapshot is alive, a later collection removes it.
+*/
+
+use log::info;
+
+use crate::{
+    errors::{Errors, Result},
+    records::log_record::{LogRecord, LogRecordPtr},
+    store::store::{Stamp, Store},
+};
+
+use super::blob_files::{BlobEntry, BlobPtr};
+
+impl Store {
+    pub fn gc_blobs(&self) -> Result<()> {
+        self.check_writable()?;
+        let _merge_lock = self.merge_lock.try_lock().ok_or(Errors::MergeInProgress)?;
+        {
+            let _write_lock = self.write_lock.lock();
+            self.blobs.seal_active()?;
+        }
+
+        let gc_ratio = self.blobs.config().gc_ratio;
+        for file_id in self.blobs.sealed_ids() {
+            let (entries, file_size) = self.blobs.scan(file_id)?;
+            let live: Vec<(BlobEntry, LogRecordPtr)> = entries
+                .into_iter()
+                .filter_map(|entry| {
+                    let ptr = self.blob_referrer(&entry)?;
+                    Some((entry, ptr))
+                })
+                .collect();
+            let live_bytes: u64 = live.iter().map(|(entry, _)| entry.disk_size).sum();
+            if file_size == 0 || ((file_size - live_bytes) as f64 / file_size as f64) < gc_ratio {
+                continue;
+            }
+
+            for (entry, ptr) in live {
+                self.relocate_blob(entry, ptr)?;
+            }
+            // the new references must be on disk before the old blobs are gone
+            self.sync_until(self.group_commit.ticket())?;
+
+            let pins = self.snapshot_pins.lock();
+            if pins.any_live() {
+                info!("Blob file {} kept for a live snapshot", file_id);
+                continue;
+            }
+            self.blobs.remove(file_id)?;
+            info!(
+                "Blob file {} collected, {} live bytes moved",
+                file_id, live_bytes
+            );
+        }
+        Ok(())
+    }
+
+    /// Pointer to the record referring to the blob of `entry`, if the index points to one.
+    fn blob_referrer(&self, entry: &BlobEntry) -> Option<LogRecordPtr> {
+        let ptr = self.index.get(entry.key.clone())?;
+        match self.get_stored_at(ptr) {
+            Ok((_, Some(blob))) if blob == entry.ptr => Some(ptr),
+            _ => None,
+        }
+    }
+
+    /// Copy a blob to the blob file written to, and refer to it by a new record.
+    fn relocate_blob(&self, entry: BlobEntry, ptr: LogRecordPtr) -> Result<()> {
+        let value = self.blobs.read(entry.ptr)?;
+
+        let _write_lock = self.write_lock.lock();
+        // overwritten since
+        if self.index.get(entry.key.clone()) != Some(ptr) {
+            return Ok(());
+        }
+        let (record, _, meta) = self.get_stamped_at(ptr)?;
+        let moved = self
+            .blobs
+            .write(&entry.key, &value, &self.store_config.compression)?;
+        // on its own: a batch record is only replayed along with the rest of its batch
+        let mut record = record.unbatched();
+        *record
+            .value_mut()
+            .expect("Internal error: blob referred to by a non-data record") = moved.encode();
+
+        let new_ptr = self.log_blob_ref(&mut record, Stamp::Keep(meta))?;
+        if let Some(old_ptr) = self.index.put(entry.key, new_ptr) {
+            self.mark_stale(old_ptr);
+        }
+        Ok(())
+    }
+}
+
+impl Store {
+    /// Fill in the value of a record whose value was separated into `blob`.
+    pub(crate) fn resolve_blob(&self, record: &mut LogRecord, blob: Option<BlobPtr>) -> Result<()> {
+        if let Some(blob) = blob {
+            *record
+                .value_mut()
+                .expect("Internal error: blob referred to by a non-data record") =
+                self.blobs.read(blob)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path, sync::Arc, time::Duration};
+
+    use bytes::Bytes;
+
+    use crate::{
+        batched::batched_write::CreateBatch,
+        blob::blob_files::BlobConfig,
+        config::config::Config,
+        store::{store::Store, utils::format_blob_filename},
+    };
+
+    fn open(dir: &str) -> Store {
+        let (mut store_config, file_config, batched_config) =
+            Config::from_toml("config.toml".into());
+        store_config.dir = dir.into();
+        store_config.blob = BlobConfig {
+            enabled: true,
+            min_size: 1024,
+            max_file_size: 64 << 10,
+            gc_ratio: 0.5,
+        };
+        Store::open(store_config, file_config, batched_config).unwrap()
+    }
+
+    fn big(i: usize) -> Bytes {
+        format!("{:04}:", i).repeat(400).into()
+    }
+
+    /// (file name, size) of every file in `dir` with `extension`
+    fn files(dir: &str, extension: &str) -> Vec<(String, u64)> {
+        let mut files: Vec<_> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == extension))
+            .map(|path| {
+                let size = fs::metadata(&path).unwrap().len();
+                (
+                    path.file_name().unwrap().to_str().unwrap().to_string(),
+                    size,
+                )
+            })
+            .collect();
+        files.sort();
+        files
+    }
+
+    fn check(store: &Store) {
+        for i in 0..50 {
+            assert_eq!(store.get(format!("{}", i).into()).unwrap(), "small");
+        }
+        for i in 50..100 {
+            assert_eq!(store.get(format!("{}", i).into()).unwrap(), big(i));
+        }
+        assert_eq!(store.get("batched".into()).unwrap(), big(1000));
+        assert_eq!(store.get("expiring".into()).unwrap(), big(1001));
+    }
+
+    #[test]
+    fn test_blob_store() {
+        let dir = "store/test_51";
+        let backup_dir = "store/test_51_backup";
+        // remove if exist
+        fs::remove_dir_all(dir);
+        fs::remove_dir_all(backup_dir);
+
+        let store = Arc::new(open(dir));
+        for i in 0..100 {
+            store.put(format!("{}", i).into(), big(i)).unwrap();
+        }
+        let batch = store.new_batched();
+        batch.put("batched".into(), big(1000)).unwrap();
+        batch.commit().unwrap();
+        drop(batch);
+        store
+            .put_with_ttl("expiring".into(), big(1001), Duration::from_secs(3600))
+            .unwrap();
+        // store files only hold references
+        let stored: u64 = files(dir, "store").iter().map(|(_, size)| size).sum();
+        assert!(stored < 102 * 100, "{}", stored);
+        assert_eq!(store.get("42".into()).unwrap(), big(42));
+        let kvs: Vec<_> = store
+            .iter_options()
+            .with_key_prefix(b"9".to_vec())
+            .make()
+            .collect();
+        assert_eq!(kvs.len(), 11);
+        assert!(kvs.iter().all(|kv| kv.value.len() == big(0).len()));
+
+        // merge leaves blob files alone
+        let blob_files = files(dir, "blob");
+        assert!(blob_files.len() >= 4, "{:?}", blob_files);
+        for i in 0..50 {
+            store.put(format!("{}", i).into(), "small".into()).unwrap();
+        }
+        store.merge().unwrap();
+        assert_eq!(files(dir, "blob"), blob_files);
+        check(&store);
+
+        // the first files are mostly garbage now
+        store.gc_blobs().unwrap();
+        assert!(!Path::new(&format_blob_filename(dir.into(), 0)).exists());
+        assert!(!Path::new(&format_blob_filename(dir.into(), 1)).exists());
+        let collected: u64 = files(dir, "blob").iter().map(|(_, size)| size).sum();
+        let before: u64 = blob_files.iter().map(|(_, size)| size).sum();
+        assert!(collected < before * 3 / 4, "{} of {}", collected, before);
+        check(&store);
+
+        store.blocking_copy_to(backup_dir.into()).unwrap();
+        drop(store);
+        for dir in [dir, backup_dir] {
+            let store = open(dir);
+            check(&store);
+            assert_eq!(store.list_keys().len(), 102);
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+        fs::remove_dir_all(backup_dir).unwrap();
+    }
+}
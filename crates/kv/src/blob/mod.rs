@@ -0,0 +1,2 @@
+pub mod blob_files;
+pub mod gc;
@@ -2,7 +2,10 @@ use std::{fs, path::PathBuf};
 
 use serde::{Deserialize, Serialize};
 
-use crate::index::index_impl::IndexType;
+use crate::{
+    blob::blob_files::BlobConfig, index::index_impl::IndexType, io::cipher::EncryptionConfig,
+    records::compression::CompressionConfig,
+};
 
 #[derive(Serialize, Deserialize, Debug, Clone, Copy)]
 pub struct BatchedConfig {
@@ -15,6 +18,12 @@ pub struct StoreConfig {
     pub(crate) dir: PathBuf,
     pub(crate) sync_every_write: bool,
     pub(crate) index_type: IndexType,
+    #[serde(default)]
+    pub(crate) compression: CompressionConfig,
+    #[serde(default)]
+    pub(crate) encryption: EncryptionConfig,
+    #[serde(default)]
+    pub(crate) blob: BlobConfig,
 }
 
 #[derive(Clone, Copy, Serialize, Deserialize, Debug)]
@@ -22,11 +31,73 @@ pub struct FileConfig {
     pub(crate) max_file_size: u64,
 }
 
+/// Background merge, see `merge::auto`.
+///     The whole `[merge]` section is optional, automatic merge is off by default.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct MergeConfig {
+    pub(crate) auto: bool,
+    /// how often the background thread looks at the statistics
+    pub(crate) check_interval_ms: u64,
+    /// merge when dead bytes / total bytes reaches this ratio
+    pub(crate) garbage_ratio: f64,
+    /// merge when total bytes on disk reaches this size, 0 to disable
+    pub(crate) max_total_size: u64,
+    /// never merge for less garbage than this
+    pub(crate) min_dead_bytes: u64,
+}
+
+impl MergeConfig {
+    pub fn auto(&self) -> bool {
+        self.auto
+    }
+}
+
+impl Default for MergeConfig {
+    fn default() -> Self {
+        Self {
+            auto: false,
+            check_interval_ms: 10_000,
+            garbage_ratio: 0.5,
+            max_total_size: 0,
+            min_dead_bytes: 1 << 20,
+        }
+    }
+}
+
+/// Log shipping, see `replication`.
+///     The whole `[replication]` section is optional.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct ReplicationConfig {
+    /// how often the primary looks for new records while a follower is caught up
+    pub(crate) poll_interval_ms: u64,
+    /// an idle primary sends a heartbeat this often,
+    ///     a follower hearing nothing for three of them reconnects
+    pub(crate) heartbeat_interval_ms: u64,
+    /// how long a follower waits before reconnecting
+    pub(crate) retry_interval_ms: u64,
+}
+
+impl Default for ReplicationConfig {
+    fn default() -> Self {
+        Self {
+            poll_interval_ms: 10,
+            heartbeat_interval_ms: 1_000,
+            retry_interval_ms: 500,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Config {
     pub store: StoreConfig,
     pub file: FileConfig,
     pub batched: BatchedConfig,
+    #[serde(default)]
+    pub merge: MergeConfig,
+    #[serde(default)]
+    pub replication: ReplicationConfig,
 }
 
 impl Config {
@@ -40,6 +111,30 @@ impl Config {
 
         (config.store, config.file, config.batched)
     }
+
+    /// `[merge]` section of the same file, defaults if absent.
+    pub fn merge_from_toml(path: PathBuf) -> MergeConfig {
+        let config: Self = toml::from_str(
+            fs::read_to_string(path)
+                .expect("File does not exist")
+                .as_str(),
+        )
+        .expect("Deserialize configuration file failed!");
+
+        config.merge
+    }
+
+    /// `[replication]` section of the same file, defaults if absent.
+    pub fn replication_from_toml(path: PathBuf) -> ReplicationConfig {
+        let config: Self = toml::from_str(
+            fs::read_to_string(path)
+                .expect("File does not exist")
+                .as_str(),
+        )
+        .expect("Deserialize configuration file failed!");
+
+        config.replication
+    }
 }
 
 #[cfg(test)]
@@ -55,4 +150,49 @@ mod tests {
 
         dbg!(config);
     }
+
+    #[test]
+    fn merge_section_is_optional() {
+        let config: Config = toml::from_str(
+            r#"
+            [store]
+            dir = "store/kv_test"
+            sync_every_write = true
+            index_type = "BTree"
+
+            [file]
+            max_file_size = 4096
+
+            [batched]
+            max_batch_size = 128
+            sync_every_write = true
+            "#,
+        )
+        .unwrap();
+        assert!(!config.merge.auto);
+
+        let config: Config = toml::from_str(
+            r#"
+            [store]
+            dir = "store/kv_test"
+            sync_every_write = true
+            index_type = "BTree"
+
+            [file]
+            max_file_size = 4096
+
+            [batched]
+            max_batch_size = 128
+            sync_every_write = true
+
+            [merge]
+            auto = true
+            garbage_ratio = 0.3
+            "#,
+        )
+        .unwrap();
+        assert!(config.merge.auto);
+        assert_eq!(config.merge.garbage_ratio, 0.3);
+        assert_eq!(config.merge.check_interval_ms, 10_000);
+    }
 }
@@ -6,12 +6,17 @@ use crate::{
 };
 
 pub const MERGE_STORE_PATH: &str = "merge";
-pub const MERGE_OK_FILE_NAME: &str = "ok.toml";
+pub const MERGE_MANIFEST_FILE_NAME: &str = "MANIFEST";
 pub const DISK_TREE_INDEX_FLIE_NAME: &str = "disk_tree_index.store";
 pub const DISK_TREE_BUCKET_NAME: &str = "index";
+pub const DISK_TREE_META_BUCKET_NAME: &str = "meta";
+pub const DISK_TREE_CHECKPOINT_KEY: &str = "applied";
 pub const LOCK_FILE_NAME: &str = "exclusive.lock";
+pub const KEY_CHECK_FILE_NAME: &str = "key.check";
 pub const SCRIPT_EXTENSION: &str = ".ksis.toml";
 pub const SCRIPT_RESULTS_EXTENSION: &str = ".ksis.results.toml";
+/// keys fetched from the index at a time by key iterators
+pub const ITER_CHUNK_SIZE: usize = 256;
 
 /// get max prefix number of
 pub fn get_max_prefix_number(dir: PathBuf) -> Result<Option<u32>> {
@@ -29,3 +34,31 @@ pub fn get_max_prefix_number(dir: PathBuf) -> Result<Option<u32>> {
         .filter_map(|file_name| file_name.parse::<u32>().ok())
         .max())
 }
+
+/// ids of all `<id>.store` files in `dir`, ascending;
+///     ids need not be contiguous, merge leaves gaps behind.
+pub fn get_prefix_numbers(dir: PathBuf) -> Result<Vec<u32>> {
+    get_numbers_with_suffix(dir, ".store")
+}
+
+/// ids of all `<id>.blob` files in `dir`, ascending.
+pub fn get_blob_numbers(dir: PathBuf) -> Result<Vec<u32>> {
+    get_numbers_with_suffix(dir, ".blob")
+}
+
+fn get_numbers_with_suffix(dir: PathBuf, suffix: &str) -> Result<Vec<u32>> {
+    let mut ids: Vec<u32> = fs::read_dir(dir.clone())
+        .map_err(propagate_err!(Errors::DirNotFound { dir: dir.clone() }))?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().is_file())
+        .filter_map(|entry| {
+            entry
+                .path()
+                .file_name()
+                .and_then(|name| name.to_str().and_then(|s| s.strip_suffix(suffix)))
+                .and_then(|id| id.parse::<u32>().ok())
+        })
+        .collect();
+    ids.sort_unstable();
+    Ok(ids)
+}
@@ -3,7 +3,10 @@ use std::path::PathBuf;
 use log::error;
 use thiserror::Error;
 
-use crate::records::log_record::LogRecord;
+use crate::{
+    definitions::types::ByteVec,
+    records::log_record::{LogRecord, LogRecordPtr},
+};
 
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum MergePhase {
@@ -46,6 +49,8 @@ pub enum Errors {
         got
     )]
     CrcMismatch { expected: u32, got: u32 },
+    #[error("An invalid record type failure occured! code {}", code)]
+    InvalidRecordType { code: u8 },
     #[error("A batched write overflow failure occured!")]
     BatchOverflow,
     #[error("An invalid batched record type failure occured!")]
@@ -64,6 +69,55 @@ pub enum Errors {
     BinarySizeMismatch { expected: u32, got: u32 },
     #[error("A list empty failure occured!")]
     ListIsEmpty,
+    #[error("A transaction conflict occured on key {:?}!", key)]
+    TransactionConflict { key: ByteVec },
+    #[error("A value decompression failure occured!")]
+    DecompressionFailure,
+    #[error("The encryption key is invalid for the store at {:?}!", dir)]
+    InvalidEncryptionKey { dir: PathBuf },
+    #[error("A record decryption failure occured!")]
+    DecryptionFailure,
+    #[error("A blob file not found failure occured! File id: {}", file_id)]
+    BlobFileNotFound { file_id: u32 },
+    #[error("An invalid blob reference failure occured!")]
+    InvalidBlobRef,
+    #[error("The file {:?} has no valid header!", path)]
+    InvalidFileHeader { path: PathBuf },
+    #[error(
+        "The file {:?} is of format version {}, newer than supported!",
+        path,
+        version
+    )]
+    UnsupportedFormatVersion { path: PathBuf, version: u16 },
+    #[error("The restore target {:?} already holds a store!", dir)]
+    RestoreTargetNotEmpty { dir: PathBuf },
+    #[error(
+        "Changes since sequence number {} are incomplete, merge compacted them until {}!",
+        seq,
+        compacted_seq
+    )]
+    ChangesCompacted { seq: u64, compacted_seq: u64 },
+    #[error("The store is a replica, only its primary writes to it!")]
+    ReplicaIsReadOnly,
+    #[error("The store is not opened as a replica!")]
+    NotAReplica,
+    #[error("Replication of a store with blob separation is not supported!")]
+    BlobReplicationUnsupported,
+    #[error("A replication connection failure occured!")]
+    ReplicationConnectionFailure,
+    #[error("An invalid replication message failure occured!")]
+    InvalidReplicationMessage,
+    #[error(
+        "Replicated records out of order! expected {:?}, got {:?}",
+        expected,
+        got
+    )]
+    ReplicationOutOfOrder {
+        expected: LogRecordPtr,
+        got: LogRecordPtr,
+    },
+    #[error("The store is opened read only!")]
+    ReadOnly,
 }
 
 /// use `ok_or` for `Option<T>`
@@ -0,0 +1,460 @@
+/*
+    Hint files:
+    a sealed `<id>.store` file never changes, so its index contribution
+    can be written once to `<id>.hint` and replayed on open
+    without decoding any value.
+*/
+
+use std::{fs, path::PathBuf};
+
+use bytes::{Buf, Bytes};
+use log::{error, warn};
+
+use crate::{
+    definitions::types::ByteVec,
+    errors::{Errors, Result},
+    io::cipher::RecordCipher,
+    propagate_err,
+    records::log_record::LogRecord,
+    store::{file_handle::FileHandle, utils::format_hint_filename},
+};
+
+pub const HINT_FORMAT_VERSION: u8 = 2;
+
+/// A log record with its value stripped off.
+///
+/// There is exactly one hint record per log record (tombs and batch markers included),
+///     so that replaying hints follows the same batch state machine as replaying the file.
+#[derive(Debug, PartialEq, Clone)]
+pub enum HintRecord {
+    Data {
+        key: ByteVec,
+        offset: u64,
+        size: u64,
+        expire_at: Option<u64>,
+    },
+    Tomb {
+        key: ByteVec,
+        offset: u64,
+        size: u64,
+    },
+    DataInBatch {
+        batch_id: usize,
+        key: ByteVec,
+        offset: u64,
+        size: u64,
+        expire_at: Option<u64>,
+    },
+    TombInBatch {
+        batch_id: usize,
+        key: ByteVec,
+        offset: u64,
+        size: u64,
+    },
+    BatchDone {
+        batch_id: usize,
+        offset: u64,
+        size: u64,
+    },
+}
+
+impl HintRecord {
+    pub fn from_record(record: &LogRecord, offset: u64, size: u64) -> Self {
+        match record {
+            LogRecord::Data { key, value: _ } => HintRecord::Data {
+                key: key.clone(),
+                offset,
+                size,
+                expire_at: None,
+            },
+            LogRecord::Tomb { key } => HintRecord::Tomb {
+                key: key.clone(),
+                offset,
+                size,
+            },
+            LogRecord::DataInBatch {
+                batch_id,
+                key,
+                value: _,
+            } => HintRecord::DataInBatch {
+                batch_id: *batch_id,
+                key: key.clone(),
+                offset,
+                size,
+                expire_at: None,
+            },
+            LogRecord::TombInBatch { batch_id, key } => HintRecord::TombInBatch {
+                batch_id: *batch_id,
+                key: key.clone(),
+                offset,
+                size,
+            },
+            LogRecord::BatchDone { batch_id } => HintRecord::BatchDone {
+                batch_id: *batch_id,
+                offset,
+                size,
+            },
+            // expiry is replayed along with the index, without reading the record
+            LogRecord::Expiring { key, expire_at, .. } => HintRecord::Data {
+                key: key.clone(),
+                offset,
+                size,
+                expire_at: Some(*expire_at),
+            },
+            LogRecord::ExpiringInBatch {
+                batch_id,
+                key,
+                expire_at,
+                ..
+            } => HintRecord::DataInBatch {
+                batch_id: *batch_id,
+                key: key.clone(),
+                offset,
+                size,
+                expire_at: Some(*expire_at),
+            },
+        }
+    }
+
+    /// Same type ids as `LogRecord`
+    fn type_id(&self) -> u8 {
+        match self {
+            HintRecord::Data { .. } => 0,
+            HintRecord::Tomb { .. } => 1,
+            HintRecord::DataInBatch { .. } => 2,
+            HintRecord::TombInBatch { .. } => 3,
+            HintRecord::BatchDone { .. } => 4,
+        }
+    }
+
+    /// (batch_id, key, offset, size, expire_at), zeros and empty key where not applicable
+    fn fields(&self) -> (usize, &[u8], u64, u64, u64) {
+        match self {
+            HintRecord::Data {
+                key,
+                offset,
+                size,
+                expire_at,
+            } => (0, key, *offset, *size, expire_at.unwrap_or(0)),
+            HintRecord::Tomb { key, offset, size } => (0, key, *offset, *size, 0),
+            HintRecord::DataInBatch {
+                batch_id,
+                key,
+                offset,
+                size,
+                expire_at,
+            } => (*batch_id, key, *offset, *size, expire_at.unwrap_or(0)),
+            HintRecord::TombInBatch {
+                batch_id,
+                key,
+                offset,
+                size,
+            } => (*batch_id, key, *offset, *size, 0),
+            HintRecord::BatchDone {
+                batch_id,
+                offset,
+                size,
+            } => (*batch_id, &[], *offset, *size, 0),
+        }
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.fields().2
+    }
+
+    pub fn size(&self) -> u64 {
+        self.fields().3
+    }
+
+    /// |type|batch_id|offset|size|expire_at|ksz|k|
+    fn encode_into(&self, buf: &mut ByteVec) {
+        let (batch_id, key, offset, size, expire_at) = self.fields();
+        buf.push(self.type_id());
+        buf.extend_from_slice(&(batch_id as u64).to_be_bytes());
+        buf.extend_from_slice(&offset.to_be_bytes());
+        buf.extend_from_slice(&size.to_be_bytes());
+        buf.extend_from_slice(&expire_at.to_be_bytes());
+        buf.extend_from_slice(&(key.len() as u32).to_be_bytes());
+        buf.extend_from_slice(key);
+    }
+
+    /// `None` if the buffer does not hold a complete, valid record
+    fn decode_from(buf: &mut Bytes) -> Option<Self> {
+        if buf.remaining() < 1 + 8 + 8 + 8 + 8 + 4 {
+            return None;
+        }
+        let record_type = buf.get_u8();
+        let batch_id = buf.get_u64() as usize;
+        let offset = buf.get_u64();
+        let size = buf.get_u64();
+        // a record expiring at the epoch is never written
+        let expire_at = Some(buf.get_u64()).filter(|expire_at| *expire_at != 0);
+        let key_size = buf.get_u32() as usize;
+        if buf.remaining() < key_size {
+            return None;
+        }
+        let key = buf.split_to(key_size).to_vec();
+
+        match record_type {
+            0 => Some(HintRecord::Data {
+                key,
+                offset,
+                size,
+                expire_at,
+            }),
+            1 => Some(HintRecord::Tomb { key, offset, size }),
+            2 => Some(HintRecord::DataInBatch {
+                batch_id,
+                key,
+                offset,
+                size,
+                expire_at,
+            }),
+            3 => Some(HintRecord::TombInBatch {
+                batch_id,
+                key,
+                offset,
+                size,
+            }),
+            4 => Some(HintRecord::BatchDone {
+                batch_id,
+                offset,
+                size,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Layout: |version|hint records...|crc|, crc covers everything before it;
+///     sealed as a whole in an encrypted store.
+pub struct HintFile;
+
+impl HintFile {
+    /// Scan a store file from the beginning and collect its hints.
+    pub fn scan(file: &FileHandle) -> Result<Vec<HintRecord>> {
+        let mut hints = Vec::new();
+        let mut offset = 0;
+        loop {
+            let (record, size) = match file.read_at_offset(offset) {
+                Ok(record) => record,
+                Err(Errors::Eof) => break,
+                Err(e) => return Err(e),
+            };
+            hints.push(HintRecord::from_record(&record, offset, size));
+            offset += size;
+        }
+        Ok(hints)
+    }
+
+    /// Written to a temporary file and renamed, so a hint file is either complete or absent.
+    pub fn save(
+        dir: PathBuf,
+        file_id: u32,
+        hints: &[HintRecord],
+        cipher: Option<&RecordCipher>,
+    ) -> Result<()> {
+        let mut buf = vec![HINT_FORMAT_VERSION];
+        for hint in hints {
+            hint.encode_into(&mut buf);
+        }
+        let crc = FileHandle::crc(&buf);
+        buf.extend_from_slice(&crc.to_be_bytes());
+        if let Some(cipher) = cipher {
+            buf = cipher.seal_blob(&buf);
+        }
+
+        let path = format_hint_filename(dir, file_id);
+        let tmp_path = path.with_extension("hint.tmp");
+        fs::write(tmp_path.clone(), buf).map_err(propagate_err!(Errors::FileIoWriteError))?;
+        fs::rename(tmp_path, path).map_err(propagate_err!(Errors::FileIoWriteError))?;
+        Ok(())
+    }
+
+    /// Returns `None` if the hint file is missing or corrupt,
+    ///     in which case the caller should fall back to scanning the store file.
+    pub fn load(
+        dir: PathBuf,
+        file_id: u32,
+        cipher: Option<&RecordCipher>,
+    ) -> Option<Vec<HintRecord>> {
+        let path = format_hint_filename(dir, file_id);
+        let mut buf = fs::read(path.clone()).ok()?;
+        if let Some(cipher) = cipher {
+            buf = match cipher.open_blob(&buf) {
+                Ok(buf) => buf,
+                Err(_) => {
+                    warn!("Hint file {:?} failed to decrypt, ignored.", path);
+                    return None;
+                }
+            };
+        }
+
+        if buf.len() < 1 + LogRecord::tail_length() {
+            warn!("Hint file {:?} is truncated, ignored.", path);
+            return None;
+        }
+        let (content, crc) = buf.split_at(buf.len() - LogRecord::tail_length());
+        let crc = u32::from_be_bytes([crc[0], crc[1], crc[2], crc[3]]);
+        if FileHandle::verify_crc(content, crc).is_err() {
+            warn!("Hint file {:?} is corrupted, ignored.", path);
+            return None;
+        }
+        if content[0] != HINT_FORMAT_VERSION {
+            warn!(
+                "Hint file {:?} has version {}, expected {}, ignored.",
+                path, content[0], HINT_FORMAT_VERSION
+            );
+            return None;
+        }
+
+        let mut content = Bytes::copy_from_slice(&content[1..]);
+        let mut hints = Vec::new();
+        while content.has_remaining() {
+            match HintRecord::decode_from(&mut content) {
+                Some(hint) => hints.push(hint),
+                None => {
+                    warn!("Hint file {:?} has an invalid record, ignored.", path);
+                    return None;
+                }
+            }
+        }
+        Some(hints)
+    }
+
+    /// Best effort: a missing hint only costs a scan on next open.
+    pub fn write_for(dir: PathBuf, file_id: u32, file: &FileHandle) {
+        let res =
+            Self::scan(file).and_then(|hints| Self::save(dir, file_id, &hints, file.cipher()));
+        if let Err(e) = res {
+            error!(
+                "Failed to write hint file for store file {}: {}",
+                file_id, e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Write, sync::Arc};
+
+    use crate::{
+        batched::batched_write::CreateBatch,
+        config::config::Config,
+        store::{store::Store, utils::format_hint_filename},
+    };
+
+    use super::{HintFile, HintRecord};
+
+    #[test]
+    fn test_hint_roundtrip() {
+        let dir = std::path::PathBuf::from("./test_data");
+        fs::create_dir_all(&dir).unwrap();
+        let hints = vec![
+            HintRecord::Data {
+                key: b"key".to_vec(),
+                offset: 0,
+                size: 20,
+                expire_at: None,
+            },
+            HintRecord::DataInBatch {
+                batch_id: 3,
+                key: b"expiring".to_vec(),
+                offset: 20,
+                size: 33,
+                expire_at: Some(1_700_000_000_000),
+            },
+            HintRecord::TombInBatch {
+                batch_id: 3,
+                key: b"other".to_vec(),
+                offset: 53,
+                size: 22,
+            },
+            HintRecord::BatchDone {
+                batch_id: 3,
+                offset: 75,
+                size: 13,
+            },
+        ];
+        HintFile::save(dir.clone(), 100, &hints, None).unwrap();
+        assert_eq!(HintFile::load(dir.clone(), 100, None).unwrap(), hints);
+
+        // flip the last byte of a key
+        let path = format_hint_filename(dir.clone(), 100);
+        let mut bin = fs::read(path.clone()).unwrap();
+        let len = bin.len();
+        bin[len - 5] ^= 0xff;
+        fs::write(path.clone(), bin).unwrap();
+        assert!(HintFile::load(dir, 100, None).is_none());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_build_index_with_hints() {
+        let test_id = 30;
+        let dir = format!("store/test_{}", test_id);
+        {
+            // remove if exist
+            fs::remove_dir_all(dir.clone());
+            let (mut store_config, file_config, batched_config) =
+                Config::from_toml("config.toml".into());
+            store_config.dir = dir.clone().into();
+            let store = Arc::new(Store::open(store_config, file_config, batched_config).unwrap());
+
+            for i in 0..500 {
+                let key = format!("{}", i);
+                let val = english_numbers::convert_all_fmt(i);
+                store.put(key.into(), val.into()).unwrap();
+            }
+            for i in 0..100 {
+                store.delete(format!("{}", i).into()).unwrap();
+            }
+            let batch = store.new_batched();
+            for i in 1000..1100 {
+                let key = format!("{}", i);
+                let val = english_numbers::convert_all_fmt(i);
+                batch.put(key.into(), val.into()).unwrap();
+            }
+            batch.commit().unwrap();
+        }
+
+        // every sealed file got a hint, the active one did not
+        let active_file_id =
+            crate::definitions::constants::get_max_prefix_number(dir.clone().into())
+                .unwrap()
+                .unwrap();
+        assert!(active_file_id > 0);
+        for file_id in 0..active_file_id {
+            assert!(format_hint_filename(dir.clone().into(), file_id).is_file());
+        }
+        assert!(!format_hint_filename(dir.clone().into(), active_file_id).exists());
+
+        // damage one hint, the store file behind it is scanned instead
+        let mut hint = fs::OpenOptions::new()
+            .append(true)
+            .open(format_hint_filename(dir.clone().into(), 0))
+            .unwrap();
+        hint.write_all(b"garbage").unwrap();
+
+        {
+            let (mut store_config, file_config, batched_config) =
+                Config::from_toml("config.toml".into());
+            store_config.dir = dir.clone().into();
+            let store = Store::open(store_config, file_config, batched_config).unwrap();
+
+            assert_eq!(store.list_keys().len(), 400 + 100);
+            assert!(store.get("42".into()).is_err());
+            assert_eq!(
+                store.get("123".into()).unwrap(),
+                english_numbers::convert_all_fmt(123)
+            );
+            assert_eq!(
+                store.get("1099".into()).unwrap(),
+                english_numbers::convert_all_fmt(1099)
+            );
+            assert_eq!(store.batch_id.load(std::sync::atomic::Ordering::Relaxed), 1);
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
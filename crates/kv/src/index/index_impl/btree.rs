@@ -1,6 +1,9 @@
 use crate::{
     definitions::types::ByteVec,
-    index::{iter::KeyIteratorOptions, traits::KeyIndex},
+    index::{
+        iter::{take_chunk, KeyIteratorOptions, KeyRange},
+        traits::KeyIndex,
+    },
     records::log_record::LogRecordPtr,
 };
 use parking_lot::RwLock;
@@ -45,10 +48,17 @@ impl KeyIndex for BTreeIndex {
         tree.get(&key).copied()
     }
 
-    fn iter_snapshot(&self) -> KeyIteratorOptions {
+    fn iter_snapshot(&self) -> KeyIteratorOptions<'_> {
+        KeyIteratorOptions::begin(self)
+    }
+
+    fn scan(&self, range: &KeyRange, reversed: bool, limit: usize) -> Vec<(ByteVec, LogRecordPtr)> {
+        // `BTreeMap::range` panics on an empty range
+        if range.is_empty() {
+            return Vec::new();
+        }
         let tree = self.tree.read();
-        let items_iter = Box::new(tree.clone().into_iter());
-        KeyIteratorOptions::begin(items_iter)
+        take_chunk(tree.range(range.clone()), reversed, limit)
     }
 }
 
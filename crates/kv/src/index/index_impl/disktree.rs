@@ -4,67 +4,78 @@ use uuid::Uuid;
 
 use crate::{
     definitions::{
-        constants::{DISK_TREE_BUCKET_NAME, DISK_TREE_INDEX_FLIE_NAME},
+        constants::{
+            DISK_TREE_BUCKET_NAME, DISK_TREE_CHECKPOINT_KEY, DISK_TREE_INDEX_FLIE_NAME,
+            DISK_TREE_META_BUCKET_NAME,
+        },
         types::ByteVec,
     },
-    index::{iter::KeyIteratorOptions, traits::KeyIndex},
+    index::{
+        iter::{KeyIteratorOptions, KeyRange},
+        traits::{IndexCheckpoint, KeyIndex},
+    },
     records::log_record::LogRecordPtr,
 };
-use std::{fs, path::PathBuf, sync::Arc};
+use std::{
+    fs,
+    ops::{Bound, RangeBounds},
+    path::PathBuf,
+    sync::Arc,
+};
 
 pub struct DiskTreeIndex {
     path: PathBuf,
     tree: Arc<DB>,
+    /// Persistent indexes outlive the store; temporary copies are removed on drop.
+    persistent: bool,
 }
 
 impl DiskTreeIndex {
+    /// Reopens the index left by the previous run, if any.
     pub fn new(dir: PathBuf) -> Self {
         let path = dir.join(DISK_TREE_INDEX_FLIE_NAME);
-
-        // remove if exist:
-        fs::remove_file(path.clone());
-
-        let db = DB::open(path.clone())
-            .expect("Internal error: failed to initialize on-disk B+ tree index!");
-        let tx = db
-            .tx(true)
-            .expect("Internal error: failed to initialize on-disk B+ tree index!");
-        tx.create_bucket(DISK_TREE_BUCKET_NAME)
-            .expect("Internal error: failed to initialize on-disk B+ tree index!");
-        tx.commit()
-            .expect("Internal error: failed to initialize on-disk B+ tree index!");
-
-        Self {
-            path,
-            tree: Arc::new(db),
-        }
+        Self::open_at(path, true)
     }
 
     pub fn copy_to(filename: PathBuf) -> Self {
         // remove if exist:
         fs::remove_file(filename.clone());
+        Self::open_at(filename, false)
+    }
 
-        let db = DB::open(filename.clone())
+    /// Removes the persisted index, e.g. when it no longer matches the store files.
+    pub fn discard(dir: PathBuf) {
+        // remove if exist:
+        fs::remove_file(dir.join(DISK_TREE_INDEX_FLIE_NAME));
+    }
+
+    fn open_at(path: PathBuf, persistent: bool) -> Self {
+        let db = DB::open(path.clone())
             .expect("Internal error: failed to initialize on-disk B+ tree index!");
         let tx = db
             .tx(true)
             .expect("Internal error: failed to initialize on-disk B+ tree index!");
-        tx.create_bucket(DISK_TREE_BUCKET_NAME)
+        tx.get_or_create_bucket(DISK_TREE_BUCKET_NAME)
+            .expect("Internal error: failed to initialize on-disk B+ tree index!");
+        tx.get_or_create_bucket(DISK_TREE_META_BUCKET_NAME)
             .expect("Internal error: failed to initialize on-disk B+ tree index!");
         tx.commit()
             .expect("Internal error: failed to initialize on-disk B+ tree index!");
 
         Self {
-            path: filename,
+            path,
             tree: Arc::new(db),
+            persistent,
         }
     }
 }
 
 impl Drop for DiskTreeIndex {
     fn drop(&mut self) {
-        // remove if exist:
-        fs::remove_file(self.path.clone());
+        if !self.persistent {
+            // remove if exist:
+            fs::remove_file(self.path.clone());
+        }
     }
 }
 
@@ -138,27 +149,96 @@ impl KeyIndex for DiskTreeIndex {
         val
     }
 
-    /// temporarily we use in-memory vector,
-    /// but further a <disk-io-per-next-call> iterator can be used.
-    fn iter_snapshot(&self) -> KeyIteratorOptions {
+    fn iter_snapshot(&self) -> KeyIteratorOptions<'_> {
+        KeyIteratorOptions::begin(self)
+    }
+
+    /// jammdb cursors only move forward:
+    ///     a reversed scan has to read through the whole range, so it returns all of it,
+    ///     an iterator then reads the range once instead of once per chunk.
+    fn scan(&self, range: &KeyRange, reversed: bool, limit: usize) -> Vec<(ByteVec, LogRecordPtr)> {
+        if range.is_empty() || limit == 0 {
+            return Vec::new();
+        }
         let tx = self
             .tree
-            .tx(true)
-            .expect("Internal error: failed to update on-disk index!");
+            .tx(false)
+            .expect("Internal error: failed to read on-disk index!");
         let index_bucket = tx
             .get_bucket(DISK_TREE_BUCKET_NAME)
+            .expect("Internal error: failed to read on-disk index!");
+
+        // seek to the start, an excluded start is skipped below
+        let seek = match &range.start {
+            Bound::Included(start) | Bound::Excluded(start) => Bound::Included(start.as_slice()),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let items = index_bucket
+            .range((seek, Bound::Unbounded))
+            .map(|kv| (kv.key().to_vec(), kv.kv().value().to_vec()))
+            .skip_while(|(key, _)| !range.contains(key))
+            .take_while(|(key, _)| range.contains(key))
+            .map(|(key, value)| (key, value.into()));
+
+        if reversed {
+            let mut all: Vec<_> = items.collect();
+            all.reverse();
+            all
+        } else {
+            items.take(limit).collect()
+        }
+    }
+
+    fn applied_until(&self) -> Option<IndexCheckpoint> {
+        let tx = self
+            .tree
+            .tx(false)
+            .expect("Internal error: failed to read on-disk index!");
+        let meta_bucket = tx
+            .get_bucket(DISK_TREE_META_BUCKET_NAME)
+            .expect("Internal error: failed to read on-disk index!");
+
+        let checkpoint = meta_bucket
+            .get(DISK_TREE_CHECKPOINT_KEY)
+            .map(|bin| ByteVec::from(bin.kv().value()).into());
+        checkpoint
+    }
+
+    fn mark_applied(&self, checkpoint: IndexCheckpoint) {
+        let tx = self
+            .tree
+            .tx(true)
+            .expect("Internal error: failed to update on-disk index!");
+        let meta_bucket = tx
+            .get_bucket(DISK_TREE_META_BUCKET_NAME)
             .expect("Internal error: failed to update on-disk index!");
 
-        let items: Vec<_> = index_bucket
-            .cursor()
-            .map(|kv| {
-                let key = kv.key().to_vec();
-                let value = kv.kv().value().to_vec().into();
-                (key, value)
-            })
-            .collect();
+        let bin: ByteVec = checkpoint.into();
+        meta_bucket
+            .put(DISK_TREE_CHECKPOINT_KEY, bin)
+            .expect("Internal error: failed to update on-disk index!");
 
-        KeyIteratorOptions::begin(Box::new(items.into_iter()))
+        tx.commit()
+            .expect("Internal error: failed to update on-disk index!");
+    }
+
+    fn clear_applied(&self) {
+        let tx = self
+            .tree
+            .tx(true)
+            .expect("Internal error: failed to update on-disk index!");
+        let meta_bucket = tx
+            .get_bucket(DISK_TREE_META_BUCKET_NAME)
+            .expect("Internal error: failed to update on-disk index!");
+
+        if meta_bucket.get(DISK_TREE_CHECKPOINT_KEY).is_some() {
+            meta_bucket
+                .delete(DISK_TREE_CHECKPOINT_KEY)
+                .expect("Internal error: failed to update on-disk index!");
+        }
+
+        tx.commit()
+            .expect("Internal error: failed to update on-disk index!");
     }
 
     fn deepcopy(&self) -> Box<dyn KeyIndex> {
@@ -203,3 +283,154 @@ impl KeyIndex for DiskTreeIndex {
         Box::new(copied)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, ops::Bound, path::PathBuf};
+
+    use super::DiskTreeIndex;
+    use crate::{
+        definitions::constants::ITER_CHUNK_SIZE,
+        index::{
+            iter::KeyRange,
+            traits::{IndexCheckpoint, KeyIndex},
+        },
+        merge::stats::{FileUsage, StatsCheckpoint},
+        records::log_record::LogRecordPtr,
+    };
+
+    #[test]
+    fn test_reopen() {
+        let dir = PathBuf::from("store/test_disk_tree");
+        fs::create_dir_all(&dir).unwrap();
+        DiskTreeIndex::discard(dir.clone());
+
+        let ptr = LogRecordPtr {
+            file_id: 3,
+            offset: 42,
+        };
+        let checkpoint = IndexCheckpoint {
+            ptr: LogRecordPtr {
+                file_id: 4,
+                offset: 1024,
+            },
+            next_batch_id: 7,
+            next_seq: 99,
+            stats: Some(StatsCheckpoint {
+                files: [(
+                    3,
+                    FileUsage {
+                        total_bytes: 4096,
+                        dead_bytes: 1024,
+                    },
+                )]
+                .into(),
+                expirations: [(b"key".to_vec(), 1_700_000_000_000)].into(),
+            }),
+        };
+        {
+            let index = DiskTreeIndex::new(dir.clone());
+            assert!(index.applied_until().is_none());
+            index.put(b"key".to_vec(), ptr);
+            index.mark_applied(checkpoint.clone());
+        }
+        {
+            let index = DiskTreeIndex::new(dir.clone());
+            assert_eq!(index.get(b"key".to_vec()), Some(ptr));
+            assert_eq!(index.applied_until(), Some(checkpoint));
+            index.clear_applied();
+        }
+        {
+            let index = DiskTreeIndex::new(dir.clone());
+            assert_eq!(index.get(b"key".to_vec()), Some(ptr));
+            assert!(index.applied_until().is_none());
+        }
+
+        DiskTreeIndex::discard(dir.clone());
+        let index = DiskTreeIndex::new(dir.clone());
+        assert!(index.get(b"key".to_vec()).is_none());
+        drop(index);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_reversed_scan() {
+        let dir = PathBuf::from("store/test_disk_tree_scan");
+        fs::create_dir_all(&dir).unwrap();
+        DiskTreeIndex::discard(dir.clone());
+
+        let index = DiskTreeIndex::new(dir.clone());
+        for i in 0..100u64 {
+            let key = format!("{:03}", i).into_bytes();
+            index.put(
+                key,
+                LogRecordPtr {
+                    file_id: 0,
+                    offset: i,
+                },
+            );
+        }
+        let keys = |reversed, limit| -> Vec<String> {
+            index
+                .scan(&KeyRange::prefix(b"0".to_vec()), reversed, limit)
+                .into_iter()
+                .map(|(key, _)| String::from_utf8(key).unwrap())
+                .collect()
+        };
+
+        assert_eq!(keys(false, 3), ["000", "001", "002"]);
+        assert_eq!(keys(true, 3)[..3], ["099", "098", "097"]);
+        assert_eq!(keys(true, 3).len(), 100);
+        assert_eq!(keys(true, 1000).len(), 100);
+        assert!(keys(true, 0).is_empty());
+
+        drop(index);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_reversed_iter() {
+        let dir = PathBuf::from("store/test_disk_tree_rev_iter");
+        fs::create_dir_all(&dir).unwrap();
+        DiskTreeIndex::discard(dir.clone());
+
+        // spans several chunks
+        let count = ITER_CHUNK_SIZE * 2 + 10;
+        let index = DiskTreeIndex::new(dir.clone());
+        let keys: Vec<_> = (0..count)
+            .map(|i| format!("{:05}", i).into_bytes())
+            .collect();
+        for (i, key) in keys.iter().enumerate() {
+            index.put(
+                key.clone(),
+                LogRecordPtr {
+                    file_id: 0,
+                    offset: i as u64,
+                },
+            );
+        }
+
+        let rev: Vec<_> = index
+            .iter_snapshot()
+            .rev()
+            .make()
+            .map(|(key, _)| key)
+            .collect();
+        assert!(rev.iter().eq(keys.iter().rev()));
+
+        let limited: Vec<_> = index
+            .iter_snapshot()
+            .rev()
+            .with_range(Bound::Unbounded, Bound::Excluded(keys[count - 5].clone()))
+            .limit(ITER_CHUNK_SIZE + 1)
+            .make()
+            .map(|(key, _)| key)
+            .collect();
+        assert!(limited
+            .iter()
+            .eq(keys[..count - 5].iter().rev().take(ITER_CHUNK_SIZE + 1)));
+
+        drop(index);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -25,4 +25,12 @@ impl IndexType {
             IndexType::DiskTree => Box::new(DiskTreeIndex::new(dir)),
         }
     }
+
+    /// Drop whatever a persistent index kept from the previous run.
+    pub fn discard_index(&self, dir: PathBuf) {
+        match self {
+            IndexType::BTree | IndexType::Skiplist => {}
+            IndexType::DiskTree => DiskTreeIndex::discard(dir),
+        }
+    }
 }
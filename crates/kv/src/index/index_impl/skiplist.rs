@@ -1,13 +1,19 @@
 use crate::{
     definitions::types::ByteVec,
-    index::{iter::KeyIteratorOptions, traits::KeyIndex},
+    index::{
+        iter::{KeyIteratorOptions, KeyRange},
+        traits::KeyIndex,
+    },
     records::log_record::LogRecordPtr,
 };
-use crossbeam_skiplist::SkipMap;
-use std::sync::Arc;
+use crossbeam_skiplist::{map::Entry, SkipMap};
+use parking_lot::Mutex;
+use std::{mem, sync::Arc};
 
 pub struct SkiplistIndex {
-    list: Arc<SkipMap<ByteVec, LogRecordPtr>>,
+    /// pointers are replaced in place: `SkipMap::insert` removes the entry of a key before adding the new one,
+    ///     a concurrent `get` in between would not find the key at all
+    list: Arc<SkipMap<ByteVec, Mutex<LogRecordPtr>>>,
 }
 
 impl SkiplistIndex {
@@ -17,40 +23,57 @@ impl SkiplistIndex {
         }
     }
 
-    pub fn list_cloned(&self) -> SkipMap<ByteVec, LogRecordPtr> {
+    pub fn list_cloned(&self) -> SkipMap<ByteVec, Mutex<LogRecordPtr>> {
         self.list
             .iter()
-            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .map(|entry| (entry.key().clone(), Mutex::new(*entry.value().lock())))
             .collect()
     }
 }
 
 impl KeyIndex for SkiplistIndex {
     fn put(&self, key: ByteVec, ptr: LogRecordPtr) -> Option<LogRecordPtr> {
-        let prev_value = self.list.get(&key).map(|item| item.value().clone());
-        self.list.insert(key, ptr);
-        prev_value
+        if let Some(item) = self.list.get(&key) {
+            let mut value = item.value().lock();
+            if !item.is_removed() {
+                return Some(mem::replace(&mut value, ptr));
+            }
+        }
+        self.list.insert(key, Mutex::new(ptr));
+        None
     }
 
     fn delete(&self, key: ByteVec) -> Option<LogRecordPtr> {
-        self.list.remove(&key).map(|item| item.value().clone())
+        self.list.remove(&key).map(|item| *item.value().lock())
     }
 
     fn get(&self, key: ByteVec) -> Option<LogRecordPtr> {
-        self.list.get(&key).map(|item| item.value().clone())
+        self.list.get(&key).map(|item| *item.value().lock())
     }
 
-    fn iter_snapshot(&self) -> KeyIteratorOptions {
-        // An ugly workaround
-        let keys: Vec<_> = self.list_cloned().into_iter().collect();
-        let items_iter = Box::new(keys.into_iter());
-        KeyIteratorOptions::begin(items_iter)
+    fn iter_snapshot(&self) -> KeyIteratorOptions<'_> {
+        KeyIteratorOptions::begin(self)
+    }
+
+    fn scan(&self, range: &KeyRange, reversed: bool, limit: usize) -> Vec<(ByteVec, LogRecordPtr)> {
+        if range.is_empty() {
+            return Vec::new();
+        }
+        let items = self.list.range(range.clone());
+        let clone = |entry: Entry<ByteVec, Mutex<LogRecordPtr>>| {
+            (entry.key().clone(), *entry.value().lock())
+        };
+        if reversed {
+            items.rev().take(limit).map(clone).collect()
+        } else {
+            items.take(limit).map(clone).collect()
+        }
     }
 
     fn deepcopy(&self) -> Box<dyn KeyIndex> {
         // Why the heck can a collection not support `clone`?
         // An ugly workaround
-        let list: SkipMap<ByteVec, LogRecordPtr> = self.list_cloned();
+        let list: SkipMap<ByteVec, Mutex<LogRecordPtr>> = self.list_cloned();
 
         Box::new(Self {
             list: Arc::new(list),
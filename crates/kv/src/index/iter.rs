@@ -1,40 +1,213 @@
-use std::sync::Arc;
+use std::{
+    collections::VecDeque,
+    fmt::{self, Debug},
+    ops::{Bound, RangeBounds},
+    sync::Arc,
+};
 
 use crate::{
-    definitions::types::{ByteVec, KvBytes},
+    definitions::{
+        constants::ITER_CHUNK_SIZE,
+        types::{ByteVec, KvBytes},
+    },
+    errors::Errors,
+    index::traits::KeyIndex,
     records::log_record::LogRecordPtr,
-    store::store::Store,
+    store::{snapshot::Snapshot, store::Store},
 };
-use either::Either;
 use parking_lot::RwLock;
 
 /*
 
     Note:
-    If unordered index is to be further supported,
-    `KeyIterator` should then be an `enum`,
-    where method `find` should implement
-    - linear search (unordered), or
-    - binary search (ordered)
-    on demand.
+    Key iterators are cursors over the index, not copies of it:
+    they fetch `ITER_CHUNK_SIZE` keys at a time with `KeyIndex::scan`,
+    and resume after the last key fetched.
+    So they see writes made while iterating,
+    use `Store::snapshot` for a frozen view.
+    Except a reversed scan of the on-disk tree, which fetches its whole range at once.
 
 */
 
-pub struct KeyIteratorOptions {
-    begin: Box<dyn DoubleEndedIterator<Item = (ByteVec, LogRecordPtr)>>,
+/// Bounds of a key scan.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeyRange {
+    pub(crate) start: Bound<ByteVec>,
+    pub(crate) end: Bound<ByteVec>,
+}
+
+impl KeyRange {
+    pub fn all() -> Self {
+        Self {
+            start: Bound::Unbounded,
+            end: Bound::Unbounded,
+        }
+    }
+
+    /// Every key starting with `prefix`
+    pub fn prefix(prefix: ByteVec) -> Self {
+        // the first key after all keys with the prefix:
+        //     drop trailing 0xff, then increment the last byte
+        let mut end = prefix.clone();
+        while end.last() == Some(&u8::MAX) {
+            end.pop();
+        }
+        let end = match end.last_mut() {
+            Some(last) => {
+                *last += 1;
+                Bound::Excluded(end)
+            }
+            None => Bound::Unbounded,
+        };
+        Self {
+            start: Bound::Included(prefix),
+            end,
+        }
+    }
+
+    /// Keys between `start` and `end`
+    pub fn between(start: Bound<ByteVec>, end: Bound<ByteVec>) -> Self {
+        Self { start, end }
+    }
+
+    /// Keys in both ranges
+    pub fn intersect(self, other: Self) -> Self {
+        let start = match (self.start, other.start) {
+            (Bound::Unbounded, bound) | (bound, Bound::Unbounded) => bound,
+            (a, b) => {
+                // the larger start, exclusive wins a tie
+                let (a_key, b_key) = (bound_key(&a), bound_key(&b));
+                if a_key > b_key || (a_key == b_key && matches!(a, Bound::Excluded(_))) {
+                    a
+                } else {
+                    b
+                }
+            }
+        };
+        let end = match (self.end, other.end) {
+            (Bound::Unbounded, bound) | (bound, Bound::Unbounded) => bound,
+            (a, b) => {
+                // the smaller end, exclusive wins a tie
+                let (a_key, b_key) = (bound_key(&a), bound_key(&b));
+                if a_key < b_key || (a_key == b_key && matches!(a, Bound::Excluded(_))) {
+                    a
+                } else {
+                    b
+                }
+            }
+        };
+        Self { start, end }
+    }
+
+    /// Whether no key can fall into the range
+    pub fn is_empty(&self) -> bool {
+        match (&self.start, &self.end) {
+            (Bound::Included(start), Bound::Included(end)) => start > end,
+            (Bound::Included(start), Bound::Excluded(end))
+            | (Bound::Excluded(start), Bound::Included(end))
+            | (Bound::Excluded(start), Bound::Excluded(end)) => start >= end,
+            _ => false,
+        }
+    }
+
+    /// The rest of the range once `key` has been returned by a scan.
+    fn after(&self, key: ByteVec, reversed: bool) -> Self {
+        if reversed {
+            Self {
+                start: self.start.clone(),
+                end: Bound::Excluded(key),
+            }
+        } else {
+            Self {
+                start: Bound::Excluded(key),
+                end: self.end.clone(),
+            }
+        }
+    }
+
+    /// The part of the range from `key` on, in scan order.
+    fn from(&self, key: ByteVec, reversed: bool) -> Self {
+        // never widen the range
+        let mut range = self.clone();
+        if reversed {
+            if self.end_contains(&key) {
+                range.end = Bound::Included(key);
+            }
+        } else if self.start_contains(&key) {
+            range.start = Bound::Included(key);
+        }
+        range
+    }
+
+    fn start_contains(&self, key: &ByteVec) -> bool {
+        match &self.start {
+            Bound::Included(start) => key >= start,
+            Bound::Excluded(start) => key > start,
+            Bound::Unbounded => true,
+        }
+    }
+
+    fn end_contains(&self, key: &ByteVec) -> bool {
+        match &self.end {
+            Bound::Included(end) => key <= end,
+            Bound::Excluded(end) => key < end,
+            Bound::Unbounded => true,
+        }
+    }
+}
+
+fn bound_key(bound: &Bound<ByteVec>) -> Option<&ByteVec> {
+    match bound {
+        Bound::Included(key) | Bound::Excluded(key) => Some(key),
+        Bound::Unbounded => None,
+    }
+}
+
+impl RangeBounds<ByteVec> for KeyRange {
+    fn start_bound(&self) -> Bound<&ByteVec> {
+        self.start.as_ref()
+    }
+
+    fn end_bound(&self) -> Bound<&ByteVec> {
+        self.end.as_ref()
+    }
+}
+
+/// For `KeyIndex::scan` implementations: up to `limit` items from either end of `items`.
+pub(crate) fn take_chunk<'a>(
+    items: impl DoubleEndedIterator<Item = (&'a ByteVec, &'a LogRecordPtr)>,
+    reversed: bool,
+    limit: usize,
+) -> Vec<(ByteVec, LogRecordPtr)> {
+    let clone = |(key, ptr): (&ByteVec, &LogRecordPtr)| (key.clone(), *ptr);
+    if reversed {
+        items.rev().take(limit).map(clone).collect()
+    } else {
+        items.take(limit).map(clone).collect()
+    }
+}
+
+pub struct KeyIteratorOptions<'a> {
+    index: &'a dyn KeyIndex,
 
     /// options: reversed
     reversed: bool,
     /// options: prefix
     prefix: Option<ByteVec>,
+    /// options: range
+    range: Option<KeyRange>,
+    /// options: limit
+    limit: Option<usize>,
 }
 
-impl KeyIteratorOptions {
-    pub fn begin(begin: Box<dyn DoubleEndedIterator<Item = (ByteVec, LogRecordPtr)>>) -> Self {
+impl<'a> KeyIteratorOptions<'a> {
+    pub fn begin(index: &'a dyn KeyIndex) -> Self {
         Self {
-            begin,
+            index,
             reversed: false,
             prefix: None,
+            range: None,
+            limit: None,
         }
     }
 
@@ -55,53 +228,105 @@ impl KeyIteratorOptions {
         self
     }
 
-    pub(crate) fn make(mut self) -> KeyIterator {
-        if let Some(prefix) = self.prefix {
-            self.begin = Box::new(self.begin.filter(move |(key, _)| {
-                prefix.len() <= key.len() && prefix.as_slice() == &key[0..prefix.len()]
-            }));
+    /// Only keys between `start` and `end`, combined with a prefix if both are set.
+    pub fn with_range(mut self, start: Bound<ByteVec>, end: Bound<ByteVec>) -> Self {
+        match self.range {
+            Some(_) => {
+                panic!("Range already exist! Should not set range multiple times.");
+            }
+            None => {
+                self.range = Some(KeyRange::between(start, end));
+            }
         }
-        let items = if self.reversed {
-            self.begin.rev().collect()
-        } else {
-            self.begin.collect()
+        self
+    }
+
+    /// At most `n` keys, counted again after each `rewind` or `find`.
+    pub fn limit(mut self, n: usize) -> Self {
+        self.limit = Some(n);
+        self
+    }
+
+    pub(crate) fn make(self) -> KeyIterator<'a> {
+        let range = match self.prefix {
+            Some(prefix) => KeyRange::prefix(prefix),
+            None => KeyRange::all(),
+        };
+        let range = match self.range {
+            Some(bounds) => range.intersect(bounds),
+            None => range,
         };
 
         KeyIterator {
-            items,
-            cur_index: 0,
+            index: self.index,
+            reversed: self.reversed,
+            cursor: range.clone(),
+            range,
+            buffer: VecDeque::new(),
+            exhausted: false,
+            limit: self.limit,
+            returned: 0,
         }
     }
 }
 
 pub struct KvIteratorOptions<'a> {
-    key_options: KeyIteratorOptions,
+    key_options: KeyIteratorOptions<'a>,
     store: &'a Store,
+    /// values are read through the key pointers of this snapshot instead of the index
+    snapshot: Option<&'a Snapshot<'a>>,
 }
 
 impl<'a> KvIteratorOptions<'a> {
-    pub fn begin(key_options: KeyIteratorOptions, store: &'a Store) -> Self {
-        Self { key_options, store }
+    pub fn begin(key_options: KeyIteratorOptions<'a>, store: &'a Store) -> Self {
+        Self {
+            key_options,
+            store,
+            snapshot: None,
+        }
     }
 
     pub fn rev(self) -> Self {
         Self {
             key_options: self.key_options.rev(),
-            store: self.store,
+            ..self
         }
     }
 
     pub fn with_key_prefix(self, prefix: ByteVec) -> Self {
         Self {
             key_options: self.key_options.with_prefix(prefix),
-            store: self.store,
+            ..self
+        }
+    }
+
+    pub fn with_key_range(self, start: Bound<ByteVec>, end: Bound<ByteVec>) -> Self {
+        Self {
+            key_options: self.key_options.with_range(start, end),
+            ..self
+        }
+    }
+
+    /// At most `n` pairs, expired keys skipped do not count.
+    pub fn limit(self, n: usize) -> Self {
+        Self {
+            key_options: self.key_options.limit(n),
+            ..self
+        }
+    }
+
+    pub(crate) fn in_snapshot(self, snapshot: &'a Snapshot<'a>) -> Self {
+        Self {
+            snapshot: Some(snapshot),
+            ..self
         }
     }
 
     pub fn make(self) -> KvIterator<'a> {
         KvIterator {
             key_iter: Arc::new(RwLock::new(self.key_options.make())),
-            store: &self.store,
+            store: self.store,
+            snapshot: self.snapshot,
         }
     }
 }
@@ -110,43 +335,91 @@ impl<'a> KvIteratorOptions<'a> {
     Iterators
 */
 
-#[derive(Debug)]
-pub struct KeyIterator {
-    /// sorted: (key, value_ptr)
-    pub(crate) items: Vec<(ByteVec, LogRecordPtr)>,
-    pub(crate) cur_index: usize,
+pub struct KeyIterator<'a> {
+    index: &'a dyn KeyIndex,
+    reversed: bool,
+    /// everything this iterator may return
+    range: KeyRange,
+    /// what is left of `range` after the keys fetched so far
+    cursor: KeyRange,
+    /// fetched, not yet returned: (key, value_ptr)
+    buffer: VecDeque<(ByteVec, LogRecordPtr)>,
+    /// the last fetch came back short, nothing is left in `cursor`
+    exhausted: bool,
+    /// keys to return at most since the last seek
+    limit: Option<usize>,
+    returned: usize,
 }
 
-impl KeyIterator {
+impl KeyIterator<'_> {
     pub fn rewind(&mut self) {
-        self.cur_index = 0
+        self.seek(self.range.clone());
     }
 
+    /// Continue from `key`, or from the first key after it in iteration order.
     pub fn find(&mut self, key: ByteVec) {
-        let cur_index: Either<usize, usize> =
-            self.items.binary_search_by(|(k, _)| k.cmp(&key)).into();
+        self.seek(self.range.from(key, self.reversed));
+    }
+
+    fn seek(&mut self, cursor: KeyRange) {
+        self.cursor = cursor;
+        self.buffer.clear();
+        self.exhausted = false;
+        self.returned = 0;
+    }
 
-        self.cur_index = cur_index.into_inner();
+    /// A key returned by `next` does not count towards the limit.
+    pub(crate) fn uncount(&mut self) {
+        self.returned -= 1;
+    }
+
+    fn fetch(&mut self) {
+        // only fetched once the buffer is empty: fetch no more than may still be returned
+        let want = match self.limit {
+            Some(limit) => ITER_CHUNK_SIZE.min(limit - self.returned),
+            None => ITER_CHUNK_SIZE,
+        };
+        let chunk = self.index.scan(&self.cursor, self.reversed, want);
+        self.exhausted = chunk.len() < want;
+        if let Some((key, _)) = chunk.last() {
+            self.cursor = self.cursor.after(key.clone(), self.reversed);
+        }
+        self.buffer.extend(chunk);
     }
 }
 
-impl Iterator for KeyIterator {
+impl Debug for KeyIterator<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyIterator")
+            .field("reversed", &self.reversed)
+            .field("range", &self.range)
+            .field("cursor", &self.cursor)
+            .field("buffered", &self.buffer.len())
+            .field("exhausted", &self.exhausted)
+            .finish()
+    }
+}
+
+impl Iterator for KeyIterator<'_> {
     type Item = (ByteVec, LogRecordPtr);
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.cur_index < self.items.len() {
-            let res = self.items[self.cur_index].clone();
-            self.cur_index += 1;
-            Some(res)
-        } else {
-            None
+        if self.limit.is_some_and(|limit| self.returned >= limit) {
+            return None;
         }
+        if self.buffer.is_empty() && !self.exhausted {
+            self.fetch();
+        }
+        let item = self.buffer.pop_front()?;
+        self.returned += 1;
+        Some(item)
     }
 }
 
 pub struct KvIterator<'a> {
-    key_iter: Arc<RwLock<KeyIterator>>,
+    key_iter: Arc<RwLock<KeyIterator<'a>>>,
     store: &'a Store,
+    snapshot: Option<&'a Snapshot<'a>>,
 }
 
 impl KvIterator<'_> {
@@ -164,28 +437,40 @@ impl Iterator for KvIterator<'_> {
 
     fn next(&mut self) -> Option<Self::Item> {
         let mut key_iter = self.key_iter.write();
-        let res = key_iter.next();
-        res.map(|(key, _)| {
-            let value = self
-                .store
-                .get(key.clone().into())
-                // internal error, panic
-                .expect("Key not found while iterating index! Internal invariant broken.");
-
-            KvBytes {
+        while let Some((key, ptr)) = key_iter.next() {
+            let value = match self.snapshot {
+                Some(snapshot) => match snapshot.get_at(ptr) {
+                    Ok(value) => value,
+                    // expired as of the snapshot
+                    Err(Errors::KeyNotFound) => {
+                        key_iter.uncount();
+                        continue;
+                    }
+                    Err(e) => panic!("Failed to read a snapshot value while iterating: {}", e),
+                },
+                None => match self.store.get(key.clone().into()) {
+                    Ok(value) => value,
+                    // deleted or expired since the keys were listed
+                    Err(Errors::KeyNotFound) => {
+                        key_iter.uncount();
+                        continue;
+                    }
+                    Err(e) => panic!("Failed to read a value while iterating: {}", e),
+                },
+            };
+
+            return Some(KvBytes {
                 key: key.into(),
                 value,
-            }
-        })
+            });
+        }
+        None
     }
 }
 
 impl Store {
     pub fn iter_options<'a>(&'a self) -> KvIteratorOptions<'a> {
-        KvIteratorOptions {
-            key_options: self.index.iter_snapshot(),
-            store: self,
-        }
+        KvIteratorOptions::begin(self.index.iter_snapshot(), self)
     }
 }
 
@@ -194,9 +479,191 @@ impl Store {
 */
 #[cfg(test)]
 mod tests {
+    use std::{fs, ops::Bound, thread};
+
     use bytes::Bytes;
 
-    use crate::{definitions::types::KvBytes, store::utils::TempStore};
+    use crate::{
+        definitions::{constants::ITER_CHUNK_SIZE, types::KvBytes},
+        index::index_impl::IndexType,
+        records::log_record::LogRecordPtr,
+        store::utils::TempStore,
+    };
+
+    use super::{KeyIteratorOptions, KeyRange};
+
+    #[test]
+    fn key_range_prefix_test() {
+        let range = KeyRange::prefix(b"ab".to_vec());
+        assert!(range.start_contains(&b"ab".to_vec()));
+        assert!(range.end_contains(&b"ab\xff\xff".to_vec()));
+        assert!(!range.end_contains(&b"ac".to_vec()));
+
+        // trailing 0xff carries over
+        let range = KeyRange::prefix(b"a\xff".to_vec());
+        assert_eq!(range.end, std::ops::Bound::Excluded(b"b".to_vec()));
+        let range = KeyRange::prefix(b"\xff\xff".to_vec());
+        assert_eq!(range.end, std::ops::Bound::Unbounded);
+        assert!(!range.is_empty());
+    }
+
+    #[test]
+    fn key_range_limit_test() {
+        let dir = "store/test_45";
+        // remove if exist
+        fs::remove_dir_all(dir);
+        fs::create_dir_all(dir).unwrap();
+
+        let keys: Vec<_> = (0..1000)
+            .map(|i| format!("{:04}", i).into_bytes())
+            .collect();
+        for index_type in [IndexType::BTree, IndexType::Skiplist, IndexType::DiskTree] {
+            let index = index_type.create_index(dir.into());
+            for (i, key) in keys.iter().enumerate() {
+                let ptr = LogRecordPtr {
+                    file_id: 0,
+                    offset: i as u64,
+                };
+                index.put(key.clone(), ptr);
+            }
+            let scan = |options: KeyIteratorOptions| -> Vec<_> {
+                options.make().map(|(key, _)| key).collect()
+            };
+
+            let included = scan(index.iter_snapshot().with_range(
+                Bound::Included(b"0100".to_vec()),
+                Bound::Included(b"0599".to_vec()),
+            ));
+            assert_eq!(included, keys[100..600], "{:?}", index_type);
+            let excluded = scan(index.iter_snapshot().rev().with_range(
+                Bound::Excluded(b"0100".to_vec()),
+                Bound::Excluded(b"0599".to_vec()),
+            ));
+            assert!(
+                excluded.iter().eq(keys[101..599].iter().rev()),
+                "{:?}",
+                index_type
+            );
+            // bounds between keys
+            let between = scan(
+                index
+                    .iter_snapshot()
+                    .with_range(Bound::Excluded(b"0099x".to_vec()), Bound::Unbounded),
+            );
+            assert_eq!(between, keys[100..], "{:?}", index_type);
+
+            // combined with a prefix, the narrower bound wins
+            let combined = scan(
+                index
+                    .iter_snapshot()
+                    .with_prefix(b"05".to_vec())
+                    .with_range(Bound::Included(b"0550".to_vec()), Bound::Unbounded),
+            );
+            assert_eq!(combined, keys[550..600], "{:?}", index_type);
+            let empty = scan(
+                index
+                    .iter_snapshot()
+                    .with_prefix(b"05".to_vec())
+                    .with_range(Bound::Unbounded, Bound::Excluded(b"0500".to_vec())),
+            );
+            assert!(empty.is_empty(), "{:?}", index_type);
+
+            let limited = scan(index.iter_snapshot().rev().limit(3));
+            assert_eq!(
+                limited,
+                keys[997..].iter().rev().cloned().collect::<Vec<_>>()
+            );
+            let mut iter = index
+                .iter_snapshot()
+                .with_range(Bound::Included(b"0200".to_vec()), Bound::Unbounded)
+                .limit(300)
+                .make();
+            assert_eq!(iter.by_ref().count(), 300, "{:?}", index_type);
+            // the limit counts again after a seek
+            iter.find(b"0900".to_vec());
+            assert_eq!(iter.count(), 100, "{:?}", index_type);
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn kv_iter_limit_test() {
+        let (_raii, store) = TempStore::init(46);
+        for i in 0..10 {
+            let key = format!("{}", i);
+            if i % 2 == 0 {
+                // already expired
+                store.put_expiring(key.into(), "gone".into(), 1).unwrap();
+            } else {
+                store.put(key.into(), "kept".into()).unwrap();
+            }
+        }
+
+        let keys: Vec<Bytes> = store
+            .iter_options()
+            .with_key_range(Bound::Included(b"2".to_vec()), Bound::Unbounded)
+            .limit(3)
+            .make()
+            .map(|kv| kv.key)
+            .collect();
+        assert_eq!(keys, vec!["3", "5", "7"]);
+    }
+
+    #[test]
+    fn key_iter_backends_test() {
+        let dir = "store/test_44";
+        // remove if exist
+        fs::remove_dir_all(dir);
+        fs::create_dir_all(dir).unwrap();
+
+        // spans several chunks
+        let count = ITER_CHUNK_SIZE * 3 + 7;
+        let keys: Vec<_> = (0..count)
+            .map(|i| format!("{:05}", i).into_bytes())
+            .collect();
+        for index_type in [IndexType::BTree, IndexType::Skiplist, IndexType::DiskTree] {
+            let index = index_type.create_index(dir.into());
+            for (i, key) in keys.iter().enumerate() {
+                let ptr = LogRecordPtr {
+                    file_id: 0,
+                    offset: i as u64,
+                };
+                index.put(key.clone(), ptr);
+            }
+
+            let all: Vec<_> = index.iter_snapshot().make().map(|(key, _)| key).collect();
+            assert_eq!(all, keys, "{:?}", index_type);
+            let rev: Vec<_> = index
+                .iter_snapshot()
+                .rev()
+                .make()
+                .map(|(key, _)| key)
+                .collect();
+            assert!(rev.iter().eq(keys.iter().rev()), "{:?}", index_type);
+
+            let prefixed: Vec<_> = index
+                .iter_snapshot()
+                .with_prefix(b"002".to_vec())
+                .make()
+                .map(|(key, _)| key)
+                .collect();
+            assert_eq!(prefixed, keys[200..300], "{:?}", index_type);
+
+            let mut iter = index.iter_snapshot().rev().make();
+            iter.find(b"00100".to_vec());
+            assert_eq!(iter.next().unwrap().0, b"00100");
+            assert_eq!(iter.next().unwrap().0, b"00099");
+            iter.rewind();
+            assert_eq!(iter.next().unwrap().0, keys[count - 1]);
+
+            // writes behind the cursor are not seen, writes ahead are
+            let mut iter = index.iter_snapshot().make();
+            assert_eq!(iter.next().unwrap().0, keys[0]);
+            index.delete(keys[count - 1].clone());
+            assert_eq!(iter.count(), count - 2, "{:?}", index_type);
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
 
     #[test]
     fn kv_iter_test() {
@@ -271,4 +738,30 @@ mod tests {
         assert_eq!(iter.next().unwrap().key, Bytes::from(b"307".as_slice()));
         assert_eq!(iter.next().unwrap().key, Bytes::from(b"306".as_slice()));
     }
+
+    #[test]
+    fn kv_iter_concurrent_delete_test() {
+        let (_raii, store) = TempStore::init(64);
+        for i in 0..500 {
+            let key = format!("{:03}", i);
+            store.put(key.into(), "value".into()).unwrap();
+        }
+
+        let mut iter = store.iter_options().make();
+        assert_eq!(iter.next().unwrap().key, Bytes::from(b"000".as_slice()));
+        // keys fetched by the iterator already, and keys still to fetch
+        thread::scope(|s| {
+            s.spawn(|| {
+                for i in (2..500).step_by(2) {
+                    store.delete(format!("{:03}", i).into()).unwrap();
+                }
+            });
+        });
+        let keys: Vec<_> = iter.map(|KvBytes { key, .. }| key).collect();
+        let expected: Vec<_> = (1..500)
+            .step_by(2)
+            .map(|i| Bytes::from(format!("{:03}", i)))
+            .collect();
+        assert_eq!(keys, expected);
+    }
 }
@@ -1,15 +1,74 @@
-use crate::{definitions::types::ByteVec, records::log_record::LogRecordPtr};
+use crate::{
+    definitions::types::ByteVec, merge::stats::StatsCheckpoint, records::log_record::LogRecordPtr,
+};
 
-use super::iter::{KeyIterator, KeyIteratorOptions};
+use super::iter::{KeyIteratorOptions, KeyRange};
 
 pub trait KeyIndex: Sync + Send {
     fn put(&self, key: ByteVec, ptr: LogRecordPtr) -> Option<LogRecordPtr>;
     fn delete(&self, key: ByteVec) -> Option<LogRecordPtr>;
     fn get(&self, key: ByteVec) -> Option<LogRecordPtr>;
-    fn iter_snapshot(&self) -> KeyIteratorOptions;
+    /// A lazy cursor over the keys, see `KeyIterator`.
+    fn iter_snapshot(&self) -> KeyIteratorOptions<'_>;
+    /// Keys in `range` in ascending order, descending if `reversed`;
+    ///     at least `limit` of them if there are as many, more only if cheaper for the index.
+    fn scan(&self, range: &KeyRange, reversed: bool, limit: usize) -> Vec<(ByteVec, LogRecordPtr)>;
+    /// Persistent indexes only: position in the log up to which this index is up to date.
+    fn applied_until(&self) -> Option<IndexCheckpoint> {
+        None
+    }
+    /// Persistent indexes only: remember the position returned by `applied_until`.
+    fn mark_applied(&self, _checkpoint: IndexCheckpoint) {}
+    /// Persistent indexes only: forget the position, until it is marked applied again.
+    fn clear_applied(&self) {}
     // object safe: size of everything in parameter/return value should be known at compile time
     // do not use `Self` here
     fn deepcopy(&self) -> Box<dyn KeyIndex>;
 }
 
 pub trait KvIterator: Sync + Send {}
+
+/// Everything a persistent index needs to resume replaying the log.
+#[derive(Clone, Debug, PartialEq)]
+pub struct IndexCheckpoint {
+    /// End of the last record applied, i.e. where replay starts.
+    pub(crate) ptr: LogRecordPtr,
+    pub(crate) next_batch_id: usize,
+    /// 0 for a checkpoint saved before records were stamped
+    pub(crate) next_seq: u64,
+    /// `None` for a checkpoint saved before merge statistics were
+    pub(crate) stats: Option<StatsCheckpoint>,
+}
+
+impl From<IndexCheckpoint> for ByteVec {
+    /// used for storing it in disk
+    fn from(value: IndexCheckpoint) -> Self {
+        let mut res: ByteVec = value.ptr.into();
+        res.extend_from_slice(&(value.next_batch_id as u64).to_be_bytes());
+        res.extend_from_slice(&value.next_seq.to_be_bytes());
+        if let Some(stats) = value.stats {
+            stats.encode_into(&mut res);
+        }
+        res
+    }
+}
+
+impl From<ByteVec> for IndexCheckpoint {
+    fn from(value: ByteVec) -> Self {
+        let next_batch_id = u64::from_be_bytes([
+            value[12], value[13], value[14], value[15], value[16], value[17], value[18], value[19],
+        ]) as usize;
+        let next_seq = match value.get(20..28) {
+            Some(bin) => u64::from_be_bytes(bin.try_into().expect("slice of 8 bytes")),
+            None => 0,
+        };
+        let stats = value.get(28..).and_then(StatsCheckpoint::decode_from);
+
+        Self {
+            ptr: value[..12].to_vec().into(),
+            next_batch_id,
+            next_seq,
+            stats,
+        }
+    }
+}
@@ -0,0 +1,404 @@
+/*
+    Encryption at rest:
+    every record is sealed on its own with AES-256-GCM, into a frame
+        |sealed size|nonce|ciphertext|tag|
+    whose ciphertext is the record exactly as it is stored without encryption.
+    The nonce is drawn at random for every frame, 96 bits of it:
+        merge writes its files under ids of a store of its own before renaming them,
+        so nothing derived from file id and offset is unique across files.
+    The offset of the frame in its file is authenticated along with it,
+        frames can not be moved around within a file.
+    The file is not: a frame copied to the same offset of another file of the store still opens.
+        Merge seals its files before they are renamed and their headers rewritten,
+        and files upgraded by `Store::migrate` were sealed before they had a header,
+        so neither file id nor header is known for certain when a frame is sealed.
+
+    A store keeps a sealed check value in `KEY_CHECK_FILE_NAME`,
+        so a wrong key fails on open, not as a broken record later.
+*/
+
+use std::{env, fmt, fs, path::PathBuf, sync::Arc};
+
+use aes_gcm::{
+    aead::{rand_core::RngCore, Aead, KeyInit, OsRng, Payload},
+    Aes256Gcm, Key, Nonce,
+};
+use log::error;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    definitions::{
+        constants::{get_prefix_numbers, KEY_CHECK_FILE_NAME},
+        types::ByteVec,
+    },
+    errors::{Errors, Result},
+};
+
+use super::traits::IoLayer;
+
+const KEY_CHECK_VALUE: &[u8] = b"kv store key check";
+
+/// `[store.encryption]`, off unless a key is given.
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EncryptionConfig {
+    /// 32 bytes, hex encoded
+    pub(crate) key: Option<String>,
+    /// environment variable holding the key, if `key` is not set
+    pub(crate) key_env: Option<String>,
+}
+
+impl fmt::Debug for EncryptionConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EncryptionConfig")
+            .field("key", &self.key.as_ref().map(|_| "<redacted>"))
+            .field("key_env", &self.key_env)
+            .finish()
+    }
+}
+
+impl EncryptionConfig {
+    /// The cipher of the store in `dir`, `None` if it is not encrypted.
+    ///
+    /// Fails with `InvalidEncryptionKey` if the key is malformed,
+    ///     does not match the store, or is missing for an encrypted store.
+    pub fn open_cipher(&self, dir: PathBuf) -> Result<Option<Arc<RecordCipher>>> {
+        let invalid = || Errors::InvalidEncryptionKey { dir: dir.clone() };
+        let key = match (&self.key, &self.key_env) {
+            (Some(key), _) => Some(key.clone()),
+            (None, Some(var)) => Some(env::var(var).map_err(|_| {
+                error!("Encryption key variable {} is not set", var);
+                invalid()
+            })?),
+            (None, None) => None,
+        };
+        let check_path = dir.join(KEY_CHECK_FILE_NAME);
+
+        let Some(key) = key else {
+            if check_path.is_file() {
+                error!("Store {:?} is encrypted, but no key is configured", dir);
+                return Err(invalid());
+            }
+            return Ok(None);
+        };
+        let cipher = RecordCipher::from_hex(&key).ok_or_else(|| {
+            error!("Encryption key must be 32 bytes in hex");
+            invalid()
+        })?;
+
+        if check_path.is_file() {
+            let sealed = fs::read(check_path).map_err(|_| invalid())?;
+            if cipher.open_blob(&sealed).ok().as_deref() != Some(KEY_CHECK_VALUE) {
+                error!("Encryption key does not match store {:?}", dir);
+                return Err(invalid());
+            }
+        } else {
+            if !get_prefix_numbers(dir.clone())?.is_empty() {
+                error!("Store {:?} was written without encryption", dir);
+                return Err(invalid());
+            }
+            fs::write(check_path, cipher.seal_blob(KEY_CHECK_VALUE))
+                .map_err(|_| Errors::FileIoWriteError)?;
+        }
+        Ok(Some(Arc::new(cipher)))
+    }
+}
+
+pub struct RecordCipher {
+    cipher: Aes256Gcm,
+}
+
+impl RecordCipher {
+    pub fn new(key: &[u8; 32]) -> Self {
+        Self {
+            cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key)),
+        }
+    }
+
+    fn from_hex(key: &str) -> Option<Self> {
+        let key = key.trim();
+        if key.len() != 64 {
+            return None;
+        }
+        let mut bytes = [0u8; 32];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(key.get(2 * i..2 * i + 2)?, 16).ok()?;
+        }
+        Some(Self::new(&bytes))
+    }
+
+    pub fn frame_header_length() -> usize {
+        4 /* sealed size */ + Self::nonce_length()
+    }
+
+    pub fn nonce_length() -> usize {
+        12
+    }
+
+    pub fn tag_length() -> usize {
+        16
+    }
+
+    /// Frame of the record `plain`, to be written at `offset`.
+    pub(crate) fn seal_frame(&self, offset: u64, plain: &[u8]) -> ByteVec {
+        let mut nonce = [0u8; 12];
+        OsRng.fill_bytes(&mut nonce);
+        let payload = Payload {
+            msg: plain,
+            aad: &offset.to_be_bytes(),
+        };
+        let sealed = self
+            .cipher
+            .encrypt(Nonce::from_slice(&nonce), payload)
+            .expect("Internal error: failed to encrypt a record");
+
+        let mut frame = (sealed.len() as u32).to_be_bytes().to_vec();
+        frame.extend_from_slice(&nonce);
+        frame.extend_from_slice(&sealed);
+        frame
+    }
+
+    /// Record of the frame at `offset` and the frame size.
+    pub(crate) fn open_frame(&self, io: &dyn IoLayer, offset: u64) -> Result<(ByteVec, u64)> {
+        let mut header = vec![0; Self::frame_header_length()];
+        io.read(&mut header, offset)?;
+        let sealed_size = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+        if sealed_size == 0 {
+            return Err(Errors::Eof);
+        }
+        // a torn or garbled size, not allocated for
+        let frame_end = offset + header.len() as u64 + sealed_size as u64;
+        if frame_end > io.size() {
+            return Err(Errors::Eof);
+        }
+        let nonce = &header[4..];
+
+        let mut sealed = vec![0; sealed_size as usize];
+        io.read(&mut sealed, offset + header.len() as u64)?;
+        // a frame written at another offset fails too
+        let payload = Payload {
+            msg: sealed.as_slice(),
+            aad: &offset.to_be_bytes(),
+        };
+        let plain = self
+            .cipher
+            .decrypt(Nonce::from_slice(nonce), payload)
+            .map_err(|_| {
+                error!("Failed to decrypt record at offset {}", offset);
+                Errors::DecryptionFailure
+            })?;
+        Ok((plain, (header.len() + sealed.len()) as u64))
+    }
+
+    /// |nonce|ciphertext|tag| of a whole file, under a random nonce.
+    pub(crate) fn seal_blob(&self, plain: &[u8]) -> ByteVec {
+        let mut nonce = [0u8; 12];
+        OsRng.fill_bytes(&mut nonce);
+        let mut blob = nonce.to_vec();
+        blob.extend(
+            self.cipher
+                .encrypt(Nonce::from_slice(&nonce), plain)
+                .expect("Internal error: failed to encrypt a file"),
+        );
+        blob
+    }
+
+    pub(crate) fn open_blob(&self, blob: &[u8]) -> Result<ByteVec> {
+        if blob.len() < Self::nonce_length() + Self::tag_length() {
+            return Err(Errors::DecryptionFailure);
+        }
+        let (nonce, sealed) = blob.split_at(Self::nonce_length());
+        self.cipher
+            .decrypt(Nonce::from_slice(nonce), sealed)
+            .map_err(|_| Errors::DecryptionFailure)
+    }
+}
+
+/// A decrypted record, read through the same decoder as a file.
+pub(crate) struct PlainIo {
+    buf: ByteVec,
+}
+
+impl PlainIo {
+    pub(crate) fn new(buf: ByteVec) -> Self {
+        Self { buf }
+    }
+}
+
+impl IoLayer for PlainIo {
+    /// A decrypted record is only ever read
+    fn write(&self, _buf: &[u8]) -> Result<usize> {
+        Err(Errors::FileIoWriteError)
+    }
+
+    fn read(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        let offset = offset as usize;
+        let end = offset + buf.len();
+        if end > self.buf.len() {
+            return Err(Errors::Eof);
+        }
+        buf.copy_from_slice(&self.buf[offset..end]);
+        Ok(buf.len())
+    }
+
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn truncate(&self, _size: u64) -> Result<()> {
+        Err(Errors::FileIoWriteError)
+    }
+
+    fn size(&self) -> u64 {
+        self.buf.len() as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, path::Path};
+
+    use crate::{
+        config::config::{BatchedConfig, Config, FileConfig, StoreConfig},
+        errors::Errors,
+        io::traits::IoLayer,
+        store::store::Store,
+    };
+
+    use super::{EncryptionConfig, PlainIo, RecordCipher};
+
+    const KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+    fn configs(dir: &str, key: Option<&str>) -> (StoreConfig, FileConfig, BatchedConfig) {
+        let (mut store_config, file_config, batched_config) =
+            Config::from_toml("config.toml".into());
+        store_config.dir = dir.into();
+        store_config.encryption = EncryptionConfig {
+            key: key.map(String::from),
+            key_env: None,
+        };
+        (store_config, file_config, batched_config)
+    }
+
+    fn open(dir: &str, key: Option<&str>) -> crate::errors::Result<Store> {
+        let (store_config, file_config, batched_config) = configs(dir, key);
+        Store::open(store_config, file_config, batched_config)
+    }
+
+    /// Whether any file under `dir` contains `needle`
+    fn leaks(dir: &Path, needle: &[u8]) -> bool {
+        fs::read_dir(dir).unwrap().any(|entry| {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                return leaks(&path, needle);
+            }
+            let bin = fs::read(path).unwrap();
+            bin.windows(needle.len()).any(|window| window == needle)
+        })
+    }
+
+    #[test]
+    fn test_frame_nonce() {
+        let cipher = RecordCipher::new(&[7; 32]);
+        let nonce_of = |frame: &[u8]| frame[4..RecordCipher::frame_header_length()].to_vec();
+
+        // every file starts at offset 0, its frames must not share a nonce
+        let first = cipher.seal_frame(0, b"record");
+        let second = cipher.seal_frame(0, b"record");
+        assert_ne!(nonce_of(&first), nonce_of(&second));
+        assert_ne!(first, second);
+
+        let (plain, size) = cipher.open_frame(&PlainIo::new(first.clone()), 0).unwrap();
+        assert_eq!(plain, b"record");
+        assert_eq!(size, first.len() as u64);
+
+        // nothing is written to a decrypted record
+        let io = PlainIo::new(plain);
+        assert_eq!(io.write(b"more"), Err(Errors::FileIoWriteError));
+        assert_eq!(io.truncate(0), Err(Errors::FileIoWriteError));
+
+        // a frame moved to another offset does not open
+        let mut moved = vec![0; 8];
+        moved.extend_from_slice(&first);
+        assert_eq!(
+            cipher.open_frame(&PlainIo::new(moved), 8),
+            Err(Errors::DecryptionFailure)
+        );
+
+        // a garbled size reads as a frame cut short, nothing is allocated for it
+        let mut garbled = first.clone();
+        garbled[..4].copy_from_slice(&[0xff; 4]);
+        assert_eq!(
+            cipher.open_frame(&PlainIo::new(garbled), 0),
+            Err(Errors::Eof)
+        );
+    }
+
+    #[test]
+    fn test_encrypted_store() {
+        let dir = "store/test_48";
+        let backup_dir = "store/test_48_backup";
+        // remove if exist
+        fs::remove_dir_all(dir);
+        fs::remove_dir_all(backup_dir);
+
+        let store = open(dir, Some(KEY)).unwrap();
+        for i in 0..100 {
+            let key = format!("secret-key-{}", i);
+            let val = format!("secret-value-{}", english_numbers::convert_all_fmt(i));
+            store.put(key.into(), val.into()).unwrap();
+        }
+        for i in 0..50 {
+            store.delete(format!("secret-key-{}", i).into()).unwrap();
+        }
+        store.merge().unwrap();
+        store.blocking_copy_to(backup_dir.into()).unwrap();
+        drop(store);
+        assert!(!leaks(Path::new(dir), b"secret"));
+        assert!(!leaks(Path::new(backup_dir), b"secret"));
+
+        // wrong or missing key
+        let wrong_key = KEY.replace("1f", "ff");
+        assert!(matches!(
+            open(dir, Some(&wrong_key)),
+            Err(Errors::InvalidEncryptionKey { .. })
+        ));
+        assert!(matches!(
+            open(backup_dir, None),
+            Err(Errors::InvalidEncryptionKey { .. })
+        ));
+        assert!(matches!(
+            open(dir, Some("not a key")),
+            Err(Errors::InvalidEncryptionKey { .. })
+        ));
+
+        // key from the environment
+        env::set_var("KV_TEST_48_KEY", KEY);
+        for dir in [dir, backup_dir] {
+            let (mut store_config, file_config, batched_config) = configs(dir, None);
+            store_config.encryption.key_env = Some("KV_TEST_48_KEY".into());
+            let store = Store::open(store_config, file_config, batched_config).unwrap();
+            assert_eq!(store.list_keys().len(), 50);
+            assert_eq!(
+                store.get("secret-key-77".into()).unwrap(),
+                format!("secret-value-{}", english_numbers::convert_all_fmt(77))
+            );
+        }
+
+        // a plain store does not take a key
+        let plain_dir = "store/test_48_plain";
+        fs::remove_dir_all(plain_dir);
+        open(plain_dir, None)
+            .unwrap()
+            .put("key".into(), "value".into())
+            .unwrap();
+        assert!(matches!(
+            open(plain_dir, Some(KEY)),
+            Err(Errors::InvalidEncryptionKey { .. })
+        ));
+
+        fs::remove_dir_all(dir).unwrap();
+        fs::remove_dir_all(backup_dir).unwrap();
+        fs::remove_dir_all(plain_dir).unwrap();
+    }
+}
@@ -45,6 +45,15 @@ impl FileIo {
             file: Arc::new(RwLock::new(file)),
         })
     }
+
+    // writes fail, and a file without write permission opens
+    pub fn open_read_only(path: PathBuf) -> Result<Self> {
+        let file = File::open(path).map_err(propagate_err!(Errors::FileInitError))?;
+
+        Ok(Self {
+            file: Arc::new(RwLock::new(file)),
+        })
+    }
 }
 
 impl IoLayer for FileIo {
@@ -67,6 +76,13 @@ impl IoLayer for FileIo {
             .map_err(propagate_err!(Errors::FileIoSyncError))
     }
 
+    fn truncate(&self, size: u64) -> Result<()> {
+        let file = self.file.write();
+        file.set_len(size)
+            .and_then(|_| file.sync_all())
+            .map_err(propagate_err!(Errors::FileIoWriteError))
+    }
+
     fn size(&self) -> u64 {
         self.file.read().metadata().unwrap().len()
     }
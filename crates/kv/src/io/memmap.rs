@@ -1,4 +1,4 @@
-use std::{fs, path::PathBuf, sync::Arc};
+use std::{fs::File, path::PathBuf, sync::Arc};
 
 use memmap2::Mmap;
 use parking_lot::Mutex;
@@ -15,14 +15,11 @@ pub struct MemMappedIo {
 }
 
 impl MemMappedIo {
+    /// only ever read from, the file is opened for reads only
     pub fn open(path: PathBuf) -> Result<Self> {
-        let file = fs::OpenOptions::new()
-            .append(true)
-            .read(true)
-            .open(path)
-            .map_err(propagate_err!(Errors::FileInitError))?;
+        let file = File::open(path).map_err(propagate_err!(Errors::FileInitError))?;
 
-        let map = unsafe { Mmap::map(&file).expect("Internal error: failed to map file") };
+        let map = unsafe { Mmap::map(&file) }.map_err(propagate_err!(Errors::FileInitError))?;
 
         Ok(Self {
             map: Arc::new(Mutex::new(map)),
@@ -31,8 +28,9 @@ impl MemMappedIo {
 }
 
 impl IoLayer for MemMappedIo {
-    fn write(&self, buf: &[u8]) -> crate::errors::Result<usize> {
-        unimplemented!()
+    /// a mapped file is only ever read
+    fn write(&self, _buf: &[u8]) -> crate::errors::Result<usize> {
+        Err(Errors::FileIoWriteError)
     }
 
     fn read(&self, buf: &mut [u8], offset: u64) -> crate::errors::Result<usize> {
@@ -48,8 +46,14 @@ impl IoLayer for MemMappedIo {
         }
     }
 
+    /// nothing was written
     fn sync(&self) -> crate::errors::Result<()> {
-        unimplemented!()
+        Ok(())
+    }
+
+    /// a mapped file is only ever read
+    fn truncate(&self, _size: u64) -> crate::errors::Result<()> {
+        Err(Errors::FileIoWriteError)
     }
 
     fn size(&self) -> u64 {
@@ -3,6 +3,7 @@
     memory <-> hard drive / external storage
 */
 
+pub mod cipher;
 pub mod file;
-pub mod traits;
 pub mod memmap;
+pub mod traits;
@@ -8,36 +8,27 @@ pub trait IoLayer: Sync + Send {
     fn write(&self, buf: &[u8]) -> Result<usize>;
     fn read(&self, buf: &mut [u8], offset: u64) -> Result<usize>;
     fn sync(&self) -> Result<()>;
+    /// cut the file down to `size` bytes
+    fn truncate(&self, size: u64) -> Result<()>;
     /// maintain the size to get write offset at open.
     fn size(&self) -> u64;
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum IoType {
     File,
     MemMapped,
+    /// a file opened for reads only, which needs no write permission
+    ReadOnly,
 }
 
 impl IoType {
-    pub fn make(self, filename: PathBuf) -> Box<dyn IoLayer> {
-        match self {
-            IoType::File => Box::new(
-                FileIo::open(filename.clone()).expect(
-                    format!(
-                        "File {:?} not found! Storage directory is corrupted.",
-                        filename
-                    )
-                    .as_str(),
-                ),
-            ),
-            IoType::MemMapped => Box::new(
-                MemMappedIo::open(filename.clone()).expect(
-                    format!(
-                        "File {:?} not found! Storage directory is corrupted.",
-                        filename
-                    )
-                    .as_str(),
-                ),
-            ),
-        }
+    /// Fails if the file can not be opened, a missing file included.
+    pub fn make(self, filename: PathBuf) -> Result<Box<dyn IoLayer>> {
+        Ok(match self {
+            IoType::File => Box::new(FileIo::open(filename)?),
+            IoType::MemMapped => Box::new(MemMappedIo::open(filename)?),
+            IoType::ReadOnly => Box::new(FileIo::open_read_only(filename)?),
+        })
     }
 }
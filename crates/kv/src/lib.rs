@@ -3,12 +3,15 @@ extern crate pretty_env_logger;
 extern crate log;
 
 pub mod batched;
+pub mod blob;
 pub mod config;
 pub mod definitions;
 pub mod errors;
+pub mod hint;
 pub mod index;
 pub mod io;
 pub mod merge;
 pub mod records;
+pub mod replication;
 pub mod store;
 pub mod storelock;
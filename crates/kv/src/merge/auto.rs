@@ -0,0 +1,187 @@
+/*
+    Background merge:
+    a thread wakes up every `check_interval_ms`,
+    looks at the garbage statistics and merges when thresholds are passed.
+*/
+
+use std::{
+    sync::{
+        mpsc::{self, RecvTimeoutError, Sender},
+        Arc, Weak,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use log::{error, info};
+
+use crate::{config::config::MergeConfig, errors::Errors, store::store::Store};
+
+/// Handle of the background merge thread, the thread stops when this is dropped.
+///
+/// The thread only holds a weak reference,
+///     so it never keeps the store alive on its own.
+pub struct AutoMerge {
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl AutoMerge {
+    pub fn start(store: &Arc<Store>, config: MergeConfig) -> Self {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let store = Arc::downgrade(store);
+        let interval = Duration::from_millis(config.check_interval_ms);
+
+        // stops once a stop is requested or the handle is dropped
+        let thread = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                if !Self::check(&store, &config) {
+                    break;
+                }
+            }
+        });
+
+        Self {
+            stop: Some(stop),
+            thread: Some(thread),
+        }
+    }
+
+    /// Returns false if the store is gone.
+    fn check(store: &Weak<Store>, config: &MergeConfig) -> bool {
+        let store = match store.upgrade() {
+            Some(store) => store,
+            None => return false,
+        };
+        if !store.should_merge(config) {
+            return true;
+        }
+
+        let usage = store.merge_stats.total();
+        info!(
+            "Starting background merge: {} of {} bytes are dead",
+            usage.dead_bytes, usage.total_bytes
+        );
+        match store.merge() {
+            Ok(_) => info!("Background merge done"),
+            Err(Errors::MergeInProgress) => {}
+            Err(e) => error!("Background merge failed: {}", e),
+        }
+        true
+    }
+}
+
+impl Drop for AutoMerge {
+    fn drop(&mut self) {
+        // dropping the sender wakes the thread up
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("Background merge thread panicked");
+            }
+        }
+    }
+}
+
+impl Store {
+    /// Whether the garbage statistics pass the thresholds in `config`.
+    pub fn should_merge(&self, config: &MergeConfig) -> bool {
+        let usage = self.merge_stats.total();
+        if usage.dead_bytes == 0 || usage.dead_bytes < config.min_dead_bytes {
+            return false;
+        }
+        usage.garbage_ratio() >= config.garbage_ratio
+            || (config.max_total_size > 0 && usage.total_bytes >= config.max_total_size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::Arc,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use crate::{config::config::MergeConfig, store::utils::TempStore};
+
+    use super::AutoMerge;
+
+    #[test]
+    fn test_should_merge() {
+        let (_raii, store) = TempStore::init(33);
+        let config = MergeConfig {
+            auto: true,
+            check_interval_ms: 10,
+            garbage_ratio: 0.5,
+            max_total_size: 0,
+            min_dead_bytes: 1,
+        };
+
+        for i in 0..100 {
+            let key = format!("{}", i);
+            let val = english_numbers::convert_all_fmt(i);
+            store.put(key.into(), val.into()).unwrap();
+        }
+        assert!(!store.should_merge(&config));
+
+        for i in 0..80 {
+            store.delete(format!("{}", i).into()).unwrap();
+        }
+        assert!(store.should_merge(&config));
+        assert!(!store.should_merge(&MergeConfig {
+            min_dead_bytes: u64::MAX,
+            ..config
+        }));
+
+        // size threshold alone
+        let config = MergeConfig {
+            garbage_ratio: 1.1,
+            max_total_size: 1,
+            ..config
+        };
+        assert!(store.should_merge(&config));
+    }
+
+    #[test]
+    fn test_auto_merge() {
+        let (_raii, store) = TempStore::init(34);
+        let store = Arc::new(store);
+
+        for i in 0..300 {
+            let key = format!("{}", i);
+            let val = english_numbers::convert_all_fmt(i);
+            store.put(key.into(), val.into()).unwrap();
+        }
+        for i in 0..250 {
+            store.delete(format!("{}", i).into()).unwrap();
+        }
+
+        let auto_merge = AutoMerge::start(
+            &store,
+            MergeConfig {
+                auto: true,
+                check_interval_ms: 10,
+                garbage_ratio: 0.5,
+                max_total_size: 0,
+                min_dead_bytes: 1,
+            },
+        );
+        // merged files hold live records only
+        let begin = Instant::now();
+        while store.merge_stats.total().dead_bytes > 0 && begin.elapsed() < Duration::from_secs(10)
+        {
+            thread::sleep(Duration::from_millis(10));
+        }
+        drop(auto_merge);
+        assert_eq!(store.merge_stats.total().dead_bytes, 0);
+        assert_eq!(store.list_keys().len(), 50);
+        assert_eq!(
+            store.get("299".into()).unwrap(),
+            english_numbers::convert_all_fmt(299)
+        );
+
+        // the thread does not keep the store alive
+        assert_eq!(Arc::strong_count(&store), 1);
+    }
+}
@@ -1,47 +1,178 @@
+/*
+    Online merge, all behind `merge_lock`:
+    1. compact: seal the active file and skip one file id for every sealed file,
+        then rewrite the live records of sealed files into a temporary store in `merge/`;
+    2. validate: the compacted files must fit into the skipped ids;
+    3. combine: stage the compacted files under generation names next to the store files,
+        publish them by renaming the manifest (the commit point),
+        then install them into the skipped ids, which sort after the files they replace
+        and before anything written since, and point the index at them;
+    4. clean: remove the replaced files and `merge/`.
+    Readers are never blocked, writers only while the index is remapped.
+
+    A crash before the manifest is renamed leaves the old files,
+        a crash after it is rolled forward by `merge_recover` on open.
+*/
+
 use std::{
     fs::{self, File},
-    io::{self, Seek, Write},
+    io::Write,
     path::PathBuf,
 };
 
+use log::{info, warn};
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    definitions::constants::{get_max_prefix_number, MERGE_OK_FILE_NAME, MERGE_STORE_PATH},
+    definitions::{
+        constants::{MERGE_MANIFEST_FILE_NAME, MERGE_STORE_PATH},
+        types::ByteVec,
+    },
     errors::{Errors, MergePhase, Result},
+    hint::hint::HintFile,
+    io::traits::IoType,
     propagate_err,
-    records::log_record::LogRecord,
-    store::{self, store::Store, utils::format_filename},
+    records::log_record::{LogRecord, LogRecordPtr},
+    store::{
+        expiry::now_millis,
+        file_handle::FileHandle,
+        file_header::FileHeader,
+        store::{Stamp, Store},
+        utils::{format_filename, format_generation_filename, format_hint_filename, sync_dir},
+    },
 };
 
-#[derive(Serialize, Deserialize)]
-pub struct MergeMetadata {
-    pub(crate) cur_active_file_id: u32,
-    pub(crate) cur_write_offset: u64,
+use super::stats::FileUsage;
+
+/// The last published merge.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct MergeManifest {
+    /// increases with every merge, names the staged files of that merge
+    pub(crate) generation: u64,
+    /// compacted files
+    pub(crate) installed: Vec<u32>,
+    /// files replaced by the compacted ones
+    pub(crate) replaced: Vec<u32>,
+    /// sequence number of the store when compaction started:
+    ///     overwritten values and deletes before it are gone, see `Store::changes_since`
+    #[serde(default)]
+    pub(crate) compacted_seq: u64,
 }
 
-impl MergeMetadata {
-    pub fn save(&self, dir: PathBuf) -> Result<()> {
-        let toml = toml::to_string(self).expect("Failed to parse merge metadata to TOML format!");
-        fs::write(dir, toml).map_err(propagate_err!(Errors::FileInitError))
+impl MergeManifest {
+    /// The first file written after this merge: merge skips one id for every file it replaces
+    ///     after the newest of them, the active file at the time.
+    pub(crate) fn kept_from(&self) -> u32 {
+        let newest_replaced = self.replaced.iter().max().map_or(0, |file_id| file_id + 1);
+        newest_replaced + self.replaced.len() as u32
+    }
+
+    /// Generation of the manifest in `store_dir`, 0 before any merge
+    pub(crate) fn generation_in(store_dir: PathBuf) -> Result<u64> {
+        Ok(Self::load(store_dir)?.map_or(0, |manifest| manifest.generation))
     }
+
+    pub fn load(store_dir: PathBuf) -> Result<Option<Self>> {
+        let path = store_dir.join(MERGE_MANIFEST_FILE_NAME);
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let toml = fs::read_to_string(path).map_err(propagate_err!(Errors::MergeFailure {
+            phase: MergePhase::Validate
+        }))?;
+        let manifest = toml::from_str(&toml).map_err(propagate_err!(Errors::MergeFailure {
+            phase: MergePhase::Validate
+        }))?;
+        Ok(Some(manifest))
+    }
+
+    /// Atomically replaces the manifest: written to a temporary file, synced, renamed.
+    pub fn save(&self, store_dir: PathBuf) -> Result<()> {
+        let path = store_dir.join(MERGE_MANIFEST_FILE_NAME);
+        let tmp_path = path.with_extension("tmp");
+        let toml = toml::to_string(self).expect("Failed to parse merge manifest to TOML format!");
+
+        let mut file =
+            File::create(tmp_path.clone()).map_err(propagate_err!(Errors::MergeFailure {
+                phase: MergePhase::Combine
+            }))?;
+        file.write_all(toml.as_bytes())
+            .and_then(|_| file.sync_all())
+            .map_err(propagate_err!(Errors::MergeFailure {
+                phase: MergePhase::Combine
+            }))?;
+        fs::rename(tmp_path, path).map_err(propagate_err!(Errors::MergeFailure {
+            phase: MergePhase::Combine
+        }))?;
+        sync_dir(store_dir)
+    }
+}
+
+/// What `merge_compact` produced, to be installed by `merge_combine`.
+pub(crate) struct MergeOutput {
+    /// generation of the manifest publishing this merge
+    pub(crate) generation: u64,
+    /// sealed files replaced by this merge
+    pub(crate) merged_file_ids: Vec<u32>,
+    /// compacted file `i` in `merge/` becomes `base_file_id + i`
+    pub(crate) base_file_id: u32,
+    /// number of file ids skipped for compacted files
+    pub(crate) reserved_files: u32,
+    /// usage of each compacted file, indexed by its id in `merge/`
+    pub(crate) compacted_files: Vec<FileUsage>,
+    /// (key, pointer before merge, pointer in `merge/`)
+    pub(crate) moves: Vec<(ByteVec, LogRecordPtr, LogRecordPtr)>,
+    /// (key, pointer before merge) of expired records, which are not copied
+    pub(crate) expired: Vec<(ByteVec, LogRecordPtr)>,
+    /// sequence number of the store when compaction started, every record compacted is older
+    pub(crate) compacted_seq: u64,
 }
 
 impl Store {
-    pub fn merge(&self) -> Result<()> {
-        self.merge_compact()?;
+    /// Generation of the last merge published in this store, 0 before any;
+    ///     kept in memory, the manifest is not read again.
+    pub(crate) fn merge_generation(&self) -> u64 {
+        self.merge_generation
+            .load(std::sync::atomic::Ordering::Acquire)
+    }
+
+    /// Publish `manifest`, a merge holding `merge_lock` commits with it.
+    pub(crate) fn publish_manifest(&self, manifest: &MergeManifest) -> Result<()> {
+        manifest.save(self.store_config.dir.clone())?;
+        self.merge_generation
+            .store(manifest.generation, std::sync::atomic::Ordering::Release);
         Ok(())
     }
 
-    pub(crate) fn merge_finalize(store_dir: PathBuf) -> Result<()> {
-        Self::merge_combine(store_dir.clone())?;
-        Self::merge_clean(store_dir)?;
+    pub fn merge(&self) -> Result<()> {
+        self.check_writable()?;
+        let _merge_lock = self.merge_lock.try_lock().ok_or(Errors::MergeInProgress)?;
+
+        let output = self
+            .merge_compact()
+            .and_then(|output| Self::merge_validate(&output).map(|_| output));
+        let output = match output {
+            Ok(output) => output,
+            Err(e) => {
+                // nothing was installed, the store files are untouched
+                Self::merge_clean(self.store_config.dir.clone())?;
+                return Err(e);
+            }
+        };
+        let merged_file_ids = output.merged_file_ids.clone();
+        self.merge_combine(output)?;
+        self.merge_retire(&merged_file_ids)?;
+        Self::merge_clean(self.store_config.dir.clone())?;
+
+        info!("Merge done, replaced files {:?}", merged_file_ids);
         Ok(())
     }
 
     pub(crate) fn merge_temp_store(&self) -> Result<Self> {
         let mut store_config = self.store_config.clone();
         store_config.dir = store_config.dir.join(MERGE_STORE_PATH);
+        // blob references are copied as they are, no blob file is written here
+        store_config.blob.enabled = false;
         // remove if exists
         fs::remove_dir_all(store_config.dir.clone());
         Store::open(store_config, self.file_config, self.batched_config)
@@ -49,193 +180,375 @@ impl Store {
 
     /// Merge store is a minimal instance
     ///     with only 1 record associated to 1 key.
-    /// Therefore, no hint file is needed
-    ///     since the merge_store gives similar performance.
-    pub(crate) fn merge_compact(&self) -> Result<()> {
-        let merge_lock = self.merge_lock.try_lock().ok_or(Errors::MergeInProgress)?;
+    pub(crate) fn merge_compact(&self) -> Result<MergeOutput> {
+        // seal everything written so far, and take the index as of that moment
+        let (merged_file_ids, base_file_id, index, compacted_seq) = {
+            let _write_lock = self.write_lock.lock();
+            let mut active_file = self.active_file.write();
+            let active_file_id = self
+                .active_file_id
+                .load(std::sync::atomic::Ordering::Relaxed);
+            let mut merged_file_ids: Vec<u32> = self.legacy_files.read().keys().copied().collect();
+            merged_file_ids.push(active_file_id);
+            merged_file_ids.sort_unstable();
+
+            let base_file_id = active_file_id + 1;
+            self.rotate(
+                &mut active_file,
+                base_file_id + merged_file_ids.len() as u32,
+            )?;
+            (
+                merged_file_ids,
+                base_file_id,
+                self.index.deepcopy(),
+                self.seq.load(std::sync::atomic::Ordering::Relaxed),
+            )
+        };
+        let reserved_files = merged_file_ids.len() as u32;
+        let generation = self.merge_generation() + 1;
 
         // create merge store in new directory
         let merge_store = self.merge_temp_store()?;
-        let cur_active_file_id = self
-            .active_file_id
-            .load(std::sync::atomic::Ordering::Relaxed);
-        let cur_write_offset = self
-            .active_file
-            .read()
-            .write_offset
-            .load(std::sync::atomic::Ordering::Relaxed);
-        let index = self.index.deepcopy();
-        let keys = index.iter_snapshot().make();
-
-        for (key, _) in keys {
-            let ptr = index
-                .get(key)
-                .expect("Internal error: key not found while merging.");
-            let record = self
-                .get_at(ptr)
+        let mut moves = Vec::new();
+        let mut expired = Vec::new();
+        let now = now_millis();
+
+        for (_, ptr) in index.iter_snapshot().make() {
+            let (record, blob, meta) = self
+                .get_stamped_at(ptr)
                 .expect("Internal error: log record not found while merging.");
+            // a moved record keeps its sequence number and time of write
+            let stamp = Stamp::Keep(meta);
+
+            // a separated value stays in its blob file, only the reference moves
+            if blob.is_some() {
+                if record.expire_at().is_some_and(|expire_at| expire_at <= now) {
+                    let key = record.key().expect("expiring record without key").clone();
+                    expired.push((key, ptr));
+                    continue;
+                }
+                let key = record.key().expect("data record without key").clone();
+                let merge_ptr = merge_store
+                    .log_blob_ref(&mut record.unbatched(), stamp)
+                    .expect("Internal error: original record invalid while merging");
+                moves.push((key, ptr, merge_ptr));
+                continue;
+            }
 
             // process the record associated with the original index
             match record {
                 LogRecord::Data { key, value } => {
-                    merge_store
-                        .put(key.into(), value.into())
-                        .expect("Internal error: original record invalid while merging");
-                }
-                LogRecord::Tomb { key } => {
-                    merge_store
-                        .delete(key.into())
+                    let merge_ptr = merge_store
+                        .log_stamped(
+                            &mut LogRecord::Data {
+                                key: key.clone(),
+                                value,
+                            },
+                            stamp,
+                        )
                         .expect("Internal error: original record invalid while merging");
+                    moves.push((key, ptr, merge_ptr));
                 }
                 LogRecord::DataInBatch {
                     batch_id: _,
                     key,
                     value,
                 } => {
-                    merge_store
-                        .put(key.into(), value.into())
+                    let merge_ptr = merge_store
+                        .log_stamped(
+                            &mut LogRecord::Data {
+                                key: key.clone(),
+                                value,
+                            },
+                            stamp,
+                        )
                         .expect("Internal error: original record invalid while merging");
+                    moves.push((key, ptr, merge_ptr));
                 }
-                LogRecord::TombInBatch { batch_id: _, key } => {
-                    merge_store
-                        .delete(key.into())
+                LogRecord::Expiring {
+                    key,
+                    value,
+                    expire_at,
+                }
+                | LogRecord::ExpiringInBatch {
+                    batch_id: _,
+                    key,
+                    value,
+                    expire_at,
+                } => {
+                    if expire_at <= now {
+                        expired.push((key, ptr));
+                        continue;
+                    }
+                    // the expiry is absolute, it does not move with the record
+                    let mut record = LogRecord::Expiring {
+                        key: key.clone(),
+                        value,
+                        expire_at,
+                    };
+                    let merge_ptr = merge_store
+                        .log_stamped(&mut record, stamp)
                         .expect("Internal error: original record invalid while merging");
+                    moves.push((key, ptr, merge_ptr));
                 }
-                LogRecord::BatchDone { batch_id: _ } => {
-                    // do nothing
+                LogRecord::Tomb { .. }
+                | LogRecord::TombInBatch { .. }
+                | LogRecord::BatchDone { .. } => {
+                    panic!("Internal error: index points to a non-data record while merging.")
                 }
             }
         }
 
-        let meta = MergeMetadata {
-            cur_active_file_id,
-            cur_write_offset,
-        };
-        meta.save(merge_store.store_config.dir.join(MERGE_OK_FILE_NAME))?;
+        // files sealed during compaction got their hints on rotation, the last one did not
+        let merge_active_file_id = merge_store
+            .active_file_id
+            .load(std::sync::atomic::Ordering::Relaxed);
+        {
+            let merge_active_file = merge_store.active_file.read();
+            merge_active_file.sync()?;
+            HintFile::write_for(
+                merge_store.store_config.dir.clone(),
+                merge_active_file_id,
+                &merge_active_file,
+            );
+        }
+        let compacted_files = (0..=merge_active_file_id)
+            .map(|file_id| {
+                merge_store
+                    .merge_stats
+                    .file_usage(file_id)
+                    .unwrap_or_default()
+            })
+            .collect();
+
+        Ok(MergeOutput {
+            generation,
+            merged_file_ids,
+            base_file_id,
+            reserved_files,
+            compacted_files,
+            moves,
+            expired,
+            compacted_seq,
+        })
+    }
 
+    /// A merge that needs more files than it replaces gains nothing, and would not fit.
+    pub(crate) fn merge_validate(output: &MergeOutput) -> Result<()> {
+        if output.compacted_files.len() as u32 > output.reserved_files {
+            warn!(
+                "Merge abandoned: {} compacted files do not fit into {} reserved ids",
+                output.compacted_files.len(),
+                output.reserved_files
+            );
+            return Err(Errors::MergeFailure {
+                phase: MergePhase::Validate,
+            });
+        }
         Ok(())
     }
 
-    pub fn merge_validate(store_dir: PathBuf) -> Result<MergeMetadata> {
-        let merge_meta_path = store_dir
-            .clone()
-            .join(MERGE_STORE_PATH)
-            .join(MERGE_OK_FILE_NAME);
-
-        let meta_string =
-            fs::read_to_string(merge_meta_path).map_err(propagate_err!(Errors::MergeFailure {
-                phase: MergePhase::Validate
-            }))?;
-
-        let meta: MergeMetadata =
-            toml::from_str(&meta_string).map_err(propagate_err!(Errors::MergeFailure {
-                phase: MergePhase::Validate
-            }))?;
+    pub(crate) fn merge_combine(&self, output: MergeOutput) -> Result<()> {
+        let manifest = self.merge_stage(&output)?;
+        self.publish_manifest(&manifest)?;
+        self.merge_install(output)
+    }
 
-        Ok(meta)
+    /// Move compacted files next to the store files under names of this generation,
+    ///     which are ignored until the manifest is published.
+    pub(crate) fn merge_stage(&self, output: &MergeOutput) -> Result<MergeManifest> {
+        let dir = self.store_config.dir.clone();
+        let merge_store_dir = dir.join(MERGE_STORE_PATH);
+
+        let mut installed = Vec::new();
+        for merge_file_id in 0..output.compacted_files.len() as u32 {
+            let file_id = output.base_file_id + merge_file_id;
+            let staged = [
+                (
+                    format_filename(merge_store_dir.clone(), merge_file_id),
+                    format_filename(dir.clone(), file_id),
+                ),
+                (
+                    format_hint_filename(merge_store_dir.clone(), merge_file_id),
+                    format_hint_filename(dir.clone(), file_id),
+                ),
+            ];
+            // the header names the file by the id it is installed under,
+            //      and bounds the sequence numbers of its records like that of any file
+            FileHeader::rewrite(&staged[0].0, |header| {
+                header.file_id = file_id;
+                header.first_seq = output.compacted_seq;
+            })?;
+            for (from, to) in staged {
+                // hints are optional
+                if !from.is_file() {
+                    continue;
+                }
+                fs::rename(from, format_generation_filename(to, output.generation)).map_err(
+                    propagate_err!(Errors::MergeFailure {
+                        phase: MergePhase::Combine
+                    }),
+                )?;
+            }
+            installed.push(file_id);
+        }
+        sync_dir(dir)?;
+
+        Ok(MergeManifest {
+            generation: output.generation,
+            installed,
+            replaced: output.merged_file_ids.clone(),
+            compacted_seq: output.compacted_seq,
+        })
     }
 
-    pub fn merge_combine(store_dir: PathBuf) -> Result<()> {
-        let merge_store_dir = store_dir.clone().join(MERGE_STORE_PATH);
+    /// Give published files their final names and point the index at them.
+    pub(crate) fn merge_install(&self, output: MergeOutput) -> Result<()> {
+        let dir = self.store_config.dir.clone();
+
+        for (merge_file_id, usage) in output.compacted_files.into_iter().enumerate() {
+            let file_id = output.base_file_id + merge_file_id as u32;
+            for path in [
+                format_filename(dir.clone(), file_id),
+                format_hint_filename(dir.clone(), file_id),
+            ] {
+                let staged = format_generation_filename(path.clone(), output.generation);
+                if staged.is_file() {
+                    fs::rename(staged, path).map_err(propagate_err!(Errors::MergeFailure {
+                        phase: MergePhase::Combine
+                    }))?;
+                }
+            }
 
-        if merge_store_dir.is_dir() {
-            let meta = Self::merge_validate(store_dir.clone())?;
+            let file = FileHandle::open(dir.clone(), file_id, self.file_config, IoType::File)?
+                .with_cipher(self.cipher.clone());
+            file.set_write_offset(file.size());
+            self.legacy_files.write().insert(file_id, file);
+            self.merge_stats.insert(file_id, usage);
+        }
+        sync_dir(dir)?;
+
+        // keys written during compaction keep their newer records
+        let _write_lock = self.write_lock.lock();
+        for (key, ptr, merge_ptr) in output.moves {
+            let new_ptr = LogRecordPtr {
+                file_id: output.base_file_id + merge_ptr.file_id,
+                offset: merge_ptr.offset,
+            };
+            if self.index.get(key.clone()) == Some(ptr) {
+                self.index.put(key, new_ptr);
+            } else {
+                self.mark_stale(new_ptr);
+            }
+        }
+        // the replaced files are the only place expired keys were left
+        for (key, ptr) in output.expired {
+            if self.index.get(key.clone()) == Some(ptr) {
+                self.expirations.set(key.clone(), None);
+                self.index.delete(key);
+            }
+        }
 
-            // update merge directory
-            let newest_file_id = get_max_prefix_number(store_dir.clone())?
-                .expect("Should not call merge on empty store!");
-            let mut merge_file_id = get_max_prefix_number(merge_store_dir.clone())?
-                .expect("Should not call merge on empty store!");
+        Ok(())
+    }
 
-            for file_id in meta.cur_active_file_id..=newest_file_id {
-                merge_file_id += 1;
-                let merge_filename = format_filename(merge_store_dir.clone(), merge_file_id);
-                let orig_filename = format_filename(store_dir.clone(), file_id);
-                let offset = if file_id == meta.cur_active_file_id {
-                    meta.cur_write_offset
-                } else {
-                    0
-                };
-                // copy to merge file
-                let mut orig_file = File::open(orig_filename.clone()).expect(
-                    format!("Internal error: file not found: {:?}", orig_filename).as_str(),
-                );
-                orig_file
-                    .seek(std::io::SeekFrom::Start(offset))
-                    .expect(format!("Offset = {} is greater than file size!", offset).as_str());
-                let mut merge_file = File::create(merge_filename)
-                    .expect(format!("Offset = {} is greater than file size!", offset).as_str());
-
-                io::copy(&mut orig_file, &mut merge_file)
-                    .expect("Failed to copy from original file to merge directory!");
+    /// Remove files replaced by a merge;
+    ///     a reader still holding a pointer into them retries through the index,
+    ///     a snapshot keeps reading them through its pinned handles.
+    pub(crate) fn merge_retire(&self, file_ids: &[u32]) -> Result<()> {
+        let dir = self.store_config.dir.clone();
+        {
+            // snapshot readers look for the files here once they left `legacy_files`
+            let mut pins = self.snapshot_pins.lock();
+            let mut legacy_files = self.legacy_files.write();
+            for file_id in file_ids {
+                if let Some(file) = legacy_files.remove(file_id) {
+                    pins.retire(*file_id, file);
+                }
+            }
+        }
+        for file_id in file_ids {
+            self.merge_stats.remove(*file_id);
+            fs::remove_file(format_filename(dir.clone(), *file_id)).map_err(propagate_err!(
+                Errors::MergeFailure {
+                    phase: MergePhase::Clean
+                }
+            ))?;
+            let hint = format_hint_filename(dir.clone(), *file_id);
+            if hint.is_file() {
+                fs::remove_file(hint).map_err(propagate_err!(Errors::MergeFailure {
+                    phase: MergePhase::Clean
+                }))?;
+            }
+        }
+        sync_dir(dir)
+    }
+
+    /// Bring the store directory to the state of the last published merge:
+    ///     staged files of an unpublished merge are dropped, those of the published one installed,
+    ///     and files it replaced removed.
+    pub(crate) fn merge_recover(store_dir: PathBuf) -> Result<()> {
+        let manifest = MergeManifest::load(store_dir.clone())?;
+        let generation = manifest.as_ref().map(|manifest| manifest.generation);
+
+        let entries = fs::read_dir(store_dir.clone())
+            .map_err(propagate_err!(Errors::DirNotFound {
+                dir: store_dir.clone()
+            }))?
+            .filter_map(|entry| entry.ok());
+        for entry in entries {
+            let file_name = entry.file_name();
+            let Some((name, staged_generation)) = file_name
+                .to_str()
+                .and_then(|file_name| file_name.rsplit_once(".gen"))
+                .and_then(|(name, gen)| Some((name, gen.parse::<u64>().ok()?)))
+            else {
+                continue;
+            };
+            if !is_numbered_file(name, ".store") && !is_numbered_file(name, ".hint") {
+                continue;
             }
 
-            // delete all .store files in original directory
-            if let Ok(entries) = fs::read_dir(store_dir.clone()) {
-                for entry in entries {
-                    if let Ok(entry) = entry {
-                        let path = entry.path();
-                        // 检查文件名是否符合条件
-                        if let Some(file_name) = path.clone().file_name() {
-                            if let Some(file_name_str) = file_name.to_str() {
-                                // 检查文件名是否以 ".store" 结尾，并且前面的部分是纯数字
-                                if file_name_str.ends_with(".store")
-                                    && file_name_str[..file_name_str.len() - 6]
-                                        .chars()
-                                        .all(|c| c.is_digit(10))
-                                {
-                                    // 删除文件
-                                    fs::remove_file(path.clone()).expect(
-                                        format!("Failed to remove file: {:?}", path).as_str(),
-                                    );
-                                }
-                            }
-                        }
+            if Some(staged_generation) == generation {
+                info!("Installing {:?} of a published merge", file_name);
+                fs::rename(entry.path(), store_dir.join(name)).map_err(propagate_err!(
+                    Errors::MergeFailure {
+                        phase: MergePhase::Combine
                     }
-                }
+                ))?;
+            } else {
+                info!("Removing {:?} of an unpublished merge", file_name);
+                fs::remove_file(entry.path()).map_err(propagate_err!(Errors::MergeFailure {
+                    phase: MergePhase::Clean
+                }))?;
             }
+        }
 
-            // copy from merge directory to orginal directory
-            if let Ok(entries) = fs::read_dir(merge_store_dir.clone()) {
-                for entry in entries {
-                    if let Ok(entry) = entry {
-                        let path = entry.path();
-                        // 检查文件名是否符合条件
-                        if let Some(file_name) = path.file_name() {
-                            if let Some(file_name_str) = file_name.to_str() {
-                                // 检查文件名是否以 ".store" 结尾，并且前面的部分是纯数字
-                                if file_name_str.ends_with(".store")
-                                    && file_name_str[..file_name_str.len() - 6]
-                                        .chars()
-                                        .all(|c| c.is_digit(10))
-                                {
-                                    // 构建目标文件路径
-                                    let dest_path = store_dir.join(file_name);
-                                    // 复制文件
-                                    fs::copy(&path, &dest_path).expect(
-                                        format!(
-                                            "Failed to copy from file: {:?} to {:?}",
-                                            path, dest_path
-                                        )
-                                        .as_str(),
-                                    );
-                                }
-                            }
-                        }
+        if let Some(manifest) = manifest {
+            for file_id in manifest.replaced {
+                for path in [
+                    format_filename(store_dir.clone(), file_id),
+                    format_hint_filename(store_dir.clone(), file_id),
+                ] {
+                    if path.is_file() {
+                        fs::remove_file(path).map_err(propagate_err!(Errors::MergeFailure {
+                            phase: MergePhase::Clean
+                        }))?;
                     }
                 }
             }
-
-            // set write offset to size of active file
-
-            Ok(())
-        } else {
-            Err(Errors::MergeNotFound)
         }
+
+        Self::merge_clean(store_dir.clone())?;
+        sync_dir(store_dir)
     }
 
+    /// Remove `merge/` if present.
     pub(crate) fn merge_clean(store_dir: PathBuf) -> Result<()> {
         let merge_store_dir = store_dir.join(MERGE_STORE_PATH);
+        if !merge_store_dir.exists() {
+            return Ok(());
+        }
         fs::remove_dir_all(merge_store_dir).map_err(propagate_err!(Errors::MergeFailure {
             phase: MergePhase::Clean
         }))?;
@@ -243,11 +556,272 @@ impl Store {
     }
 }
 
+/// `<digits><extension>`, e.g. `12.store`
+fn is_numbered_file(file_name: &str, extension: &str) -> bool {
+    file_name
+        .strip_suffix(extension)
+        .is_some_and(|id| !id.is_empty() && id.chars().all(|c| c.is_ascii_digit()))
+}
+
 #[cfg(test)]
 mod tests {
-    use std::{fs, sync::Arc};
+    use std::{fs, sync::Arc, thread, time::Duration};
+
+    use crate::{
+        batched::batched_write::CreateBatch,
+        config::config::Config,
+        definitions::constants::{get_prefix_numbers, MERGE_STORE_PATH},
+        store::{
+            backup::copy_dir_contents,
+            store::Store,
+            utils::{format_filename, format_generation_filename},
+        },
+    };
+
+    fn open_at(dir: &str) -> Store {
+        let (mut store_config, file_config, batched_config) =
+            Config::from_toml("config.toml".into());
+        store_config.dir = dir.into();
+        Store::open(store_config, file_config, batched_config).unwrap()
+    }
+
+    /// The store directory as a crash at this point would leave it.
+    fn crash_image(dir: &str, point: &str) -> String {
+        let image = format!("{}_crash_{}", dir, point);
+        // remove if exist
+        fs::remove_dir_all(image.clone());
+        copy_dir_contents(dir, image.clone()).unwrap();
+        image
+    }
+
+    /// Opens the image, which must hold exactly the files in `present` and none in `absent`.
+    fn assert_recovered(image: &str, present: &[u32], absent: &[u32]) {
+        {
+            let store = open_at(image);
+            assert_eq!(store.list_keys().len(), 100 + 100);
+            assert_eq!(store.get("0".into()).unwrap(), "new");
+            assert_eq!(
+                store.get("99".into()).unwrap(),
+                english_numbers::convert_all_fmt(99)
+            );
+            assert!(store.get("100".into()).is_err());
+            assert_eq!(
+                store.get("599".into()).unwrap(),
+                english_numbers::convert_all_fmt(599)
+            );
+        }
+        let file_ids = get_prefix_numbers(image.into()).unwrap();
+        assert!(present.iter().all(|id| file_ids.contains(id)), "{}", image);
+        assert!(absent.iter().all(|id| !file_ids.contains(id)), "{}", image);
+        // nothing staged or half merged is left behind
+        for entry in fs::read_dir(image).unwrap() {
+            let file_name = entry.unwrap().file_name().into_string().unwrap();
+            assert!(!file_name.contains(".gen"), "{}: {}", image, file_name);
+            assert_ne!(file_name, MERGE_STORE_PATH);
+        }
+        fs::remove_dir_all(image).unwrap();
+    }
+
+    #[test]
+    fn test_merge_crash() {
+        let test_id = 36;
+        let dir = format!("store/test_{}", test_id);
+        // remove if exist
+        fs::remove_dir_all(dir.clone());
+        let store = open_at(&dir);
+
+        for i in 0..600 {
+            let key = format!("{}", i);
+            let val = english_numbers::convert_all_fmt(i);
+            store.put(key.into(), val.into()).unwrap();
+        }
+        for i in 100..500 {
+            store.delete(format!("{}", i).into()).unwrap();
+        }
+        for i in 0..50 {
+            store.put(format!("{}", i).into(), "new".into()).unwrap();
+        }
+
+        // run the phases one by one, taking an image after each step
+        let output = store.merge_compact().unwrap();
+        let old_ids = output.merged_file_ids.clone();
+        let compacted = crash_image(&dir, "compact");
+        Store::merge_validate(&output).unwrap();
+        let validated = crash_image(&dir, "validate");
+        let manifest = store.merge_stage(&output).unwrap();
+        let new_ids = manifest.installed.clone();
+        let staged = crash_image(&dir, "stage");
+        // crashed while staging: the last file was not staged yet
+        let staged_partly = crash_image(&dir, "stage_partly");
+        fs::remove_file(format_generation_filename(
+            format_filename(staged_partly.clone().into(), *new_ids.last().unwrap()),
+            manifest.generation,
+        ))
+        .unwrap();
+        manifest.save(dir.clone().into()).unwrap();
+        let published = crash_image(&dir, "publish");
+        // crashed while installing: only the first file got its final name
+        let installed_partly = crash_image(&dir, "install_partly");
+        fs::rename(
+            format_generation_filename(
+                format_filename(installed_partly.clone().into(), new_ids[0]),
+                manifest.generation,
+            ),
+            format_filename(installed_partly.clone().into(), new_ids[0]),
+        )
+        .unwrap();
+        store.merge_install(output).unwrap();
+        let installed = crash_image(&dir, "install");
+        store.merge_retire(&old_ids).unwrap();
+        let retired = crash_image(&dir, "retire");
+        Store::merge_clean(dir.clone().into()).unwrap();
+        drop(store);
+
+        // before the manifest is published: the old files
+        for image in [compacted, validated, staged, staged_partly] {
+            assert_recovered(&image, &old_ids, &new_ids);
+        }
+        // after: the new ones
+        for image in [published, installed_partly, installed, retired] {
+            assert_recovered(&image, &new_ids, &old_ids);
+        }
+        assert_recovered(&dir, &new_ids, &old_ids);
+    }
+
+    #[test]
+    fn test_online_merge() {
+        let test_id = 35;
+        let dir = format!("store/test_{}", test_id);
+        {
+            // remove if exist
+            fs::remove_dir_all(dir.clone());
+            let (mut store_config, file_config, batched_config) =
+                Config::from_toml("config.toml".into());
+            store_config.dir = dir.clone().into();
+            let store = Arc::new(Store::open(store_config, file_config, batched_config).unwrap());
+
+            for i in 0..1000 {
+                let key = format!("{}", i);
+                let val = english_numbers::convert_all_fmt(i);
+                store.put(key.into(), val.into()).unwrap();
+            }
+            for i in 100..900 {
+                store.delete(format!("{}", i).into()).unwrap();
+            }
+            let files_before = get_prefix_numbers(dir.clone().into()).unwrap();
+
+            // writes and reads keep going while merging
+            let writer = {
+                let store = Arc::clone(&store);
+                thread::spawn(move || {
+                    for i in 0..50 {
+                        store.put(format!("{}", i).into(), "new".into()).unwrap();
+                    }
+                    for i in 950..1000 {
+                        store.delete(format!("{}", i).into()).unwrap();
+                    }
+                    for i in 2000..2100 {
+                        let key = format!("{}", i);
+                        let val = english_numbers::convert_all_fmt(i);
+                        store.put(key.into(), val.into()).unwrap();
+                    }
+                })
+            };
+            let reader = {
+                let store = Arc::clone(&store);
+                thread::spawn(move || {
+                    for _ in 0..20 {
+                        assert_eq!(
+                            store.get("900".into()).unwrap(),
+                            english_numbers::convert_all_fmt(900)
+                        );
+                    }
+                })
+            };
+            store.merge().unwrap();
+            writer.join().unwrap();
+            reader.join().unwrap();
 
-    use crate::{batched::batched_write::CreateBatch, config::config::Config, store::store::Store};
+            // replaced files are gone, without reopening
+            let files_after = get_prefix_numbers(dir.clone().into()).unwrap();
+            assert!(files_before.iter().all(|id| !files_after.contains(id)));
+            assert!(files_after.len() < files_before.len());
+            assert!(!store.store_config.dir.join(MERGE_STORE_PATH).exists());
+
+            assert_eq!(store.get("0".into()).unwrap(), "new");
+            assert_eq!(
+                store.get("50".into()).unwrap(),
+                english_numbers::convert_all_fmt(50)
+            );
+            assert!(store.get("500".into()).is_err());
+            assert!(store.get("999".into()).is_err());
+            assert_eq!(
+                store.get("2099".into()).unwrap(),
+                english_numbers::convert_all_fmt(2099)
+            );
+            assert_eq!(store.list_keys().len(), 100 + 50 + 100);
+
+            // a second merge works on top of the first one
+            store.merge().unwrap();
+            assert_eq!(store.list_keys().len(), 100 + 50 + 100);
+        }
+        {
+            let (mut store_config, file_config, batched_config) =
+                Config::from_toml("config.toml".into());
+            store_config.dir = dir.clone().into();
+            let store = Store::open(store_config, file_config, batched_config).unwrap();
+
+            assert_eq!(store.get("0".into()).unwrap(), "new");
+            assert_eq!(
+                store.get("949".into()).unwrap(),
+                english_numbers::convert_all_fmt(949)
+            );
+            assert!(store.get("950".into()).is_err());
+            assert_eq!(store.list_keys().len(), 100 + 50 + 100);
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_merge_expired() {
+        let test_id = 38;
+        let dir = format!("store/test_{}", test_id);
+        // remove if exist
+        fs::remove_dir_all(dir.clone());
+        {
+            let store = open_at(&dir);
+            for i in 0..300 {
+                let key = format!("{}", i);
+                let val = english_numbers::convert_all_fmt(i);
+                let ttl = if i < 200 {
+                    Duration::from_millis(50)
+                } else {
+                    Duration::from_secs(3600)
+                };
+                store.put_with_ttl(key.into(), val.into(), ttl).unwrap();
+            }
+            let expire_at = store.expirations.expire_at(b"299").unwrap();
+            thread::sleep(Duration::from_millis(100));
+
+            store.merge().unwrap();
+            // expired keys are gone from the index, not only hidden
+            assert_eq!(store.index.iter_snapshot().make().count(), 100);
+            assert!(store.expirations.expire_at(b"0").is_none());
+            assert_eq!(store.merge_stats.total().dead_bytes, 0);
+            assert_eq!(store.expirations.expire_at(b"299"), Some(expire_at));
+        }
+        {
+            let store = open_at(&dir);
+            assert_eq!(store.list_keys().len(), 100);
+            assert!(store.get("0".into()).is_err());
+            assert_eq!(
+                store.get("299".into()).unwrap(),
+                english_numbers::convert_all_fmt(299)
+            );
+            assert!(store.expirations.expire_at(b"299").is_some());
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
 
     #[test]
     fn test_merge() {
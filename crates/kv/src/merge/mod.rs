@@ -1 +1,3 @@
+pub mod auto;
 pub mod merge;
+pub mod stats;
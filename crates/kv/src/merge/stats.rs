@@ -0,0 +1,296 @@
+/*
+    Garbage accounting for merge decisions:
+    every appended record adds to the total bytes of its file,
+    and becomes dead bytes once nothing in the index points to it anymore.
+    Tombs and batch markers are dead from the start, merge never copies them.
+    On open, usage is counted as the log is replayed into the index, without reading records;
+    a persistent index saves it along with its checkpoint, see `StatsCheckpoint`.
+*/
+
+use std::collections::HashMap;
+
+use bytes::Buf;
+use log::warn;
+use parking_lot::Mutex;
+
+use crate::{definitions::types::ByteVec, records::log_record::LogRecordPtr, store::store::Store};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FileUsage {
+    pub total_bytes: u64,
+    pub dead_bytes: u64,
+}
+
+impl FileUsage {
+    pub fn live_bytes(&self) -> u64 {
+        self.total_bytes - self.dead_bytes
+    }
+
+    /// dead / total, 0 for an empty file
+    pub fn garbage_ratio(&self) -> f64 {
+        if self.total_bytes == 0 {
+            0.0
+        } else {
+            self.dead_bytes as f64 / self.total_bytes as f64
+        }
+    }
+}
+
+/// file id -> usage
+pub struct MergeStats {
+    files: Mutex<HashMap<u32, FileUsage>>,
+}
+
+impl MergeStats {
+    pub fn new() -> Self {
+        Self {
+            files: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub(crate) fn on_append(&self, file_id: u32, size: u64, dead: bool) {
+        let mut files = self.files.lock();
+        let usage = files.entry(file_id).or_default();
+        usage.total_bytes += size;
+        if dead {
+            usage.dead_bytes += size;
+        }
+    }
+
+    /// A record counted dead on append turned out live, while replaying.
+    pub(crate) fn on_live(&self, file_id: u32, size: u64) {
+        let mut files = self.files.lock();
+        let usage = files.entry(file_id).or_default();
+        usage.dead_bytes = usage.dead_bytes.saturating_sub(size);
+    }
+
+    pub(crate) fn on_stale(&self, file_id: u32, size: u64) {
+        let mut files = self.files.lock();
+        let usage = files.entry(file_id).or_default();
+        usage.dead_bytes = (usage.dead_bytes + size).min(usage.total_bytes);
+    }
+
+    pub(crate) fn insert(&self, file_id: u32, usage: FileUsage) {
+        self.files.lock().insert(file_id, usage);
+    }
+
+    pub(crate) fn remove(&self, file_id: u32) {
+        self.files.lock().remove(&file_id);
+    }
+
+    pub(crate) fn reset(&self, files: HashMap<u32, FileUsage>) {
+        *self.files.lock() = files;
+    }
+
+    pub(crate) fn snapshot(&self) -> HashMap<u32, FileUsage> {
+        self.files.lock().clone()
+    }
+
+    pub fn file_usage(&self, file_id: u32) -> Option<FileUsage> {
+        self.files.lock().get(&file_id).copied()
+    }
+
+    /// Sum over all files
+    pub fn total(&self) -> FileUsage {
+        self.files
+            .lock()
+            .values()
+            .fold(FileUsage::default(), |acc, usage| FileUsage {
+                total_bytes: acc.total_bytes + usage.total_bytes,
+                dead_bytes: acc.dead_bytes + usage.dead_bytes,
+            })
+    }
+}
+
+impl Default for MergeStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Usage of every file and expiry of live keys, as of an index checkpoint
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StatsCheckpoint {
+    pub(crate) files: HashMap<u32, FileUsage>,
+    pub(crate) expirations: HashMap<ByteVec, u64>,
+}
+
+impl StatsCheckpoint {
+    /// |n|(file_id|total|dead)*n|m|(ksz|k|expire_at)*m|
+    pub(crate) fn encode_into(&self, buf: &mut ByteVec) {
+        buf.extend_from_slice(&(self.files.len() as u32).to_be_bytes());
+        for (file_id, usage) in self.files.iter() {
+            buf.extend_from_slice(&file_id.to_be_bytes());
+            buf.extend_from_slice(&usage.total_bytes.to_be_bytes());
+            buf.extend_from_slice(&usage.dead_bytes.to_be_bytes());
+        }
+        buf.extend_from_slice(&(self.expirations.len() as u32).to_be_bytes());
+        for (key, expire_at) in self.expirations.iter() {
+            buf.extend_from_slice(&(key.len() as u32).to_be_bytes());
+            buf.extend_from_slice(key);
+            buf.extend_from_slice(&expire_at.to_be_bytes());
+        }
+    }
+
+    /// `None` if the buffer does not hold a complete checkpoint
+    pub(crate) fn decode_from(mut buf: &[u8]) -> Option<Self> {
+        let mut checkpoint = Self::default();
+        if buf.remaining() < 4 {
+            return None;
+        }
+        for _ in 0..buf.get_u32() {
+            if buf.remaining() < 4 + 8 + 8 {
+                return None;
+            }
+            let file_id = buf.get_u32();
+            let usage = FileUsage {
+                total_bytes: buf.get_u64(),
+                dead_bytes: buf.get_u64(),
+            };
+            checkpoint.files.insert(file_id, usage);
+        }
+        if buf.remaining() < 4 {
+            return None;
+        }
+        for _ in 0..buf.get_u32() {
+            if buf.remaining() < 4 {
+                return None;
+            }
+            let key_size = buf.get_u32() as usize;
+            if buf.remaining() < key_size + 8 {
+                return None;
+            }
+            let key = buf[..key_size].to_vec();
+            buf.advance(key_size);
+            checkpoint.expirations.insert(key, buf.get_u64());
+        }
+        Some(checkpoint)
+    }
+}
+
+impl Store {
+    /// The record at `ptr` was overwritten or deleted.
+    ///
+    /// Statistics are best effort, a failed read is only logged.
+    pub(crate) fn mark_stale(&self, ptr: LogRecordPtr) {
+        match self.record_size(ptr) {
+            Ok(size) => self.merge_stats.on_stale(ptr.file_id, size),
+            Err(e) => warn!("Failed to read size of stale record {:?}: {}", ptr, e),
+        }
+    }
+
+    /// Replayed: `key` points to the record at `ptr`, of `size` bytes, from now on;
+    ///     replayed records count as dead until then.
+    ///
+    /// `sizes` keeps the size of every live record replayed, while opening,
+    ///     so that replacing one costs no read; others are read back as on writes.
+    pub(crate) fn replay_put(
+        &self,
+        key: ByteVec,
+        ptr: LogRecordPtr,
+        size: u64,
+        expire_at: Option<u64>,
+        mut sizes: Option<&mut HashMap<LogRecordPtr, u64>>,
+    ) {
+        self.merge_stats.on_live(ptr.file_id, size);
+        self.expirations.set(key.clone(), expire_at);
+        if let Some(sizes) = sizes.as_deref_mut() {
+            sizes.insert(ptr, size);
+        }
+        if let Some(old_ptr) = self.index.put(key, ptr) {
+            self.replay_stale(old_ptr, sizes);
+        }
+    }
+
+    /// Replayed: `key` is gone, `sizes` as in `replay_put`.
+    pub(crate) fn replay_delete(
+        &self,
+        key: ByteVec,
+        sizes: Option<&mut HashMap<LogRecordPtr, u64>>,
+    ) {
+        self.expirations.set(key.clone(), None);
+        if let Some(old_ptr) = self.index.delete(key) {
+            self.replay_stale(old_ptr, sizes);
+        }
+    }
+
+    fn replay_stale(&self, ptr: LogRecordPtr, sizes: Option<&mut HashMap<LogRecordPtr, u64>>) {
+        match sizes.and_then(|sizes| sizes.remove(&ptr)) {
+            Some(size) => self.merge_stats.on_stale(ptr.file_id, size),
+            None => self.mark_stale(ptr),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, sync::Arc, time::Duration};
+
+    use crate::{
+        batched::batched_write::CreateBatch, config::config::Config, index::index_impl::IndexType,
+        store::store::Store,
+    };
+
+    fn open_at(dir: &str, index_type: IndexType) -> Store {
+        let (mut store_config, file_config, batched_config) =
+            Config::from_toml("config.toml".into());
+        store_config.dir = dir.into();
+        store_config.index_type = index_type;
+        Store::open(store_config, file_config, batched_config).unwrap()
+    }
+
+    #[test]
+    fn test_merge_stats() {
+        for (test_id, index_type) in [(32, IndexType::BTree), (62, IndexType::DiskTree)] {
+            let dir = format!("store/test_{}", test_id);
+            // remove if exist
+            fs::remove_dir_all(dir.clone());
+            let store = Arc::new(open_at(&dir, index_type));
+
+            for i in 0..300 {
+                let key = format!("{}", i);
+                let val = english_numbers::convert_all_fmt(i);
+                store.put(key.into(), val.into()).unwrap();
+            }
+            let usage = store.merge_stats.total();
+            assert!(usage.total_bytes > 0);
+            assert_eq!(usage.dead_bytes, 0);
+
+            // overwrite and delete
+            for i in 0..100 {
+                let key = format!("{}", i);
+                store.put(key.into(), "overwritten".into()).unwrap();
+            }
+            for i in 100..200 {
+                store.delete(format!("{}", i).into()).unwrap();
+            }
+            let batch = store.new_batched();
+            for i in 200..250 {
+                batch.delete(format!("{}", i).into()).unwrap();
+            }
+            batch
+                .put_with_ttl("250".into(), "batched".into(), Duration::from_secs(600))
+                .unwrap();
+            batch.commit().unwrap();
+            drop(batch);
+            store
+                .put_with_ttl("260".into(), "expiring".into(), Duration::from_secs(600))
+                .unwrap();
+
+            let usage = store.merge_stats.snapshot();
+            let expirations = store.expirations.snapshot();
+            assert!(store.merge_stats.total().dead_bytes > 0);
+            assert_eq!(expirations.len(), 2);
+            drop(store);
+
+            // counted again from scratch, then resumed from the checkpoint of a persistent index:
+            //     both agree with what was tracked on the fly
+            for _ in 0..2 {
+                let store = open_at(&dir, index_type);
+                assert_eq!(store.merge_stats.snapshot(), usage);
+                assert_eq!(store.expirations.snapshot(), expirations);
+            }
+            fs::remove_dir_all(dir).unwrap();
+        }
+    }
+}
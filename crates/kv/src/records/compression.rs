@@ -0,0 +1,173 @@
+/*
+    Value compression:
+    a compressed record has `COMPRESSED` set on its type byte,
+    and a flag byte naming the codec right after it.
+    Only the value is compressed, sizes and crc describe the bytes on disk:
+    crc is verified before decompressing.
+    Files written without compression never set the bit, and read as before.
+*/
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    definitions::types::ByteVec,
+    errors::{Errors, Result},
+    propagate_err,
+};
+use log::error;
+
+/// Set on the type byte of a compressed record
+pub const COMPRESSED: u8 = 0x80;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum Codec {
+    #[default]
+    None,
+    Lz4,
+    Zstd,
+}
+
+impl Codec {
+    pub(crate) fn flag(&self) -> u8 {
+        match self {
+            Codec::None => 0,
+            Codec::Lz4 => 1,
+            Codec::Zstd => 2,
+        }
+    }
+
+    pub(crate) fn from_flag(flag: u8) -> Result<Self> {
+        match flag {
+            0 => Ok(Codec::None),
+            1 => Ok(Codec::Lz4),
+            2 => Ok(Codec::Zstd),
+            _ => Err(Errors::DecompressionFailure),
+        }
+    }
+
+    pub(crate) fn compress(&self, value: &[u8], level: i32) -> Result<ByteVec> {
+        match self {
+            Codec::None => Ok(value.to_vec()),
+            Codec::Lz4 => Ok(lz4_flex::compress_prepend_size(value)),
+            Codec::Zstd => {
+                zstd::encode_all(value, level).map_err(propagate_err!(Errors::FileIoWriteError))
+            }
+        }
+    }
+
+    pub(crate) fn decompress(&self, value: &[u8]) -> Result<ByteVec> {
+        match self {
+            Codec::None => Ok(value.to_vec()),
+            Codec::Lz4 => lz4_flex::decompress_size_prepended(value)
+                .map_err(propagate_err!(Errors::DecompressionFailure)),
+            Codec::Zstd => {
+                zstd::decode_all(value).map_err(propagate_err!(Errors::DecompressionFailure))
+            }
+        }
+    }
+}
+
+/// `[store.compression]`, off by default.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct CompressionConfig {
+    pub(crate) codec: Codec,
+    /// values shorter than this are stored as they are
+    pub(crate) min_size: usize,
+    /// zstd only
+    pub(crate) level: i32,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            codec: Codec::None,
+            min_size: 512,
+            level: 3,
+        }
+    }
+}
+
+impl CompressionConfig {
+    /// The codec and compressed value, `None` if the value is better stored as it is.
+    pub(crate) fn compress(&self, value: &[u8]) -> Result<Option<(Codec, ByteVec)>> {
+        if self.codec == Codec::None || value.len() < self.min_size {
+            return Ok(None);
+        }
+        let compressed = self.codec.compress(value, self.level)?;
+        if compressed.len() >= value.len() {
+            return Ok(None);
+        }
+        Ok(Some((self.codec, compressed)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use bytes::Bytes;
+
+    use crate::{
+        config::config::Config,
+        store::{store::Store, utils::format_filename},
+    };
+
+    use super::{Codec, CompressionConfig};
+
+    fn json(i: usize) -> Bytes {
+        format!(
+            r#"{{"id": {}, "name": "{}", "tags": ["alpha", "beta", "gamma"], "padding": "{}"}}"#,
+            i,
+            english_numbers::convert_all_fmt(i as i64),
+            "-".repeat(600)
+        )
+        .into()
+    }
+
+    #[test]
+    fn test_compressed_store() {
+        let dir = "store/test_47";
+        // remove if exist
+        fs::remove_dir_all(dir);
+        let open = |codec| {
+            let (mut store_config, mut file_config, batched_config) =
+                Config::from_toml("config.toml".into());
+            store_config.dir = dir.into();
+            store_config.compression = CompressionConfig {
+                codec,
+                ..Default::default()
+            };
+            file_config.max_file_size = 1 << 20;
+            Store::open(store_config, file_config, batched_config).unwrap()
+        };
+
+        let store = open(Codec::Zstd);
+        for i in 0..100 {
+            store.put(format!("{}", i).into(), json(i)).unwrap();
+        }
+        // below the threshold
+        store.put("small".into(), "value".into()).unwrap();
+        assert_eq!(store.get("42".into()).unwrap(), json(42));
+        let size = fs::metadata(format_filename(dir.into(), 0)).unwrap().len();
+        assert!(size < 100 * json(0).len() as u64 / 4, "{}", size);
+        drop(store);
+
+        // compressed records stay readable with another setting
+        let store = open(Codec::Lz4);
+        for i in 100..200 {
+            store.put(format!("{}", i).into(), json(i)).unwrap();
+        }
+        drop(store);
+        let store = open(Codec::None);
+        for i in 0..200 {
+            assert_eq!(store.get(format!("{}", i).into()).unwrap(), json(i));
+        }
+        assert_eq!(store.get("small".into()).unwrap(), "value");
+        store.merge().unwrap();
+        assert_eq!(store.get("150".into()).unwrap(), json(150));
+        drop(store);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -3,7 +3,7 @@ use crate::{
     errors::{Errors, Result},
 };
 
-#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub struct LogRecordPtr {
     pub(crate) file_id: u32,
     pub(crate) offset: u64,
@@ -50,6 +50,18 @@ pub enum LogRecord {
     BatchDone {
         batch_id: usize,
     },
+    /// `expire_at`: milliseconds since UNIX epoch
+    Expiring {
+        key: ByteVec,
+        value: ByteVec,
+        expire_at: u64,
+    },
+    ExpiringInBatch {
+        batch_id: usize,
+        key: ByteVec,
+        value: ByteVec,
+        expire_at: u64,
+    },
 }
 
 // metadata
@@ -65,6 +77,72 @@ impl LogRecord {
             } => key.is_empty(),
             LogRecord::TombInBatch { batch_id: _, key } => key.is_empty(),
             LogRecord::BatchDone { batch_id: _ } => false,
+            LogRecord::Expiring { key, .. } => key.is_empty(),
+            LogRecord::ExpiringInBatch { key, .. } => key.is_empty(),
+        }
+    }
+
+    pub fn key(&self) -> Option<&ByteVec> {
+        match self {
+            LogRecord::Data { key, .. }
+            | LogRecord::Tomb { key }
+            | LogRecord::DataInBatch { key, .. }
+            | LogRecord::TombInBatch { key, .. }
+            | LogRecord::Expiring { key, .. }
+            | LogRecord::ExpiringInBatch { key, .. } => Some(key),
+            LogRecord::BatchDone { .. } => None,
+        }
+    }
+
+    /// The same write as a record of its own, outside of any batch.
+    pub fn unbatched(self) -> Self {
+        match self {
+            LogRecord::DataInBatch {
+                batch_id: _,
+                key,
+                value,
+            } => LogRecord::Data { key, value },
+            LogRecord::TombInBatch { batch_id: _, key } => LogRecord::Tomb { key },
+            LogRecord::ExpiringInBatch {
+                batch_id: _,
+                key,
+                value,
+                expire_at,
+            } => LogRecord::Expiring {
+                key,
+                value,
+                expire_at,
+            },
+            record => record,
+        }
+    }
+
+    /// Milliseconds since UNIX epoch, `None` if the record never expires
+    pub fn expire_at(&self) -> Option<u64> {
+        match self {
+            LogRecord::Expiring { expire_at, .. } => Some(*expire_at),
+            LogRecord::ExpiringInBatch { expire_at, .. } => Some(*expire_at),
+            _ => None,
+        }
+    }
+
+    pub fn value(&self) -> Option<&ByteVec> {
+        match self {
+            LogRecord::Data { value, .. }
+            | LogRecord::DataInBatch { value, .. }
+            | LogRecord::Expiring { value, .. }
+            | LogRecord::ExpiringInBatch { value, .. } => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn value_mut(&mut self) -> Option<&mut ByteVec> {
+        match self {
+            LogRecord::Data { value, .. }
+            | LogRecord::DataInBatch { value, .. }
+            | LogRecord::Expiring { value, .. }
+            | LogRecord::ExpiringInBatch { value, .. } => Some(value),
+            _ => None,
         }
     }
 
@@ -82,6 +160,8 @@ impl LogRecord {
                 key: _,
             } => 3,
             LogRecord::BatchDone { batch_id: _ } => 4,
+            LogRecord::Expiring { .. } => 5,
+            LogRecord::ExpiringInBatch { .. } => 6,
         }
     }
 
@@ -109,6 +189,14 @@ impl LogRecord {
             LogRecord::BatchDone { batch_id: _ } => {
                 1 /* type */ + 8 /* batch id */
             }
+            LogRecord::Expiring { key, value, .. } => {
+                1 /* type */ + 8 /* expire at */ + 8 /* sizes */
+                    + key.len() + value.len() + 4 /* crc */
+            }
+            LogRecord::ExpiringInBatch { key, value, .. } => {
+                1 /* type */ + 8 /* batch id */ + 8 /* expire at */ + 8 /* sizes */
+                    + key.len() + value.len() + 4 /* crc */
+            }
         }
     }
 }
@@ -119,6 +207,11 @@ impl LogRecord {
         1
     }
 
+    /// codec of a compressed record
+    pub fn flag_length() -> usize {
+        1
+    }
+
     pub fn header_length_data() -> usize {
         size_of::<KvSizeType>() * 2 /* keysize + valuesize */
     }
@@ -139,6 +232,14 @@ impl LogRecord {
         size_of::<usize>()
     }
 
+    pub fn header_length_expiring() -> usize {
+        8 /* expire at */ + size_of::<KvSizeType>() * 2 /* keysize + valuesize */
+    }
+
+    pub fn header_length_expiring_in_batch() -> usize {
+        8 /* batch id */ + 8 /* expire at */ + size_of::<KvSizeType>() * 2 /* keysize + valuesize */
+    }
+
     pub fn tail_length() -> usize {
         4 /* crc */
     }
@@ -0,0 +1,40 @@
+/*
+    Record stamps:
+    a stamped record has `RECORD_META` set on its type byte,
+    and |seq u64|timestamp u64| right after the type (and the codec flag of a compressed value),
+    covered by the crc like everything else.
+    Sequence numbers are assigned by the store in log order, and are kept by merge and blob collection,
+    which move records without changing them.
+    Records written before stamps carry none, and read as before.
+*/
+
+use bytes::Buf;
+
+/// Set on the type byte of a stamped record
+pub const RECORD_META: u8 = 0x20;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub struct RecordMeta {
+    /// position of the write among all writes to the store, from 0
+    pub seq: u64,
+    /// milliseconds since UNIX epoch
+    pub timestamp: u64,
+}
+
+impl RecordMeta {
+    pub const LENGTH: usize = 8 /* seq */ + 8 /* timestamp */;
+
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut res = Vec::with_capacity(Self::LENGTH);
+        res.extend_from_slice(&self.seq.to_be_bytes());
+        res.extend_from_slice(&self.timestamp.to_be_bytes());
+        res
+    }
+
+    pub(crate) fn decode(mut bin: &[u8]) -> Self {
+        Self {
+            seq: bin.get_u64(),
+            timestamp: bin.get_u64(),
+        }
+    }
+}
@@ -1 +1,3 @@
+pub mod compression;
 pub mod log_record;
+pub mod meta;
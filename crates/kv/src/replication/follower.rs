@@ -0,0 +1,727 @@
+/*
+    Follower side of replication:
+    a thread connects to the primary, says where the log of the replica ends,
+    then applies what is shipped until it is stopped, reconnecting whenever the connection fails.
+    Shipped records are appended to the active file and replayed into the index as on open,
+    along with the state of a batch not committed yet, which survives reconnects.
+    A merge is applied as the primary applied it: staged, published by the manifest, installed;
+    the index is pointed at the compacted files for keys not written since,
+    which readers never see go back to older values.
+*/
+
+use std::{
+    collections::HashSet,
+    fs::{self, File, OpenOptions},
+    io::BufReader,
+    net::{SocketAddr, TcpStream},
+    os::unix::fs::FileExt,
+    sync::{
+        mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError},
+        Arc, Weak,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use log::{error, info, warn};
+
+use crate::{
+    config::config::ReplicationConfig,
+    errors::{Errors, Result},
+    hint::hint::{HintFile, HintRecord},
+    io::traits::IoType,
+    merge::merge::MergeManifest,
+    propagate_err,
+    records::log_record::LogRecordPtr,
+    store::{
+        file_handle::FileHandle,
+        file_header::FileHeader,
+        store::{Replay, Store, StoreMode},
+        utils::{format_filename, format_generation_filename, sync_dir},
+    },
+};
+
+use super::protocol::Message;
+
+/// Handle of the thread following a primary, following stops when this is dropped.
+pub struct Follower {
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Follower {
+    /// Apply to `store`, opened with `Store::open_replica`, what the primary at `primary` ships.
+    pub fn start(
+        store: &Arc<Store>,
+        primary: SocketAddr,
+        config: ReplicationConfig,
+    ) -> Result<Self> {
+        if store.mode() != StoreMode::Replica {
+            return Err(Errors::NotAReplica);
+        }
+        let mut replay = store.replica_resume()?;
+
+        let (stop, stopped) = mpsc::channel::<()>();
+        let store = Arc::downgrade(store);
+        let retry_interval = Duration::from_millis(config.retry_interval_ms);
+
+        // stops once a stop is requested or the handle is dropped
+        let thread = thread::spawn(move || loop {
+            match Self::follow(&store, primary, &config, &mut replay, &stopped) {
+                Ok(()) => return,
+                Err(e) => warn!("Following primary {} failed: {}", primary, e),
+            }
+            if let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(retry_interval) {
+                continue;
+            }
+            return;
+        });
+
+        Ok(Self {
+            stop: Some(stop),
+            thread: Some(thread),
+        })
+    }
+
+    /// Returns once a stop is requested or the store is gone.
+    fn follow(
+        store: &Weak<Store>,
+        primary: SocketAddr,
+        config: &ReplicationConfig,
+        replay: &mut Replay,
+        stopped: &Receiver<()>,
+    ) -> Result<()> {
+        let heartbeat_interval = Duration::from_millis(config.heartbeat_interval_ms);
+        let mut stream = TcpStream::connect_timeout(&primary, heartbeat_interval * 3)
+            .map_err(propagate_err!(Errors::ReplicationConnectionFailure))?;
+        // a primary silent for three heartbeats is gone
+        stream
+            .set_nodelay(true)
+            .and_then(|_| stream.set_read_timeout(Some(heartbeat_interval * 3)))
+            .and_then(|_| stream.set_write_timeout(Some(heartbeat_interval * 3)))
+            .map_err(propagate_err!(Errors::ReplicationConnectionFailure))?;
+
+        let hello = match store.upgrade() {
+            Some(store) => Message::Hello {
+                position: store.log_end(),
+                generation: store.merge_generation(),
+            },
+            None => return Ok(()),
+        };
+        info!("Following primary {} from {:?}", primary, hello);
+        hello.write_to(&mut stream)?;
+
+        let mut stream = BufReader::new(stream);
+        loop {
+            let message = Message::read_from(&mut stream)?;
+            if let Err(TryRecvError::Empty) = stopped.try_recv() {
+            } else {
+                return Ok(());
+            }
+            let Some(store) = store.upgrade() else {
+                return Ok(());
+            };
+            store.replica_apply(message, replay)?;
+        }
+    }
+}
+
+impl Drop for Follower {
+    fn drop(&mut self) {
+        // dropping the sender wakes the thread up
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("Replication follower thread panicked");
+            }
+        }
+    }
+}
+
+impl Store {
+    /// The batch the log ends in, if not committed yet, to be carried on by shipped records.
+    fn replica_resume(&self) -> Result<Replay> {
+        let _write_lock = self.write_lock.lock();
+        let mut replay = Replay::new();
+
+        let active_file = self.active_file.read();
+        let active_file_id = self
+            .active_file_id
+            .load(std::sync::atomic::Ordering::Relaxed);
+        let legacy_files = self.legacy_files.read();
+        // a batch spans at most the rotation of the file it started in
+        let newest_legacy_file = legacy_files
+            .iter()
+            .max_by_key(|(file_id, _)| **file_id)
+            .map(|(file_id, file)| (*file_id, file));
+        for (file_id, file) in newest_legacy_file
+            .into_iter()
+            .chain([(active_file_id, &*active_file)])
+        {
+            // everything committed is in the index already
+            for hint in HintFile::scan(file)? {
+                match hint {
+                    // counted in merge statistics on open
+                    HintRecord::DataInBatch { .. } | HintRecord::TombInBatch { .. } => {
+                        self.replay_hint(hint, file_id, &mut replay)
+                    }
+                    HintRecord::BatchDone { batch_id, .. } => {
+                        replay.newest_batch_id = batch_id;
+                        replay.reset();
+                    }
+                    HintRecord::Data { .. } | HintRecord::Tomb { .. } => replay.reset(),
+                }
+            }
+        }
+        Ok(replay)
+    }
+
+    pub(crate) fn replica_apply(&self, message: Message, replay: &mut Replay) -> Result<()> {
+        match message {
+            Message::Records { position, bytes } => self.replica_append(position, &bytes, replay),
+            Message::File { header } => self.replica_rotate(header),
+            Message::Stage {
+                generation,
+                file_id,
+                offset,
+                bytes,
+            } => self.replica_stage(generation, file_id, offset, &bytes),
+            Message::Merge {
+                generation,
+                compacted_seq,
+                installed,
+                kept_from,
+            } => self.replica_merge(generation, compacted_seq, installed, kept_from, replay),
+            Message::Heartbeat => Ok(()),
+            Message::Hello { .. } => Err(Errors::InvalidReplicationMessage),
+        }
+    }
+
+    /// Append records shipped for the end of the log, and replay them.
+    fn replica_append(
+        &self,
+        position: LogRecordPtr,
+        bytes: &[u8],
+        replay: &mut Replay,
+    ) -> Result<()> {
+        let _write_lock = self.write_lock.lock();
+        let log_end = self.log_end();
+        if position != log_end {
+            return Err(Errors::ReplicationOutOfOrder {
+                expected: log_end,
+                got: position,
+            });
+        }
+
+        let active_file = self.active_file.read();
+        active_file.append(bytes)?;
+        let mut next_seq = 0;
+        let valid_end = self.update_index_on_active_file(
+            &active_file,
+            position.file_id,
+            position.offset,
+            replay,
+            &mut next_seq,
+        );
+        self.seq
+            .fetch_max(next_seq, std::sync::atomic::Ordering::Relaxed);
+        self.batch_id.fetch_max(
+            replay.newest_batch_id + 1,
+            std::sync::atomic::Ordering::Relaxed,
+        );
+
+        // records replayed so far stay, the rest is shipped again
+        if valid_end != active_file.get_write_offset() {
+            error!(
+                "Shipped records of file {} from offset {} do not read back",
+                position.file_id, valid_end
+            );
+            active_file.truncate(valid_end)?;
+            return Err(Errors::InvalidReplicationMessage);
+        }
+        Ok(())
+    }
+
+    /// Seal the active file as the primary did, continuing in a new file of `header`.
+    fn replica_rotate(&self, header: FileHeader) -> Result<()> {
+        let _write_lock = self.write_lock.lock();
+        let mut active_file = self.active_file.write();
+        let active_file_id = self
+            .active_file_id
+            .load(std::sync::atomic::Ordering::Relaxed);
+        if header.file_id <= active_file_id {
+            return Err(Errors::ReplicationOutOfOrder {
+                expected: LogRecordPtr {
+                    file_id: active_file_id,
+                    offset: active_file.get_write_offset(),
+                },
+                got: LogRecordPtr {
+                    file_id: header.file_id,
+                    offset: 0,
+                },
+            });
+        }
+
+        let first_seq = header.first_seq;
+        self.rotate_to(&mut active_file, header)?;
+        self.seq
+            .fetch_max(first_seq, std::sync::atomic::Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Write part of a compacted file under the name merge stages it under.
+    fn replica_stage(
+        &self,
+        generation: u64,
+        file_id: u32,
+        offset: u64,
+        bytes: &[u8],
+    ) -> Result<()> {
+        let path = format_generation_filename(
+            format_filename(self.store_config.dir.clone(), file_id),
+            generation,
+        );
+        // a file shipped again after a reconnect starts over
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(offset == 0)
+            .open(path)
+            .map_err(propagate_err!(Errors::FileInitError))?;
+        file.write_all_at(bytes, offset)
+            .map_err(propagate_err!(Errors::FileIoWriteError))
+    }
+
+    /// Publish and install the staged files of a merge, then retire the files it replaced,
+    ///     every file before `kept_from` apart from those installed.
+    fn replica_merge(
+        &self,
+        generation: u64,
+        compacted_seq: u64,
+        installed: Vec<u32>,
+        kept_from: FileHeader,
+        replay: &mut Replay,
+    ) -> Result<()> {
+        let _merge_lock = self.merge_lock.lock();
+        let dir = self.store_config.dir.clone();
+
+        for file_id in installed.iter().copied() {
+            File::open(format_generation_filename(
+                format_filename(dir.clone(), file_id),
+                generation,
+            ))
+            .and_then(|file| file.sync_all())
+            .map_err(propagate_err!(Errors::FileIoWriteError))?;
+        }
+        let mut replaced: Vec<u32> = {
+            let _active_file = self.active_file.read();
+            let active_file_id = self
+                .active_file_id
+                .load(std::sync::atomic::Ordering::Relaxed);
+            self.legacy_files
+                .read()
+                .keys()
+                .copied()
+                .chain([active_file_id])
+                .filter(|file_id| *file_id < kept_from.file_id && !installed.contains(file_id))
+                .collect()
+        };
+        replaced.sort_unstable();
+        // the commit point, as on the primary
+        self.publish_manifest(&MergeManifest {
+            generation,
+            installed: installed.clone(),
+            replaced: replaced.clone(),
+            compacted_seq,
+        })?;
+
+        for file_id in installed.iter().copied() {
+            let path = format_filename(dir.clone(), file_id);
+            fs::rename(format_generation_filename(path.clone(), generation), path)
+                .map_err(propagate_err!(Errors::FileIoWriteError))?;
+            let file = FileHandle::open(dir.clone(), file_id, self.file_config, IoType::File)?
+                .with_cipher(self.cipher.clone());
+            file.set_write_offset(file.size());
+            self.legacy_files.write().insert(file_id, file);
+        }
+        sync_dir(dir)?;
+
+        {
+            let _write_lock = self.write_lock.lock();
+            {
+                let mut active_file = self.active_file.write();
+                let active_file_id = self
+                    .active_file_id
+                    .load(std::sync::atomic::Ordering::Relaxed);
+                // what is left of the replaced files is not shipped, the compacted files hold it
+                if active_file_id < kept_from.file_id {
+                    let first_seq = kept_from.first_seq;
+                    self.rotate_to(&mut active_file, kept_from)?;
+                    self.seq
+                        .fetch_max(first_seq, std::sync::atomic::Ordering::Relaxed);
+                    replay.reset();
+                }
+            }
+            self.replica_remap(&installed, &replaced, kept_from.file_id)?;
+        }
+
+        self.merge_retire(&replaced)?;
+        info!(
+            "Applied merge generation {}, installed {:?}, replaced {:?}",
+            generation, installed, replaced
+        );
+        Ok(())
+    }
+
+    /// Point the index at the compacted files for keys not written since the merge,
+    ///     and drop keys only the replaced files held, which the merge dropped.
+    fn replica_remap(&self, installed: &[u32], replaced: &[u32], kept_from: u32) -> Result<()> {
+        let files: Vec<(u32, Vec<HintRecord>)> = {
+            let active_file = self.active_file.read();
+            let active_file_id = self
+                .active_file_id
+                .load(std::sync::atomic::Ordering::Relaxed);
+            let legacy_files = self.legacy_files.read();
+            let mut file_ids: Vec<u32> = legacy_files
+                .keys()
+                .copied()
+                .filter(|file_id| installed.contains(file_id) || *file_id >= kept_from)
+                .collect();
+            file_ids.sort_unstable();
+            let mut files = Vec::new();
+            for file_id in file_ids {
+                files.push((file_id, HintFile::scan(&legacy_files[&file_id])?));
+            }
+            files.push((active_file_id, HintFile::scan(&active_file)?));
+            files
+        };
+
+        // keys written since the merge, as far as committed
+        let done: HashSet<usize> = files
+            .iter()
+            .filter(|(file_id, _)| *file_id >= kept_from)
+            .flat_map(|(_, hints)| hints)
+            .filter_map(|hint| match hint {
+                HintRecord::BatchDone { batch_id, .. } => Some(*batch_id),
+                _ => None,
+            })
+            .collect();
+        let written: HashSet<&[u8]> = files
+            .iter()
+            .filter(|(file_id, _)| *file_id >= kept_from)
+            .flat_map(|(_, hints)| hints)
+            .filter_map(|hint| match hint {
+                HintRecord::Data { key, .. } | HintRecord::Tomb { key, .. } => Some(key),
+                HintRecord::DataInBatch { batch_id, key, .. }
+                | HintRecord::TombInBatch { batch_id, key, .. } => {
+                    done.contains(batch_id).then_some(key)
+                }
+                HintRecord::BatchDone { .. } => None,
+            })
+            .map(|key| key.as_slice())
+            .collect();
+
+        for (file_id, hints) in files.iter().filter(|(file_id, _)| *file_id < kept_from) {
+            for hint in hints {
+                match hint {
+                    HintRecord::Data {
+                        key,
+                        offset,
+                        expire_at,
+                        ..
+                    } if !written.contains(key.as_slice()) => {
+                        self.expirations.set(key.clone(), *expire_at);
+                        self.index.put(
+                            key.clone(),
+                            LogRecordPtr {
+                                file_id: *file_id,
+                                offset: *offset,
+                            },
+                        );
+                    }
+                    HintRecord::Tomb { key, .. } if !written.contains(key.as_slice()) => {
+                        self.expirations.set(key.clone(), None);
+                        self.index.delete(key.clone());
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        let dropped: Vec<_> = self
+            .index
+            .iter_snapshot()
+            .make()
+            .filter(|(_, ptr)| replaced.contains(&ptr.file_id))
+            .map(|(key, _)| key)
+            .collect();
+        for key in dropped {
+            self.expirations.set(key.clone(), None);
+            self.index.delete(key);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env, fs,
+        io::{BufRead, BufReader, Lines, Write},
+        net::SocketAddr,
+        process::{Child, ChildStdin, ChildStdout, Command, Stdio},
+        sync::Arc,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use crate::{
+        batched::batched_write::CreateBatch,
+        config::config::{Config, ReplicationConfig},
+        definitions::constants::get_prefix_numbers,
+        errors::Errors,
+        replication::primary::Primary,
+        store::store::Store,
+    };
+
+    use super::Follower;
+
+    /// `<store dir> <address>` of the primary run by `primary_process`
+    const PRIMARY_ENV: &str = "KV_REPLICATION_PRIMARY";
+
+    fn config() -> ReplicationConfig {
+        ReplicationConfig {
+            poll_interval_ms: 5,
+            heartbeat_interval_ms: 100,
+            retry_interval_ms: 50,
+        }
+    }
+
+    fn open_at(dir: &str, replica: bool) -> Arc<Store> {
+        let (mut store_config, file_config, batched_config) =
+            Config::from_toml("config.toml".into());
+        store_config.dir = dir.into();
+        let store = if replica {
+            Store::open_replica(store_config, file_config, batched_config)
+        } else {
+            Store::open(store_config, file_config, batched_config)
+        };
+        Arc::new(store.unwrap())
+    }
+
+    /// The primary of `test_replication`, in a process of its own:
+    ///     runs the commands read from stdin, answering each with `done`.
+    #[test]
+    fn primary_process() {
+        let Ok(primary) = env::var(PRIMARY_ENV) else {
+            return;
+        };
+        let (dir, addr) = primary.split_once(' ').unwrap();
+        let store = open_at(dir, false);
+        let primary = Primary::start(&store, addr, config()).unwrap();
+        println!("listening {}", primary.local_addr());
+
+        for line in std::io::stdin().lines() {
+            let line = line.unwrap();
+            let command: Vec<&str> = line.split(' ').collect();
+            let range = || command[1].parse::<usize>().unwrap()..command[2].parse().unwrap();
+            match command[0] {
+                "put" => {
+                    for i in range() {
+                        let val = format!("{}{}", command[3], i);
+                        store.put(i.to_string().into(), val.into()).unwrap();
+                    }
+                }
+                "delete" => {
+                    for i in range() {
+                        store.delete(i.to_string().into()).unwrap();
+                    }
+                }
+                "batch" => {
+                    let batch = store.new_batched();
+                    for i in range() {
+                        let val = format!("{}{}", command[3], i);
+                        batch.put(i.to_string().into(), val.into()).unwrap();
+                    }
+                    batch.commit().unwrap();
+                }
+                // shippers hold the merge lock while moving on to the next file
+                "merge" => loop {
+                    match store.merge() {
+                        Err(Errors::MergeInProgress) => thread::sleep(Duration::from_millis(1)),
+                        res => break res.unwrap(),
+                    }
+                },
+                command => panic!("Unknown command {:?}", command),
+            }
+            println!("done");
+        }
+    }
+
+    struct PrimaryProcess {
+        child: Child,
+        stdin: ChildStdin,
+        stdout: Lines<BufReader<ChildStdout>>,
+    }
+
+    impl PrimaryProcess {
+        fn spawn(dir: &str, addr: &str) -> (Self, SocketAddr) {
+            let mut child = Command::new(env::current_exe().unwrap())
+                .args([
+                    "replication::follower::tests::primary_process",
+                    "--exact",
+                    "--nocapture",
+                ])
+                .env(PRIMARY_ENV, format!("{} {}", dir, addr))
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .spawn()
+                .unwrap();
+            let mut primary = Self {
+                stdin: child.stdin.take().unwrap(),
+                stdout: BufReader::new(child.stdout.take().unwrap()).lines(),
+                child,
+            };
+            let addr = primary.wait_for("listening ");
+            (primary, addr.parse().unwrap())
+        }
+
+        /// What follows `prefix` on the next line holding it,
+        ///     the first one starts with the name of the test.
+        fn wait_for(&mut self, prefix: &str) -> String {
+            for line in &mut self.stdout {
+                if let Some((_, rest)) = line.unwrap().split_once(prefix) {
+                    return rest.to_string();
+                }
+            }
+            panic!("Primary exited before printing {:?}", prefix);
+        }
+
+        fn run(&mut self, command: &str) {
+            writeln!(self.stdin, "{}", command).unwrap();
+            self.wait_for("done");
+        }
+    }
+
+    impl Drop for PrimaryProcess {
+        fn drop(&mut self) {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+    }
+
+    fn wait_until(what: &str, done: impl Fn() -> bool) {
+        let start = Instant::now();
+        while !done() {
+            assert!(start.elapsed() < Duration::from_secs(20), "{}", what);
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// keys `from..to` hold `tag<key>`
+    fn assert_range(store: &Store, from: usize, to: usize, tag: &str) {
+        for i in from..to {
+            assert_eq!(
+                store.get(i.to_string().into()).unwrap(),
+                format!("{}{}", tag, i)
+            );
+        }
+    }
+
+    fn caught_up(store: &Store, key: usize, tag: &str) -> bool {
+        store
+            .get(key.to_string().into())
+            .is_ok_and(|val| val == format!("{}{}", tag, key))
+    }
+
+    #[test]
+    fn test_replication() {
+        let test_id = 59;
+        let primary_dir = format!("store/test_{}_primary", test_id);
+        let replica_dir = format!("store/test_{}_replica", test_id);
+        // remove if exist
+        fs::remove_dir_all(primary_dir.clone());
+        fs::remove_dir_all(replica_dir.clone());
+
+        let (mut primary, addr) = PrimaryProcess::spawn(&primary_dir, "127.0.0.1:0");
+        primary.run("put 0 500 a");
+
+        // catches up from scratch, across rotations
+        let replica = open_at(&replica_dir, true);
+        let follower = Follower::start(&replica, addr, config()).unwrap();
+        wait_until("initial catch up", || caught_up(&replica, 499, "a"));
+        assert_range(&replica, 0, 500, "a");
+        assert!(
+            get_prefix_numbers(replica_dir.clone().into())
+                .unwrap()
+                .len()
+                > 1
+        );
+
+        // a replica is read only
+        assert!(matches!(
+            replica.put("a".into(), "b".into()),
+            Err(Errors::ReplicaIsReadOnly)
+        ));
+        assert!(matches!(
+            replica.delete("0".into()),
+            Err(Errors::ReplicaIsReadOnly)
+        ));
+        assert!(matches!(replica.merge(), Err(Errors::ReplicaIsReadOnly)));
+        assert!(matches!(
+            Primary::start(&replica, "127.0.0.1:0", config()),
+            Err(Errors::ReplicaIsReadOnly)
+        ));
+
+        // deletes, batches and a merge, which retires the same files on both sides
+        primary.run("delete 0 100");
+        primary.run("put 100 200 b");
+        primary.run("batch 200 250 c");
+        primary.run("merge");
+        primary.run("put 500 600 d");
+        wait_until("catch up after merge", || caught_up(&replica, 599, "d"));
+        wait_until("same files after merge", || {
+            get_prefix_numbers(replica_dir.clone().into()).unwrap()
+                == get_prefix_numbers(primary_dir.clone().into()).unwrap()
+        });
+        assert!(replica.get("0".into()).is_err());
+        assert_range(&replica, 100, 200, "b");
+        assert_range(&replica, 200, 250, "c");
+        assert_range(&replica, 250, 500, "a");
+        assert_range(&replica, 500, 600, "d");
+        assert_eq!(replica.list_keys().len(), 500);
+
+        // the follower reconnects to a restarted primary
+        drop(primary);
+        let (mut primary, _) = PrimaryProcess::spawn(&primary_dir, &addr.to_string());
+        primary.run("put 600 700 e");
+        wait_until("catch up after primary restart", || {
+            caught_up(&replica, 699, "e")
+        });
+        assert_range(&replica, 600, 700, "e");
+
+        // and a reopened replica resumes where it was
+        drop(follower);
+        drop(replica);
+        primary.run("put 0 50 f");
+        primary.run("merge");
+        primary.run("put 700 800 g");
+        let replica = open_at(&replica_dir, true);
+        let follower = Follower::start(&replica, addr, config()).unwrap();
+        wait_until("catch up after replica restart", || {
+            caught_up(&replica, 799, "g")
+        });
+        assert_range(&replica, 0, 50, "f");
+        assert!(replica.get("50".into()).is_err());
+        assert_range(&replica, 100, 200, "b");
+        assert_range(&replica, 250, 500, "a");
+        assert_range(&replica, 700, 800, "g");
+        assert_eq!(replica.list_keys().len(), 750);
+
+        drop(follower);
+        drop(replica);
+        drop(primary);
+        fs::remove_dir_all(primary_dir).unwrap();
+        fs::remove_dir_all(replica_dir).unwrap();
+    }
+}
@@ -0,0 +1,14 @@
+/*
+    Log shipping replication:
+    a primary ships the bytes of its store files to followers over TCP, as they are appended,
+    so the files of a replica are those of its primary, at the same ids and offsets.
+    Rotations are shipped as the header of the new file,
+    merges as the compacted files, then the manifest publishing them.
+    A follower applies them to a store opened with `Store::open_replica`, which serves reads meanwhile,
+    and after a disconnect resumes from the end of its log and the last merge it applied.
+    Blob files are not shipped: a store separating values into blobs can not be replicated.
+*/
+
+pub mod follower;
+pub mod primary;
+pub mod protocol;
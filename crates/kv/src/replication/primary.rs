@@ -0,0 +1,400 @@
+/*
+    Primary side of replication:
+    every follower connection gets a thread that ships from where the follower's log ends,
+    polling the store files for what was appended since.
+    Only whole records below the write offset are shipped, those never change.
+    A merge is shipped under `merge_lock`, as is every move on to the next file,
+    so files installed by a merge only ever reach a follower along with their merge;
+    a merge started meanwhile fails with `MergeInProgress`, as with any other holder of the lock.
+*/
+
+use std::{
+    fs::File,
+    io::ErrorKind,
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    os::unix::fs::FileExt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Weak,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use log::{error, info, warn};
+
+use crate::{
+    config::config::ReplicationConfig,
+    definitions::types::ByteVec,
+    errors::{Errors, Result},
+    merge::merge::MergeManifest,
+    propagate_err,
+    records::log_record::LogRecordPtr,
+    store::{file_handle::FileHandle, store::Store, utils::format_filename},
+};
+
+use super::protocol::{Message, CHUNK_SIZE};
+
+/// Handle of the thread accepting followers, replication stops when this is dropped.
+///
+/// Threads only hold a weak reference,
+///     so they never keep the store alive on their own.
+pub struct Primary {
+    local_addr: SocketAddr,
+    stopped: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Primary {
+    /// Ship `store` to every follower connecting to `addr`.
+    pub fn start(
+        store: &Arc<Store>,
+        addr: impl ToSocketAddrs,
+        config: ReplicationConfig,
+    ) -> Result<Self> {
+        store.check_writable()?;
+        if store.store_config.blob.enabled {
+            return Err(Errors::BlobReplicationUnsupported);
+        }
+        let listener = TcpListener::bind(addr)
+            .map_err(propagate_err!(Errors::ReplicationConnectionFailure))?;
+        // accepting must not block, to notice a stop
+        listener
+            .set_nonblocking(true)
+            .map_err(propagate_err!(Errors::ReplicationConnectionFailure))?;
+        let local_addr = listener
+            .local_addr()
+            .map_err(propagate_err!(Errors::ReplicationConnectionFailure))?;
+
+        let stopped = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let store = Arc::downgrade(store);
+            let stopped = stopped.clone();
+            thread::spawn(move || Self::accept(listener, store, config, stopped))
+        };
+        info!("Replication primary listening on {}", local_addr);
+
+        Ok(Self {
+            local_addr,
+            stopped,
+            thread: Some(thread),
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    fn accept(
+        listener: TcpListener,
+        store: Weak<Store>,
+        config: ReplicationConfig,
+        stopped: Arc<AtomicBool>,
+    ) {
+        let poll_interval = Duration::from_millis(config.poll_interval_ms);
+        let mut shippers: Vec<JoinHandle<()>> = Vec::new();
+        while !stopped.load(Ordering::Relaxed) && store.strong_count() > 0 {
+            match listener.accept() {
+                Ok((stream, peer)) => {
+                    info!("Follower {} connected", peer);
+                    let store = store.clone();
+                    let stopped = stopped.clone();
+                    shippers.push(thread::spawn(move || {
+                        match Shipper::ship(stream, store, config, stopped) {
+                            Ok(()) => info!("Stopped shipping to follower {}", peer),
+                            Err(e) => warn!("Shipping to follower {} failed: {}", peer, e),
+                        }
+                    }));
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(poll_interval),
+                Err(e) => {
+                    warn!("Failed to accept a follower: {}", e);
+                    thread::sleep(poll_interval);
+                }
+            }
+            shippers.retain(|shipper| !shipper.is_finished());
+        }
+
+        // the shippers see the stop too
+        stopped.store(true, Ordering::Relaxed);
+        for shipper in shippers {
+            if shipper.join().is_err() {
+                error!("Replication shipper thread panicked");
+            }
+        }
+    }
+}
+
+impl Drop for Primary {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("Replication primary thread panicked");
+            }
+        }
+    }
+}
+
+/// Where a follower is, as far as shipped
+struct Shipper {
+    position: LogRecordPtr,
+    generation: u64,
+}
+
+impl Shipper {
+    fn ship(
+        mut stream: TcpStream,
+        store: Weak<Store>,
+        config: ReplicationConfig,
+        stopped: Arc<AtomicBool>,
+    ) -> Result<()> {
+        let poll_interval = Duration::from_millis(config.poll_interval_ms);
+        let heartbeat_interval = Duration::from_millis(config.heartbeat_interval_ms);
+        stream
+            .set_nonblocking(false)
+            .and_then(|_| stream.set_nodelay(true))
+            .and_then(|_| stream.set_read_timeout(Some(heartbeat_interval * 3)))
+            .and_then(|_| stream.set_write_timeout(Some(heartbeat_interval * 3)))
+            .map_err(propagate_err!(Errors::ReplicationConnectionFailure))?;
+
+        let Message::Hello {
+            position,
+            generation,
+        } = Message::read_from(&mut stream)?
+        else {
+            return Err(Errors::InvalidReplicationMessage);
+        };
+        info!(
+            "Shipping from {:?}, follower at merge generation {}",
+            position, generation
+        );
+        let mut shipper = Self {
+            position,
+            generation,
+        };
+
+        let mut last_sent = Instant::now();
+        while !stopped.load(Ordering::Relaxed) {
+            let Some(store) = store.upgrade() else {
+                return Ok(());
+            };
+            let shipped = shipper.step(&store, &mut stream)?;
+            drop(store);
+
+            if shipped {
+                last_sent = Instant::now();
+                continue;
+            }
+            if last_sent.elapsed() >= heartbeat_interval {
+                Message::Heartbeat.write_to(&mut stream)?;
+                last_sent = Instant::now();
+            }
+            thread::sleep(poll_interval);
+        }
+        Ok(())
+    }
+
+    /// Ship what comes next, returns false if the follower is caught up.
+    fn step(&mut self, store: &Store, stream: &mut TcpStream) -> Result<bool> {
+        if store.merge_generation() != self.generation {
+            return self.ship_merge(store, stream);
+        }
+
+        // read under the lock of the file, shipped without it
+        let res = store.with_file(self.position.file_id, |file, sealed| {
+            Ok((self.read_records(file, sealed)?, sealed))
+        });
+        match res {
+            Ok((Some(bytes), _)) => {
+                let len = bytes.len() as u64;
+                Message::Records {
+                    position: self.position,
+                    bytes,
+                }
+                .write_to(stream)?;
+                self.position.offset += len;
+                Ok(true)
+            }
+            // more may be appended to the active file before it is sealed
+            Ok((None, false)) => Ok(false),
+            Ok((None, true)) => self.ship_next_file(store, stream),
+            Err(Errors::StoreFileNotFound { .. }) => self.file_gone(store),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Whole records of `file` from the position on, none if there are none yet:
+    ///     only records below the write offset of the active file are complete.
+    fn read_records(&self, file: &FileHandle, sealed: bool) -> Result<Option<ByteVec>> {
+        let end = if sealed {
+            file.size()
+        } else {
+            file.get_write_offset()
+        };
+        if self.position.offset > end {
+            return Err(Errors::ReplicationOutOfOrder {
+                expected: LogRecordPtr {
+                    file_id: self.position.file_id,
+                    offset: end,
+                },
+                got: self.position,
+            });
+        }
+
+        let mut len = 0;
+        while self.position.offset + len < end {
+            let size = file.record_size_at(self.position.offset + len)?;
+            if len > 0 && len + size > CHUNK_SIZE as u64 {
+                break;
+            }
+            len += size;
+        }
+        if len == 0 {
+            return Ok(None);
+        }
+        Ok(Some(file.read_raw(self.position.offset, len as usize)?))
+    }
+
+    /// The position is at the end of a sealed file, continue in the next one.
+    fn ship_next_file(&mut self, store: &Store, stream: &mut TcpStream) -> Result<bool> {
+        // files a merge installs sort before the next file, they go with their merge
+        let _merge_lock = store.merge_lock.lock();
+        if store.merge_generation() != self.generation {
+            return Ok(true);
+        }
+
+        let next_file_id = {
+            let _active_file = store.active_file.read();
+            let active_file_id = store
+                .active_file_id
+                .load(std::sync::atomic::Ordering::Relaxed);
+            store
+                .legacy_files
+                .read()
+                .keys()
+                .copied()
+                .filter(|file_id| *file_id > self.position.file_id)
+                .min()
+                .unwrap_or(active_file_id)
+        };
+        let header = store.with_file(next_file_id, |file, _| Ok(file.header()))?;
+        Message::File { header }.write_to(stream)?;
+        self.position = LogRecordPtr {
+            file_id: next_file_id,
+            offset: 0,
+        };
+        Ok(true)
+    }
+
+    /// The file at the position was retired by a merge.
+    fn file_gone(&mut self, store: &Store) -> Result<bool> {
+        // a merge under way publishes its manifest before retiring files
+        let _merge_lock = store.merge_lock.lock();
+        if store.merge_generation() == self.generation {
+            return Err(Errors::StoreFileNotFound {
+                file_id: self.position.file_id,
+            });
+        }
+        Ok(true)
+    }
+
+    /// Ship the files installed by the last merge, then its manifest.
+    fn ship_merge(&mut self, store: &Store, stream: &mut TcpStream) -> Result<bool> {
+        // a merge under way finishes first
+        let _merge_lock = store.merge_lock.lock();
+        let dir = store.store_config.dir.clone();
+        let manifest = MergeManifest::load(dir.clone())?.ok_or(Errors::MergeNotFound)?;
+
+        for file_id in manifest.installed.iter().copied() {
+            let file = File::open(format_filename(dir.clone(), file_id))
+                .map_err(propagate_err!(Errors::FileInitError))?;
+            let size = file
+                .metadata()
+                .map_err(propagate_err!(Errors::FileIoReadError))?
+                .len();
+            let mut offset = 0;
+            while offset < size {
+                let mut bytes: ByteVec = vec![0; CHUNK_SIZE.min((size - offset) as usize)];
+                file.read_exact_at(&mut bytes, offset)
+                    .map_err(propagate_err!(Errors::FileIoReadError))?;
+                let len = bytes.len() as u64;
+                Message::Stage {
+                    generation: manifest.generation,
+                    file_id,
+                    offset,
+                    bytes,
+                }
+                .write_to(stream)?;
+                offset += len;
+            }
+        }
+
+        let kept_from = manifest.kept_from();
+        Message::Merge {
+            generation: manifest.generation,
+            compacted_seq: manifest.compacted_seq,
+            installed: manifest.installed.clone(),
+            kept_from: store.with_file(kept_from, |file, _| Ok(file.header()))?,
+        }
+        .write_to(stream)?;
+        info!(
+            "Shipped merge generation {}, installing {:?}",
+            manifest.generation, manifest.installed
+        );
+
+        self.generation = manifest.generation;
+        // what the follower had of replaced files is replaced along with them
+        if self.position.file_id < kept_from {
+            self.position = LogRecordPtr {
+                file_id: kept_from,
+                offset: 0,
+            };
+        }
+        Ok(true)
+    }
+}
+
+impl Store {
+    /// Where the log of this store ends: the write offset of its active file.
+    pub fn log_end(&self) -> LogRecordPtr {
+        let active_file = self.active_file.read();
+        LogRecordPtr {
+            file_id: self
+                .active_file_id
+                .load(std::sync::atomic::Ordering::Relaxed),
+            offset: active_file.get_write_offset(),
+        }
+    }
+
+    /// Runs `f` on store file `file_id`, along with whether it is sealed.
+    fn with_file<T>(
+        &self,
+        file_id: u32,
+        f: impl FnOnce(&FileHandle, bool) -> Result<T>,
+    ) -> Result<T> {
+        // the active file id only changes under the write lock of the active file
+        let active_file = self.active_file.read();
+        let active_file_id = self
+            .active_file_id
+            .load(std::sync::atomic::Ordering::Relaxed);
+        if file_id == active_file_id {
+            return f(&active_file, false);
+        }
+        if file_id > active_file_id {
+            return Err(Errors::ReplicationOutOfOrder {
+                expected: LogRecordPtr {
+                    file_id: active_file_id,
+                    offset: active_file.get_write_offset(),
+                },
+                got: LogRecordPtr { file_id, offset: 0 },
+            });
+        }
+        drop(active_file);
+        let files = self.legacy_files.read();
+        let file = files
+            .get(&file_id)
+            .ok_or(Errors::StoreFileNotFound { file_id })?;
+        f(file, true)
+    }
+}
@@ -0,0 +1,278 @@
+/*
+    Replication messages, framed as |kind u8|payload length u32|payload|:
+        Hello       follower -> primary, once: |file id u32|offset u64|merge generation u64|
+                    where the follower's log ends, and the last merge it applied
+        Records     |file id u32|offset u64|bytes|, whole records appended at the end of the active file
+        File        |header|, the active file is sealed, writes continue in a new file of this header
+        Stage       |generation u64|file id u32|offset u64|bytes|, part of a file compacted by merge,
+                    offsets count from the start of the file, header included
+        Merge       |generation u64|compacted seq u64|n u32|n file ids u32|header|:
+                    publish the staged files; every older file is replaced,
+                    files from the one of `header` on are kept
+        Heartbeat   ||, the primary is alive but has nothing to ship
+*/
+
+use std::{
+    io::{Read, Write},
+    path::Path,
+};
+
+use bytes::{Buf, BufMut};
+use log::error;
+
+use crate::{
+    definitions::types::ByteVec,
+    errors::{Errors, Result},
+    records::log_record::LogRecordPtr,
+    store::file_header::FileHeader,
+};
+
+/// Upper bound of the bytes shipped in one message, whole records may exceed it
+pub(crate) const CHUNK_SIZE: usize = 1 << 16;
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum Message {
+    Hello {
+        position: LogRecordPtr,
+        generation: u64,
+    },
+    Records {
+        position: LogRecordPtr,
+        bytes: ByteVec,
+    },
+    File {
+        header: FileHeader,
+    },
+    Stage {
+        generation: u64,
+        file_id: u32,
+        offset: u64,
+        bytes: ByteVec,
+    },
+    Merge {
+        generation: u64,
+        compacted_seq: u64,
+        installed: Vec<u32>,
+        /// header of the first file kept
+        kept_from: FileHeader,
+    },
+    Heartbeat,
+}
+
+impl Message {
+    const HELLO: u8 = 0;
+    const RECORDS: u8 = 1;
+    const FILE: u8 = 2;
+    const STAGE: u8 = 3;
+    const MERGE: u8 = 4;
+    const HEARTBEAT: u8 = 5;
+
+    /// |kind|payload length|
+    const FRAME_HEADER_LENGTH: usize = 1 + 4;
+
+    pub(crate) fn write_to(&self, stream: &mut impl Write) -> Result<()> {
+        stream.write_all(&self.encode()).map_err(propagate_io_err)
+    }
+
+    pub(crate) fn read_from(stream: &mut impl Read) -> Result<Self> {
+        let mut frame_header = [0; Self::FRAME_HEADER_LENGTH];
+        stream
+            .read_exact(&mut frame_header)
+            .map_err(propagate_io_err)?;
+        let mut frame_header = &frame_header[..];
+        let kind = frame_header.get_u8();
+        let mut payload = vec![0; frame_header.get_u32() as usize];
+        stream.read_exact(&mut payload).map_err(propagate_io_err)?;
+        Self::decode(kind, &payload)
+    }
+
+    fn encode(&self) -> ByteVec {
+        let mut payload = Vec::new();
+        let kind = match self {
+            Message::Hello {
+                position,
+                generation,
+            } => {
+                payload.put_u32(position.file_id);
+                payload.put_u64(position.offset);
+                payload.put_u64(*generation);
+                Self::HELLO
+            }
+            Message::Records { position, bytes } => {
+                payload.put_u32(position.file_id);
+                payload.put_u64(position.offset);
+                payload.put_slice(bytes);
+                Self::RECORDS
+            }
+            Message::File { header } => {
+                payload.put_slice(&header.encode());
+                Self::FILE
+            }
+            Message::Stage {
+                generation,
+                file_id,
+                offset,
+                bytes,
+            } => {
+                payload.put_u64(*generation);
+                payload.put_u32(*file_id);
+                payload.put_u64(*offset);
+                payload.put_slice(bytes);
+                Self::STAGE
+            }
+            Message::Merge {
+                generation,
+                compacted_seq,
+                installed,
+                kept_from,
+            } => {
+                payload.put_u64(*generation);
+                payload.put_u64(*compacted_seq);
+                payload.put_u32(installed.len() as u32);
+                for file_id in installed {
+                    payload.put_u32(*file_id);
+                }
+                payload.put_slice(&kept_from.encode());
+                Self::MERGE
+            }
+            Message::Heartbeat => Self::HEARTBEAT,
+        };
+
+        let mut res = Vec::with_capacity(Self::FRAME_HEADER_LENGTH + payload.len());
+        res.put_u8(kind);
+        res.put_u32(payload.len() as u32);
+        res.extend_from_slice(&payload);
+        res
+    }
+
+    fn decode(kind: u8, mut payload: &[u8]) -> Result<Self> {
+        // the lengths of fixed fields, checked before they are read
+        let fixed_length = match kind {
+            Self::HELLO => 4 + 8 + 8,
+            Self::RECORDS => 4 + 8,
+            Self::STAGE => 8 + 4 + 8,
+            Self::MERGE => 8 + 8 + 4,
+            Self::FILE | Self::HEARTBEAT => 0,
+            _ => {
+                error!("Unknown replication message kind {}", kind);
+                return Err(Errors::InvalidReplicationMessage);
+            }
+        };
+        if payload.len() < fixed_length {
+            error!("Replication message of kind {} cut short", kind);
+            return Err(Errors::InvalidReplicationMessage);
+        }
+
+        let message = match kind {
+            Self::HELLO => Message::Hello {
+                position: LogRecordPtr {
+                    file_id: payload.get_u32(),
+                    offset: payload.get_u64(),
+                },
+                generation: payload.get_u64(),
+            },
+            Self::RECORDS => Message::Records {
+                position: LogRecordPtr {
+                    file_id: payload.get_u32(),
+                    offset: payload.get_u64(),
+                },
+                bytes: payload.to_vec(),
+            },
+            Self::FILE => Message::File {
+                header: decode_header(payload)?,
+            },
+            Self::STAGE => Message::Stage {
+                generation: payload.get_u64(),
+                file_id: payload.get_u32(),
+                offset: payload.get_u64(),
+                bytes: payload.to_vec(),
+            },
+            Self::MERGE => {
+                let generation = payload.get_u64();
+                let compacted_seq = payload.get_u64();
+                let n = payload.get_u32() as usize;
+                if payload.len() < n * 4 {
+                    error!("Replication message of kind {} cut short", kind);
+                    return Err(Errors::InvalidReplicationMessage);
+                }
+                let installed = (0..n).map(|_| payload.get_u32()).collect();
+                Message::Merge {
+                    generation,
+                    compacted_seq,
+                    installed,
+                    kept_from: decode_header(payload)?,
+                }
+            }
+            _ => Message::Heartbeat,
+        };
+        Ok(message)
+    }
+}
+
+fn decode_header(bin: &[u8]) -> Result<FileHeader> {
+    FileHeader::decode(bin, Path::new("<replication stream>"))
+}
+
+fn propagate_io_err(e: std::io::Error) -> Errors {
+    error!("Replication connection failed: {}", e);
+    Errors::ReplicationConnectionFailure
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{records::log_record::LogRecordPtr, store::file_header::FileHeader};
+
+    use super::Message;
+
+    #[test]
+    fn test_message_roundtrip() {
+        let messages = [
+            Message::Hello {
+                position: LogRecordPtr {
+                    file_id: 3,
+                    offset: 1024,
+                },
+                generation: 2,
+            },
+            Message::Records {
+                position: LogRecordPtr {
+                    file_id: 4,
+                    offset: 0,
+                },
+                bytes: b"records".to_vec(),
+            },
+            Message::File {
+                header: FileHeader::new(5).with_first_seq(77),
+            },
+            Message::Stage {
+                generation: 3,
+                file_id: 6,
+                offset: 30,
+                bytes: vec![0; 100],
+            },
+            Message::Merge {
+                generation: 3,
+                compacted_seq: 120,
+                installed: vec![6, 7],
+                kept_from: FileHeader::new(9).with_first_seq(120),
+            },
+            Message::Heartbeat,
+        ];
+
+        let mut stream = Vec::new();
+        for message in &messages {
+            message.write_to(&mut stream).unwrap();
+        }
+        let mut stream = &stream[..];
+        for message in messages {
+            assert_eq!(Message::read_from(&mut stream).unwrap(), message);
+        }
+        assert!(Message::read_from(&mut stream).is_err());
+
+        // a frame cut short
+        let mut bin = Vec::new();
+        Message::Heartbeat.write_to(&mut bin).unwrap();
+        bin[0] = Message::HELLO;
+        assert!(Message::read_from(&mut &bin[..]).is_err());
+    }
+}
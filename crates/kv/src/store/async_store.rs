@@ -0,0 +1,221 @@
+/*
+    Async access, behind the `async` feature:
+    every call runs the blocking store operation on a pool of threads of its own,
+    so callers on an async runtime never block its workers on disk I/O or fsync.
+    The pool belongs to the `AsyncStore`, not to the caller's runtime,
+    a burst of slow writes can not starve the runtime of blocking threads either.
+
+    Iteration streams pairs through a bounded channel,
+    the pool thread walking the store waits while the consumer is behind,
+    and stops once the stream is dropped.
+*/
+
+use std::{
+    io, panic,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use bytes::Bytes;
+use futures_core::Stream;
+use tokio::{
+    runtime::{Builder, Runtime},
+    sync::mpsc,
+};
+
+use crate::{
+    batched::batched_write::BatchedWrite,
+    definitions::{constants::ITER_CHUNK_SIZE, types::KvBytes},
+    errors::Result,
+    index::iter::KvIteratorOptions,
+    records::log_record::LogRecordPtr,
+};
+
+use super::store::Store;
+
+pub struct AsyncStore {
+    store: Arc<Store>,
+    /// only its blocking pool is used; `None` once shut down on drop
+    pool: Option<Runtime>,
+}
+
+impl AsyncStore {
+    /// Default number of pool threads
+    pub const POOL_THREADS: usize = 8;
+
+    pub fn new(store: Arc<Store>) -> io::Result<Self> {
+        Self::with_threads(store, Self::POOL_THREADS)
+    }
+
+    pub fn with_threads(store: Arc<Store>, threads: usize) -> io::Result<Self> {
+        let pool = Builder::new_multi_thread()
+            .worker_threads(1)
+            .max_blocking_threads(threads.max(1))
+            .thread_name("kv-blocking")
+            .build()?;
+        Ok(Self {
+            store,
+            pool: Some(pool),
+        })
+    }
+
+    pub fn store(&self) -> &Arc<Store> {
+        &self.store
+    }
+
+    /// Runs `f` on the pool, resuming its panic if it panics.
+    pub async fn blocking<T, F>(&self, f: F) -> T
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let pool = self.pool.as_ref().expect("Internal error: pool shut down");
+        match pool.spawn_blocking(f).await {
+            Ok(res) => res,
+            Err(e) => panic::resume_unwind(e.into_panic()),
+        }
+    }
+
+    /// Runs `f` on the store, on the pool.
+    pub async fn run<T, F>(&self, f: F) -> T
+    where
+        T: Send + 'static,
+        F: FnOnce(&Store) -> T + Send + 'static,
+    {
+        let store = Arc::clone(&self.store);
+        self.blocking(move || f(&store)).await
+    }
+
+    pub async fn get(&self, key: Bytes) -> Result<Bytes> {
+        self.run(move |store| store.get(key)).await
+    }
+
+    pub async fn put(&self, key: Bytes, value: Bytes) -> Result<LogRecordPtr> {
+        self.run(move |store| store.put(key, value)).await
+    }
+
+    pub async fn delete(&self, key: Bytes) -> Result<LogRecordPtr> {
+        self.run(move |store| store.delete(key)).await
+    }
+
+    /// Commits `batch`, made with `CreateBatch::new_batched` on `store()`.
+    pub async fn commit(&self, batch: BatchedWrite) -> Result<()> {
+        self.blocking(move || batch.commit()).await
+    }
+
+    pub async fn merge(&self) -> Result<()> {
+        self.run(|store| store.merge()).await
+    }
+
+    /// Streams the pairs of the iterator `options` sets up, e.g.
+    ///     `async_store.iter(|options| options.with_key_prefix(prefix))`
+    pub fn iter<F>(&self, options: F) -> KvStream
+    where
+        F: for<'a> FnOnce(KvIteratorOptions<'a>) -> KvIteratorOptions<'a> + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel(ITER_CHUNK_SIZE);
+        let store = Arc::clone(&self.store);
+        let pool = self.pool.as_ref().expect("Internal error: pool shut down");
+        pool.spawn_blocking(move || {
+            for kv in options(store.iter_options()).make() {
+                if sender.blocking_send(kv).is_err() {
+                    // stream dropped
+                    break;
+                }
+            }
+        });
+        KvStream { receiver }
+    }
+}
+
+impl Drop for AsyncStore {
+    fn drop(&mut self) {
+        // may be dropped on an async runtime, where waiting for the pool is not allowed;
+        //      running tasks finish on their own
+        if let Some(pool) = self.pool.take() {
+            pool.shutdown_background();
+        }
+    }
+}
+
+/// Pairs of `AsyncStore::iter`, in iterator order.
+pub struct KvStream {
+    receiver: mpsc::Receiver<KvBytes>,
+}
+
+impl KvStream {
+    pub async fn next(&mut self) -> Option<KvBytes> {
+        self.receiver.recv().await
+    }
+}
+
+impl Stream for KvStream {
+    type Item = KvBytes;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<KvBytes>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::runtime::Builder;
+
+    use crate::{batched::batched_write::CreateBatch, errors::Errors, store::utils::TempStore};
+
+    use super::AsyncStore;
+
+    #[test]
+    fn test_async_store() {
+        let (_raii, store) = TempStore::init(50);
+        let store = AsyncStore::with_threads(Arc::new(store), 4).unwrap();
+        // the caller's runtime has a single thread, the pool does the I/O
+        let runtime = Builder::new_current_thread().build().unwrap();
+
+        runtime.block_on(async {
+            for i in 0..200 {
+                let key = format!("{:03}", i);
+                let val = english_numbers::convert_all_fmt(i);
+                store.put(key.into(), val.into()).await.unwrap();
+            }
+            assert_eq!(
+                store.get("042".into()).await.unwrap(),
+                english_numbers::convert_all_fmt(42)
+            );
+            store.delete("042".into()).await.unwrap();
+            assert!(matches!(
+                store.get("042".into()).await,
+                Err(Errors::KeyNotFound)
+            ));
+
+            let batch = store.store().new_batched();
+            batch.put("batched".into(), "value".into()).unwrap();
+            batch.delete("000".into()).unwrap();
+            store.commit(batch).await.unwrap();
+            assert_eq!(store.get("batched".into()).await.unwrap(), "value");
+
+            store.merge().await.unwrap();
+
+            // "100".."199", backwards
+            let mut stream = store.iter(|options| options.with_key_prefix(b"1".to_vec()).rev());
+            let mut count = 0;
+            while let Some(kv) = stream.next().await {
+                let i = 199 - count;
+                assert_eq!(kv.key, format!("{:03}", i));
+                assert_eq!(kv.value, english_numbers::convert_all_fmt(i));
+                count += 1;
+            }
+            assert_eq!(count, 100);
+
+            // dropped halfway, the pool thread stops
+            let mut stream = store.iter(|options| options);
+            assert_eq!(stream.next().await.unwrap().key, "001");
+            drop(stream);
+        });
+
+        assert_eq!(store.store().list_keys().len(), 199);
+    }
+}
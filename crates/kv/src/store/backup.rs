@@ -1,16 +1,23 @@
 use std::{
-    fs,
+    fs::{self, File},
+    io,
     path::{Path, PathBuf},
 };
 
+use crate::{
+    definitions::constants::{KEY_CHECK_FILE_NAME, MERGE_MANIFEST_FILE_NAME},
+    store::utils::{format_blob_filename, format_filename, format_hint_filename, sync_dir},
+};
+
 use super::store::Store;
 
 impl Store {
     pub fn blocking_copy_to(&self, dest_dir: PathBuf) -> anyhow::Result<()> {
-        // lock all writes
-        let _lock1 = self.active_file.write();
+        // lock all writes, in the same order as merge and batch commit do
+        let _lock1 = self.merge_lock.lock();
         let _lock2 = self.batch_commit_lock.lock();
-        let _lock3 = self.merge_lock.lock();
+        let _lock3 = self.write_lock.lock();
+        let _lock4 = self.active_file.write();
 
         // backup
         let dir = self.store_config.dir.clone();
@@ -19,9 +26,98 @@ impl Store {
 
         Ok(())
     }
+
+    /// Copy of the store as of now into `dest_dir`, which opens as a store of its own.
+    ///     Sealed files never change and are hard linked, of the files written to
+    ///     only what was written by now is copied; writes wait only while the files are listed.
+    /// A persisted index is left out, the copy rebuilds it on open.
+    pub fn checkpoint(&self, dest_dir: PathBuf) -> anyhow::Result<()> {
+        // merge and blob collection remove sealed files
+        let _merge_lock = self.merge_lock.lock();
+        let dir = self.store_config.dir.clone();
+        let (sealed_files, active_file, sealed_blobs, active_blob) = {
+            // a batch is written under the lock as a whole, the copy holds all of it or none
+            let _write_lock = self.write_lock.lock();
+            let mut sealed_files: Vec<u32> = self.legacy_files.read().keys().copied().collect();
+            sealed_files.sort_unstable();
+            let active_file = self.active_file.read();
+            let active_file = (
+                self.active_file_id
+                    .load(std::sync::atomic::Ordering::Relaxed),
+                active_file.header().length() + active_file.get_write_offset(),
+            );
+            (
+                sealed_files,
+                active_file,
+                self.blobs.sealed_ids(),
+                self.blobs.active_end(),
+            )
+        };
+
+        fs::create_dir_all(&dest_dir)?;
+        for file_id in sealed_files {
+            link_or_copy(
+                format_filename(dir.clone(), file_id),
+                format_filename(dest_dir.clone(), file_id),
+            )?;
+            let hint = format_hint_filename(dir.clone(), file_id);
+            if hint.is_file() {
+                link_or_copy(hint, format_hint_filename(dest_dir.clone(), file_id))?;
+            }
+        }
+        for file_id in sealed_blobs {
+            link_or_copy(
+                format_blob_filename(dir.clone(), file_id),
+                format_blob_filename(dest_dir.clone(), file_id),
+            )?;
+        }
+
+        let (file_id, len) = active_file;
+        copy_prefix(
+            format_filename(dir.clone(), file_id),
+            format_filename(dest_dir.clone(), file_id),
+            len,
+        )?;
+        if let Some((file_id, len)) = active_blob {
+            copy_prefix(
+                format_blob_filename(dir.clone(), file_id),
+                format_blob_filename(dest_dir.clone(), file_id),
+                len,
+            )?;
+        }
+        for name in [KEY_CHECK_FILE_NAME, MERGE_MANIFEST_FILE_NAME] {
+            let path = dir.join(name);
+            if path.is_file() {
+                fs::copy(path, dest_dir.join(name))?;
+            }
+        }
+
+        sync_dir(dest_dir)?;
+        Ok(())
+    }
 }
 
-fn copy_dir_contents<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> std::io::Result<()> {
+/// Hard link `from` at `to`, or copy it where links are not possible, e.g. across file systems.
+pub(crate) fn link_or_copy(from: PathBuf, to: PathBuf) -> io::Result<()> {
+    if fs::hard_link(&from, &to).is_ok() {
+        return Ok(());
+    }
+    fs::copy(&from, &to)?;
+    File::open(to)?.sync_all()
+}
+
+/// Copy the first `len` bytes of `from` to `to`.
+pub(crate) fn copy_prefix(from: PathBuf, to: PathBuf, len: u64) -> io::Result<()> {
+    let from = File::open(from)?;
+    let mut to = File::create(to)?;
+    io::copy(&mut io::Read::take(from, len), &mut to)?;
+    to.sync_all()
+}
+
+pub(crate) fn copy_dir_contents<P: AsRef<Path>, Q: AsRef<Path>>(
+    from: P,
+    to: Q,
+) -> std::io::Result<()> {
     let from = from.as_ref();
     let to = to.as_ref();
 
@@ -46,14 +142,27 @@ fn copy_dir_contents<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> std::io:
 
 #[cfg(test)]
 mod tests {
-    use std::{fs, sync::Arc};
+    use std::{
+        fs,
+        os::unix::fs::MetadataExt,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        thread,
+    };
 
     use bytes::Bytes;
 
     use crate::{
         batched::batched_write::CreateBatch,
+        blob::blob_files::BlobConfig,
         config::config::Config,
-        store::{store::Store, utils::TempStore},
+        definitions::constants::get_prefix_numbers,
+        store::{
+            store::Store,
+            utils::{format_filename, TempStore},
+        },
     };
 
     #[test]
@@ -118,4 +227,103 @@ mod tests {
             fs::remove_dir_all(backup_name).unwrap();
         }
     }
+
+    #[test]
+    fn test_checkpoint() {
+        let dir = "store/test_55";
+        let checkpoint_dir = "store/test_55_checkpoint";
+        let open = |dir: &str| {
+            let (mut store_config, file_config, batched_config) =
+                Config::from_toml("config.toml".into());
+            store_config.dir = dir.into();
+            store_config.blob = BlobConfig {
+                enabled: true,
+                min_size: 1024,
+                ..Default::default()
+            };
+            Store::open(store_config, file_config, batched_config).unwrap()
+        };
+        let big = |i: usize| Bytes::from(format!("{:04}:", i).repeat(300));
+        // remove if exist
+        fs::remove_dir_all(dir);
+        fs::remove_dir_all(checkpoint_dir);
+
+        let store = Arc::new(open(dir));
+        for i in 0..200 {
+            let key = format!("{}", i);
+            let val = english_numbers::convert_all_fmt(i as i64);
+            store.put(key.into(), val.into()).unwrap();
+        }
+        for i in 0..20 {
+            store.put(format!("big{}", i).into(), big(i)).unwrap();
+        }
+        let batch = store.new_batched();
+        batch.put("batched".into(), "value".into()).unwrap();
+        batch.commit().unwrap();
+        drop(batch);
+
+        // writes go on during the checkpoint
+        let stop = Arc::new(AtomicBool::new(false));
+        let writer = {
+            let store = Arc::clone(&store);
+            let stop = Arc::clone(&stop);
+            thread::spawn(move || {
+                let mut i = 0;
+                while !stop.load(Ordering::Relaxed) {
+                    store.put(format!("during{}", i).into(), big(i)).unwrap();
+                    i += 1;
+                }
+                i
+            })
+        };
+        store.checkpoint(checkpoint_dir.into()).unwrap();
+        stop.store(true, Ordering::Relaxed);
+        let written = writer.join().unwrap();
+
+        // sealed files are shared
+        let file_ids = get_prefix_numbers(checkpoint_dir.into()).unwrap();
+        let sealed = format_filename(dir.into(), file_ids[0]);
+        let linked = format_filename(checkpoint_dir.into(), file_ids[0]);
+        assert_eq!(
+            fs::metadata(sealed).unwrap().ino(),
+            fs::metadata(linked).unwrap().ino()
+        );
+        assert!(Store::verify(checkpoint_dir.into()).is_clean());
+
+        let copy = open(checkpoint_dir);
+        for i in 0..200 {
+            assert_eq!(
+                copy.get(format!("{}", i).into()).unwrap(),
+                english_numbers::convert_all_fmt(i as i64)
+            );
+        }
+        for i in 0..20 {
+            assert_eq!(copy.get(format!("big{}", i).into()).unwrap(), big(i));
+        }
+        assert_eq!(copy.get("batched".into()).unwrap(), "value");
+        // a prefix of the concurrent writes
+        let during = copy
+            .list_keys()
+            .iter()
+            .filter(|key| key.starts_with(b"during"))
+            .count();
+        assert!(during <= written);
+        for i in 0..during {
+            assert_eq!(copy.get(format!("during{}", i).into()).unwrap(), big(i));
+        }
+
+        // both go their own way
+        copy.put("copy".into(), "only".into()).unwrap();
+        store.merge().unwrap();
+        assert!(store.get("copy".into()).is_err());
+        drop(copy);
+        let copy = open(checkpoint_dir);
+        assert_eq!(copy.get("copy".into()).unwrap(), "only");
+        assert_eq!(copy.get("big7".into()).unwrap(), big(7));
+
+        drop(copy);
+        drop(store);
+        fs::remove_dir_all(dir).unwrap();
+        fs::remove_dir_all(checkpoint_dir).unwrap();
+    }
 }
@@ -0,0 +1,534 @@
+/*
+    Change data capture:
+    every put, delete and batch commit is turned into a `ChangeEvent` as it is logged, under the write lock,
+    so events queue up in commit order. An event is handed to subscribers once its record is durable:
+    whoever syncs publishes every queued event up to what the sync covered, in order.
+    Without `sync_every_write`, events wait for the next sync: `Store::sync`, a synced batch, or closing the store.
+    Moves by merge and blob collection are no changes and make no event.
+
+    Past events are read back from the log by sequence number (`Store::changes_since`),
+    as far as merge has not compacted them away.
+*/
+
+use std::{
+    collections::{BTreeMap, HashSet, VecDeque},
+    sync::mpsc::{channel, Receiver, Sender},
+};
+
+use bytes::Bytes;
+use parking_lot::Mutex;
+
+use crate::{
+    definitions::types::ByteVec,
+    errors::{Errors, Result},
+    merge::merge::MergeManifest,
+    records::{
+        log_record::{LogRecord, LogRecordPtr},
+        meta::RecordMeta,
+    },
+};
+
+use super::store::Store;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ChangeEvent {
+    Put {
+        seq: u64,
+        key: Bytes,
+        value: Bytes,
+        /// milliseconds since UNIX epoch, `None` if the key never expires
+        expire_at: Option<u64>,
+    },
+    Delete {
+        seq: u64,
+        key: Bytes,
+    },
+    /// a batch, applied as a whole; `seq` is that of its commit, after those of its changes
+    Batch {
+        seq: u64,
+        changes: Vec<ChangeEvent>,
+    },
+}
+
+impl ChangeEvent {
+    pub fn seq(&self) -> u64 {
+        match self {
+            ChangeEvent::Put { seq, .. }
+            | ChangeEvent::Delete { seq, .. }
+            | ChangeEvent::Batch { seq, .. } => *seq,
+        }
+    }
+
+    /// The change of a put or delete record, `None` for a `BatchDone`
+    fn from_record(record: &LogRecord, seq: u64) -> Option<Self> {
+        match record {
+            LogRecord::Data { key, value } | LogRecord::DataInBatch { key, value, .. } => {
+                Some(ChangeEvent::Put {
+                    seq,
+                    key: Bytes::copy_from_slice(key),
+                    value: Bytes::copy_from_slice(value),
+                    expire_at: None,
+                })
+            }
+            LogRecord::Expiring {
+                key,
+                value,
+                expire_at,
+            }
+            | LogRecord::ExpiringInBatch {
+                key,
+                value,
+                expire_at,
+                ..
+            } => Some(ChangeEvent::Put {
+                seq,
+                key: Bytes::copy_from_slice(key),
+                value: Bytes::copy_from_slice(value),
+                expire_at: Some(*expire_at),
+            }),
+            LogRecord::Tomb { key } | LogRecord::TombInBatch { key, .. } => {
+                Some(ChangeEvent::Delete {
+                    seq,
+                    key: Bytes::copy_from_slice(key),
+                })
+            }
+            LogRecord::BatchDone { .. } => None,
+        }
+    }
+
+    /// The part of this event on keys starting with `prefix`, if any
+    fn matching(&self, prefix: &[u8]) -> Option<Self> {
+        match self {
+            ChangeEvent::Put { key, .. } | ChangeEvent::Delete { key, .. } => {
+                key.starts_with(prefix).then(|| self.clone())
+            }
+            ChangeEvent::Batch { seq, changes } => {
+                let changes: Vec<_> = changes
+                    .iter()
+                    .filter_map(|change| change.matching(prefix))
+                    .collect();
+                (!changes.is_empty()).then_some(ChangeEvent::Batch { seq: *seq, changes })
+            }
+        }
+    }
+}
+
+/// Batch records are gathered until their `BatchDone`,
+///     the same way replay does: any other record drops a batch in progress.
+#[derive(Default)]
+struct BatchAssembler {
+    batch: Option<(usize, Vec<ChangeEvent>)>,
+}
+
+impl BatchAssembler {
+    /// The event completed by `record` of sequence number `seq`:
+    ///     itself if not in a batch, a whole batch for a `BatchDone`, `None` meanwhile.
+    ///     `change` is the change of the record, `None` to leave it out of its batch.
+    fn push(
+        &mut self,
+        record: &LogRecord,
+        seq: u64,
+        change: Option<ChangeEvent>,
+    ) -> Option<ChangeEvent> {
+        match record {
+            LogRecord::DataInBatch { batch_id, .. }
+            | LogRecord::TombInBatch { batch_id, .. }
+            | LogRecord::ExpiringInBatch { batch_id, .. } => {
+                match &mut self.batch {
+                    Some((cur_batch_id, changes)) if cur_batch_id == batch_id => {
+                        changes.extend(change);
+                    }
+                    _ => self.batch = Some((*batch_id, change.into_iter().collect())),
+                }
+                None
+            }
+            LogRecord::BatchDone { batch_id } => match self.batch.take() {
+                Some((cur_batch_id, changes)) if cur_batch_id == *batch_id => {
+                    Some(ChangeEvent::Batch { seq, changes })
+                }
+                _ => None,
+            },
+            _ => {
+                self.batch = None;
+                change
+            }
+        }
+    }
+}
+
+struct Subscriber {
+    prefix: ByteVec,
+    sender: Sender<ChangeEvent>,
+}
+
+#[derive(Default)]
+struct FeedState {
+    subscribers: Vec<Subscriber>,
+    /// (group commit ticket, event) of events logged but not published yet, in commit order
+    pending: VecDeque<(u64, ChangeEvent)>,
+    batches: BatchAssembler,
+}
+
+/// Events of the store on their way to subscribers
+#[derive(Default)]
+pub struct ChangeFeed {
+    state: Mutex<FeedState>,
+}
+
+impl ChangeFeed {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Called for every record logged as a write, under the write lock;
+    ///     `ticket` is the group commit ticket it is durable with.
+    pub(crate) fn on_logged(&self, record: &LogRecord, seq: u64, ticket: u64) {
+        let mut state = self.state.lock();
+        if state.subscribers.is_empty() {
+            return;
+        }
+        let change = ChangeEvent::from_record(record, seq);
+        if let Some(event) = state.batches.push(record, seq, change) {
+            state.pending.push_back((ticket, event));
+        }
+    }
+
+    /// Hand every event durable with `ticket` to the subscribers it concerns.
+    pub(crate) fn publish(&self, ticket: u64) {
+        let mut state = self.state.lock();
+        while state
+            .pending
+            .front()
+            .is_some_and(|(pending_ticket, _)| *pending_ticket <= ticket)
+        {
+            let (_, event) = state.pending.pop_front().expect("checked to be there");
+            // a dropped receiver unsubscribes
+            state
+                .subscribers
+                .retain(|subscriber| match event.matching(&subscriber.prefix) {
+                    Some(event) => subscriber.sender.send(event).is_ok(),
+                    None => true,
+                });
+        }
+    }
+
+    fn subscribe(&self, prefix: ByteVec) -> Receiver<ChangeEvent> {
+        let (sender, receiver) = channel();
+        let mut state = self.state.lock();
+        if state.subscribers.is_empty() {
+            // events of batches logged while no one listened are incomplete
+            state.batches = BatchAssembler::default();
+        }
+        state.subscribers.push(Subscriber { prefix, sender });
+        receiver
+    }
+}
+
+impl Store {
+    /// Events of every write to a key starting with `prefix` from now on, in commit order,
+    ///     each once it is durable; of a batch, only its changes on such keys.
+    /// Dropping the receiver unsubscribes; events wait in it until received.
+    pub fn subscribe(&self, prefix: Bytes) -> Receiver<ChangeEvent> {
+        // not in the middle of a batch
+        let _write_lock = self.write_lock.lock();
+        self.changes.subscribe(prefix.to_vec())
+    }
+
+    /// Events of every write of sequence number `seq` or later, in order, read from the log;
+    ///     a consumer resumes with the sequence number after the last event it got.
+    /// Merge drops overwritten values and deletes: changes from before the last merge
+    ///     fail with `ChangesCompacted`, a consumer this far behind needs a full scan.
+    /// Writes of records written before records were stamped have no sequence number and are left out.
+    pub fn changes_since(&self, seq: u64) -> Result<Vec<ChangeEvent>> {
+        // merge and blob collection remove files
+        let _merge_lock = self.merge_lock.lock();
+        let compacted_seq = MergeManifest::load(self.store_config.dir.clone())?
+            .map_or(0, |manifest| manifest.compacted_seq);
+        if seq < compacted_seq {
+            return Err(Errors::ChangesCompacted { seq, compacted_seq });
+        }
+
+        let (file_ids, until) = {
+            // not in the middle of a batch
+            let _write_lock = self.write_lock.lock();
+            let mut file_ids: Vec<u32> = self.legacy_files.read().keys().copied().collect();
+            file_ids.push(
+                self.active_file_id
+                    .load(std::sync::atomic::Ordering::Relaxed),
+            );
+            file_ids.sort_unstable();
+            (
+                file_ids,
+                self.seq.load(std::sync::atomic::Ordering::Relaxed),
+            )
+        };
+        // past events are durable ones
+        self.sync()?;
+
+        // merged files are not in order of sequence numbers,
+        //      and a record moved by blob collection is there twice under one sequence number
+        let mut events = BTreeMap::new();
+        let mut seen = HashSet::new();
+        let mut batches = BatchAssembler::default();
+        for (i, file_id) in file_ids.iter().copied().enumerate() {
+            // a file created later bounds the sequence numbers of those before
+            if let Some(next_file_id) = file_ids.get(i + 1) {
+                if self.first_seq(*next_file_id)? <= seq {
+                    continue;
+                }
+            }
+            let mut offset = 0;
+            loop {
+                let ptr = LogRecordPtr { file_id, offset };
+                let (mut record, size, blob, meta) = match self.read_stored(ptr) {
+                    Ok(res) => res,
+                    Err(Errors::Eof) => break,
+                    Err(e) => return Err(e),
+                };
+                offset += size;
+                let Some(RecordMeta {
+                    seq: record_seq, ..
+                }) = meta
+                else {
+                    continue;
+                };
+                if record_seq >= until {
+                    continue;
+                }
+                let change = if seen.insert(record_seq) {
+                    match self.resolve_blob(&mut record, blob) {
+                        Ok(()) => ChangeEvent::from_record(&record, record_seq),
+                        // collected after the key was written again, a later event follows
+                        Err(Errors::BlobFileNotFound { .. }) => {
+                            seen.remove(&record_seq);
+                            None
+                        }
+                        Err(e) => return Err(e),
+                    }
+                } else {
+                    None
+                };
+                if let Some(event) = batches.push(&record, record_seq, change) {
+                    if event.seq() >= seq {
+                        events.insert(event.seq(), event);
+                    }
+                }
+            }
+        }
+        Ok(events.into_values().collect())
+    }
+
+    /// Sequence number of the store when file `file_id` was created
+    fn first_seq(&self, file_id: u32) -> Result<u64> {
+        let active_file = self.active_file.read();
+        if file_id
+            == self
+                .active_file_id
+                .load(std::sync::atomic::Ordering::Relaxed)
+        {
+            return Ok(active_file.header().first_seq);
+        }
+        drop(active_file);
+        self.legacy_files
+            .read()
+            .get(&file_id)
+            .map(|file| file.header().first_seq)
+            .ok_or(Errors::StoreFileNotFound { file_id })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, sync::Arc, thread};
+
+    use bytes::Bytes;
+
+    use crate::{
+        batched::batched_write::CreateBatch, config::config::Config, errors::Errors,
+        store::store::Store,
+    };
+
+    use super::ChangeEvent;
+
+    fn put(seq: u64, key: &str, value: &str) -> ChangeEvent {
+        ChangeEvent::Put {
+            seq,
+            key: Bytes::copy_from_slice(key.as_bytes()),
+            value: Bytes::copy_from_slice(value.as_bytes()),
+            expire_at: None,
+        }
+    }
+
+    fn delete(seq: u64, key: &str) -> ChangeEvent {
+        ChangeEvent::Delete {
+            seq,
+            key: Bytes::copy_from_slice(key.as_bytes()),
+        }
+    }
+
+    #[test]
+    fn test_changes() {
+        let dir = "store/test_58";
+        // remove if exist
+        fs::remove_dir_all(dir);
+
+        let (mut store_config, file_config, batched_config) =
+            Config::from_toml("config.toml".into());
+        store_config.dir = dir.into();
+        let store = Arc::new(Store::open(store_config, file_config, batched_config).unwrap());
+        // before any subscription
+        store.put("a/0".into(), "zero".into()).unwrap();
+
+        let all = store.subscribe(Bytes::new());
+        let only_a = store.subscribe("a/".into());
+        store.put("a/1".into(), "one".into()).unwrap();
+        store.put("b/1".into(), "one".into()).unwrap();
+        store.delete("a/0".into()).unwrap();
+        let batch = store.new_batched();
+        batch.put("a/2".into(), "two".into()).unwrap();
+        batch.delete("b/1".into()).unwrap();
+        batch.commit().unwrap();
+        drop(batch);
+
+        let received: Vec<_> = all.try_iter().collect();
+        assert_eq!(received.len(), 4, "{:?}", received);
+        assert_eq!(received[0], put(1, "a/1", "one"));
+        assert_eq!(received[1], put(2, "b/1", "one"));
+        assert_eq!(received[2], delete(3, "a/0"));
+        let ChangeEvent::Batch { seq, changes } = &received[3] else {
+            panic!("not a batch: {:?}", received[3]);
+        };
+        // two records and the `BatchDone`
+        assert_eq!(*seq, 6);
+        assert_eq!(changes.len(), 2);
+        // batch records are logged in no particular order
+        let mut seqs: Vec<_> = changes.iter().map(ChangeEvent::seq).collect();
+        seqs.sort();
+        assert_eq!(seqs, vec![4, 5]);
+        assert!(changes.iter().any(
+            |change| matches!(change, ChangeEvent::Put { key, value, .. } if key == "a/2" && value == "two")
+        ));
+        assert!(changes
+            .iter()
+            .any(|change| matches!(change, ChangeEvent::Delete { key, .. } if key == "b/1")));
+
+        // of a batch, only the changes on matching keys
+        let received: Vec<_> = only_a.try_iter().collect();
+        assert_eq!(received.len(), 3, "{:?}", received);
+        assert_eq!(received[0], put(1, "a/1", "one"));
+        assert_eq!(received[1], delete(3, "a/0"));
+        let ChangeEvent::Batch { seq: 6, changes } = &received[2] else {
+            panic!("not the batch: {:?}", received[2]);
+        };
+        assert!(matches!(&changes[..], [ChangeEvent::Put { key, .. }] if key == "a/2"));
+
+        // concurrent writers, delivered in commit order
+        drop(only_a);
+        let handles: Vec<_> = (0..4)
+            .map(|t| {
+                let store = store.clone();
+                thread::spawn(move || {
+                    for i in 0..100 {
+                        let key = format!("t{}/{}", t, i);
+                        store.put(key.into(), "value".into()).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        let received: Vec<_> = all.try_iter().collect();
+        assert_eq!(received.len(), 400);
+        assert!(received.windows(2).all(|w| w[0].seq() < w[1].seq()));
+        for t in 0..4 {
+            let prefix = format!("t{}/", t);
+            let keys: Vec<_> = received
+                .iter()
+                .filter_map(|event| match event {
+                    ChangeEvent::Put { key, .. } if key.starts_with(prefix.as_bytes()) => {
+                        Some(key.clone())
+                    }
+                    _ => None,
+                })
+                .collect();
+            let expected: Vec<_> = (0..100)
+                .map(|i| Bytes::from(format!("t{}/{}", t, i)))
+                .collect();
+            assert_eq!(keys, expected);
+        }
+
+        // read back from the log, the same as delivered
+        let past = store.changes_since(0).unwrap();
+        assert_eq!(past.len(), 405);
+        assert_eq!(past[0], put(0, "a/0", "zero"));
+        assert_eq!(past[5..], received[..]);
+        let since = store.changes_since(3).unwrap();
+        assert_eq!(since[0], delete(3, "a/0"));
+        assert_eq!(since.len(), 402);
+        // within a batch, the whole batch
+        assert_eq!(store.changes_since(5).unwrap()[0].seq(), 6);
+        assert!(store.changes_since(1_000).unwrap().is_empty());
+
+        // merge compacts what came before it
+        store.merge().unwrap();
+        store.put("after".into(), "merge".into()).unwrap();
+        assert!(matches!(
+            store.changes_since(0),
+            Err(Errors::ChangesCompacted {
+                seq: 0,
+                compacted_seq: 407
+            })
+        ));
+        assert_eq!(
+            store.changes_since(407).unwrap(),
+            vec![put(407, "after", "merge")]
+        );
+        drop(all);
+        drop(store);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_changes_unsynced() {
+        let dir = "store/test_63";
+        // remove if exist
+        fs::remove_dir_all(dir);
+
+        let (mut store_config, file_config, mut batched_config) =
+            Config::from_toml("config.toml".into());
+        store_config.dir = dir.into();
+        store_config.sync_every_write = false;
+        batched_config.sync_every_write = false;
+        let store = Arc::new(Store::open(store_config, file_config, batched_config).unwrap());
+
+        let all = store.subscribe(Bytes::new());
+        store.put("a".into(), "one".into()).unwrap();
+        store.delete("a".into()).unwrap();
+        let batch = store.new_batched();
+        batch.put("b".into(), "two".into()).unwrap();
+        batch.commit().unwrap();
+        drop(batch);
+        // not on disk yet
+        assert!(all.try_recv().is_err());
+
+        store.sync().unwrap();
+        let received: Vec<_> = all.try_iter().collect();
+        assert_eq!(received.len(), 3, "{:?}", received);
+        assert_eq!(received[0], put(0, "a", "one"));
+        assert_eq!(received[1], delete(1, "a"));
+        assert_eq!(received[2].seq(), 3);
+
+        // closing syncs what is left
+        store.put("c".into(), "three".into()).unwrap();
+        assert!(all.try_recv().is_err());
+        drop(store);
+        assert_eq!(
+            all.try_iter().collect::<Vec<_>>(),
+            vec![put(4, "c", "three")]
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
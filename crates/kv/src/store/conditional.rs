@@ -0,0 +1,145 @@
+/*
+    Conditional writes:
+    the current value is read and the new record logged under `write_lock`,
+    the same lock `put`, `delete` and batch commit hold until the index is updated,
+    so no other write can land between the check and the write.
+*/
+
+use bytes::Bytes;
+
+use crate::errors::{Errors, Result};
+
+use super::store::Store;
+
+impl Store {
+    /// Writes `new` only if the current value is `expected`, `None` meaning absent (or expired).
+    ///
+    /// `new` of `None` deletes the key.
+    /// Returns whether the write happened.
+    pub fn compare_and_swap(
+        &self,
+        key: Bytes,
+        expected: Option<Bytes>,
+        new: Option<Bytes>,
+    ) -> Result<bool> {
+        self.check_writable()?;
+        if key.is_empty() {
+            return Err(Errors::KeyIsEmpty);
+        }
+
+        let ticket = {
+            let _write_lock = self.write_lock.lock();
+            let current = match self.get(key.clone()) {
+                Ok(value) => Some(value),
+                Err(Errors::KeyNotFound) => None,
+                Err(e) => return Err(e),
+            };
+            if current != expected {
+                return Ok(false);
+            }
+
+            match new {
+                Some(value) => {
+                    self.put_locked(key, value)?;
+                }
+                None => {
+                    // an expired key is still in the index
+                    if self.index.get(key.to_vec()).is_some() {
+                        self.delete_locked(key)?;
+                    }
+                }
+            }
+            self.group_commit.ticket()
+        };
+        self.wait_durable(ticket)?;
+        Ok(true)
+    }
+
+    /// Returns false if the key already exists.
+    pub fn put_if_absent(&self, key: Bytes, value: Bytes) -> Result<bool> {
+        self.compare_and_swap(key, None, Some(value))
+    }
+
+    /// Returns false if the key is absent or holds another value.
+    pub fn delete_if_equals(&self, key: Bytes, expected: Bytes) -> Result<bool> {
+        self.compare_and_swap(key, Some(expected), None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread};
+
+    use crate::store::utils::TempStore;
+
+    #[test]
+    fn test_conditional_writes() {
+        let (_raii, store) = TempStore::init(39);
+
+        assert!(store.put_if_absent("key".into(), "first".into()).unwrap());
+        assert!(!store.put_if_absent("key".into(), "second".into()).unwrap());
+        assert_eq!(store.get("key".into()).unwrap(), "first");
+
+        assert!(!store
+            .compare_and_swap("key".into(), Some("other".into()), Some("third".into()))
+            .unwrap());
+        assert!(store
+            .compare_and_swap("key".into(), Some("first".into()), Some("third".into()))
+            .unwrap());
+        assert_eq!(store.get("key".into()).unwrap(), "third");
+
+        assert!(!store
+            .delete_if_equals("key".into(), "first".into())
+            .unwrap());
+        assert!(store
+            .delete_if_equals("key".into(), "third".into())
+            .unwrap());
+        assert!(store.get("key".into()).is_err());
+        assert!(!store
+            .delete_if_equals("key".into(), "third".into())
+            .unwrap());
+        // swapping absent for absent is a no-op that succeeds
+        assert!(store.compare_and_swap("key".into(), None, None).unwrap());
+        assert!(store.list_keys().is_empty());
+    }
+
+    #[test]
+    fn test_concurrent_compare_and_swap() {
+        let (_raii, store) = TempStore::init(40);
+        let store = Arc::new(store);
+        store.put("counter".into(), "0".into()).unwrap();
+
+        // every increment is a read-modify-write, none of them may get lost
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let store = Arc::clone(&store);
+                thread::spawn(move || {
+                    for _ in 0..100 {
+                        loop {
+                            let current = store.get("counter".into()).unwrap();
+                            let count: u64 = String::from_utf8_lossy(&current).parse().unwrap();
+                            let next = format!("{}", count + 1);
+                            if store
+                                .compare_and_swap(
+                                    "counter".into(),
+                                    Some(current),
+                                    Some(next.into()),
+                                )
+                                .unwrap()
+                            {
+                                break;
+                            }
+                        }
+                        // plain writes to other keys interleave freely
+                        store.put("other".into(), "value".into()).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(store.get("counter".into()).unwrap(), "800");
+    }
+}
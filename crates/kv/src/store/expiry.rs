@@ -0,0 +1,204 @@
+/*
+    Per-key expiry:
+    a key written with a ttl is logged with the absolute time it expires at,
+    so the expiry survives reopening and merging unchanged.
+    Expired keys stay in the index until a merge drops them,
+    reads and iterators hide them in the meantime.
+*/
+
+use std::{
+    collections::HashMap,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use bytes::Bytes;
+use parking_lot::RwLock;
+
+use crate::{
+    definitions::types::ByteVec,
+    errors::{Errors, Result},
+    records::log_record::{LogRecord, LogRecordPtr},
+};
+
+use super::store::Store;
+
+/// Milliseconds since UNIX epoch
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("System time is before UNIX epoch!")
+        .as_millis() as u64
+}
+
+/// Absolute expiry of a ttl starting now
+pub fn expire_at_after(ttl: Duration) -> u64 {
+    now_millis().saturating_add(ttl.as_millis() as u64)
+}
+
+/// key -> expiry, for keys whose record in the index carries one
+pub struct Expirations {
+    keys: RwLock<HashMap<ByteVec, u64>>,
+}
+
+impl Expirations {
+    pub fn new() -> Self {
+        Self {
+            keys: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// `None` for a key that never expires, or is gone
+    pub(crate) fn set(&self, key: ByteVec, expire_at: Option<u64>) {
+        let mut keys = self.keys.write();
+        match expire_at {
+            Some(expire_at) => keys.insert(key, expire_at),
+            None => keys.remove(&key),
+        };
+    }
+
+    pub(crate) fn reset(&self, keys: HashMap<ByteVec, u64>) {
+        *self.keys.write() = keys;
+    }
+
+    pub(crate) fn snapshot(&self) -> HashMap<ByteVec, u64> {
+        self.keys.read().clone()
+    }
+
+    pub fn expire_at(&self, key: &[u8]) -> Option<u64> {
+        self.keys.read().get(key).copied()
+    }
+
+    pub fn is_expired(&self, key: &[u8], now: u64) -> bool {
+        self.expire_at(key)
+            .is_some_and(|expire_at| expire_at <= now)
+    }
+}
+
+impl Default for Expirations {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Store {
+    /// Same as `put`, but the key disappears once `ttl` has passed.
+    pub fn put_with_ttl(&self, key: Bytes, value: Bytes, ttl: Duration) -> Result<LogRecordPtr> {
+        self.put_expiring(key, value, expire_at_after(ttl))
+    }
+
+    /// Put with an absolute expiry, in milliseconds since UNIX epoch.
+    pub(crate) fn put_expiring(
+        &self,
+        key: Bytes,
+        value: Bytes,
+        expire_at: u64,
+    ) -> Result<LogRecordPtr> {
+        if key.is_empty() {
+            return Err(Errors::KeyIsEmpty);
+        }
+
+        let mut record = LogRecord::Expiring {
+            key: key.to_vec(),
+            value: value.to_vec(),
+            expire_at,
+        };
+
+        let (record_ptr, ticket) = {
+            let _write_lock = self.write_lock.lock();
+            let record_ptr = self.log(&mut record)?;
+
+            self.expirations.set(key.to_vec(), Some(expire_at));
+            if let Some(old_ptr) = self.index.put(key.to_vec(), record_ptr) {
+                self.mark_stale(old_ptr);
+            }
+            (record_ptr, self.group_commit.ticket())
+        };
+        self.wait_durable(ticket)?;
+
+        Ok(record_ptr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, sync::Arc, thread, time::Duration};
+
+    use bytes::Bytes;
+
+    use crate::{
+        batched::batched_write::CreateBatch, config::config::Config, errors::Errors,
+        store::store::Store,
+    };
+
+    fn open_at(dir: &str) -> Store {
+        let (mut store_config, file_config, batched_config) =
+            Config::from_toml("config.toml".into());
+        store_config.dir = dir.into();
+        Store::open(store_config, file_config, batched_config).unwrap()
+    }
+
+    #[test]
+    fn test_put_with_ttl() {
+        let test_id = 37;
+        let dir = format!("store/test_{}", test_id);
+        // remove if exist
+        fs::remove_dir_all(dir.clone());
+        let expected = [
+            Bytes::from("forever"),
+            Bytes::from("long"),
+            Bytes::from("renewed"),
+        ];
+        {
+            let store = Arc::new(open_at(&dir));
+
+            store.put("forever".into(), "value".into()).unwrap();
+            store
+                .put_with_ttl("short".into(), "value".into(), Duration::from_millis(50))
+                .unwrap();
+            store
+                .put_with_ttl("long".into(), "value".into(), Duration::from_secs(3600))
+                .unwrap();
+            // a plain put makes the key permanent again
+            store
+                .put_with_ttl("renewed".into(), "old".into(), Duration::from_millis(50))
+                .unwrap();
+            store.put("renewed".into(), "new".into()).unwrap();
+            let batch = store.new_batched();
+            batch
+                .put_with_ttl("batched".into(), "value".into(), Duration::from_millis(50))
+                .unwrap();
+            batch.commit().unwrap();
+
+            assert_eq!(store.get("short".into()).unwrap(), "value");
+            assert_eq!(store.get("batched".into()).unwrap(), "value");
+            thread::sleep(Duration::from_millis(100));
+
+            assert!(matches!(
+                store.get("short".into()),
+                Err(Errors::KeyNotFound)
+            ));
+            assert!(matches!(
+                store.get("batched".into()),
+                Err(Errors::KeyNotFound)
+            ));
+            assert_eq!(store.get("renewed".into()).unwrap(), "new");
+            assert_eq!(store.list_keys(), expected);
+            let keys: Vec<Bytes> = store.iter_options().make().map(|kv| kv.key).collect();
+            assert_eq!(keys, expected);
+        }
+
+        // expiry is read back from the log on open
+        {
+            let store = open_at(&dir);
+            assert!(matches!(
+                store.get("short".into()),
+                Err(Errors::KeyNotFound)
+            ));
+            assert_eq!(store.get("long".into()).unwrap(), "value");
+            assert_eq!(store.list_keys(), expected);
+            assert!(store.expirations.expire_at(b"long").is_some());
+            assert!(store.expirations.expire_at(b"renewed").is_none());
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
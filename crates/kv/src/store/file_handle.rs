@@ -7,32 +7,46 @@
 
 use std::{
     path::{Path, PathBuf},
-    sync::atomic::AtomicU64,
+    sync::{atomic::AtomicU64, Arc},
 };
 
 use bytes::{Buf, BytesMut};
 use log::{error, info};
 
 use crate::{
+    blob::blob_files::{BlobPtr, BLOB_REF},
     config::config::FileConfig,
     definitions::types::ByteVec,
     errors::{Errors, Result},
     io::{
+        cipher::{PlainIo, RecordCipher},
         file::FileIo,
         traits::{IoLayer, IoType},
     },
-    records::log_record::LogRecord,
-    store::utils::format_filename,
+    records::{
+        compression::{Codec, CompressionConfig, COMPRESSED},
+        log_record::LogRecord,
+        meta::{RecordMeta, RECORD_META},
+    },
+    store::{
+        file_header::{BodyIo, FileHeader},
+        utils::{format_filename, sync_dir},
+    },
 };
 
 pub struct FileHandle {
     pub(crate) write_offset: AtomicU64,
+    /// past the header, offsets count from its end
     io: Box<dyn IoLayer>,
+    io_type: IoType,
+    header: FileHeader,
     file_config: FileConfig,
+    /// seals every record if set, see `io::cipher`
+    cipher: Option<Arc<RecordCipher>>,
 }
 
 impl FileHandle {
-    /// panic at file not found
+    /// fails at file not found
     pub fn open(
         dir: PathBuf,
         file_id: u32,
@@ -41,24 +55,28 @@ impl FileHandle {
     ) -> Result<Self> {
         /*
            1. find file with formatted name; (success)
-           2. if cannot find, fail, panic.
+           2. if cannot find, fail.
         */
-        let filename = format_filename(dir, file_id);
-        let io = io_type.make(filename);
-        // let io = Box::new(
-        //     FileIo::open(filename.clone()).expect(
-        //         format!(
-        //             "File {:?} not found! Storage directory is corrupted.",
-        //             filename
-        //         )
-        //         .as_str(),
-        //     ),
-        // );
+        Self::open_at(format_filename(dir, file_id), file_id, file_config, io_type)
+    }
+
+    /// Same as `open`, for a file of any name
+    pub fn open_at(
+        filename: PathBuf,
+        file_id: u32,
+        file_config: FileConfig,
+        io_type: IoType,
+    ) -> Result<Self> {
+        let io = io_type.make(filename.clone())?;
+        let header = FileHeader::read_from(io.as_ref(), &filename, file_id)?;
 
         Ok(Self {
             write_offset: AtomicU64::new(0),
-            io,
+            io: Box::new(BodyIo::new(io, &header)),
+            io_type,
+            header,
             file_config,
+            cipher: None,
         })
     }
 
@@ -67,18 +85,56 @@ impl FileHandle {
            1. if found, panic
            2. else, create (success)
         */
-        let filename = format_filename(dir, file_id);
+        Self::create_at(
+            format_filename(dir, file_id),
+            FileHeader::new(file_id),
+            file_config,
+        )
+    }
+
+    /// Same as `create`, for a file of any name, starting with `header`
+    pub fn create_at(
+        filename: PathBuf,
+        header: FileHeader,
+        file_config: FileConfig,
+    ) -> Result<Self> {
         if Path::exists(&filename) {
             panic!("File found while should to be created! Storage directory is corrupted.")
         }
-        let io = Box::new(FileIo::create(filename)?);
+        let io = Box::new(FileIo::create(filename.clone())?);
+        io.write(&header.encode())?;
+        // a crash must not leave the file without its header once it is written to
+        io.sync()?;
+        if let Some(dir) = filename.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            sync_dir(dir.into())?;
+        }
         Ok(Self {
             write_offset: AtomicU64::new(0),
-            io,
+            io: Box::new(BodyIo::new(io, &header)),
+            io_type: IoType::File,
+            header,
             file_config,
+            cipher: None,
         })
     }
 
+    pub fn with_cipher(mut self, cipher: Option<Arc<RecordCipher>>) -> Self {
+        self.cipher = cipher;
+        self
+    }
+
+    pub fn header(&self) -> FileHeader {
+        self.header
+    }
+
+    pub(crate) fn io_type(&self) -> IoType {
+        self.io_type
+    }
+
+    pub(crate) fn cipher(&self) -> Option<&RecordCipher> {
+        self.cipher.as_deref()
+    }
+
     pub fn get_write_offset(&self) -> u64 {
         self.write_offset.load(std::sync::atomic::Ordering::Relaxed)
     }
@@ -89,14 +145,62 @@ impl FileHandle {
     }
 
     // returns the current record and its size in bytes
+    //      the value of a record referring to a blob is the encoded `BlobPtr`
     pub fn read_at_offset(&self, offset: u64) -> Result<(LogRecord, u64)> {
+        let (record, size, _, _) = self.read_stored_at(offset)?;
+        Ok((record, size))
+    }
+
+    /// Same as `read_at_offset`, along with the blob the value was separated into
+    ///     and the stamp of the record, if it has one.
+    pub(crate) fn read_stored_at(&self, offset: u64) -> Result<StoredRecord> {
+        match &self.cipher {
+            None => Self::decode_at(self.io.as_ref(), offset),
+            Some(cipher) => {
+                // the frame size counts on disk, not the record inside
+                let (plain, frame_size) = cipher.open_frame(self.io.as_ref(), offset)?;
+                let (record, _, blob, meta) = Self::decode_at(&PlainIo::new(plain), 0)?;
+                Ok((record, frame_size, blob, meta))
+            }
+        }
+    }
+
+    fn decode_at(io: &dyn IoLayer, offset: u64) -> Result<StoredRecord> {
         let mut all_buf = Vec::new();
         let mut type_buf = BytesMut::zeroed(LogRecord::type_length());
-        self.io.read(&mut type_buf, offset)?;
+        io.read(&mut type_buf, offset)?;
         all_buf.extend(type_buf.to_vec());
-        let record_type = type_buf.get_u8();
+        let mut record_type = type_buf.get_u8();
         let mut offset_delta = LogRecord::type_length() as u64;
 
+        // the value is a reference to a blob file
+        let blob_ref = record_type & BLOB_REF != 0;
+        record_type &= !BLOB_REF;
+
+        // a compressed value: the flag byte names the codec
+        let codec = if record_type & COMPRESSED != 0 {
+            record_type &= !COMPRESSED;
+            let mut flag_buf = BytesMut::zeroed(LogRecord::flag_length());
+            io.read(&mut flag_buf, offset + offset_delta)?;
+            all_buf.extend(flag_buf.to_vec());
+            offset_delta += LogRecord::flag_length() as u64;
+            Some(Codec::from_flag(flag_buf.get_u8())?)
+        } else {
+            None
+        };
+
+        // a stamped record: seq and timestamp follow
+        let meta = if record_type & RECORD_META != 0 {
+            record_type &= !RECORD_META;
+            let mut meta_buf = BytesMut::zeroed(RecordMeta::LENGTH);
+            io.read(&mut meta_buf, offset + offset_delta)?;
+            all_buf.extend(meta_buf.to_vec());
+            offset_delta += RecordMeta::LENGTH as u64;
+            Some(RecordMeta::decode(&meta_buf))
+        } else {
+            None
+        };
+
         // decode & verify logic:
         //      this is even more cumbersome to abstract away
         //      so I simply leave it here...
@@ -106,12 +210,12 @@ impl FileHandle {
            2. remember to use all_buf to read the content <before> crc
                 when creating new record types.
         */
-        match record_type {
+        let (mut record, size) = match record_type {
             // Data
             0 => {
                 // read ksize, vsize
                 let mut header_buf = BytesMut::zeroed(LogRecord::header_length_data());
-                self.io.read(&mut header_buf, offset + offset_delta)?;
+                io.read(&mut header_buf, offset + offset_delta)?;
                 all_buf.extend(header_buf.to_vec());
                 let key_size = header_buf.get_u32();
                 let value_size = header_buf.get_u32();
@@ -121,9 +225,10 @@ impl FileHandle {
                 offset_delta += LogRecord::header_length_data() as u64;
 
                 // read k, v
-                let mut kv_buf =
-                    BytesMut::zeroed((key_size + value_size) as usize + LogRecord::tail_length());
-                self.io.read(&mut kv_buf, offset + offset_delta)?;
+                let body_length =
+                    Self::body_length(io, offset + offset_delta, key_size, value_size)?;
+                let mut kv_buf = BytesMut::zeroed(body_length);
+                io.read(&mut kv_buf, offset + offset_delta)?;
 
                 let key = kv_buf
                     .get(0..key_size as usize)
@@ -140,8 +245,7 @@ impl FileHandle {
                 kv_buf.advance(value_size as usize);
                 // read crc
                 let crc = kv_buf.get_u32();
-                offset_delta +=
-                    ((key_size + value_size) as usize + LogRecord::tail_length()) as u64;
+                offset_delta += body_length as u64;
 
                 Self::verify_crc(&all_buf, crc)?;
 
@@ -153,7 +257,7 @@ impl FileHandle {
             1 => {
                 // read ksize, vsize
                 let mut header_buf = BytesMut::zeroed(LogRecord::header_length_tomb());
-                self.io.read(&mut header_buf, offset + offset_delta)?;
+                io.read(&mut header_buf, offset + offset_delta)?;
                 all_buf.extend(header_buf.to_vec());
                 let key_size = header_buf.get_u32();
                 if key_size == 0 {
@@ -162,8 +266,9 @@ impl FileHandle {
                 offset_delta += LogRecord::header_length_tomb() as u64;
 
                 // read key
-                let mut kv_buf = BytesMut::zeroed(key_size as usize + LogRecord::tail_length());
-                self.io.read(&mut kv_buf, offset + offset_delta)?;
+                let body_length = Self::body_length(io, offset + offset_delta, key_size, 0)?;
+                let mut kv_buf = BytesMut::zeroed(body_length);
+                io.read(&mut kv_buf, offset + offset_delta)?;
 
                 let key = kv_buf
                     .get(0..key_size as usize)
@@ -173,7 +278,7 @@ impl FileHandle {
                 kv_buf.advance(key_size as usize);
                 // read crc
                 let crc = kv_buf.get_u32();
-                offset_delta += (key_size as usize + LogRecord::tail_length()) as u64;
+                offset_delta += body_length as u64;
 
                 Self::verify_crc(&all_buf, crc)?;
 
@@ -184,7 +289,7 @@ impl FileHandle {
             // Data in batch
             2 => {
                 let mut header_buf = BytesMut::zeroed(LogRecord::header_length_data_in_batch());
-                self.io.read(&mut header_buf, offset + offset_delta)?;
+                io.read(&mut header_buf, offset + offset_delta)?;
                 all_buf.extend(header_buf.to_vec());
                 // read batch id
                 let batch_id = header_buf.get_u64();
@@ -197,9 +302,10 @@ impl FileHandle {
                 offset_delta += LogRecord::header_length_data_in_batch() as u64;
 
                 // read k, v
-                let mut kv_buf =
-                    BytesMut::zeroed((key_size + value_size) as usize + LogRecord::tail_length());
-                self.io.read(&mut kv_buf, offset + offset_delta)?;
+                let body_length =
+                    Self::body_length(io, offset + offset_delta, key_size, value_size)?;
+                let mut kv_buf = BytesMut::zeroed(body_length);
+                io.read(&mut kv_buf, offset + offset_delta)?;
 
                 let key = kv_buf
                     .get(0..key_size as usize)
@@ -216,8 +322,7 @@ impl FileHandle {
                 kv_buf.advance(value_size as usize);
                 // read crc
                 let crc = kv_buf.get_u32();
-                offset_delta +=
-                    ((key_size + value_size) as usize + LogRecord::tail_length()) as u64;
+                offset_delta += body_length as u64;
 
                 Self::verify_crc(&all_buf, crc)?;
 
@@ -232,7 +337,7 @@ impl FileHandle {
             // Tomb in batch
             3 => {
                 let mut header_buf = BytesMut::zeroed(LogRecord::header_length_tomb_in_batch());
-                self.io.read(&mut header_buf, offset + offset_delta)?;
+                io.read(&mut header_buf, offset + offset_delta)?;
                 all_buf.extend(header_buf.to_vec());
                 // read batch id
                 let batch_id = header_buf.get_u64();
@@ -244,8 +349,9 @@ impl FileHandle {
                 offset_delta += LogRecord::header_length_tomb_in_batch() as u64;
 
                 // read key
-                let mut kv_buf = BytesMut::zeroed(key_size as usize + LogRecord::tail_length());
-                self.io.read(&mut kv_buf, offset + offset_delta)?;
+                let body_length = Self::body_length(io, offset + offset_delta, key_size, 0)?;
+                let mut kv_buf = BytesMut::zeroed(body_length);
+                io.read(&mut kv_buf, offset + offset_delta)?;
 
                 let key = kv_buf
                     .get(0..key_size as usize)
@@ -255,7 +361,7 @@ impl FileHandle {
                 kv_buf.advance(key_size as usize);
                 // read crc
                 let crc = kv_buf.get_u32();
-                offset_delta += (key_size as usize + LogRecord::tail_length()) as u64;
+                offset_delta += body_length as u64;
 
                 Self::verify_crc(&all_buf, crc)?;
 
@@ -269,13 +375,13 @@ impl FileHandle {
             // BatchDone
             4 => {
                 let mut header_buf = BytesMut::zeroed(LogRecord::header_length_batch_done());
-                self.io.read(&mut header_buf, offset + offset_delta)?;
+                io.read(&mut header_buf, offset + offset_delta)?;
                 all_buf.extend(header_buf.to_vec());
                 let batch_id = header_buf.get_u64();
                 offset_delta += LogRecord::header_length_batch_done() as u64;
 
                 let mut crc_buf = BytesMut::zeroed(LogRecord::tail_length());
-                self.io.read(&mut crc_buf, offset + offset_delta)?;
+                io.read(&mut crc_buf, offset + offset_delta)?;
                 // read crc
                 let crc = crc_buf.get_u32();
                 offset_delta += LogRecord::tail_length() as u64;
@@ -288,15 +394,155 @@ impl FileHandle {
 
                 Ok((record, offset_delta))
             }
-            _ => {
-                panic!(
-                    "Abort: invalid record type: code {}, expected (0..5)",
-                    record_type
-                );
+            // Expiring data, with or without batch
+            5 | 6 => {
+                let in_batch = record_type == 6;
+                let header_length = if in_batch {
+                    LogRecord::header_length_expiring_in_batch()
+                } else {
+                    LogRecord::header_length_expiring()
+                };
+                let mut header_buf = BytesMut::zeroed(header_length);
+                io.read(&mut header_buf, offset + offset_delta)?;
+                all_buf.extend(header_buf.to_vec());
+                // read batch id
+                let batch_id = if in_batch { header_buf.get_u64() } else { 0 };
+                let expire_at = header_buf.get_u64();
+                // read ksize, vsize
+                let key_size = header_buf.get_u32();
+                let value_size = header_buf.get_u32();
+                if key_size == 0 && value_size == 0 {
+                    return Err(Errors::Eof);
+                }
+                offset_delta += header_length as u64;
+
+                // read k, v
+                let body_length =
+                    Self::body_length(io, offset + offset_delta, key_size, value_size)?;
+                let mut kv_buf = BytesMut::zeroed(body_length);
+                io.read(&mut kv_buf, offset + offset_delta)?;
+
+                let key = kv_buf
+                    .get(0..key_size as usize)
+                    .expect("read key failed")
+                    .to_vec();
+                all_buf.extend_from_slice(&key);
+                kv_buf.advance(key_size as usize);
+
+                let value = kv_buf
+                    .get(0..value_size as usize)
+                    .expect("read value failed")
+                    .to_vec();
+                all_buf.extend_from_slice(&value);
+                kv_buf.advance(value_size as usize);
+                // read crc
+                let crc = kv_buf.get_u32();
+                offset_delta += body_length as u64;
+
+                Self::verify_crc(&all_buf, crc)?;
+
+                let record = if in_batch {
+                    LogRecord::ExpiringInBatch {
+                        batch_id: batch_id as usize,
+                        key,
+                        value,
+                        expire_at,
+                    }
+                } else {
+                    LogRecord::Expiring {
+                        key,
+                        value,
+                        expire_at,
+                    }
+                };
+
+                Ok((record, offset_delta))
             }
+            // a corrupted type byte, what follows can not be read
+            _ => Err(Errors::InvalidRecordType { code: record_type }),
+        }?;
+
+        // crc covers the bytes on disk, decompress only once verified
+        if let (Some(codec), Some(value)) = (codec, record.value_mut()) {
+            *value = codec.decompress(value)?;
         }
+        let blob = match (blob_ref, record.value()) {
+            (true, Some(value)) => Some(BlobPtr::decode(value)?),
+            _ => None,
+        };
+        Ok((record, size, blob, meta))
+    }
 
-        // todo!()
+    /// Length of the key, value and crc of a record, starting at `offset`;
+    ///     sizes torn or garbled to point past the end of the file read as a record cut short,
+    ///     before anything is allocated for them.
+    fn body_length(io: &dyn IoLayer, offset: u64, key_size: u32, value_size: u32) -> Result<usize> {
+        let length = key_size as u64 + value_size as u64 + LogRecord::tail_length() as u64;
+        if offset.checked_add(length).is_none_or(|end| end > io.size()) {
+            return Err(Errors::Eof);
+        }
+        Ok(length as usize)
+    }
+
+    /// Size in bytes of the record at `offset`;
+    ///     only the header is read for data records, since values can be large.
+    pub fn record_size_at(&self, offset: u64) -> Result<u64> {
+        Ok(self.record_meta_at(offset)?.0)
+    }
+
+    /// Same as `record_size_at`, along with the expiry of the record.
+    pub fn record_meta_at(&self, offset: u64) -> Result<(u64, Option<u64>)> {
+        if self.cipher.is_some() {
+            // the header is sealed along with the value
+            let (record, size) = self.read_at_offset(offset)?;
+            return Ok((size, record.expire_at()));
+        }
+        let mut type_buf = BytesMut::zeroed(LogRecord::type_length());
+        self.io.read(&mut type_buf, offset)?;
+        let mut header_offset = offset + LogRecord::type_length() as u64;
+
+        let mut record_type = type_buf.get_u8() & !BLOB_REF;
+        let mut flag_length = 0;
+        if record_type & COMPRESSED != 0 {
+            record_type &= !COMPRESSED;
+            flag_length = LogRecord::flag_length();
+        }
+        if record_type & RECORD_META != 0 {
+            record_type &= !RECORD_META;
+            flag_length += RecordMeta::LENGTH;
+        }
+        header_offset += flag_length as u64;
+        let header_length = match record_type {
+            0 => LogRecord::header_length_data(),
+            2 => LogRecord::header_length_data_in_batch(),
+            5 => LogRecord::header_length_expiring(),
+            6 => LogRecord::header_length_expiring_in_batch(),
+            _ => {
+                let (record, size) = self.read_at_offset(offset)?;
+                return Ok((size, record.expire_at()));
+            }
+        };
+        let mut header_buf = BytesMut::zeroed(header_length);
+        self.io.read(&mut header_buf, header_offset)?;
+        if record_type == 2 || record_type == 6 {
+            // batch id
+            header_buf.advance(8);
+        }
+        let expire_at = if record_type == 5 || record_type == 6 {
+            Some(header_buf.get_u64())
+        } else {
+            None
+        };
+        let key_size = header_buf.get_u32() as u64;
+        let value_size = header_buf.get_u32() as u64;
+
+        Ok((
+            (LogRecord::type_length() + flag_length + header_length + LogRecord::tail_length())
+                as u64
+                + key_size
+                + value_size,
+            expire_at,
+        ))
     }
 
     pub fn sync(&self) -> Result<()> {
@@ -305,10 +551,60 @@ impl FileHandle {
 
     /// returns bytes written
     pub fn try_append(&self, record: &mut LogRecord) -> Result<usize> {
+        self.try_append_with(record, &CompressionConfig::default(), None)
+    }
+
+    /// Same as `try_append`, compressing the value as configured,
+    ///     and stamping the record with `meta` if given.
+    pub fn try_append_with(
+        &self,
+        record: &mut LogRecord,
+        compression: &CompressionConfig,
+        meta: Option<RecordMeta>,
+    ) -> Result<usize> {
         if record.key_is_empty() {
             panic!("LogRecord has empty key! Internal invariant broken.");
         }
-        let bin = FileHandle::encode_record(record);
+        let compressed = match record.value() {
+            Some(value) => compression.compress(value)?,
+            None => None,
+        };
+        let bin = match compressed {
+            Some((codec, compressed)) => {
+                // encode with the compressed value swapped in, then give the raw one back
+                let value = record
+                    .value_mut()
+                    .expect("compressed a record without value");
+                let raw = std::mem::replace(value, compressed);
+                let bin = FileHandle::encode_record(record, ValueEncoding::Compressed(codec), meta);
+                *record
+                    .value_mut()
+                    .expect("compressed a record without value") = raw;
+                bin
+            }
+            None => FileHandle::encode_record(record, ValueEncoding::Plain, meta),
+        };
+        self.append_encoded(bin)
+    }
+
+    /// Same as `try_append_with`, for a record whose value is an encoded `BlobPtr`.
+    pub fn try_append_blob_ref(
+        &self,
+        record: &mut LogRecord,
+        meta: Option<RecordMeta>,
+    ) -> Result<usize> {
+        if record.key_is_empty() {
+            panic!("LogRecord has empty key! Internal invariant broken.");
+        }
+        let bin = FileHandle::encode_record(record, ValueEncoding::BlobRef, meta);
+        self.append_encoded(bin)
+    }
+
+    fn append_encoded(&self, bin: ByteVec) -> Result<usize> {
+        let bin = match &self.cipher {
+            Some(cipher) => cipher.seal_frame(self.get_write_offset(), &bin),
+            None => bin,
+        };
         if self.write_offset.load(std::sync::atomic::Ordering::Relaxed) + bin.len() as u64
             >= self.file_config.max_file_size
         {
@@ -318,16 +614,50 @@ impl FileHandle {
     }
 }
 
+/// (record, size on disk, blob of a separated value, stamp)
+pub(crate) type StoredRecord = (LogRecord, u64, Option<BlobPtr>, Option<RecordMeta>);
+
+/// How the value of a record is written
+#[derive(Clone, Copy)]
+enum ValueEncoding {
+    Plain,
+    Compressed(Codec),
+    BlobRef,
+}
+
 /// private
 impl FileHandle {
-    fn encode_record(record: &LogRecord) -> ByteVec {
+    /// |type|, |type + COMPRESSED|codec| for a compressed value,
+    ///     or |type + BLOB_REF| for a value separated into a blob file;
+    ///     followed by |seq|timestamp| with RECORD_META set on the type for a stamped record
+    fn encode_type(
+        record: &LogRecord,
+        encoding: ValueEncoding,
+        meta: Option<RecordMeta>,
+    ) -> ByteVec {
+        let mut res = match encoding {
+            ValueEncoding::Plain => vec![record.type_id()],
+            ValueEncoding::Compressed(codec) => vec![record.type_id() | COMPRESSED, codec.flag()],
+            ValueEncoding::BlobRef => vec![record.type_id() | BLOB_REF],
+        };
+        if let Some(meta) = meta {
+            res[0] |= RECORD_META;
+            res.extend_from_slice(&meta.encode());
+        }
+        res
+    }
+
+    fn encode_record(
+        record: &LogRecord,
+        encoding: ValueEncoding,
+        meta: Option<RecordMeta>,
+    ) -> ByteVec {
         match record {
             LogRecord::Data { key, value } => {
-                let mut res = Vec::new();
+                let mut res = Self::encode_type(record, encoding, meta);
                 // make sure here key_size and value_size are 32-bit!!!!!
                 let key_size = key.len() as u32;
                 let value_size = value.len() as u32;
-                res.push(record.type_id());
                 res.extend_from_slice(&key_size.to_be_bytes());
                 res.extend_from_slice(&value_size.to_be_bytes());
                 res.extend_from_slice(&key.as_slice());
@@ -339,9 +669,8 @@ impl FileHandle {
                 res
             }
             LogRecord::Tomb { key } => {
-                let mut res = Vec::new();
+                let mut res = Self::encode_type(record, encoding, meta);
                 let key_size = key.len() as u32;
-                res.push(record.type_id());
                 res.extend_from_slice(&key_size.to_be_bytes());
                 res.extend_from_slice(&key.as_slice());
 
@@ -357,12 +686,11 @@ impl FileHandle {
             } => {
                 // store batch_id as usize
                 // |type|batch_id|ksz|vsz|k|v|crc|
-                let mut res = Vec::new();
+                let mut res = Self::encode_type(record, encoding, meta);
                 // make sure here key_size and value_size are 32-bit!!!!!
                 let batch_id = *batch_id as u64;
                 let key_size = key.len() as u32;
                 let value_size = value.len() as u32;
-                res.push(record.type_id());
                 res.extend_from_slice(&batch_id.to_be_bytes());
                 res.extend_from_slice(&key_size.to_be_bytes());
                 res.extend_from_slice(&value_size.to_be_bytes());
@@ -375,10 +703,9 @@ impl FileHandle {
                 res
             }
             LogRecord::TombInBatch { batch_id, key } => {
-                let mut res = Vec::new();
+                let mut res = Self::encode_type(record, encoding, meta);
                 let batch_id = *batch_id as u64;
                 let key_size = key.len() as u32;
-                res.push(record.type_id());
                 res.extend_from_slice(&batch_id.to_be_bytes());
                 res.extend_from_slice(&key_size.to_be_bytes());
                 res.extend_from_slice(&key.as_slice());
@@ -389,21 +716,63 @@ impl FileHandle {
                 res
             }
             LogRecord::BatchDone { batch_id } => {
-                let mut res = Vec::new();
-                res.push(record.type_id());
+                let mut res = Self::encode_type(record, encoding, meta);
                 let batch_id = *batch_id as u64;
                 res.extend_from_slice(&batch_id.to_be_bytes());
 
                 let crc = Self::crc(res.as_slice());
                 res.extend_from_slice(&crc.to_be_bytes());
 
+                res
+            }
+            LogRecord::Expiring {
+                key,
+                value,
+                expire_at,
+            } => {
+                // |type|expire_at|ksz|vsz|k|v|crc|
+                let mut res = Self::encode_type(record, encoding, meta);
+                let key_size = key.len() as u32;
+                let value_size = value.len() as u32;
+                res.extend_from_slice(&expire_at.to_be_bytes());
+                res.extend_from_slice(&key_size.to_be_bytes());
+                res.extend_from_slice(&value_size.to_be_bytes());
+                res.extend_from_slice(key.as_slice());
+                res.extend_from_slice(value.as_slice());
+
+                let crc = Self::crc(res.as_slice());
+                res.extend_from_slice(&crc.to_be_bytes());
+
+                res
+            }
+            LogRecord::ExpiringInBatch {
+                batch_id,
+                key,
+                value,
+                expire_at,
+            } => {
+                // |type|batch_id|expire_at|ksz|vsz|k|v|crc|
+                let mut res = Self::encode_type(record, encoding, meta);
+                let batch_id = *batch_id as u64;
+                let key_size = key.len() as u32;
+                let value_size = value.len() as u32;
+                res.extend_from_slice(&batch_id.to_be_bytes());
+                res.extend_from_slice(&expire_at.to_be_bytes());
+                res.extend_from_slice(&key_size.to_be_bytes());
+                res.extend_from_slice(&value_size.to_be_bytes());
+                res.extend_from_slice(key.as_slice());
+                res.extend_from_slice(value.as_slice());
+
+                let crc = Self::crc(res.as_slice());
+                res.extend_from_slice(&crc.to_be_bytes());
+
                 res
             }
         }
     }
 
     /// returns bytes written
-    fn append(&self, buf: &[u8]) -> Result<usize> {
+    pub(crate) fn append(&self, buf: &[u8]) -> Result<usize> {
         let n_bytes = self.io.write(buf)?;
         self.write_offset
             .fetch_add(n_bytes as u64, std::sync::atomic::Ordering::Relaxed);
@@ -435,6 +804,26 @@ impl FileHandle {
     pub fn size(&self) -> u64 {
         self.io.size()
     }
+
+    /// The `len` bytes at `offset` as they are on disk, sealed if the file is encrypted.
+    pub(crate) fn read_raw(&self, offset: u64, len: usize) -> Result<ByteVec> {
+        let mut buf = vec![0; len];
+        let mut read = 0;
+        while read < len {
+            match self.io.read(&mut buf[read..], offset + read as u64)? {
+                0 => return Err(Errors::Eof),
+                n => read += n,
+            }
+        }
+        Ok(buf)
+    }
+
+    /// Drop everything from `offset` on, and write from there.
+    pub fn truncate(&self, offset: u64) -> Result<()> {
+        self.io.truncate(offset)?;
+        self.set_write_offset(offset);
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -454,14 +843,31 @@ mod tests {
             max_file_size: 1024,
         };
 
-        // Create a file for testing
+        // remove if exist
         let filename = format_filename(dir.clone(), file_id);
-        fs::create_dir_all(&dir).unwrap();
-        let mut file = fs::File::create(&filename).unwrap();
-        file.write_all(b"test data").unwrap();
+        fs::remove_file(&filename);
+        let file_handle = FileHandle::create(dir.clone(), file_id, file_config).unwrap();
+        let mut record = LogRecord::Data {
+            key: b"key".to_vec(),
+            value: b"value".to_vec(),
+        };
+        let size = file_handle.try_append(&mut record).unwrap();
+        let header = file_handle.header();
+        drop(file_handle);
 
-        let file_handle = FileHandle::open(dir, file_id, file_config, IoType::File).unwrap();
+        let file_handle =
+            FileHandle::open(dir.clone(), file_id, file_config, IoType::File).unwrap();
         assert_eq!(file_handle.get_write_offset(), 0);
+        assert_eq!(file_handle.header(), header);
+        assert_eq!(file_handle.size(), size as u64);
+        assert_eq!(file_handle.read_at_offset(0).unwrap().0, record);
+
+        // a file of no header
+        fs::write(&filename, b"test data").unwrap();
+        assert!(matches!(
+            FileHandle::open(dir, file_id, file_config, IoType::File),
+            Err(Errors::InvalidFileHeader { .. })
+        ));
     }
 
     #[test]
@@ -632,6 +1038,114 @@ mod tests {
         // fs::remove_file(format_filename(dir, file_id)).unwrap();
     }
 
+    #[test]
+    fn test_compressed() {
+        let dir = PathBuf::from("./test_data");
+        let file_id = 9;
+        let file_config = FileConfig {
+            max_file_size: 1 << 16,
+        };
+
+        // remove if exist
+        fs::remove_file(format_filename(dir.clone(), file_id));
+        let file_handle = FileHandle::create(dir.clone(), file_id, file_config).unwrap();
+        let compression = CompressionConfig {
+            codec: Codec::Lz4,
+            ..Default::default()
+        };
+
+        let value = b"compressible ".repeat(100);
+        let mut record = LogRecord::Expiring {
+            key: b"key".to_vec(),
+            value: value.clone(),
+            expire_at: 42,
+        };
+        let size = file_handle
+            .try_append_with(&mut record, &compression, None)
+            .unwrap();
+        // the caller's record keeps the raw value
+        assert_eq!(record.value(), Some(&value));
+        assert!(size < record.encoded_len() / 4);
+        assert_eq!(
+            file_handle.record_meta_at(0).unwrap(),
+            (size as u64, Some(42))
+        );
+        let (read_record, read_size) = file_handle.read_at_offset(0).unwrap();
+        assert_eq!(read_record, record);
+        assert_eq!(read_size, size as u64);
+
+        // crc covers the compressed bytes
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .open(format_filename(dir.clone(), file_id))
+            .unwrap();
+        std::io::Seek::seek(
+            &mut file,
+            std::io::SeekFrom::Start(FileHeader::LENGTH + size as u64 - 8),
+        )
+        .unwrap();
+        file.write_all(b"x").unwrap();
+        assert!(matches!(
+            file_handle.read_at_offset(0),
+            Err(Errors::CrcMismatch { .. })
+        ));
+        fs::remove_file(format_filename(dir, file_id)).unwrap();
+    }
+
+    #[test]
+    fn test_stamped() {
+        let dir = PathBuf::from("./test_data");
+        let file_id = 10;
+        let file_config = FileConfig {
+            max_file_size: 1 << 16,
+        };
+
+        // remove if exist
+        fs::remove_file(format_filename(dir.clone(), file_id));
+        let file_handle = FileHandle::create(dir.clone(), file_id, file_config).unwrap();
+        let compression = CompressionConfig {
+            codec: Codec::Lz4,
+            ..Default::default()
+        };
+        let meta = RecordMeta {
+            seq: 7,
+            timestamp: 1 << 40,
+        };
+
+        let mut records = [
+            LogRecord::Data {
+                key: b"plain".to_vec(),
+                value: b"value".to_vec(),
+            },
+            LogRecord::ExpiringInBatch {
+                batch_id: 3,
+                key: b"compressed".to_vec(),
+                value: b"compressible ".repeat(100),
+                expire_at: 42,
+            },
+            LogRecord::BatchDone { batch_id: 3 },
+        ];
+        let mut offset = 0;
+        for record in records.iter_mut() {
+            let size = file_handle
+                .try_append_with(record, &compression, Some(meta))
+                .unwrap() as u64;
+            let (read_record, read_size, _, read_meta) =
+                file_handle.read_stored_at(offset).unwrap();
+            assert_eq!(&read_record, record);
+            assert_eq!((read_size, read_meta), (size, Some(meta)));
+            assert_eq!(file_handle.record_size_at(offset).unwrap(), size);
+            offset += size;
+        }
+        // unstamped next to stamped
+        file_handle.try_append(&mut records[0]).unwrap();
+        let (read_record, _, _, read_meta) = file_handle.read_stored_at(offset).unwrap();
+        assert_eq!(read_record, records[0]);
+        assert_eq!(read_meta, None);
+
+        fs::remove_file(format_filename(dir, file_id)).unwrap();
+    }
+
     #[test]
     #[should_panic(expected = "LogRecord has empty key! Internal invariant broken.")]
     fn test_key_empty() {
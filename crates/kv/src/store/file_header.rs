@@ -0,0 +1,463 @@
+/*
+    File header:
+    every `.store` and `.blob` file starts with
+        version 1: |magic 4|format version u16|file id u32|created at u64|crc u32|
+        version 2: |magic 4|format version u16|file id u32|created at u64|first seq u64|crc u32|
+    written by `FileHandle::create` and checked by `FileHandle::open`,
+    so a foreign file, a file of a newer format or a corrupt start of file is refused.
+    Version 2 files may hold stamped records, see `records::meta`;
+    the first seq is the sequence number the store was at when the file was created,
+    so the newest file bounds every sequence number handed out before it, whatever merge removed since.
+
+    Record offsets count from the end of the header, the header is invisible past `FileHandle`.
+    Upgrading a header-less store (`Store::migrate`) is then only prepending a header to its files:
+    pointers in hints, the persisted index and encryption nonces all stay valid.
+*/
+
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use bytes::{Buf, BufMut, BytesMut};
+use log::{error, info};
+
+use crate::{
+    definitions::{
+        constants::{get_blob_numbers, get_prefix_numbers},
+        types::ByteVec,
+    },
+    errors::{Errors, Result},
+    io::traits::IoLayer,
+    propagate_err,
+    storelock::storelock::StoreExclusiveLock,
+};
+
+use super::{
+    expiry::now_millis,
+    file_handle::FileHandle,
+    store::Store,
+    utils::{format_blob_filename, format_filename, sync_dir},
+};
+
+/// The first byte is never the first byte of a header-less file:
+///     neither a record type nor, sealed, the high byte of a frame size.
+pub const FILE_MAGIC: [u8; 4] = [0x89, b'K', b'V', b'S'];
+pub const FORMAT_VERSION: u16 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FileHeader {
+    pub(crate) version: u16,
+    pub(crate) file_id: u32,
+    /// milliseconds since UNIX epoch
+    pub(crate) created_at: u64,
+    /// sequence number of the store when the file was created, 0 before version 2
+    pub(crate) first_seq: u64,
+}
+
+impl FileHeader {
+    /// Length of a header of the current version
+    pub const LENGTH: u64 = Self::length_of(FORMAT_VERSION);
+    /// magic and version, which tell the length of the rest
+    const PREFIX_LENGTH: u64 = 4 /* magic */ + 2 /* version */;
+
+    pub fn new(file_id: u32) -> Self {
+        Self {
+            version: FORMAT_VERSION,
+            file_id,
+            created_at: now_millis(),
+            first_seq: 0,
+        }
+    }
+
+    pub fn with_first_seq(mut self, first_seq: u64) -> Self {
+        self.first_seq = first_seq;
+        self
+    }
+
+    const fn length_of(version: u16) -> u64 {
+        let first_seq = if version >= 2 { 8 } else { 0 };
+        Self::PREFIX_LENGTH + 4 /* file id */ + 8 /* created at */ + first_seq + 4
+        /* crc */
+    }
+
+    /// Length of this header on disk, where the first record starts
+    pub fn length(&self) -> u64 {
+        Self::length_of(self.version)
+    }
+
+    pub(crate) fn encode(&self) -> ByteVec {
+        let mut buf = BytesMut::with_capacity(self.length() as usize);
+        buf.put_slice(&FILE_MAGIC);
+        buf.put_u16(self.version);
+        buf.put_u32(self.file_id);
+        buf.put_u64(self.created_at);
+        if self.version >= 2 {
+            buf.put_u64(self.first_seq);
+        }
+        buf.put_u32(FileHandle::crc(&buf));
+        buf.to_vec()
+    }
+
+    /// Checks magic and version, returns the length of the header they start.
+    fn decode_prefix(bin: &[u8], path: &Path) -> Result<u64> {
+        if bin.len() < Self::PREFIX_LENGTH as usize || bin[..4] != FILE_MAGIC {
+            error!("No file header in {:?}", path);
+            return Err(Errors::InvalidFileHeader { path: path.into() });
+        }
+        let version = (&bin[4..]).get_u16();
+        // the layout of a newer header is not known, not even its length
+        if version > FORMAT_VERSION {
+            return Err(Errors::UnsupportedFormatVersion {
+                path: path.into(),
+                version,
+            });
+        }
+        Ok(Self::length_of(version))
+    }
+
+    pub(crate) fn decode(bin: &[u8], path: &Path) -> Result<Self> {
+        if bin.len() != Self::decode_prefix(bin, path)? as usize {
+            error!("No file header in {:?}", path);
+            return Err(Errors::InvalidFileHeader { path: path.into() });
+        }
+        let (content, mut crc) = bin.split_at(bin.len() - 4);
+        if FileHandle::verify_crc(content, crc.get_u32()).is_err() {
+            error!("Corrupt file header in {:?}", path);
+            return Err(Errors::InvalidFileHeader { path: path.into() });
+        }
+        let mut buf = &content[4..];
+        let version = buf.get_u16();
+        Ok(Self {
+            version,
+            file_id: buf.get_u32(),
+            created_at: buf.get_u64(),
+            first_seq: if version >= 2 { buf.get_u64() } else { 0 },
+        })
+    }
+
+    /// Reads and checks the header of the file at `path` of `size` bytes,
+    ///     `read` fills a buffer from an offset and tells whether it could.
+    fn read_with(path: &Path, size: u64, read: impl Fn(&mut [u8], u64) -> bool) -> Result<Self> {
+        let mut prefix = vec![0; Self::PREFIX_LENGTH as usize];
+        if size < Self::PREFIX_LENGTH || !read(&mut prefix, 0) {
+            error!("File {:?} is shorter than its header", path);
+            return Err(Errors::InvalidFileHeader { path: path.into() });
+        }
+        let length = Self::decode_prefix(&prefix, path)?;
+        let mut buf = vec![0; length as usize];
+        if size < length || !read(&mut buf, 0) {
+            error!("File {:?} is shorter than its header", path);
+            return Err(Errors::InvalidFileHeader { path: path.into() });
+        }
+        Self::decode(&buf, path)
+    }
+
+    /// Reads and checks the header of the file `file_id` at `path`.
+    pub(crate) fn read_from(io: &dyn IoLayer, path: &Path, file_id: u32) -> Result<Self> {
+        let header = Self::read_with(path, io.size(), |buf, offset| io.read(buf, offset).is_ok())?;
+        if header.file_id != file_id {
+            error!("File {:?} has the header of file {}", path, header.file_id);
+            return Err(Errors::InvalidFileHeader { path: path.into() });
+        }
+        Ok(header)
+    }
+
+    /// Whether the file at `path` starts with a header, an empty file does not.
+    pub(crate) fn is_present(path: &Path) -> Result<bool> {
+        let file = File::open(path).map_err(propagate_err!(Errors::FileInitError))?;
+        let mut magic = [0; 4];
+        match file.read_exact_at(&mut magic, 0) {
+            Ok(()) => Ok(magic == FILE_MAGIC),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+            Err(e) => Err(propagate_err!(Errors::FileIoReadError)(e)),
+        }
+    }
+
+    /// Whether the file at `path` holds no more than the start of a header, as a crash while creating it leaves:
+    ///     nothing, part of a header, or as many zero bytes as one, never a record.
+    pub(crate) fn is_torn(path: &Path) -> Result<bool> {
+        let size = fs::metadata(path)
+            .map_err(propagate_err!(Errors::FileIoReadError))?
+            .len();
+        if size > Self::LENGTH {
+            return Ok(false);
+        }
+        let bin = fs::read(path).map_err(propagate_err!(Errors::FileIoReadError))?;
+        let magic_len = bin.len().min(FILE_MAGIC.len());
+        let torn = bin.iter().all(|byte| *byte == 0)
+            || (bin[..magic_len] == FILE_MAGIC[..magic_len] && Self::decode(&bin, path).is_err());
+        Ok(torn)
+    }
+
+    /// Rewrite the header of the file at `path` as `edit` says, for a file renamed by merge;
+    ///     the version, and so the length, stays.
+    pub(crate) fn rewrite(path: &Path, edit: impl FnOnce(&mut Self)) -> Result<()> {
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .map_err(propagate_err!(Errors::FileInitError))?;
+        let size = file
+            .metadata()
+            .map_err(propagate_err!(Errors::FileIoReadError))?
+            .len();
+        let mut header = Self::read_with(path, size, |buf, offset| {
+            file.read_exact_at(buf, offset).is_ok()
+        })?;
+        edit(&mut header);
+        file.write_all_at(&header.encode(), 0)
+            .map_err(propagate_err!(Errors::FileIoWriteError))?;
+        file.sync_all()
+            .map_err(propagate_err!(Errors::FileIoSyncError))
+    }
+
+    /// Prepend a header to the header-less file `file_id` at `path`:
+    ///     the new file is written next to it, synced, then renamed over it.
+    fn prepend_to(path: &Path, file_id: u32) -> Result<()> {
+        let mut original = File::open(path).map_err(propagate_err!(Errors::FileInitError))?;
+        // the file is as old as its last write
+        let created_at = original
+            .metadata()
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map_or_else(now_millis, |modified| modified.as_millis() as u64);
+        let header = Self {
+            created_at,
+            ..Self::new(file_id)
+        };
+
+        let tmp_path = PathBuf::from(format!("{}.migrate", path.display()));
+        let mut upgraded =
+            File::create(&tmp_path).map_err(propagate_err!(Errors::FileInitError))?;
+        upgraded
+            .write_all(&header.encode())
+            .and_then(|_| io::copy(&mut original, &mut upgraded))
+            .map_err(propagate_err!(Errors::FileIoWriteError))?;
+        upgraded
+            .sync_all()
+            .map_err(propagate_err!(Errors::FileIoSyncError))?;
+        fs::rename(&tmp_path, path).map_err(propagate_err!(Errors::FileIoWriteError))
+    }
+}
+
+/// The body of a file, past its header: offset 0 is the first record.
+pub(crate) struct BodyIo {
+    io: Box<dyn IoLayer>,
+    /// length of the header
+    base: u64,
+}
+
+impl BodyIo {
+    pub(crate) fn new(io: Box<dyn IoLayer>, header: &FileHeader) -> Self {
+        Self {
+            io,
+            base: header.length(),
+        }
+    }
+}
+
+impl IoLayer for BodyIo {
+    fn write(&self, buf: &[u8]) -> Result<usize> {
+        self.io.write(buf)
+    }
+
+    fn read(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        self.io.read(buf, offset + self.base)
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.io.sync()
+    }
+
+    fn truncate(&self, size: u64) -> Result<()> {
+        self.io.truncate(size + self.base)
+    }
+
+    fn size(&self) -> u64 {
+        self.io.size().saturating_sub(self.base)
+    }
+}
+
+impl Store {
+    /// Upgrade the store at `dir` written before files had headers, in place;
+    ///     returns the number of files upgraded. The store must not be open.
+    pub fn migrate(dir: PathBuf) -> Result<usize> {
+        let _store_lock = StoreExclusiveLock::lock_at(dir.clone())?;
+        // staged files of an interrupted merge are installed or dropped first
+        Self::merge_recover(dir.clone())?;
+
+        let files = get_prefix_numbers(dir.clone())?
+            .into_iter()
+            .map(|file_id| (format_filename(dir.clone(), file_id), file_id))
+            .chain(
+                get_blob_numbers(dir.clone())?
+                    .into_iter()
+                    .map(|file_id| (format_blob_filename(dir.clone(), file_id), file_id)),
+            );
+        let mut upgraded = 0;
+        for (path, file_id) in files {
+            if FileHeader::is_present(&path)? {
+                continue;
+            }
+            FileHeader::prepend_to(&path, file_id)?;
+            upgraded += 1;
+        }
+        sync_dir(dir.clone())?;
+        info!("Upgraded {} files of {:?}", upgraded, dir);
+        Ok(upgraded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, os::unix::fs::FileExt, path::PathBuf};
+
+    use bytes::Bytes;
+
+    use crate::{
+        blob::blob_files::BlobConfig,
+        config::config::Config,
+        definitions::constants::{get_blob_numbers, get_prefix_numbers},
+        errors::Errors,
+        store::{
+            store::Store,
+            utils::{format_blob_filename, format_filename},
+        },
+    };
+
+    use super::{FileHeader, FORMAT_VERSION};
+
+    fn open(dir: &str) -> crate::errors::Result<Store> {
+        let (mut store_config, file_config, batched_config) =
+            Config::from_toml("config.toml".into());
+        store_config.dir = dir.into();
+        store_config.blob = BlobConfig {
+            enabled: true,
+            min_size: 1024,
+            ..Default::default()
+        };
+        Store::open(store_config, file_config, batched_config)
+    }
+
+    fn value(i: usize) -> Bytes {
+        if i.is_multiple_of(10) {
+            format!("{:04}:", i).repeat(300).into()
+        } else {
+            english_numbers::convert_all_fmt(i as i64).into()
+        }
+    }
+
+    /// Strip the header off a file, as written before headers
+    fn strip_header(path: PathBuf) {
+        let content = fs::read(&path).unwrap();
+        fs::write(&path, &content[FileHeader::LENGTH as usize..]).unwrap();
+    }
+
+    /// Rewrite the header of a file as written by format version 1
+    fn downgrade_header(path: PathBuf, file_id: u32) {
+        let content = fs::read(&path).unwrap();
+        let header = FileHeader {
+            version: 1,
+            ..FileHeader::new(file_id)
+        };
+        let mut downgraded = header.encode();
+        assert_eq!(downgraded.len() as u64, header.length());
+        downgraded.extend_from_slice(&content[FileHeader::LENGTH as usize..]);
+        fs::write(&path, downgraded).unwrap();
+    }
+
+    #[test]
+    fn test_file_header() {
+        let dir = "store/test_52";
+        // remove if exist
+        fs::remove_dir_all(dir);
+
+        let store = open(dir).unwrap();
+        for i in 0..200 {
+            store.put(format!("{}", i).into(), value(i)).unwrap();
+        }
+        for i in 0..50 {
+            store.delete(format!("{}", i).into()).unwrap();
+        }
+        drop(store);
+
+        let file_ids = get_prefix_numbers(dir.into()).unwrap();
+        assert!(file_ids.len() > 1, "{:?}", file_ids);
+        let blob_ids = get_blob_numbers(dir.into()).unwrap();
+        assert!(!blob_ids.is_empty());
+        assert_eq!(Store::migrate(dir.into()).unwrap(), 0);
+
+        // a store written before headers
+        for file_id in &file_ids {
+            strip_header(format_filename(dir.into(), *file_id));
+        }
+        for file_id in &blob_ids {
+            strip_header(format_blob_filename(dir.into(), *file_id));
+        }
+        assert!(matches!(open(dir), Err(Errors::InvalidFileHeader { .. })));
+        assert_eq!(
+            Store::migrate(dir.into()).unwrap(),
+            file_ids.len() + blob_ids.len()
+        );
+        assert_eq!(Store::migrate(dir.into()).unwrap(), 0);
+
+        let store = open(dir).unwrap();
+        assert_eq!(store.list_keys().len(), 150);
+        for i in 50..200 {
+            assert_eq!(store.get(format!("{}", i).into()).unwrap(), value(i));
+        }
+        store.put("after".into(), "migration".into()).unwrap();
+        drop(store);
+
+        // a store of format version 1, but for its first file
+        let file_ids = get_prefix_numbers(dir.into()).unwrap();
+        for file_id in &file_ids[1..] {
+            downgrade_header(format_filename(dir.into(), *file_id), *file_id);
+        }
+        let store = open(dir).unwrap();
+        assert_eq!(store.get("after".into()).unwrap(), "migration");
+        // records are stamped from now on, in a file of the current version
+        assert_eq!(
+            get_prefix_numbers(dir.into()).unwrap().len(),
+            file_ids.len() + 1
+        );
+        let (_, after) = store.get_with_meta("after".into()).unwrap();
+        store.put("stamped".into(), "yes".into()).unwrap();
+        let (_, stamped) = store.get_with_meta("stamped".into()).unwrap();
+        assert_eq!(stamped.unwrap().seq, after.unwrap().seq + 1);
+        drop(store);
+
+        // a file of a newer format
+        let path = format_filename(dir.into(), file_ids[0]);
+        let file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+        let header = FileHeader {
+            version: FORMAT_VERSION + 1,
+            ..FileHeader::new(file_ids[0])
+        };
+        file.write_all_at(&header.encode(), 0).unwrap();
+        assert!(matches!(
+            open(dir),
+            Err(Errors::UnsupportedFormatVersion { version, .. }) if version == FORMAT_VERSION + 1
+        ));
+
+        // a file under the name of another
+        file.write_all_at(&FileHeader::new(file_ids[0] + 1000).encode(), 0)
+            .unwrap();
+        assert!(matches!(open(dir), Err(Errors::InvalidFileHeader { .. })));
+        FileHeader::rewrite(&path, |header| header.file_id = file_ids[0]).unwrap();
+        let store = open(dir).unwrap();
+        assert_eq!(store.get("after".into()).unwrap(), "migration");
+        drop(store);
+
+        // a corrupt start of file
+        file.write_all_at(b"x", 6).unwrap();
+        assert!(matches!(open(dir), Err(Errors::InvalidFileHeader { .. })));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
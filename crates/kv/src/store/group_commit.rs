@@ -0,0 +1,186 @@
+/*
+    Group commit:
+    with `sync_every_write`, writers append under the locks as usual,
+    then wait here, after releasing them, until their record is on disk.
+    The first waiter syncs for everything appended so far,
+    writers arriving meanwhile wait for the next sync, which covers them all;
+    so concurrent writers share one fsync instead of taking turns.
+    Change events are published by whoever syncs, up to what the sync covered.
+*/
+
+use parking_lot::{Condvar, Mutex, MutexGuard};
+
+use crate::errors::Result;
+
+use super::store::Store;
+
+pub struct GroupCommit {
+    state: Mutex<GroupState>,
+    /// signalled whenever a sync finishes
+    synced_cond: Condvar,
+}
+
+struct GroupState {
+    /// records appended so far
+    appended: u64,
+    /// records known to be on disk
+    synced: u64,
+    /// a waiter is syncing right now
+    syncing: bool,
+    /// number of syncs done
+    syncs: u64,
+}
+
+impl GroupCommit {
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(GroupState {
+                appended: 0,
+                synced: 0,
+                syncing: false,
+                syncs: 0,
+            }),
+            synced_cond: Condvar::new(),
+        }
+    }
+
+    /// Called for every record appended, under the active file lock.
+    pub(crate) fn on_append(&self) {
+        self.state.lock().appended += 1;
+    }
+
+    /// Everything appended so far, to wait for with `wait`.
+    pub(crate) fn ticket(&self) -> u64 {
+        self.state.lock().appended
+    }
+
+    /// Returns once the first `ticket` records are on disk, syncing with `sync` if no one else is.
+    pub(crate) fn wait(&self, ticket: u64, sync: impl FnOnce() -> Result<()>) -> Result<()> {
+        let mut sync = Some(sync);
+        let mut state = self.state.lock();
+        while state.synced < ticket {
+            if state.syncing {
+                // the sync under way may not cover this ticket, check again after it
+                self.synced_cond.wait(&mut state);
+                continue;
+            }
+
+            // lead a group: one sync for everything appended until now
+            let target = state.appended;
+            state.syncing = true;
+            let sync = sync
+                .take()
+                .expect("Internal error: synced twice for one ticket");
+            let res = MutexGuard::unlocked(&mut state, sync);
+            state.syncing = false;
+            if res.is_ok() {
+                state.synced = state.synced.max(target);
+                state.syncs += 1;
+            }
+            self.synced_cond.notify_all();
+            res?;
+        }
+        Ok(())
+    }
+
+    pub fn syncs(&self) -> u64 {
+        self.state.lock().syncs
+    }
+}
+
+impl Default for GroupCommit {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Store {
+    /// With `sync_every_write`, returns once `ticket` is on disk; call without holding any lock.
+    ///     Otherwise the write is published to change subscribers by the next sync.
+    pub(crate) fn wait_durable(&self, ticket: u64) -> Result<()> {
+        if self.store_config.sync_every_write {
+            self.sync_until(ticket)?;
+        }
+        Ok(())
+    }
+
+    /// Returns once `ticket` is on disk, then hands the writes up to it to change subscribers.
+    pub(crate) fn sync_until(&self, ticket: u64) -> Result<()> {
+        self.group_commit.wait(ticket, || {
+            // blobs first, the records referring to them must not be on disk without them
+            self.blobs.sync()?;
+            self.active_file.read().sync()
+        })?;
+        self.changes.publish(ticket);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Barrier,
+        },
+        thread,
+    };
+
+    use crate::store::utils::TempStore;
+
+    use super::GroupCommit;
+
+    #[test]
+    fn test_group_commit_barrier() {
+        let group_commit = GroupCommit::new();
+        let appended = Barrier::new(8);
+        let syncs = AtomicU64::new(0);
+
+        thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    group_commit.on_append();
+                    let ticket = group_commit.ticket();
+                    appended.wait();
+                    group_commit
+                        .wait(ticket, || {
+                            syncs.fetch_add(1, Ordering::Relaxed);
+                            Ok(())
+                        })
+                        .unwrap();
+                });
+            }
+        });
+
+        // all appended before anyone waited: the first sync covers everyone
+        assert_eq!(syncs.load(Ordering::Relaxed), 1);
+        assert_eq!(group_commit.syncs(), 1);
+    }
+
+    #[test]
+    fn test_group_commit() {
+        let (_raii, store) = TempStore::init(49);
+        assert!(store.store_config.sync_every_write);
+        // writers start every put together, so their appends overlap
+        let round = Barrier::new(8);
+
+        thread::scope(|s| {
+            for t in 0..8 {
+                let store = &store;
+                let round = &round;
+                s.spawn(move || {
+                    for i in 0..50 {
+                        round.wait();
+                        let key = format!("{}-{}", t, i);
+                        store.put(key.into(), "value".into()).unwrap();
+                    }
+                });
+            }
+        });
+
+        assert_eq!(store.list_keys().len(), 400);
+        // every put returned after a sync covering it, shared with the puts appended meanwhile
+        let syncs = store.group_commit.syncs();
+        assert!(syncs > 0 && syncs < 400, "{}", syncs);
+    }
+}
@@ -1,4 +1,15 @@
+#[cfg(feature = "async")]
+pub mod async_store;
+pub mod backup;
+pub mod changes;
+pub mod conditional;
+pub mod expiry;
 pub mod file_handle;
+pub mod file_header;
+pub mod group_commit;
+pub mod read_only;
+pub mod restore;
+pub mod snapshot;
 pub mod store;
 pub mod utils;
-pub mod backup;
+pub mod verify;
@@ -0,0 +1,563 @@
+/*
+    Read-only open, alongside the writer owning the store:
+    no lock is taken and nothing in the store directory is created, written or removed,
+    so what a crash or an interrupted merge left behind is the writer's to finish.
+    The index is built from the sealed files, which never change once sealed,
+    and, if asked to, tails the active file up to its last complete record;
+    `refresh` carries on from where the index got to,
+    and builds it again once the writer published a merge, which retires the files it was built from.
+
+    Files are opened for reads only, and the writer carries on meanwhile:
+    a file removed between listing the directory and opening it has the directory listed again,
+    and the newest file, its header not on disk yet while the writer creates it, is left to the next `refresh`.
+*/
+
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+};
+
+use log::info;
+use parking_lot::Mutex;
+
+use crate::{
+    blob::blob_files::BlobFiles,
+    config::config::{BatchedConfig, FileConfig, StoreConfig},
+    definitions::constants::{get_blob_numbers, get_max_prefix_number, get_prefix_numbers},
+    errors::{Errors, Result},
+    hint::hint::HintFile,
+    index::index_impl::IndexType,
+    io::{cipher::RecordCipher, traits::IoType},
+    merge::{merge::MergeManifest, stats::MergeStats},
+    records::log_record::LogRecordPtr,
+};
+
+use super::{
+    changes::ChangeFeed,
+    expiry::Expirations,
+    file_handle::FileHandle,
+    file_header::FileHeader,
+    group_commit::GroupCommit,
+    snapshot::SnapshotPins,
+    store::{Replay, Store, StoreMode},
+    utils::{format_blob_filename, format_filename},
+};
+
+/// How far the index of a read-only store follows the store files
+pub(crate) struct ReadOnlyView {
+    /// whether the index follows the active file too, or sealed files only
+    tail_active: bool,
+    /// the index covers the log up to here
+    position: LogRecordPtr,
+    /// of the last merge published when the index was built
+    generation: u64,
+    replay: Replay,
+}
+
+impl Store {
+    /// Open the store at `store_config.dir` for reads only, while its writer may have it open;
+    ///     the index covers the sealed files, and the active file if `tail_active`, see `refresh`.
+    /// Writes, merge and blob collection fail with `ReadOnly`.
+    pub fn open_read_only(
+        store_config: StoreConfig,
+        file_config: FileConfig,
+        batched_config: BatchedConfig,
+        tail_active: bool,
+    ) -> Result<Self> {
+        let dir = store_config.dir.clone();
+        // there is nothing to read, and nothing is created
+        if get_max_prefix_number(dir.clone())?.is_none() {
+            return Err(Errors::DirNotFound { dir });
+        }
+
+        let generation = MergeManifest::generation_in(dir.clone())?;
+        let cipher = store_config.encryption.open_cipher(dir.clone())?;
+        let blobs = Self::open_blobs_read_only(&store_config, cipher.clone())?;
+        let (legacy_files, active_file) = open_listed(
+            || get_prefix_numbers(dir.clone()),
+            |file_id| format_filename(dir.clone(), file_id),
+            |file_ids| match file_ids {
+                // the writer is creating the first file
+                [] => Err(Errors::DirNotFound { dir: dir.clone() }),
+                _ => Self::open_files(
+                    dir.clone(),
+                    file_ids.to_vec(),
+                    file_config,
+                    IoType::ReadOnly,
+                    cipher.clone(),
+                ),
+            },
+        )?;
+        let active_header = active_file.read().header();
+        // a persistent index belongs to the writer
+        let index_type = match store_config.index_type {
+            IndexType::DiskTree => IndexType::BTree,
+            index_type => index_type,
+        };
+
+        let store = Self {
+            index: index_type.create_index(dir),
+            store_config,
+            file_config,
+            batched_config,
+            mode: StoreMode::ReadOnly,
+            active_file,
+            active_file_id: AtomicU32::new(active_header.file_id),
+            legacy_files,
+            blobs,
+            write_lock: Mutex::new(()),
+            group_commit: GroupCommit::new(),
+            seq: active_header.first_seq.into(),
+            changes: ChangeFeed::new(),
+            batch_commit_lock: Mutex::new(()),
+            batch_id: 0.into(),
+            merge_lock: Mutex::new(()),
+            merge_stats: MergeStats::new(),
+            merge_generation: generation.into(),
+            expirations: Expirations::new(),
+            snapshot_pins: SnapshotPins::new(),
+            cipher,
+            store_lock: None,
+            read_only: Some(Mutex::new(ReadOnlyView {
+                tail_active,
+                position: LogRecordPtr {
+                    file_id: 0,
+                    offset: 0,
+                },
+                generation,
+                replay: Replay::new(),
+            })),
+        };
+        store.refresh()?;
+        Ok(store)
+    }
+
+    /// Catch up with the writer: files sealed since the index was built or last refreshed,
+    ///     and records appended to the active file if tailed.
+    /// Does nothing for a store opened for writes, whose index is always up to date.
+    pub fn refresh(&self) -> Result<()> {
+        let Some(view) = &self.read_only else {
+            return Ok(());
+        };
+        let mut view = view.lock();
+        // the writer publishes merges, the manifest on disk tells
+        if MergeManifest::generation_in(self.store_config.dir.clone())? != view.generation {
+            info!(
+                "Merge published in {:?}, building the index again",
+                self.store_config.dir
+            );
+            let rebuilt = Self::open_read_only(
+                self.store_config.clone(),
+                self.file_config,
+                self.batched_config,
+                view.tail_active,
+            )?;
+            self.adopt(rebuilt, &mut view);
+            return Ok(());
+        }
+
+        self.open_new_files()?;
+        self.follow_files(&mut view)
+    }
+
+    /// Take over the files and index of `rebuilt`, this store opened again after a merge;
+    ///     files retired by the merge are closed last, once the index no longer points to them.
+    fn adopt(&self, mut rebuilt: Store, view: &mut ReadOnlyView) {
+        let rebuilt_active = std::mem::replace(&mut rebuilt.active_file, self.active_file.clone());
+        let rebuilt_active = Arc::into_inner(rebuilt_active)
+            .expect("Internal error: active file of a store being opened shared")
+            .into_inner();
+        let rebuilt_files = std::mem::take(&mut *rebuilt.legacy_files.write());
+        let rebuilt_active_file_id = rebuilt.active_file_id.load(Ordering::Relaxed);
+        let retired: Vec<u32> = {
+            let mut legacy_files = self.legacy_files.write();
+            let mut active_file = self.active_file.write();
+            let active_file_id = self.active_file_id.load(Ordering::Relaxed);
+            let sealed = std::mem::replace(&mut *active_file, rebuilt_active);
+            legacy_files.insert(active_file_id, sealed);
+            let retired = legacy_files
+                .keys()
+                .copied()
+                .filter(|file_id| !rebuilt_files.contains_key(file_id))
+                .collect();
+            legacy_files.extend(rebuilt_files);
+            self.active_file_id
+                .store(rebuilt_active_file_id, Ordering::Relaxed);
+            retired
+        };
+        self.seq
+            .store(rebuilt.seq.load(Ordering::Relaxed), Ordering::Relaxed);
+        self.merge_generation
+            .store(rebuilt.merge_generation(), Ordering::Release);
+
+        for (key, ptr) in rebuilt.index.iter_snapshot().make() {
+            self.index.put(key, ptr);
+        }
+        let dropped: Vec<_> = self
+            .index
+            .iter_snapshot()
+            .make()
+            .filter(|(key, _)| rebuilt.index.get(key.clone()).is_none())
+            .map(|(key, _)| key)
+            .collect();
+        for key in dropped {
+            self.index.delete(key);
+        }
+        self.expirations.reset(rebuilt.expirations.snapshot());
+        self.blobs.replace(&rebuilt.blobs);
+
+        let mut legacy_files = self.legacy_files.write();
+        for file_id in retired {
+            legacy_files.remove(&file_id);
+        }
+        drop(legacy_files);
+        let rebuilt_view = rebuilt
+            .read_only
+            .take()
+            .expect("Internal error: rebuilt a store not read-only");
+        *view = rebuilt_view.into_inner();
+    }
+
+    /// The writer may have rotated since, possibly more than once:
+    ///     every file newer than the active one is opened, the newest becoming active.
+    fn open_new_files(&self) -> Result<()> {
+        let dir = self.store_config.dir.clone();
+        let mut active_file_id = self.active_file_id.load(Ordering::Relaxed);
+        let new_files = open_listed(
+            || {
+                Ok(get_prefix_numbers(dir.clone())?
+                    .into_iter()
+                    .filter(|file_id| *file_id > active_file_id)
+                    .collect())
+            },
+            |file_id| format_filename(dir.clone(), file_id),
+            |file_ids| {
+                file_ids
+                    .iter()
+                    .map(|file_id| {
+                        let file = FileHandle::open(
+                            dir.clone(),
+                            *file_id,
+                            self.file_config,
+                            IoType::ReadOnly,
+                        )?;
+                        Ok((*file_id, file.with_cipher(self.cipher.clone())))
+                    })
+                    .collect::<Result<Vec<_>>>()
+            },
+        )?;
+        for (file_id, file) in new_files {
+            let first_seq = file.header().first_seq;
+            let sealed = std::mem::replace(&mut *self.active_file.write(), file);
+            self.legacy_files.write().insert(active_file_id, sealed);
+            self.active_file_id.store(file_id, Ordering::Relaxed);
+            self.seq.fetch_max(first_seq, Ordering::Relaxed);
+            active_file_id = file_id;
+        }
+        // records read from now on may refer to blob files created meanwhile
+        self.blobs.replace(&Self::open_blobs_read_only(
+            &self.store_config,
+            self.cipher.clone(),
+        )?);
+        Ok(())
+    }
+
+    fn open_blobs_read_only(
+        store_config: &StoreConfig,
+        cipher: Option<Arc<RecordCipher>>,
+    ) -> Result<BlobFiles> {
+        let dir = store_config.dir.clone();
+        open_listed(
+            || get_blob_numbers(dir.clone()),
+            |file_id| format_blob_filename(dir.clone(), file_id),
+            |file_ids| {
+                BlobFiles::open_files(
+                    dir.clone(),
+                    store_config.blob,
+                    cipher.clone(),
+                    file_ids.to_vec(),
+                    IoType::ReadOnly,
+                )
+            },
+        )
+    }
+
+    /// Replay what the index does not cover yet.
+    fn follow_files(&self, view: &mut ReadOnlyView) -> Result<()> {
+        let dir = self.store_config.dir.clone();
+        let active_file_id = self.active_file_id.load(Ordering::Relaxed);
+
+        // sealed files never change, each is read once
+        let legacy_files = self.legacy_files.read();
+        let mut sealed_file_ids: Vec<u32> = legacy_files
+            .keys()
+            .copied()
+            .filter(|file_id| *file_id >= view.position.file_id)
+            .collect();
+        sealed_file_ids.sort_unstable();
+        for file_id in sealed_file_ids {
+            let offset = if file_id == view.position.file_id {
+                view.position.offset
+            } else {
+                0
+            };
+            let hints = match offset {
+                0 => HintFile::load(dir.clone(), file_id, self.cipher.as_deref()),
+                _ => None,
+            };
+            match hints {
+                Some(hints) => {
+                    self.update_index_on_hints(hints, file_id, &mut view.replay);
+                }
+                None => {
+                    let file = &legacy_files[&file_id];
+                    self.update_index_on_file_from(&file, file_id, offset, &mut view.replay)?;
+                }
+            }
+        }
+        drop(legacy_files);
+        if view.position.file_id < active_file_id {
+            view.position = LogRecordPtr {
+                file_id: active_file_id,
+                offset: 0,
+            };
+        }
+        if !view.tail_active {
+            return Ok(());
+        }
+
+        // the writer may be appending a record right now, it is read by the next refresh
+        let active_file = self.active_file.read();
+        let mut next_seq = self.seq.load(Ordering::Relaxed);
+        view.position.offset = self.update_index_on_active_file(
+            &active_file,
+            active_file_id,
+            view.position.offset,
+            &mut view.replay,
+            &mut next_seq,
+        );
+        self.seq.fetch_max(next_seq, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+/// Opens with `open` the files `list` names, but for the newest if it is torn, as the writer creating it leaves it;
+///     lists them again if the directory changed meanwhile, as merge, rotation and blob collection change it.
+fn open_listed<T>(
+    list: impl Fn() -> Result<Vec<u32>>,
+    path: impl Fn(u32) -> PathBuf,
+    open: impl Fn(&[u32]) -> Result<T>,
+) -> Result<T> {
+    loop {
+        let listed = list()?;
+        let mut file_ids = listed.clone();
+        // a file removed meanwhile fails to open, and has the directory listed again
+        if let Some(file_id) = file_ids.last() {
+            if FileHeader::is_torn(&path(*file_id)).unwrap_or(false) {
+                file_ids.pop();
+            }
+        }
+        match open(&file_ids) {
+            Err(e) if list()? != listed => {
+                info!(
+                    "Files changed while opening them ({}), listing them again",
+                    e
+                );
+            }
+            res => return res,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::BTreeSet,
+        fs,
+        os::unix::fs::PermissionsExt,
+        path::Path,
+        sync::{atomic::Ordering, Arc},
+        thread,
+    };
+
+    use bytes::Bytes;
+
+    use crate::{
+        config::config::Config,
+        errors::Errors,
+        store::{file_header::FileHeader, store::Store, utils::format_filename},
+    };
+
+    fn open_at(dir: &str, read_only: Option<bool>) -> crate::errors::Result<Store> {
+        let (mut store_config, file_config, batched_config) =
+            Config::from_toml("config.toml".into());
+        store_config.dir = dir.into();
+        match read_only {
+            Some(tail_active) => {
+                Store::open_read_only(store_config, file_config, batched_config, tail_active)
+            }
+            None => Store::open(store_config, file_config, batched_config),
+        }
+    }
+
+    fn file_names(dir: &str) -> BTreeSet<String> {
+        fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect()
+    }
+
+    /// `reader` holds every key the writer wrote to a file it has seen sealed, and nothing else.
+    fn assert_sealed_view(writer: &Store, reader: &Store) {
+        let sealed_until = reader
+            .active_file_id
+            .load(std::sync::atomic::Ordering::Relaxed);
+        for key in writer.list_keys() {
+            let ptr = writer.index.get(key.to_vec()).unwrap();
+            if ptr.file_id < sealed_until {
+                assert_eq!(reader.get(key.clone()).unwrap(), writer.get(key).unwrap());
+            }
+        }
+        for key in reader.list_keys() {
+            let ptr = reader.index.get(key.to_vec()).unwrap();
+            assert!(ptr.file_id < sealed_until);
+        }
+    }
+
+    #[test]
+    fn test_read_only() {
+        let test_id = 60;
+        let dir = format!("store/test_{}", test_id);
+        // remove if exist
+        fs::remove_dir_all(dir.clone());
+
+        // nothing to read, nothing created
+        assert!(matches!(
+            open_at(&dir, Some(true)),
+            Err(Errors::DirNotFound { .. })
+        ));
+        assert!(!Path::new(&dir).exists());
+
+        let writer = open_at(&dir, None).unwrap();
+        for i in 0..300 {
+            let key = format!("{}", i);
+            writer.put(key.into(), i.to_string().into()).unwrap();
+        }
+        let files = file_names(&dir);
+
+        // both open while the writer holds the store, without touching its files
+        let sealed = open_at(&dir, Some(false)).unwrap();
+        // refreshed through the `Arc` every other API hands out
+        let tailing = Arc::new(open_at(&dir, Some(true)).unwrap());
+        assert_eq!(file_names(&dir), files);
+        assert_sealed_view(&writer, &sealed);
+        assert_eq!(tailing.list_keys().len(), 300);
+        assert_eq!(tailing.get("299".into()).unwrap(), "299");
+        assert!(sealed.get("299".into()).is_err());
+
+        // every mutating call fails
+        assert!(matches!(
+            tailing.put("a".into(), "b".into()),
+            Err(Errors::ReadOnly)
+        ));
+        assert!(matches!(tailing.delete("a".into()), Err(Errors::ReadOnly)));
+        assert!(matches!(
+            tailing.put_if_absent("a".into(), "b".into()),
+            Err(Errors::ReadOnly)
+        ));
+        assert!(matches!(tailing.merge(), Err(Errors::ReadOnly)));
+        assert!(matches!(tailing.gc_blobs(), Err(Errors::ReadOnly)));
+
+        // refresh catches up, across the writer's rotations
+        for i in 300..600 {
+            let key = format!("{}", i);
+            writer.put(key.into(), i.to_string().into()).unwrap();
+        }
+        for i in 0..50 {
+            let key = format!("{}", i);
+            writer.delete(key.into()).unwrap();
+        }
+        thread::scope(|s| {
+            s.spawn(|| tailing.refresh().unwrap());
+            assert_eq!(tailing.get("299".into()).unwrap(), "299");
+        });
+        sealed.refresh().unwrap();
+        assert_eq!(tailing.list_keys().len(), 550);
+        assert!(tailing.get("0".into()).is_err());
+        assert_eq!(tailing.get("599".into()).unwrap(), "599");
+        assert_sealed_view(&writer, &sealed);
+
+        // a merge retires the files the index was built from
+        writer.merge().unwrap();
+        writer.put("new".into(), "new".into()).unwrap();
+        tailing.refresh().unwrap();
+        sealed.refresh().unwrap();
+        let keys = tailing.list_keys();
+        assert_eq!(keys.len(), 551);
+        for key in keys {
+            assert_eq!(tailing.get(key.clone()).unwrap(), writer.get(key).unwrap());
+        }
+        assert_sealed_view(&writer, &sealed);
+        assert!(sealed.get(Bytes::from("new")).is_err());
+
+        drop(sealed);
+        drop(tailing);
+        drop(writer);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_read_only_while_creating() {
+        let test_id = 66;
+        let dir = format!("store/test_{}", test_id);
+        // remove if exist
+        fs::remove_dir_all(dir.clone());
+
+        let writer = open_at(&dir, None).unwrap();
+        for i in 0..300 {
+            let key = format!("{}", i);
+            writer.put(key.into(), i.to_string().into()).unwrap();
+        }
+        writer.sync().unwrap();
+        let active_file_id = writer.active_file_id.load(Ordering::Relaxed);
+
+        // the writer is rotating: the new file has no header, or part of it, on disk yet
+        let creating = format_filename(dir.clone().into(), active_file_id + 1);
+        let header = FileHeader::new(active_file_id + 1).encode();
+        // and the reader may not write to the store
+        let set_mode = |mode: u32| {
+            for entry in fs::read_dir(&dir).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_file() {
+                    fs::set_permissions(path, fs::Permissions::from_mode(mode & 0o666)).unwrap();
+                }
+            }
+            fs::set_permissions(&dir, fs::Permissions::from_mode(mode)).unwrap();
+        };
+        for torn in [&header[..0], &header[..5]] {
+            fs::write(&creating, torn).unwrap();
+            set_mode(0o555);
+            let reader = open_at(&dir, Some(true));
+            set_mode(0o755);
+            let reader = reader.unwrap();
+            assert_eq!(
+                reader.active_file_id.load(Ordering::Relaxed),
+                active_file_id
+            );
+            assert_eq!(reader.list_keys().len(), 300);
+            assert_eq!(reader.get("299".into()).unwrap(), "299");
+            reader.refresh().unwrap();
+            assert_eq!(
+                reader.active_file_id.load(Ordering::Relaxed),
+                active_file_id
+            );
+            fs::remove_file(&creating).unwrap();
+        }
+
+        drop(writer);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,404 @@
+/*
+    Point-in-time restore:
+    the log of a store is replayed up to a cutoff into a new store directory.
+    Files before the cutoff are taken whole, hard linked where possible,
+    the file holding the cutoff is copied up to it and becomes the active file of the new store.
+    A batch cut off before its `BatchDone` is left out entirely.
+
+    History is only as long as the log: merge rewrites the files it replaces,
+    and blob collection removes blobs only records before it refer to,
+    so a cutoff before the last merge or blob collection does not restore the state of that time.
+*/
+
+use std::path::PathBuf;
+
+use log::{info, warn};
+
+use crate::{
+    config::config::FileConfig,
+    definitions::constants::{
+        get_blob_numbers, get_prefix_numbers, KEY_CHECK_FILE_NAME, MERGE_MANIFEST_FILE_NAME,
+    },
+    errors::{Errors, Result},
+    io::{cipher::EncryptionConfig, traits::IoType},
+    propagate_err,
+    records::{
+        log_record::{LogRecord, LogRecordPtr},
+        meta::RecordMeta,
+    },
+};
+
+use super::{
+    backup::{copy_prefix, link_or_copy},
+    file_handle::FileHandle,
+    store::Store,
+    utils::{format_blob_filename, format_filename, format_hint_filename, sync_dir},
+};
+
+/// The point in the log to restore the state of.
+///     The log is replayed up to the first record past the cutoff;
+///     records written before records were stamped are never past a stamp cutoff.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RestoreCutoff {
+    /// every record starting before `offset` of file `file_id`
+    Position { file_id: u32, offset: u64 },
+    /// every record up to and including the sequence number `seq`
+    Sequence(u64),
+    /// every record written up to `timestamp`, milliseconds since UNIX epoch
+    Timestamp(u64),
+}
+
+impl RestoreCutoff {
+    /// Whether the record at `ptr` stamped `meta` is past the cutoff;
+    ///     `meta` is `None` for a record not read yet, or written before stamps.
+    fn is_past(&self, ptr: LogRecordPtr, meta: Option<RecordMeta>) -> bool {
+        match (*self, meta) {
+            (RestoreCutoff::Position { file_id, offset }, _) => {
+                (ptr.file_id, ptr.offset) >= (file_id, offset)
+            }
+            (RestoreCutoff::Sequence(seq), Some(meta)) => meta.seq > seq,
+            (RestoreCutoff::Timestamp(timestamp), Some(meta)) => meta.timestamp > timestamp,
+            (_, None) => false,
+        }
+    }
+}
+
+impl Store {
+    /// Restore the state of the store at `src_dir` as of `cutoff` into `dest_dir`,
+    ///     which must not hold a store; `src_dir` is only read from.
+    /// Returns where the restored log ends.
+    pub fn restore_to(
+        src_dir: PathBuf,
+        dest_dir: PathBuf,
+        cutoff: RestoreCutoff,
+    ) -> Result<LogRecordPtr> {
+        Self::restore_to_with(src_dir, dest_dir, cutoff, &EncryptionConfig::default())
+    }
+
+    /// Same as `restore_to`, for a store that may be encrypted.
+    pub fn restore_to_with(
+        src_dir: PathBuf,
+        dest_dir: PathBuf,
+        cutoff: RestoreCutoff,
+        encryption: &EncryptionConfig,
+    ) -> Result<LogRecordPtr> {
+        if dest_dir.is_dir() && !get_prefix_numbers(dest_dir.clone())?.is_empty() {
+            return Err(Errors::RestoreTargetNotEmpty { dir: dest_dir });
+        }
+        let file_ids = get_prefix_numbers(src_dir.clone())?;
+        // opening the cipher writes the key check of a store that has none yet
+        let cipher = if src_dir.join(KEY_CHECK_FILE_NAME).is_file() {
+            encryption.open_cipher(src_dir.clone())?
+        } else {
+            None
+        };
+
+        // end of the last record before the cutoff, and the start of the batch it is in, if any
+        let mut end = LogRecordPtr {
+            file_id: file_ids.first().copied().unwrap_or(0),
+            offset: 0,
+        };
+        let mut batch_start: Option<(usize, LogRecordPtr)> = None;
+        // (file id, header length) of the files read
+        let mut headers = Vec::new();
+        'files: for (i, file_id) in file_ids.iter().copied().enumerate() {
+            let file = FileHandle::open_at(
+                format_filename(src_dir.clone(), file_id),
+                file_id,
+                // only read from
+                FileConfig { max_file_size: 0 },
+                IoType::MemMapped,
+            )?
+            .with_cipher(cipher.clone());
+            headers.push((file_id, file.header().length()));
+            let mut offset = 0;
+            loop {
+                let ptr = LogRecordPtr { file_id, offset };
+                if cutoff.is_past(ptr, None) {
+                    break 'files;
+                }
+                let (record, size) = match file.read_stored_at(offset) {
+                    Ok((_, _, _, meta)) if cutoff.is_past(ptr, meta) => break 'files,
+                    Ok((record, size, _, _)) => (record, size),
+                    Err(Errors::Eof) => break,
+                    // torn by a crash the store was not opened since
+                    Err(e) if i + 1 == file_ids.len() => {
+                        warn!("Restore stops at torn record {:?}: {}", ptr, e);
+                        break;
+                    }
+                    Err(e) => return Err(e),
+                };
+                // the same state machine as replay: a batch counts once its `BatchDone` is read,
+                //      any other record drops a batch in progress
+                batch_start = match record {
+                    LogRecord::DataInBatch { batch_id, .. }
+                    | LogRecord::TombInBatch { batch_id, .. }
+                    | LogRecord::ExpiringInBatch { batch_id, .. } => match batch_start {
+                        Some((cur_batch_id, start)) if cur_batch_id == batch_id => {
+                            Some((cur_batch_id, start))
+                        }
+                        _ => Some((batch_id, ptr)),
+                    },
+                    _ => None,
+                };
+                offset += size;
+                end = LogRecordPtr { file_id, offset };
+            }
+            // the cutoff is past this file
+            end = LogRecordPtr { file_id, offset };
+        }
+        let cut = batch_start.map_or(end, |(_, start)| start);
+
+        // the cut is never past the files read
+        if let Some((_, header_length)) = headers.iter().find(|(id, _)| *id == cut.file_id) {
+            Self::restore_files(
+                src_dir.clone(),
+                dest_dir.clone(),
+                &file_ids,
+                cut,
+                *header_length,
+            )
+            .map_err(propagate_err!(Errors::FileIoWriteError))?;
+        }
+        info!(
+            "Restored {:?} up to {:?} into {:?}, for cutoff {:?}",
+            src_dir, cut, dest_dir, cutoff
+        );
+        Ok(cut)
+    }
+
+    /// Files before `cut` are taken whole, the file of `cut` up to it,
+    ///     past its header of `header_length` bytes.
+    fn restore_files(
+        src_dir: PathBuf,
+        dest_dir: PathBuf,
+        file_ids: &[u32],
+        cut: LogRecordPtr,
+        header_length: u64,
+    ) -> std::io::Result<()> {
+        std::fs::create_dir_all(&dest_dir)?;
+        for file_id in file_ids.iter().copied().filter(|id| *id < cut.file_id) {
+            link_or_copy(
+                format_filename(src_dir.clone(), file_id),
+                format_filename(dest_dir.clone(), file_id),
+            )?;
+            let hint = format_hint_filename(src_dir.clone(), file_id);
+            if hint.is_file() {
+                link_or_copy(hint, format_hint_filename(dest_dir.clone(), file_id))?;
+            }
+        }
+        copy_prefix(
+            format_filename(src_dir.clone(), cut.file_id),
+            format_filename(dest_dir.clone(), cut.file_id),
+            header_length + cut.offset,
+        )?;
+
+        // later blobs are garbage to the restored store, collected as usual;
+        //      the newest file may still be written to, it is copied
+        let mut blob_ids = get_blob_numbers(src_dir.clone()).map_err(std::io::Error::other)?;
+        if let Some(file_id) = blob_ids.pop() {
+            std::fs::copy(
+                format_blob_filename(src_dir.clone(), file_id),
+                format_blob_filename(dest_dir.clone(), file_id),
+            )?;
+        }
+        for file_id in blob_ids {
+            link_or_copy(
+                format_blob_filename(src_dir.clone(), file_id),
+                format_blob_filename(dest_dir.clone(), file_id),
+            )?;
+        }
+        for name in [KEY_CHECK_FILE_NAME, MERGE_MANIFEST_FILE_NAME] {
+            let path = src_dir.join(name);
+            if path.is_file() {
+                std::fs::copy(path, dest_dir.join(name))?;
+            }
+        }
+        sync_dir(dest_dir).map_err(std::io::Error::other)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, sync::Arc};
+
+    use bytes::Bytes;
+
+    use crate::{
+        batched::batched_write::CreateBatch,
+        config::config::{Config, FileConfig},
+        errors::Errors,
+        io::traits::IoType,
+        records::log_record::{LogRecord, LogRecordPtr},
+        store::{file_handle::FileHandle, store::Store},
+    };
+
+    use super::RestoreCutoff;
+
+    fn open(dir: &str) -> Store {
+        let (mut store_config, file_config, batched_config) =
+            Config::from_toml("config.toml".into());
+        store_config.dir = dir.into();
+        Store::open(store_config, file_config, batched_config).unwrap()
+    }
+
+    fn position(ptr: LogRecordPtr) -> RestoreCutoff {
+        RestoreCutoff::Position {
+            file_id: ptr.file_id,
+            offset: ptr.offset,
+        }
+    }
+
+    /// Where the `BatchDone` in file `file_id` is
+    fn batch_done(dir: &str, file_id: u32) -> LogRecordPtr {
+        let file = FileHandle::open(
+            dir.into(),
+            file_id,
+            FileConfig { max_file_size: 0 },
+            IoType::File,
+        )
+        .unwrap();
+        let mut offset = 0;
+        loop {
+            let (record, size) = file.read_at_offset(offset).unwrap();
+            if let LogRecord::BatchDone { .. } = record {
+                return LogRecordPtr { file_id, offset };
+            }
+            offset += size;
+        }
+    }
+
+    #[test]
+    fn test_restore_to() {
+        let dir = "store/test_56";
+        let dests = [
+            "store/test_56_a",
+            "store/test_56_b",
+            "store/test_56_c",
+            "store/test_56_d",
+            "store/test_56_e",
+        ];
+        // remove if exist
+        for dir in [dir].iter().chain(dests.iter()) {
+            fs::remove_dir_all(dir);
+        }
+
+        let store = Arc::new(open(dir));
+        for i in 0..100 {
+            let key = format!("{}", i);
+            let val = english_numbers::convert_all_fmt(i);
+            store.put(key.into(), val.into()).unwrap();
+        }
+        // the bad deploy
+        let bad = store.put("0".into(), "garbage".into()).unwrap();
+        for i in 1..50 {
+            store
+                .put(format!("{}", i).into(), "garbage".into())
+                .unwrap();
+        }
+        store.delete("99".into()).unwrap();
+
+        let batch = store.new_batched();
+        batch.put("b1".into(), "batched".into()).unwrap();
+        batch.put("b2".into(), "batched".into()).unwrap();
+        batch.delete("98".into()).unwrap();
+        batch.commit().unwrap();
+        drop(batch);
+        let after_batch = store.put("after".into(), "batch".into()).unwrap();
+
+        // just before the bad deploy
+        assert_eq!(
+            Store::restore_to(dir.into(), dests[0].into(), position(bad)).unwrap(),
+            bad
+        );
+        // within the batch, right before its `BatchDone`
+        let within_batch = batch_done(dir, after_batch.file_id);
+        let cut = Store::restore_to(dir.into(), dests[1].into(), position(within_batch)).unwrap();
+        assert!((cut.file_id, cut.offset) < (within_batch.file_id, within_batch.offset));
+        // right after the batch
+        assert_eq!(
+            Store::restore_to(dir.into(), dests[2].into(), position(after_batch)).unwrap(),
+            after_batch
+        );
+        assert!(matches!(
+            Store::restore_to(dir.into(), dests[0].into(), position(bad)),
+            Err(Errors::RestoreTargetNotEmpty { .. })
+        ));
+        // the same points in time, by stamp
+        let (_, bad_meta) = store.get_with_meta("0".into()).unwrap();
+        let bad_seq = bad_meta.unwrap().seq;
+        assert_eq!(
+            Store::restore_to(
+                dir.into(),
+                dests[3].into(),
+                RestoreCutoff::Sequence(bad_seq - 1)
+            )
+            .unwrap(),
+            bad
+        );
+        let (_, after_meta) = store.get_with_meta("after".into()).unwrap();
+        let end = Store::restore_to(
+            dir.into(),
+            dests[4].into(),
+            RestoreCutoff::Timestamp(after_meta.unwrap().timestamp),
+        )
+        .unwrap();
+        assert!((end.file_id, end.offset) > (after_batch.file_id, after_batch.offset));
+
+        let restored = open(dests[0]);
+        assert_eq!(restored.list_keys().len(), 100);
+        for i in 0..100 {
+            assert_eq!(
+                restored.get(format!("{}", i).into()).unwrap(),
+                english_numbers::convert_all_fmt(i)
+            );
+        }
+        // a store of its own
+        restored.put("restored".into(), "yes".into()).unwrap();
+        drop(restored);
+        assert_eq!(
+            open(dests[0]).get("restored".into()).unwrap(),
+            Bytes::from("yes")
+        );
+
+        let restored = open(dests[1]);
+        assert_eq!(restored.get("0".into()).unwrap(), "garbage");
+        assert!(restored.get("99".into()).is_err());
+        assert!(restored.get("b1".into()).is_err());
+        assert_eq!(
+            restored.get("98".into()).unwrap(),
+            english_numbers::convert_all_fmt(98)
+        );
+        assert_eq!(restored.list_keys().len(), 99);
+
+        let restored = open(dests[2]);
+        assert_eq!(restored.get("b1".into()).unwrap(), "batched");
+        assert!(restored.get("98".into()).is_err());
+        assert!(restored.get("after".into()).is_err());
+        assert_eq!(restored.list_keys().len(), 100);
+
+        let restored = open(dests[3]);
+        assert_eq!(
+            restored.get("0".into()).unwrap(),
+            english_numbers::convert_all_fmt(0)
+        );
+        assert_eq!(restored.list_keys().len(), 100);
+        // sequence numbers go on from the end of the restored log
+        restored.put("restored".into(), "yes".into()).unwrap();
+        let (_, meta) = restored.get_with_meta("restored".into()).unwrap();
+        assert_eq!(meta.unwrap().seq, bad_seq);
+
+        let restored = open(dests[4]);
+        assert_eq!(restored.get("after".into()).unwrap(), "batch");
+        assert_eq!(restored.list_keys().len(), 101);
+
+        // the source is left as it was
+        assert_eq!(store.get("after".into()).unwrap(), "batch");
+        assert_eq!(store.list_keys().len(), 101);
+
+        drop(store);
+        for dir in [dir].iter().chain(dests.iter()) {
+            fs::remove_dir_all(dir).unwrap();
+        }
+    }
+}
@@ -0,0 +1,229 @@
+/*
+    Point-in-time snapshots:
+    a snapshot copies the index pointers once, and resolves every read through them,
+    so later writes are never seen.
+    The copy is made under the write lock, writes wait for as long as it takes:
+        linear in the number of keys, see `bench_snapshot` in `benches/bench.rs`.
+    Store files never change below the write offset, only merge removes them;
+    while a snapshot is alive the handles of removed files are kept open here,
+    the unlinked files stay readable until the last snapshot is dropped.
+*/
+
+use std::collections::HashMap;
+
+use bytes::Bytes;
+use parking_lot::{Mutex, MutexGuard};
+
+use crate::{
+    blob::blob_files::BlobPtr,
+    definitions::types::ByteVec,
+    errors::{Errors, Result},
+    index::{index_impl::btree::BTreeIndex, iter::KvIteratorOptions, traits::KeyIndex},
+    records::log_record::{LogRecord, LogRecordPtr},
+};
+
+use super::{expiry::now_millis, file_handle::FileHandle, store::Store};
+
+/// Files retired by merge, kept while any snapshot is alive.
+pub struct SnapshotPins {
+    inner: Mutex<PinnedFiles>,
+}
+
+pub(crate) struct PinnedFiles {
+    /// number of live snapshots
+    live: usize,
+    /// file id -> handle of a file removed from the store
+    retired: HashMap<u32, FileHandle>,
+}
+
+impl PinnedFiles {
+    /// Keeps `file` open if a snapshot may still read it, closes it otherwise.
+    pub(crate) fn retire(&mut self, file_id: u32, file: FileHandle) {
+        if self.any_live() {
+            self.retired.insert(file_id, file);
+        }
+    }
+
+    pub(crate) fn any_live(&self) -> bool {
+        self.live > 0
+    }
+}
+
+impl SnapshotPins {
+    pub fn new() -> Self {
+        Self {
+            inner: Mutex::new(PinnedFiles {
+                live: 0,
+                retired: HashMap::new(),
+            }),
+        }
+    }
+
+    pub(crate) fn lock(&self) -> MutexGuard<'_, PinnedFiles> {
+        self.inner.lock()
+    }
+
+    fn pin(&self) {
+        self.inner.lock().live += 1;
+    }
+
+    fn unpin(&self) {
+        let mut inner = self.inner.lock();
+        inner.live -= 1;
+        if inner.live == 0 {
+            inner.retired.clear();
+        }
+    }
+
+    fn read_at(&self, rec_ptr: LogRecordPtr) -> Result<(LogRecord, Option<BlobPtr>)> {
+        let inner = self.inner.lock();
+        let file = inner
+            .retired
+            .get(&rec_ptr.file_id)
+            .ok_or(Errors::StoreFileNotFound {
+                file_id: rec_ptr.file_id,
+            })?;
+        let (record, _, blob, _) = file.read_stored_at(rec_ptr.offset)?;
+        Ok((record, blob))
+    }
+}
+
+impl Default for SnapshotPins {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A frozen view of the store, taken by `Store::snapshot`.
+pub struct Snapshot<'a> {
+    store: &'a Store,
+    /// key -> pointer, as of the snapshot
+    keys: BTreeIndex,
+    /// key -> expiry, as of the snapshot
+    expirations: HashMap<ByteVec, u64>,
+    /// expiry is judged as of this time, in milliseconds since UNIX epoch
+    taken_at: u64,
+}
+
+impl Store {
+    /// Copies every key of the index, blocking writes meanwhile.
+    pub fn snapshot(&self) -> Snapshot<'_> {
+        // pin first, so that no file the copied pointers refer to is closed in between
+        self.snapshot_pins.pin();
+        // a batch updates the index under the write lock, it is either fully seen or not at all
+        let _write_lock = self.write_lock.lock();
+        let keys = BTreeIndex::new();
+        for (key, ptr) in self.index.iter_snapshot().make() {
+            keys.put(key, ptr);
+        }
+
+        Snapshot {
+            store: self,
+            keys,
+            expirations: self.expirations.snapshot(),
+            taken_at: now_millis(),
+        }
+    }
+}
+
+impl Snapshot<'_> {
+    pub fn get(&self, key: Bytes) -> Result<Bytes> {
+        if key.is_empty() {
+            return Err(Errors::KeyIsEmpty);
+        }
+        let rec_ptr = self.keys.get(key.to_vec()).ok_or(Errors::KeyNotFound)?;
+        self.get_at(rec_ptr)
+    }
+
+    /// Value of the record at `rec_ptr`, which must come from this snapshot.
+    pub(crate) fn get_at(&self, rec_ptr: LogRecordPtr) -> Result<Bytes> {
+        let (mut record, blob) = match self.store.get_stored_at(rec_ptr) {
+            // retired by a merge after the snapshot was taken
+            Err(Errors::StoreFileNotFound { .. }) => self.store.snapshot_pins.read_at(rec_ptr)?,
+            res => res?,
+        };
+        // blob files are not removed while a snapshot is alive
+        self.store.resolve_blob(&mut record, blob)?;
+        Store::record_value(record, self.taken_at).ok_or(Errors::KeyNotFound)
+    }
+
+    pub fn list_keys(&self) -> Vec<Bytes> {
+        self.keys
+            .iter_snapshot()
+            .make()
+            .filter(|(key, _)| !self.is_expired(key))
+            .map(|(key, _)| key.into())
+            .collect()
+    }
+
+    pub fn iter_options(&self) -> KvIteratorOptions<'_> {
+        KvIteratorOptions::begin(self.keys.iter_snapshot(), self.store).in_snapshot(self)
+    }
+
+    fn is_expired(&self, key: &[u8]) -> bool {
+        self.expirations
+            .get(key)
+            .is_some_and(|expire_at| *expire_at <= self.taken_at)
+    }
+}
+
+impl Drop for Snapshot<'_> {
+    fn drop(&mut self) {
+        self.store.snapshot_pins.unpin();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::store::utils::TempStore;
+
+    #[test]
+    fn test_snapshot() {
+        let (_raii, store) = TempStore::init(43);
+        for i in 0..300 {
+            let key = format!("{}", i);
+            let val = english_numbers::convert_all_fmt(i);
+            store.put(key.into(), val.into()).unwrap();
+        }
+
+        let snapshot = store.snapshot();
+        for i in 0..100 {
+            store
+                .put(format!("{}", i).into(), "overwritten".into())
+                .unwrap();
+        }
+        for i in 100..200 {
+            store.delete(format!("{}", i).into()).unwrap();
+        }
+        store.put("new".into(), "value".into()).unwrap();
+        // the files the snapshot reads from are replaced
+        store.merge().unwrap();
+
+        assert_eq!(
+            snapshot.get("0".into()).unwrap(),
+            english_numbers::convert_all_fmt(0)
+        );
+        assert_eq!(
+            snapshot.get("150".into()).unwrap(),
+            english_numbers::convert_all_fmt(150)
+        );
+        assert!(snapshot.get("new".into()).is_err());
+        assert_eq!(snapshot.list_keys().len(), 300);
+        let kvs: Vec<_> = snapshot
+            .iter_options()
+            .with_key_prefix(b"2".to_vec())
+            .make()
+            .collect();
+        // "2", "20".."29", "200".."299"
+        assert_eq!(kvs.len(), 1 + 10 + 100);
+        for kv in kvs {
+            let i: i64 = String::from_utf8_lossy(&kv.key).parse().unwrap();
+            assert_eq!(kv.value, english_numbers::convert_all_fmt(i));
+        }
+
+        assert_eq!(store.get("0".into()).unwrap(), "overwritten");
+        assert_eq!(store.list_keys().len(), 201);
+        drop(snapshot);
+        assert!(store.snapshot_pins.lock().retired.is_empty());
+    }
+}
@@ -1,38 +1,113 @@
-use super::file_handle::FileHandle;
+use super::{
+    changes::ChangeFeed,
+    expiry::{now_millis, Expirations},
+    file_handle::{FileHandle, StoredRecord},
+    file_header::{FileHeader, FORMAT_VERSION},
+    group_commit::GroupCommit,
+    read_only::ReadOnlyView,
+    snapshot::SnapshotPins,
+    utils::{format_filename, sync_dir},
+};
 use crate::{
-    batched::{batched_index::BatchedIndex, batched_write::CreateBatch},
+    batched::batched_index::BatchedIndex,
+    blob::blob_files::{BlobFiles, BlobPtr},
     config::config::{BatchedConfig, FileConfig, StoreConfig},
     definitions::{
-        constants::{get_max_prefix_number, MERGE_OK_FILE_NAME, MERGE_STORE_PATH},
+        constants::{get_max_prefix_number, get_prefix_numbers},
         types::KvBytes,
     },
-    errors::{Errors, MergePhase, Result},
-    index::traits::KeyIndex,
-    io::traits::IoType,
-    merge::{self, merge::MergeMetadata},
+    errors::{Errors, Result},
+    hint::hint::{HintFile, HintRecord},
+    index::{
+        index_impl::IndexType,
+        traits::{IndexCheckpoint, KeyIndex},
+    },
+    io::{cipher::RecordCipher, traits::IoType},
+    merge::{
+        merge::MergeManifest,
+        stats::{MergeStats, StatsCheckpoint},
+    },
     propagate_err,
     records::{
-        self,
         log_record::{LogRecord, LogRecordPtr},
+        meta::RecordMeta,
     },
-    store::utils::format_filename,
     storelock::storelock::StoreExclusiveLock,
 };
 use bytes::Bytes;
-use log::error;
+use log::{error, info, warn};
 use parking_lot::{Mutex, RwLock};
 use std::{
     collections::HashMap,
-    fs::{self, File},
-    io::{self, Seek},
+    fs,
     ops::Deref,
     path::PathBuf,
     sync::{
-        atomic::{AtomicU32, AtomicUsize},
+        atomic::{AtomicU32, AtomicU64, AtomicUsize},
         Arc,
     },
 };
 
+/// (legacy files, active file)
+pub(crate) type StoreFiles = (
+    Arc<RwLock<HashMap<u32, FileHandle>>>,
+    Arc<RwLock<FileHandle>>,
+);
+
+/// What a logged record is stamped with, see `records::meta`
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Stamp {
+    /// the next sequence number and the time of the write
+    Next,
+    /// the stamp of a record moved by merge or blob collection, which stays as it was
+    Keep(Option<RecordMeta>),
+}
+
+/// What a store is opened for
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StoreMode {
+    /// reads and writes
+    Primary,
+    /// reads only, files change by what its primary ships, see `replication`
+    Replica,
+    /// reads only, alongside the writer owning the store, see `Store::open_read_only`
+    ReadOnly,
+}
+
+/// State of a replay of the log into the index, carried from one file or message to the next
+pub(crate) struct Replay {
+    pub(crate) cur_batch_id: Option<usize>,
+    pub(crate) newest_batch_id: usize,
+    pub(crate) batched_index: BatchedIndex,
+    /// while opening only: size of every live record replayed, see `Store::replay_put`
+    pub(crate) sizes: Option<HashMap<LogRecordPtr, u64>>,
+}
+
+impl Replay {
+    pub(crate) fn new() -> Self {
+        Self {
+            cur_batch_id: None,
+            newest_batch_id: 0,
+            batched_index: BatchedIndex::new(),
+            sizes: None,
+        }
+    }
+
+    /// Replay of the whole log on open, which keeps the size of live records
+    pub(crate) fn on_open() -> Self {
+        Self {
+            sizes: Some(HashMap::new()),
+            ..Self::new()
+        }
+    }
+
+    /// Drop the batch not committed yet
+    pub(crate) fn reset(&mut self) {
+        self.cur_batch_id = None;
+        self.batched_index.reset();
+    }
+}
+
 pub struct Store {
     /// readonly
     pub(crate) store_config: StoreConfig,
@@ -40,6 +115,8 @@ pub struct Store {
     pub(crate) file_config: FileConfig,
     /// readonly
     pub(crate) batched_config: BatchedConfig,
+    /// readonly
+    pub(crate) mode: StoreMode,
 
     /// k-vptr in memory
     pub(crate) index: Box<dyn KeyIndex>,
@@ -49,6 +126,19 @@ pub struct Store {
     pub(crate) active_file_id: AtomicU32,
     /// file id -> file handle
     pub(crate) legacy_files: Arc<RwLock<HashMap<u32, FileHandle>>>,
+    /// large values, see `blob::blob_files`
+    pub(crate) blobs: BlobFiles,
+
+    /// Held by writers from logging a record until the index is updated,
+    ///     so that merge can remap the index without losing a write.
+    pub(crate) write_lock: Mutex<()>,
+    /// durability barrier shared by writers, with `sync_every_write`
+    pub(crate) group_commit: GroupCommit,
+    /// sequence number of the next record written, see `records::meta`;
+    ///     only changes under the write lock of the active file
+    pub(crate) seq: AtomicU64,
+    /// events of writes on their way to subscribers, see `store::changes`
+    pub(crate) changes: ChangeFeed,
 
     /// ############ batch related ############
     pub(crate) batch_commit_lock: Mutex<()>,
@@ -56,18 +146,56 @@ pub struct Store {
 
     // merge
     pub(crate) merge_lock: Mutex<()>,
+    pub(crate) merge_stats: MergeStats,
+    /// see `merge_generation`, only changes under `merge_lock`
+    pub(crate) merge_generation: AtomicU64,
+
+    /// key -> expiry of keys written with a ttl
+    pub(crate) expirations: Expirations,
+
+    /// files retired by merge but still referred to by snapshots
+    pub(crate) snapshot_pins: SnapshotPins,
+
+    /// seals the records of every file, if the store is encrypted
+    pub(crate) cipher: Option<Arc<RecordCipher>>,
 
     // unique ownership of directory
-    /// Used for RAII management ot file lock, not explicitly
-    pub(crate) store_lock: StoreExclusiveLock,
+    /// Used for RAII management ot file lock, not explicitly;
+    ///     a read-only store takes none
+    pub(crate) store_lock: Option<StoreExclusiveLock>,
+    /// read-only stores only: how far the index follows the store files
+    pub(crate) read_only: Option<Mutex<ReadOnlyView>>,
 }
 
 impl Drop for Store {
     fn drop(&mut self) {
-        self.active_file
-            .write()
+        // nothing was written, and a persistent index belongs to the writer
+        if self.mode == StoreMode::ReadOnly {
+            return;
+        }
+        let active_file = self.active_file.write();
+        self.blobs
             .sync()
-            .expect("Disk synchronization failed: Data failed to write to disk!")
+            .and_then(|_| active_file.sync())
+            .expect("Disk synchronization failed: Data failed to write to disk!");
+        // the writes since the last sync are durable now
+        self.changes.publish(self.group_commit.ticket());
+
+        // no one else holds the store, so every write has reached the index
+        self.index.mark_applied(IndexCheckpoint {
+            ptr: LogRecordPtr {
+                file_id: self
+                    .active_file_id
+                    .load(std::sync::atomic::Ordering::Relaxed),
+                offset: active_file.get_write_offset(),
+            },
+            next_batch_id: self.batch_id.load(std::sync::atomic::Ordering::Relaxed),
+            next_seq: self.seq.load(std::sync::atomic::Ordering::Relaxed),
+            stats: Some(StatsCheckpoint {
+                files: self.merge_stats.snapshot(),
+                expirations: self.expirations.snapshot(),
+            }),
+        });
     }
 }
 
@@ -76,6 +204,35 @@ impl Store {
         store_config: StoreConfig,
         file_config: FileConfig,
         batched_config: BatchedConfig,
+    ) -> Result<Self> {
+        Self::open_with(
+            store_config,
+            file_config,
+            batched_config,
+            StoreMode::Primary,
+        )
+    }
+
+    /// Open the store at `store_config.dir` as a replica, to follow a primary, see `replication`;
+    ///     writes, merge and blob collection fail with `ReplicaIsReadOnly`.
+    pub fn open_replica(
+        store_config: StoreConfig,
+        file_config: FileConfig,
+        batched_config: BatchedConfig,
+    ) -> Result<Self> {
+        Self::open_with(
+            store_config,
+            file_config,
+            batched_config,
+            StoreMode::Replica,
+        )
+    }
+
+    fn open_with(
+        store_config: StoreConfig,
+        file_config: FileConfig,
+        batched_config: BatchedConfig,
+        mode: StoreMode,
     ) -> Result<Self> {
         // create dir if exist
         let dir = store_config.dir.clone();
@@ -92,12 +249,12 @@ impl Store {
         }?;
 
         // init
-        let merge_finalize = Self::merge_finalize(store_config.dir.clone());
-        match merge_finalize {
-            Ok(_) => {}
-            Err(Errors::MergeNotFound) => {}
-            Err(e) => return Err(e),
-        }
+        // finish or drop a merge interrupted by a crash
+        Self::merge_recover(store_config.dir.clone())?;
+        let merge_generation = MergeManifest::generation_in(dir.clone())?;
+        let cipher = store_config.encryption.open_cipher(dir.clone())?;
+        let blobs = BlobFiles::open(dir.clone(), store_config.blob, cipher.clone())?;
+        let torn_file_id = Self::drop_torn_active_file(dir.clone())?;
 
         // 1. get all .store files, check biggest, check if corrupted;
         let active_file_id = get_max_prefix_number(dir.clone())?;
@@ -105,13 +262,14 @@ impl Store {
         match active_file_id {
             // new instance
             None => {
+                // a persisted index without store files is left over from a removed store
+                store_config.index_type.discard_index(dir.clone());
                 let active_file_id = 0;
                 let legacy_files = Arc::new(RwLock::new(HashMap::new()));
-                let active_file = Arc::new(RwLock::new(FileHandle::create(
-                    dir.clone(),
-                    active_file_id,
-                    file_config,
-                )?));
+                let active_file = Arc::new(RwLock::new(
+                    FileHandle::create(dir.clone(), active_file_id, file_config)?
+                        .with_cipher(cipher.clone()),
+                ));
 
                 let active_file_id = AtomicU32::new(active_file_id);
                 let store = Self {
@@ -121,13 +279,25 @@ impl Store {
                     store_config,
                     file_config,
                     batched_config,
+                    mode,
                     active_file,
                     active_file_id,
                     legacy_files,
+                    blobs,
+                    write_lock: Mutex::new(()),
+                    group_commit: GroupCommit::new(),
+                    seq: 0.into(),
+                    changes: ChangeFeed::new(),
                     batch_commit_lock: Mutex::new(()),
                     batch_id: 0.into(),
                     merge_lock: Mutex::new(()),
-                    store_lock,
+                    merge_stats: MergeStats::new(),
+                    merge_generation: merge_generation.into(),
+                    expirations: Expirations::new(),
+                    snapshot_pins: SnapshotPins::new(),
+                    cipher,
+                    store_lock: Some(store_lock),
+                    read_only: None,
                 };
                 // does not need to build index
 
@@ -143,7 +313,7 @@ impl Store {
                 // let (legacy_files, active_file) =
                 //     Self::fetch_files(dir.clone(), active_file_id, file_config)?;
                 let (legacy_files, active_file) =
-                    Self::fetch_files_mem_mapped(dir.clone(), active_file_id, file_config)?;
+                    Self::fetch_files_mem_mapped(dir.clone(), file_config, cipher.clone())?;
 
                 // todo!("Given all files, build index")
 
@@ -156,21 +326,43 @@ impl Store {
                     store_config,
                     file_config,
                     batched_config,
+                    mode,
                     active_file,
                     active_file_id: active_file_id_atomic,
                     legacy_files,
+                    blobs,
+                    write_lock: Mutex::new(()),
+                    group_commit: GroupCommit::new(),
+                    seq: 0.into(),
+                    changes: ChangeFeed::new(),
                     batch_commit_lock: Mutex::new(()),
                     batch_id: 0.into(),
                     merge_lock: Mutex::new(()),
-                    store_lock,
+                    merge_stats: MergeStats::new(),
+                    merge_generation: merge_generation.into(),
+                    expirations: Expirations::new(),
+                    snapshot_pins: SnapshotPins::new(),
+                    cipher,
+                    store_lock: Some(store_lock),
+                    read_only: None,
                 };
 
                 // 3. load index.
                 store.build_index()?;
+                let valid_end = store.active_file.read().get_write_offset();
 
                 // 4. load file-based storage
                 (store.legacy_files, store.active_file) =
-                    Self::fetch_files(dir.clone(), active_file_id, file_config)?;
+                    Self::fetch_files(dir.clone(), file_config, store.cipher.clone())?;
+                store.truncate_torn_tail(valid_end)?;
+                // the files of a replica are those of its primary, it creates none of its own
+                if mode == StoreMode::Primary {
+                    // created again under its id, now that the store knows its sequence number
+                    if let Some(file_id) = torn_file_id {
+                        store.rotate(&mut store.active_file.write(), file_id)?;
+                    }
+                    store.upgrade_active_file()?;
+                }
 
                 // return
                 Ok(store)
@@ -179,23 +371,58 @@ impl Store {
     }
 
     pub fn sync(&self) -> Result<()> {
-        self.active_file.write().sync()
+        // blobs first, the records referring to them must not be on disk without them
+        self.blobs.sync()?;
+        let active_file = self.active_file.write();
+        let ticket = self.group_commit.ticket();
+        active_file.sync()?;
+        drop(active_file);
+        self.changes.publish(ticket);
+        Ok(())
+    }
+
+    pub fn mode(&self) -> StoreMode {
+        self.mode
+    }
+
+    /// Fails for a store not opened for writes.
+    pub(crate) fn check_writable(&self) -> Result<()> {
+        match self.mode {
+            StoreMode::Primary => Ok(()),
+            StoreMode::Replica => Err(Errors::ReplicaIsReadOnly),
+            StoreMode::ReadOnly => Err(Errors::ReadOnly),
+        }
     }
 }
 
 // basic operations
 impl Store {
     pub fn delete(&self, key: Bytes) -> Result<LogRecordPtr> {
+        self.check_writable()?;
         if key.is_empty() {
             return Err(Errors::KeyIsEmpty);
         }
+        let (record_ptr, ticket) = {
+            let _write_lock = self.write_lock.lock();
+            (self.delete_locked(key)?, self.group_commit.ticket())
+        };
+        self.wait_durable(ticket)?;
+        Ok(record_ptr)
+    }
+
+    /// `delete` for a caller already holding `write_lock`;
+    ///     the caller waits for durability once the lock is released.
+    pub(crate) fn delete_locked(&self, key: Bytes) -> Result<LogRecordPtr> {
         if self.index.get(key.to_vec()).is_none() {
             return Err(Errors::KeyNotFound);
         }
 
         let mut record = LogRecord::Tomb { key: key.to_vec() };
         let record_ptr = self.log(&mut record)?;
-        self.index.delete(key.to_vec());
+        self.expirations.set(key.to_vec(), None);
+        if let Some(old_ptr) = self.index.delete(key.to_vec()) {
+            self.mark_stale(old_ptr);
+        }
 
         Ok(record_ptr)
     }
@@ -205,15 +432,27 @@ impl Store {
             return Err(Errors::KeyIsEmpty);
         }
 
+        let (record_ptr, ticket) = {
+            let _write_lock = self.write_lock.lock();
+            (self.put_locked(key, value)?, self.group_commit.ticket())
+        };
+        self.wait_durable(ticket)?;
+        Ok(record_ptr)
+    }
+
+    /// `put` for a caller already holding `write_lock`;
+    ///     the caller waits for durability once the lock is released.
+    pub(crate) fn put_locked(&self, key: Bytes, value: Bytes) -> Result<LogRecordPtr> {
         let mut record = LogRecord::Data {
             key: key.to_vec(),
             value: value.to_vec(),
         };
-
         let record_ptr = self.log(&mut record)?;
 
-        // leave option as it is; it just returns old value.
-        self.index.put(key.to_vec(), record_ptr);
+        self.expirations.set(key.to_vec(), None);
+        if let Some(old_ptr) = self.index.put(key.to_vec(), record_ptr) {
+            self.mark_stale(old_ptr);
+        }
 
         Ok(record_ptr)
     }
@@ -268,27 +507,100 @@ impl Store {
         if key.is_empty() {
             return Err(Errors::KeyIsEmpty);
         }
+        let (_, value) = self.get_versioned(&key)?;
+        value.ok_or(Errors::KeyNotFound)
+    }
+
+    /// Same as `get`, along with the stamp of the write, see `records::meta`;
+    ///     the stamp is `None` for a value written before records were stamped.
+    pub fn get_with_meta(&self, key: Bytes) -> Result<(Bytes, Option<RecordMeta>)> {
+        if key.is_empty() {
+            return Err(Errors::KeyIsEmpty);
+        }
+        let (_, record, meta) = self.get_current(&key)?.ok_or(Errors::KeyNotFound)?;
+        let value = Self::record_value(record, now_millis()).ok_or(Errors::KeyNotFound)?;
+        Ok((value, meta))
+    }
 
+    /// The value of `key` along with the index pointer it was read at;
+    ///     the pointer is `None` for a key not in the index,
+    ///     the value is `None` for a key not in the index or expired.
+    pub(crate) fn get_versioned(
+        &self,
+        key: &Bytes,
+    ) -> Result<(Option<LogRecordPtr>, Option<Bytes>)> {
+        match self.get_current(key)? {
+            Some((rec_ptr, record, _)) => {
+                Ok((Some(rec_ptr), Self::record_value(record, now_millis())))
+            }
+            None => Ok((None, None)),
+        }
+    }
+
+    /// The record the index points to for `key`, along with where it was read and its stamp.
+    fn get_current(
+        &self,
+        key: &Bytes,
+    ) -> Result<Option<(LogRecordPtr, LogRecord, Option<RecordMeta>)>> {
         // get log record from files
-        let rec_ptr = self.index.get(key.to_vec()).ok_or(Errors::KeyNotFound)?;
-        let record = self.get_at(rec_ptr)?;
+        let mut rec_ptr = match self.index.get(key.to_vec()) {
+            Some(rec_ptr) => rec_ptr,
+            None => return Ok(None),
+        };
+        let (record, meta) = loop {
+            match self.get_at(rec_ptr) {
+                // merge or blob collection removed the file after moving the key elsewhere
+                Err(e @ (Errors::StoreFileNotFound { .. } | Errors::BlobFileNotFound { .. })) => {
+                    let cur_ptr = match self.index.get(key.to_vec()) {
+                        Some(cur_ptr) => cur_ptr,
+                        None => return Ok(None),
+                    };
+                    if cur_ptr == rec_ptr {
+                        return Err(e);
+                    }
+                    rec_ptr = cur_ptr;
+                }
+                res => break res?,
+            }
+        };
 
-        // verify log record
+        Ok(Some((rec_ptr, record, meta)))
+    }
+
+    /// Value of a record the index points to, `None` if it is a tomb or expired at `now`.
+    pub(crate) fn record_value(record: LogRecord, now: u64) -> Option<Bytes> {
         match record {
-            LogRecord::Data { key: _, value } => Ok(Bytes::from(value)),
-            LogRecord::Tomb { key: _ } => Err(Errors::KeyNotFound),
+            LogRecord::Data { key: _, value } => Some(Bytes::from(value)),
+            LogRecord::Tomb { key: _ } => None,
             LogRecord::DataInBatch {
                 batch_id: _,
                 key: _,
                 value,
-            } => Ok(Bytes::from(value)),
+            } => Some(Bytes::from(value)),
             LogRecord::TombInBatch {
                 batch_id: _,
                 key: _,
-            } => Err(Errors::KeyNotFound),
+            } => None,
             LogRecord::BatchDone { batch_id: _ } => {
                 panic!("BatchDone variant is not a data record!")
             }
+            LogRecord::Expiring {
+                key: _,
+                value,
+                expire_at,
+            }
+            | LogRecord::ExpiringInBatch {
+                batch_id: _,
+                key: _,
+                value,
+                expire_at,
+            } => {
+                if expire_at <= now {
+                    None
+                } else {
+                    Some(Bytes::from(value))
+                }
+            }
         }
     }
 }
@@ -296,9 +608,11 @@ impl Store {
 // advanced operations
 impl Store {
     pub fn list_keys(&self) -> Vec<Bytes> {
+        let now = now_millis();
         self.index
             .iter_snapshot()
             .make()
+            .filter(|(key, _)| !self.expirations.is_expired(key, now))
             .map(|(key, _)| key.into())
             .collect()
     }
@@ -318,71 +632,227 @@ impl Store {
 // private: op utils
 impl Store {
     pub(crate) fn log(&self, record: &mut LogRecord) -> Result<LogRecordPtr> {
+        // every write goes through here
+        self.check_writable()?;
+        let separated = match (record.key(), record.value()) {
+            (Some(key), Some(value)) if self.blobs.separates(value) => Some(self.blobs.write(
+                key,
+                value,
+                &self.store_config.compression,
+            )?),
+            _ => None,
+        };
+        let res = match separated {
+            None => self.log_stamped(record, Stamp::Next),
+            Some(blob) => {
+                // log with the blob reference swapped in, then give the value back
+                let value = record
+                    .value_mut()
+                    .expect("separated a record without value");
+                let value = std::mem::replace(value, blob.encode());
+                let res = self.log_blob_ref(record, Stamp::Next);
+                *record
+                    .value_mut()
+                    .expect("separated a record without value") = value;
+                res
+            }
+        };
+        if res.is_ok() {
+            // the write lock keeps the sequence number of the record and its ticket
+            self.changes.on_logged(
+                record,
+                self.seq.load(std::sync::atomic::Ordering::Relaxed) - 1,
+                self.group_commit.ticket(),
+            );
+        }
+        res
+    }
+
+    /// Same as `log`, without separating the value into a blob file, stamped as `stamp` says.
+    pub(crate) fn log_stamped(&self, record: &mut LogRecord, stamp: Stamp) -> Result<LogRecordPtr> {
+        self.log_with(record, stamp, |file, record, meta| {
+            file.try_append_with(record, &self.store_config.compression, meta)
+        })
+    }
+
+    /// Log a record whose value is an encoded `BlobPtr`.
+    pub(crate) fn log_blob_ref(
+        &self,
+        record: &mut LogRecord,
+        stamp: Stamp,
+    ) -> Result<LogRecordPtr> {
+        self.log_with(record, stamp, |file, record, meta| {
+            file.try_append_blob_ref(record, meta)
+        })
+    }
+
+    fn log_with(
+        &self,
+        record: &mut LogRecord,
+        stamp: Stamp,
+        append: impl Fn(&FileHandle, &mut LogRecord, Option<RecordMeta>) -> Result<usize>,
+    ) -> Result<LogRecordPtr> {
         let mut active_file = self.active_file.write();
+        // sequence numbers follow the order of the log
+        let meta = match stamp {
+            Stamp::Next => Some(RecordMeta {
+                seq: self.seq.load(std::sync::atomic::Ordering::Relaxed),
+                timestamp: now_millis(),
+            }),
+            Stamp::Keep(meta) => meta,
+        };
         // track offset before write
         let mut offset = active_file.get_write_offset();
-        while let Err(Errors::BufferOverflow) = active_file.try_append(record) {
-            active_file.sync()?;
-
-            // move current file to older file hashmap
-            let active_file_id = self
+        let size = loop {
+            match append(&active_file, record, meta) {
+                Ok(size) => break size as u64,
+                Err(Errors::BufferOverflow) => {}
+                Err(e) => return Err(e),
+            }
+            let next_file_id = self
                 .active_file_id
-                .load(std::sync::atomic::Ordering::Relaxed);
-            let cur_handle = FileHandle::open(
-                self.store_config.dir.clone(),
-                active_file_id,
-                self.file_config,
-                IoType::File,
-            )?;
-            self.legacy_files.write().insert(active_file_id, cur_handle);
-
-            // create new file
-            let new_file = self.new_file()?;
-            // this line REPLACES the content in `self.active_file` with the newly created one
-            *active_file = new_file;
+                .load(std::sync::atomic::Ordering::Relaxed)
+                + 1;
+            self.rotate(&mut active_file, next_file_id)?;
             offset = active_file.get_write_offset();
+        };
+        let file_id = self
+            .active_file_id
+            .load(std::sync::atomic::Ordering::Relaxed);
+        // only data records can be referred to by the index
+        let is_data = matches!(
+            record,
+            LogRecord::Data { .. }
+                | LogRecord::DataInBatch { .. }
+                | LogRecord::Expiring { .. }
+                | LogRecord::ExpiringInBatch { .. }
+        );
+        self.merge_stats.on_append(file_id, size, !is_data);
+        if let Stamp::Next = stamp {
+            self.seq.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
         }
+        // synced by the writer through `wait_durable`, along with concurrent writes
+        self.group_commit.on_append();
 
-        if self.store_config.sync_every_write {
-            active_file.sync()?;
-        }
+        Ok(LogRecordPtr { file_id, offset })
+    }
+
+    /// Seal the active file and continue writing in a new file `next_file_id`.
+    pub(crate) fn rotate(&self, active_file: &mut FileHandle, next_file_id: u32) -> Result<()> {
+        let header = FileHeader::new(next_file_id)
+            .with_first_seq(self.seq.load(std::sync::atomic::Ordering::Relaxed));
+        self.rotate_to(active_file, header)
+    }
+
+    /// Same as `rotate`, continuing in a new file of `header`.
+    pub(crate) fn rotate_to(&self, active_file: &mut FileHandle, header: FileHeader) -> Result<()> {
+        active_file.sync()?;
+
+        // move current file to older file hashmap
+        let active_file_id = self
+            .active_file_id
+            .load(std::sync::atomic::Ordering::Relaxed);
+        // the file is sealed from now on
+        HintFile::write_for(self.store_config.dir.clone(), active_file_id, active_file);
+        let cur_handle = FileHandle::open(
+            self.store_config.dir.clone(),
+            active_file_id,
+            self.file_config,
+            IoType::File,
+        )?
+        .with_cipher(self.cipher.clone());
+        self.legacy_files.write().insert(active_file_id, cur_handle);
+
+        // create new file
+        let new_file = self.new_file(header)?;
+        // this line REPLACES the content in `self.active_file` with the newly created one
+        *active_file = new_file;
+        Ok(())
+    }
+
+    /// The record at `rec_ptr`, with its value read from its blob file if separated,
+    ///     along with its stamp.
+    pub(crate) fn get_at(&self, rec_ptr: LogRecordPtr) -> Result<(LogRecord, Option<RecordMeta>)> {
+        let (mut record, blob, meta) = self.get_stamped_at(rec_ptr)?;
+        self.resolve_blob(&mut record, blob)?;
+        Ok((record, meta))
+    }
+
+    /// The record at `rec_ptr` as stored, along with the blob its value was separated into.
+    pub(crate) fn get_stored_at(
+        &self,
+        rec_ptr: LogRecordPtr,
+    ) -> Result<(LogRecord, Option<BlobPtr>)> {
+        let (record, blob, _) = self.get_stamped_at(rec_ptr)?;
+        Ok((record, blob))
+    }
+
+    /// Same as `get_stored_at`, along with the stamp of the record.
+    pub(crate) fn get_stamped_at(
+        &self,
+        rec_ptr: LogRecordPtr,
+    ) -> Result<(LogRecord, Option<BlobPtr>, Option<RecordMeta>)> {
+        let (record, _, blob, meta) = self.read_stored(rec_ptr)?;
+        Ok((record, blob, meta))
+    }
 
-        Ok(LogRecordPtr {
-            file_id: self
+    /// The record at `rec_ptr` as stored, along with its size.
+    pub(crate) fn read_stored(&self, rec_ptr: LogRecordPtr) -> Result<StoredRecord> {
+        // the active file id only changes under the write lock of the active file
+        let active_file = self.active_file.read();
+        if rec_ptr.file_id
+            == self
                 .active_file_id
-                .load(std::sync::atomic::Ordering::Relaxed),
-            offset,
-        })
+                .load(std::sync::atomic::Ordering::Relaxed)
+        {
+            active_file.read_stored_at(rec_ptr.offset)
+        } else {
+            drop(active_file);
+            let files = self.legacy_files.read();
+            let file = files
+                .get(&rec_ptr.file_id)
+                .ok_or(Errors::StoreFileNotFound {
+                    file_id: rec_ptr.file_id,
+                })?;
+            file.read_stored_at(rec_ptr.offset)
+        }
     }
 
-    pub(crate) fn get_at(&self, rec_ptr: LogRecordPtr) -> Result<LogRecord> {
+    /// Size of the record at `rec_ptr` without reading its value.
+    pub(crate) fn record_size(&self, rec_ptr: LogRecordPtr) -> Result<u64> {
+        Ok(self.record_meta(rec_ptr)?.0)
+    }
+
+    /// (size, expiry) of the record at `rec_ptr` without reading its value.
+    pub(crate) fn record_meta(&self, rec_ptr: LogRecordPtr) -> Result<(u64, Option<u64>)> {
+        let active_file = self.active_file.read();
         if rec_ptr.file_id
             == self
                 .active_file_id
                 .load(std::sync::atomic::Ordering::Relaxed)
         {
-            let file = self.active_file.read();
-            Ok(file.read_at_offset(rec_ptr.offset)?.0)
+            active_file.record_meta_at(rec_ptr.offset)
         } else {
+            drop(active_file);
             let files = self.legacy_files.read();
             let file = files
                 .get(&rec_ptr.file_id)
                 .ok_or(Errors::StoreFileNotFound {
                     file_id: rec_ptr.file_id,
                 })?;
-            Ok(file.read_at_offset(rec_ptr.offset)?.0)
+            file.record_meta_at(rec_ptr.offset)
         }
     }
 
-    fn new_file(&self) -> Result<FileHandle> {
+    fn new_file(&self, header: FileHeader) -> Result<FileHandle> {
         self.active_file_id
-            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-        Ok(FileHandle::create(
-            self.store_config.dir.clone(),
-            self.active_file_id
-                .load(std::sync::atomic::Ordering::Relaxed),
+            .store(header.file_id, std::sync::atomic::Ordering::Relaxed);
+        Ok(FileHandle::create_at(
+            format_filename(self.store_config.dir.clone(), header.file_id),
+            header,
             self.file_config,
-        )?)
+        )?
+        .with_cipher(self.cipher.clone()))
     }
 }
 
@@ -390,48 +860,135 @@ impl Store {
 impl Store {
     fn build_index(&mut self) -> Result<()> {
         // for use of the store's batch id
-        let mut newest_batch_id = 0;
-        let mut batch_id: Option<usize> = None;
-        let mut batched_index = BatchedIndex::new();
+        let mut replay = Replay::on_open();
         // let mut batched_write = self.new_batched(self.batched_config);
 
-        // build on legacy files
-        let legacy_files = self.legacy_files.read();
         let active_file_id = self
             .active_file_id
             .load(std::sync::atomic::Ordering::Relaxed);
 
+        // a persistent index only needs what was written after it was last saved
+        let checkpoint = self.index_checkpoint(active_file_id);
+        // only a clean close saves it again: after a crash, the index may hold
+        //     entries for records that never reached the disk, or were cut off as torn
+        self.index.clear_applied();
+        let (start_file_id, start_offset) = match &checkpoint {
+            Some(checkpoint) => {
+                info!(
+                    "Resuming index from file {} offset {}",
+                    checkpoint.ptr.file_id, checkpoint.ptr.offset
+                );
+                if let Some(stats) = &checkpoint.stats {
+                    self.merge_stats.reset(stats.files.clone());
+                    self.expirations.reset(stats.expirations.clone());
+                }
+                (checkpoint.ptr.file_id, checkpoint.ptr.offset)
+            }
+            None => (0, 0),
+        };
+
+        // build on legacy files
+        let legacy_files = self.legacy_files.read();
+
         /* build index start */
-        // iterate thru legacy files
-        for file_id in 0..active_file_id {
+        // iterate thru legacy files, in order of file id
+        let mut legacy_file_ids: Vec<u32> = legacy_files
+            .keys()
+            .copied()
+            .filter(|file_id| *file_id >= start_file_id)
+            .collect();
+        legacy_file_ids.sort_unstable();
+        for file_id in legacy_file_ids {
             let file = legacy_files
                 .get(&file_id)
                 .expect(format!("File {} not found, corrupted!", file_id).as_str());
+            let offset = if file_id == start_file_id {
+                start_offset
+            } else {
+                0
+            };
 
-            let _offset = self.update_index_on_file(
-                &file,
+            // sealed files: prefer the hint file, fall back to a full scan
+            match HintFile::load(
+                self.store_config.dir.clone(),
                 file_id,
-                &mut batch_id,
-                &mut newest_batch_id,
-                &mut batched_index,
-            )?;
+                self.cipher.as_deref(),
+            ) {
+                Some(hints) => {
+                    let hints = hints.into_iter().filter(|hint| hint.offset() >= offset);
+                    let _offset = self.update_index_on_hints(hints.collect(), file_id, &mut replay);
+                }
+                None => {
+                    let _offset =
+                        self.update_index_on_file_from(&file, file_id, offset, &mut replay)?;
+                }
+            }
         }
 
         // build on active file
         let active_file = self.active_file.write();
-        let _offset = self.update_index_on_file(
+        let offset = if active_file_id == start_file_id {
+            start_offset
+        } else {
+            0
+        };
+        // the newest file bounds every sequence number handed out before it was created
+        let mut next_seq = active_file.header().first_seq;
+        let valid_end = self.update_index_on_active_file(
             &active_file,
             active_file_id,
-            &mut batch_id,
-            &mut newest_batch_id,
-            &mut batched_index,
-        )?;
+            offset,
+            &mut replay,
+            &mut next_seq,
+        );
+        // writes go after the last valid record, the torn tail is cut once the file is writable
+        active_file.set_write_offset(valid_end);
         /* build index end */
 
-        self.batch_id = (newest_batch_id + 1).into();
+        let next_batch_id = checkpoint
+            .as_ref()
+            .map_or(0, |checkpoint| checkpoint.next_batch_id);
+        self.batch_id = (replay.newest_batch_id + 1).max(next_batch_id).into();
+        // records of the active file before the checkpoint were not read again
+        let checkpoint_seq = checkpoint.map_or(0, |checkpoint| checkpoint.next_seq);
+        self.seq = next_seq.max(checkpoint_seq).into();
         Ok(())
     }
 
+    /// Returns the checkpoint of a persistent index if it still matches the store files;
+    ///     a persistent index without a valid checkpoint is discarded and rebuilt from scratch.
+    fn index_checkpoint(&mut self, active_file_id: u32) -> Option<IndexCheckpoint> {
+        if let Some(checkpoint) = self.index.applied_until() {
+            let file_size = if checkpoint.ptr.file_id == active_file_id {
+                Some(self.active_file.read().size())
+            } else {
+                self.legacy_files
+                    .read()
+                    .get(&checkpoint.ptr.file_id)
+                    .map(|file| file.size())
+            };
+            match file_size {
+                // merge statistics as of the checkpoint are not counted again
+                Some(size) if checkpoint.ptr.offset <= size && checkpoint.stats.is_some() => {
+                    return Some(checkpoint);
+                }
+                _ => warn!(
+                    "Index checkpoint {:?} does not match store files, rebuilding index.",
+                    checkpoint
+                ),
+            }
+        }
+
+        // left by a crash, or by a store never closed yet: nothing in it is known to be on disk
+        let dir = self.store_config.dir.clone();
+        let index_type = self.store_config.index_type;
+        // release the stale index before removing it
+        self.index = IndexType::BTree.create_index(dir.clone());
+        index_type.discard_index(dir.clone());
+        self.index = index_type.create_index(dir);
+        None
+    }
+
     /*
     fn build_index(&mut self) -> Result<()> {
         // build on legacy files
@@ -509,63 +1066,57 @@ impl Store {
      */
 }
 impl Store {
-    fn fetch_files(
+    pub(crate) fn fetch_files(
         dir: PathBuf,
-        active_file_id: u32,
         file_config: FileConfig,
-    ) -> Result<(
-        Arc<RwLock<HashMap<u32, FileHandle>>>,
-        Arc<RwLock<FileHandle>>,
-    )> {
-        let legacy_files = Arc::new(RwLock::new(HashMap::new()));
-        {
-            let mut legacy_files = legacy_files.write();
-            for file_id in 0..active_file_id {
-                let legacy_file =
-                    FileHandle::open(dir.clone(), file_id, file_config, IoType::File)?;
-                legacy_files.insert(file_id, legacy_file);
-            }
-        }
-        let active_file = Arc::new(RwLock::new(FileHandle::open(
-            dir.clone(),
-            active_file_id,
-            file_config,
-            IoType::File,
-        )?));
+        cipher: Option<Arc<RecordCipher>>,
+    ) -> Result<StoreFiles> {
+        Self::fetch_files_with(dir, file_config, IoType::File, cipher)
+    }
 
-        // scope to avoid borrow check
-        {
-            let active_file_write = active_file.write();
-            let active_file_size = active_file_write.size();
-            active_file_write.set_write_offset(active_file_size);
-        }
+    fn fetch_files_mem_mapped(
+        dir: PathBuf,
+        file_config: FileConfig,
+        cipher: Option<Arc<RecordCipher>>,
+    ) -> Result<StoreFiles> {
+        Self::fetch_files_with(dir, file_config, IoType::MemMapped, cipher)
+    }
 
-        Ok((legacy_files, active_file))
+    fn fetch_files_with(
+        dir: PathBuf,
+        file_config: FileConfig,
+        io_type: IoType,
+        cipher: Option<Arc<RecordCipher>>,
+    ) -> Result<StoreFiles> {
+        let file_ids = get_prefix_numbers(dir.clone())?;
+        Self::open_files(dir, file_ids, file_config, io_type, cipher)
     }
 
-    fn fetch_files_mem_mapped(
+    /// Of the files `file_ids`, the newest is the active one, all others are legacy files.
+    pub(crate) fn open_files(
         dir: PathBuf,
-        active_file_id: u32,
+        mut file_ids: Vec<u32>,
         file_config: FileConfig,
-    ) -> Result<(
-        Arc<RwLock<HashMap<u32, FileHandle>>>,
-        Arc<RwLock<FileHandle>>,
-    )> {
+        io_type: IoType,
+        cipher: Option<Arc<RecordCipher>>,
+    ) -> Result<StoreFiles> {
+        let active_file_id = file_ids
+            .pop()
+            .expect("Should not fetch files of an empty store!");
+
         let legacy_files = Arc::new(RwLock::new(HashMap::new()));
         {
             let mut legacy_files = legacy_files.write();
-            for file_id in 0..active_file_id {
-                let legacy_file =
-                    FileHandle::open(dir.clone(), file_id, file_config, IoType::MemMapped)?;
+            for file_id in file_ids {
+                let legacy_file = FileHandle::open(dir.clone(), file_id, file_config, io_type)?
+                    .with_cipher(cipher.clone());
                 legacy_files.insert(file_id, legacy_file);
             }
         }
-        let active_file = Arc::new(RwLock::new(FileHandle::open(
-            dir.clone(),
-            active_file_id,
-            file_config,
-            IoType::MemMapped,
-        )?));
+        let active_file = Arc::new(RwLock::new(
+            FileHandle::open(dir.clone(), active_file_id, file_config, io_type)?
+                .with_cipher(cipher),
+        ));
 
         // scope to avoid borrow check
         {
@@ -586,7 +1137,27 @@ impl Store {
         newest_batch_id: &mut usize, // this tracks the store's batch id
         batched_index: &mut BatchedIndex, // this maintains the ongoing batch
     ) -> Result<u64> {
-        let mut offset = 0;
+        let mut replay = Replay {
+            cur_batch_id: *cur_batch_id,
+            newest_batch_id: *newest_batch_id,
+            batched_index: std::mem::replace(batched_index, BatchedIndex::new()),
+            sizes: None,
+        };
+        let res = self.update_index_on_file_from(file, file_id, 0, &mut replay);
+        *cur_batch_id = replay.cur_batch_id;
+        *newest_batch_id = replay.newest_batch_id;
+        *batched_index = replay.batched_index;
+        res
+    }
+
+    /// Same as `update_index_on_file`, starting at `offset` instead of the beginning.
+    pub(crate) fn update_index_on_file_from(
+        &self,
+        file: &impl Deref<Target = FileHandle>,
+        file_id: u32,
+        mut offset: u64,
+        replay: &mut Replay,
+    ) -> Result<u64> {
         loop {
             // read a record
             let record_result = file.read_at_offset(offset);
@@ -600,97 +1171,228 @@ impl Store {
                     }
                 }
             };
-            // state machine of optional batch
-            match record {
-                LogRecord::Data { key, value: _ } => {
-                    let ptr = LogRecordPtr { file_id, offset };
-                    self.index.put(key, ptr);
+            let hint = HintRecord::from_record(&record, offset, size);
+            self.merge_stats.on_append(file_id, hint.size(), true);
+            self.replay_hint(hint, file_id, replay);
+            offset += size;
+        }
+        Ok(offset)
+    }
 
-                    // clear batch since we are out of batch
-                    *cur_batch_id = None;
-                    batched_index.reset();
+    /// Same as `update_index_on_file_from`, for the active file, which may end in a record
+    ///     torn by a crash mid-append: replay stops before the first record that does not read back.
+    /// Returns the end of the last valid record, and raises `next_seq` past every stamp read.
+    pub(crate) fn update_index_on_active_file(
+        &self,
+        file: &impl Deref<Target = FileHandle>,
+        file_id: u32,
+        mut offset: u64,
+        replay: &mut Replay,
+        next_seq: &mut u64,
+    ) -> u64 {
+        loop {
+            let (record, size) = match file.read_stored_at(offset) {
+                Ok((record, size, _, meta)) => {
+                    if let Some(meta) = meta {
+                        *next_seq = (*next_seq).max(meta.seq + 1);
+                    }
+                    (record, size)
                 }
-                LogRecord::Tomb { key } => {
-                    self.index.delete(key);
-
-                    // clear batch since we are out of batch
-                    *cur_batch_id = None;
-                    batched_index.reset();
+                Err(Errors::Eof) => break,
+                Err(e) => {
+                    warn!(
+                        "Record at offset {} of active file {} is torn: {}",
+                        offset, file_id, e
+                    );
+                    break;
                 }
-                // start batched
-                LogRecord::DataInBatch {
-                    batch_id,
-                    key,
-                    value: _,
-                } => {
-                    *newest_batch_id = batch_id;
-                    // Data variant points to existing record
-                    if let Some(cur_bid) = cur_batch_id {
-                        if batch_id == *cur_bid {
-                            // case 1: in same batch
-                            let ptr = LogRecordPtr { file_id, offset };
-                            batched_index.mark_put(key.into(), ptr);
-                        } else {
-                            // case3: in new batch
-                            // give up previous batch id, create new batch
-                            *cur_batch_id = Some(batch_id);
-                            batched_index.reset();
-                            // add index
-                            let ptr = LogRecordPtr { file_id, offset };
-                            batched_index.mark_put(key.into(), ptr);
-                        }
+            };
+            let hint = HintRecord::from_record(&record, offset, size);
+            self.merge_stats.on_append(file_id, hint.size(), true);
+            self.replay_hint(hint, file_id, replay);
+            offset += size;
+        }
+        offset
+    }
+
+    /// Continue in a new file if the active file is of an older format,
+    ///     which may not hold stamped records.
+    fn upgrade_active_file(&self) -> Result<()> {
+        let mut active_file = self.active_file.write();
+        if active_file.header().version < FORMAT_VERSION {
+            let next_file_id = self
+                .active_file_id
+                .load(std::sync::atomic::Ordering::Relaxed)
+                + 1;
+            info!(
+                "Active file of format version {} sealed, continuing in file {}",
+                active_file.header().version,
+                next_file_id
+            );
+            self.rotate(&mut active_file, next_file_id)?;
+        }
+        Ok(())
+    }
+
+    /// Remove the newest file if a crash left it with a torn header and so no records;
+    ///     returns its id, the file before it is active until the store is open.
+    fn drop_torn_active_file(dir: PathBuf) -> Result<Option<u32>> {
+        let Some(file_id) = get_max_prefix_number(dir.clone())? else {
+            return Ok(None);
+        };
+        let path = format_filename(dir.clone(), file_id);
+        if !FileHeader::is_torn(&path)? {
+            return Ok(None);
+        }
+        warn!("Removing active file {} left with a torn header", file_id);
+        fs::remove_file(&path).map_err(propagate_err!(Errors::FileIoWriteError))?;
+        sync_dir(dir)?;
+        Ok(Some(file_id))
+    }
+
+    /// Cut the active file down to `valid_end`, the end of its last valid record,
+    ///     so that new writes are not appended after the garbage of a torn write.
+    fn truncate_torn_tail(&self, valid_end: u64) -> Result<()> {
+        let active_file = self.active_file.write();
+        // the index is built from memory maps, which can not be cut: only once the files are opened again
+        assert_eq!(
+            active_file.io_type(),
+            IoType::File,
+            "Internal error: truncating a file not opened for writes"
+        );
+        let size = active_file.size();
+        if size > valid_end {
+            warn!(
+                "Discarding {} bytes torn off the end of active file {}, from offset {}",
+                size - valid_end,
+                self.active_file_id
+                    .load(std::sync::atomic::Ordering::Relaxed),
+                valid_end
+            );
+            active_file.truncate(valid_end)?;
+        }
+        Ok(())
+    }
+
+    /// Same as `update_index_on_file`, but reads a hint file instead of the store file.
+    pub(crate) fn update_index_on_hints(
+        &self,
+        hints: Vec<HintRecord>,
+        file_id: u32,
+        replay: &mut Replay,
+    ) -> u64 {
+        let mut offset = 0;
+        for hint in hints {
+            offset = hint.offset() + hint.size();
+            self.merge_stats.on_append(file_id, hint.size(), true);
+            self.replay_hint(hint, file_id, replay);
+        }
+        offset
+    }
+
+    /// Replay a record of `file_id` into the index;
+    ///     counting it in merge statistics, as dead until the index points to it, is up to the caller.
+    pub(crate) fn replay_hint(&self, hint: HintRecord, file_id: u32, replay: &mut Replay) {
+        let Replay {
+            cur_batch_id,
+            newest_batch_id,
+            batched_index,
+            sizes,
+        } = replay;
+        // state machine of optional batch
+        match hint {
+            HintRecord::Data {
+                key,
+                offset,
+                size,
+                expire_at,
+            } => {
+                let ptr = LogRecordPtr { file_id, offset };
+                self.replay_put(key, ptr, size, expire_at, sizes.as_mut());
+
+                // clear batch since we are out of batch
+                *cur_batch_id = None;
+                batched_index.reset();
+            }
+            HintRecord::Tomb { key, .. } => {
+                self.replay_delete(key, sizes.as_mut());
+
+                // clear batch since we are out of batch
+                *cur_batch_id = None;
+                batched_index.reset();
+            }
+            // start batched
+            HintRecord::DataInBatch {
+                batch_id,
+                key,
+                offset,
+                size,
+                expire_at,
+            } => {
+                *newest_batch_id = batch_id;
+                // Data variant points to existing record
+                if let Some(cur_bid) = cur_batch_id {
+                    if batch_id == *cur_bid {
+                        // case 1: in same batch
+                        let ptr = LogRecordPtr { file_id, offset };
+                        batched_index.mark_put(key, ptr, size, expire_at);
                     } else {
-                        // case 2: start new batch from no batch
+                        // case3: in new batch
+                        // give up previous batch id, create new batch
                         *cur_batch_id = Some(batch_id);
                         batched_index.reset();
                         // add index
                         let ptr = LogRecordPtr { file_id, offset };
-                        batched_index.mark_put(key.into(), ptr);
+                        batched_index.mark_put(key, ptr, size, expire_at);
                     }
+                } else {
+                    // case 2: start new batch from no batch
+                    *cur_batch_id = Some(batch_id);
+                    batched_index.reset();
+                    // add index
+                    let ptr = LogRecordPtr { file_id, offset };
+                    batched_index.mark_put(key, ptr, size, expire_at);
                 }
-                LogRecord::TombInBatch { batch_id, key } => {
-                    *newest_batch_id = batch_id;
-                    // Tomb variant deletes corresponding index
-                    if let Some(cur_bid) = cur_batch_id {
-                        if batch_id == *cur_bid {
-                            // case 1: in same batch
-                            batched_index.mark_delete(key.into());
-                        } else {
-                            // case3: in new batch
-                            // give up previous batch id, create new batch
-                            *cur_batch_id = Some(batch_id);
-                            batched_index.reset();
-                            // delete index
-                            batched_index.mark_delete(key.into());
-                        }
+            }
+            HintRecord::TombInBatch { batch_id, key, .. } => {
+                *newest_batch_id = batch_id;
+                // Tomb variant deletes corresponding index
+                if let Some(cur_bid) = cur_batch_id {
+                    if batch_id == *cur_bid {
+                        // case 1: in same batch
+                        batched_index.mark_delete(key);
                     } else {
-                        // case 2: start new batch
+                        // case3: in new batch
+                        // give up previous batch id, create new batch
                         *cur_batch_id = Some(batch_id);
                         batched_index.reset();
                         // delete index
-                        batched_index.mark_delete(key.into());
+                        batched_index.mark_delete(key);
                     }
+                } else {
+                    // case 2: start new batch
+                    *cur_batch_id = Some(batch_id);
+                    batched_index.reset();
+                    // delete index
+                    batched_index.mark_delete(key);
                 }
-                // end batch
-                LogRecord::BatchDone { batch_id } => {
-                    println!("batch {} done", batch_id);
-                    *newest_batch_id = batch_id;
-                    if let Some(cur_bid) = cur_batch_id {
-                        // the same batch
-                        if batch_id == *cur_bid {
-                            // end this batch, commit changes
-                            batched_index.commit(&self);
-                        }
-                        // else: got another batch
+            }
+            // end batch
+            HintRecord::BatchDone { batch_id, .. } => {
+                *newest_batch_id = batch_id;
+                if let Some(cur_bid) = cur_batch_id {
+                    // the same batch
+                    if batch_id == *cur_bid {
+                        // end this batch, commit changes
+                        batched_index.commit(self, sizes.as_mut());
                     }
-                    // else: an empty batch
-                    *cur_batch_id = None;
-                    batched_index.reset();
+                    // else: got another batch
                 }
+                // else: an empty batch
+                *cur_batch_id = None;
+                batched_index.reset();
             }
-            offset += size;
         }
-        Ok(offset)
     }
 }
 
@@ -702,11 +1404,22 @@ impl Store {
 #[cfg(test)]
 mod tests {
 
-    use std::fs;
+    use std::{fs, io::Write, sync::Arc};
 
     use bytes::Bytes;
 
-    use crate::{config::config::Config, errors::Errors, store::utils::TempStore};
+    use crate::{
+        batched::batched_write::CreateBatch,
+        config::config::Config,
+        definitions::constants::get_prefix_numbers,
+        errors::Errors,
+        index::index_impl::IndexType,
+        store::{
+            expiry::now_millis,
+            file_header::FileHeader,
+            utils::{format_filename, format_hint_filename, TempStore},
+        },
+    };
 
     use super::Store;
 
@@ -885,6 +1598,337 @@ mod tests {
         )
     }
 
+    #[test]
+    fn test_disk_tree_resume() {
+        let test_id = 31;
+        let dir = format!("store/test_{}", test_id);
+        let open = || {
+            let (mut store_config, file_config, batched_config) =
+                Config::from_toml("config.toml".into());
+            store_config.dir = dir.clone().into();
+            store_config.index_type = IndexType::DiskTree;
+            Store::open(store_config, file_config, batched_config).unwrap()
+        };
+
+        // remove if exist
+        fs::remove_dir_all(dir.clone());
+        {
+            let store = open();
+            for i in 0..300 {
+                let key = format!("{}", i);
+                let val = english_numbers::convert_all_fmt(i);
+                store.put(key.into(), val.into()).unwrap();
+            }
+        }
+
+        // the process dies before a write reaches the disk:
+        //     the index holds it, but no checkpoint says it is up to date
+        let active_file = || {
+            let file_id = *get_prefix_numbers(dir.clone().into())
+                .unwrap()
+                .last()
+                .unwrap();
+            format_filename(dir.clone().into(), file_id)
+        };
+        {
+            let mut store = open();
+            let synced = fs::metadata(active_file()).unwrap().len();
+            store.put("lost".into(), "lost".into()).unwrap();
+
+            store.index = IndexType::BTree.create_index(dir.clone().into());
+            drop(store.store_lock.take());
+            std::mem::forget(store);
+            fs::OpenOptions::new()
+                .write(true)
+                .open(active_file())
+                .unwrap()
+                .set_len(synced)
+                .unwrap();
+        }
+        {
+            let store = open();
+            assert!(matches!(store.get("lost".into()), Err(Errors::KeyNotFound)));
+            assert_eq!(store.list_keys().len(), 300);
+        }
+
+        // Damage the first record of the first file (and its hint):
+        //      reopening only succeeds if the index does not replay it.
+        fs::remove_file(format_hint_filename(dir.clone().into(), 0)).unwrap();
+        let first_file = format_filename(dir.clone().into(), 0);
+        let mut bin = fs::read(first_file.clone()).unwrap();
+        bin[FileHeader::LENGTH as usize + 10] ^= 0xff;
+        fs::write(first_file, bin).unwrap();
+
+        {
+            // resumed from the checkpoint, which holds again once closed cleanly
+            let store = open();
+            assert!(store.index.applied_until().is_none());
+            assert_eq!(store.list_keys().len(), 300);
+            assert_eq!(
+                store.get("299".into()).unwrap(),
+                english_numbers::convert_all_fmt(299)
+            );
+
+            // writes after the checkpoint are replayed as usual
+            store.put("299".into(), "Last".into()).unwrap();
+            store.delete("298".into()).unwrap();
+        }
+        {
+            let store = open();
+            assert_eq!(store.list_keys().len(), 299);
+            assert_eq!(store.get("299".into()).unwrap(), Bytes::from("Last"));
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_torn_tail() {
+        let test_id = 53;
+        let dir = format!("store/test_{}", test_id);
+        let open = || {
+            let (mut store_config, file_config, batched_config) =
+                Config::from_toml("config.toml".into());
+            store_config.dir = dir.clone().into();
+            Store::open(store_config, file_config, batched_config).unwrap()
+        };
+        let active_file = || {
+            let file_id = *get_prefix_numbers(dir.clone().into())
+                .unwrap()
+                .last()
+                .unwrap();
+            format_filename(dir.clone().into(), file_id)
+        };
+        let append = |bin: &[u8]| {
+            let mut file = fs::OpenOptions::new()
+                .append(true)
+                .open(active_file())
+                .unwrap();
+            file.write_all(bin).unwrap();
+        };
+
+        // remove if exist
+        fs::remove_dir_all(dir.clone());
+        {
+            let store = open();
+            for i in 0..100 {
+                let key = format!("{}", i);
+                let val = english_numbers::convert_all_fmt(i);
+                store.put(key.into(), val.into()).unwrap();
+            }
+        }
+
+        // the process died halfway through appending a data record
+        let size = fs::metadata(active_file()).unwrap().len();
+        append(&[0, 0, 0, 0, 3, 0, 0, 0, 5, b'k']);
+        {
+            let store = open();
+            assert_eq!(fs::metadata(active_file()).unwrap().len(), size);
+            assert_eq!(store.list_keys().len(), 100);
+            store.put("after".into(), "torn".into()).unwrap();
+        }
+
+        // a whole record of garbage: its crc does not match
+        append(&[0, 0, 0, 0, 1, 0, 0, 0, 1, b'k', b'v', 0, 0, 0, 0]);
+        {
+            let store = open();
+            assert_eq!(store.get("after".into()).unwrap(), Bytes::from("torn"));
+            store.put("after".into(), "garbage".into()).unwrap();
+            store.delete("42".into()).unwrap();
+        }
+        {
+            let store = open();
+            assert_eq!(store.list_keys().len(), 100);
+            assert_eq!(store.get("after".into()).unwrap(), Bytes::from("garbage"));
+            assert_eq!(
+                store.get("99".into()).unwrap(),
+                english_numbers::convert_all_fmt(99)
+            );
+        }
+
+        // a record whose type byte is garbage: no record is of that type
+        let size = fs::metadata(active_file()).unwrap().len();
+        append(&[0x08, 0, 0, 0, 1, 0, 0, 0, 1, b'k', b'v', 0, 0, 0, 0]);
+        {
+            let store = open();
+            assert_eq!(fs::metadata(active_file()).unwrap().len(), size);
+            assert_eq!(store.list_keys().len(), 100);
+            assert_eq!(store.get("after".into()).unwrap(), Bytes::from("garbage"));
+            store.put("after".into(), "typed".into()).unwrap();
+        }
+        {
+            let store = open();
+            assert_eq!(store.get("after".into()).unwrap(), Bytes::from("typed"));
+        }
+
+        // sizes torn to garbage: key and value would take up 8 GiB
+        let size = fs::metadata(active_file()).unwrap().len();
+        append(&[0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, b'k']);
+        {
+            let store = open();
+            assert_eq!(fs::metadata(active_file()).unwrap().len(), size);
+            assert_eq!(store.get("after".into()).unwrap(), Bytes::from("typed"));
+            store.put("after".into(), "sized".into()).unwrap();
+        }
+        {
+            let store = open();
+            assert_eq!(store.list_keys().len(), 100);
+            assert_eq!(store.get("after".into()).unwrap(), Bytes::from("sized"));
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_torn_header() {
+        let test_id = 65;
+        let dir = format!("store/test_{}", test_id);
+        let open = || {
+            let (mut store_config, file_config, batched_config) =
+                Config::from_toml("config.toml".into());
+            store_config.dir = dir.clone().into();
+            Store::open(store_config, file_config, batched_config).unwrap()
+        };
+
+        // remove if exist
+        fs::remove_dir_all(dir.clone());
+        {
+            let store = open();
+            for i in 0..100 {
+                let key = format!("{}", i);
+                let val = english_numbers::convert_all_fmt(i);
+                store.put(key.into(), val.into()).unwrap();
+            }
+        }
+
+        // the process died right after a rotation, the new file's header never fully reached the disk
+        for (i, torn_size) in [0, 5].into_iter().enumerate() {
+            let file_id = {
+                let store = open();
+                let file_id = store
+                    .active_file_id
+                    .load(std::sync::atomic::Ordering::Relaxed)
+                    + 1;
+                store
+                    .rotate(&mut store.active_file.write(), file_id)
+                    .unwrap();
+                file_id
+            };
+            let active_file = format_filename(dir.clone().into(), file_id);
+            fs::OpenOptions::new()
+                .write(true)
+                .open(&active_file)
+                .unwrap()
+                .set_len(torn_size)
+                .unwrap();
+
+            {
+                let store = open();
+                assert_eq!(
+                    store
+                        .active_file_id
+                        .load(std::sync::atomic::Ordering::Relaxed),
+                    file_id
+                );
+                let header = store.active_file.read().header();
+                assert_eq!(header.file_id, file_id);
+                assert_eq!(header.first_seq, 100 + i as u64);
+                assert_eq!(store.list_keys().len(), 100 + i);
+                store
+                    .put(format!("torn {}", torn_size).into(), "header".into())
+                    .unwrap();
+            }
+            {
+                let store = open();
+                assert_eq!(store.list_keys().len(), 101 + i);
+                assert_eq!(
+                    store.get(format!("torn {}", torn_size).into()).unwrap(),
+                    Bytes::from("header")
+                );
+            }
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_record_meta() {
+        let test_id = 57;
+        let dir = format!("store/test_{}", test_id);
+        for index_type in [IndexType::Skiplist, IndexType::DiskTree] {
+            let open = || {
+                let (mut store_config, file_config, batched_config) =
+                    Config::from_toml("config.toml".into());
+                store_config.dir = dir.clone().into();
+                store_config.index_type = index_type;
+                Arc::new(Store::open(store_config, file_config, batched_config).unwrap())
+            };
+            let seq = |store: &Store, key: &str| {
+                let (_, meta) = store.get_with_meta(key.to_string().into()).unwrap();
+                meta.unwrap().seq
+            };
+
+            // remove if exist
+            fs::remove_dir_all(dir.clone());
+            let started_at = now_millis();
+            {
+                let store = open();
+                for i in 0..200 {
+                    let key = format!("{}", i);
+                    let val = english_numbers::convert_all_fmt(i);
+                    store.put(key.into(), val.into()).unwrap();
+                }
+                let mut timestamp = started_at;
+                for i in 0..200 {
+                    let (value, meta) = store.get_with_meta(format!("{}", i).into()).unwrap();
+                    let meta = meta.unwrap();
+                    assert_eq!(value, english_numbers::convert_all_fmt(i));
+                    assert_eq!(meta.seq, i as u64);
+                    assert!(meta.timestamp >= timestamp && meta.timestamp <= now_millis());
+                    timestamp = meta.timestamp;
+                }
+
+                // every record counts, `BatchDone` included
+                let batch = store.new_batched();
+                batch.put("b1".into(), "batched".into()).unwrap();
+                batch.put("b2".into(), "batched".into()).unwrap();
+                batch.commit().unwrap();
+                drop(batch);
+                // records of a batch are logged in no particular order
+                let mut seqs = [seq(&store, "b1"), seq(&store, "b2")];
+                seqs.sort();
+                assert_eq!(seqs, [200, 201]);
+                store.delete("199".into()).unwrap();
+                assert!(matches!(
+                    store.get_with_meta("199".into()),
+                    Err(Errors::KeyNotFound)
+                ));
+            }
+            {
+                let store = open();
+                let mut seqs = [seq(&store, "b1"), seq(&store, "b2")];
+                seqs.sort();
+                assert_eq!(seqs, [200, 201]);
+                store.put("reopened".into(), "yes".into()).unwrap();
+                assert_eq!(seq(&store, "reopened"), 204);
+
+                // merge moves records but keeps their stamps,
+                //      the newest of them are gone along with the deleted keys
+                let (_, before) = store.get_with_meta("5".into()).unwrap();
+                for key in ["b1", "b2", "reopened"] {
+                    store.delete(key.into()).unwrap();
+                }
+                store.merge().unwrap();
+                assert_eq!(store.get_with_meta("5".into()).unwrap().1, before);
+            }
+            {
+                let store = open();
+                assert_eq!(seq(&store, "5"), 5);
+                store.put("merged".into(), "yes".into()).unwrap();
+                assert_eq!(seq(&store, "merged"), 208);
+            }
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
     #[test]
     fn test_fold() {
         let (_raii, store) = TempStore::init(6);
@@ -1,6 +1,13 @@
-use std::{fs, path::PathBuf};
+use std::{
+    fs::{self, File},
+    path::PathBuf,
+};
 
-use crate::config::config::Config;
+use crate::{
+    config::config::Config,
+    errors::{Errors, Result},
+    propagate_err,
+};
 
 use super::store::Store;
 
@@ -15,6 +22,31 @@ pub fn format_filename(dir: PathBuf, file_id: u32) -> PathBuf {
     full_path.into()
 }
 
+pub fn format_blob_filename(dir: PathBuf, file_id: u32) -> PathBuf {
+    dir.join(format!("{}.blob", file_id))
+}
+
+pub fn format_hint_filename(dir: PathBuf, file_id: u32) -> PathBuf {
+    dir.join(format!("{}.hint", file_id))
+}
+
+/// `<id>.store` -> `<id>.store.gen<generation>`, a file staged by merge but not yet published
+pub fn format_generation_filename(path: PathBuf, generation: u64) -> PathBuf {
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or_default()
+        .to_string();
+    path.with_extension(format!("{}.gen{}", extension, generation))
+}
+
+/// fsync a directory, so that renames and removals in it survive a crash
+pub fn sync_dir(dir: PathBuf) -> Result<()> {
+    File::open(dir)
+        .and_then(|dir| dir.sync_all())
+        .map_err(propagate_err!(Errors::FileIoSyncError))
+}
+
 pub fn legacy_files(dir: PathBuf, active_file_id: u32) -> impl IntoIterator<Item = PathBuf> {
     (0..active_file_id - 1).map(move |i| format_filename(dir.clone(), i))
 }
@@ -0,0 +1,389 @@
+/*
+    Integrity check of a store directory, open or not:
+    every `.store` file is read back record by record, nothing is written, locked or repaired;
+    files are opened for reads only, one that can not be is reported like a damaged one.
+    Reading a file stops at its first record that does not read back,
+    what follows it can not be told apart from garbage.
+*/
+
+use std::{collections::BTreeSet, path::PathBuf};
+
+use crate::{
+    config::config::FileConfig,
+    definitions::constants::{get_prefix_numbers, KEY_CHECK_FILE_NAME, MERGE_STORE_PATH},
+    errors::Errors,
+    io::{cipher::EncryptionConfig, traits::IoType},
+    records::log_record::{LogRecord, LogRecordPtr},
+};
+
+use super::{file_handle::FileHandle, store::Store, utils::format_filename};
+
+/// Bytes after the last record of a file that do not make up a record.
+#[derive(Debug, PartialEq)]
+pub struct TruncatedTail {
+    pub file_id: u32,
+    /// end of the last record
+    pub offset: u64,
+    pub bytes: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    /// ids of the store files read, ascending
+    pub files: Vec<u32>,
+    /// records read back
+    pub records: usize,
+    pub crc_mismatches: Vec<LogRecordPtr>,
+    /// records failing otherwise, e.g. to decrypt or decompress
+    pub unreadable_records: Vec<(LogRecordPtr, Errors)>,
+    pub truncated_tails: Vec<TruncatedTail>,
+    /// ids of batches with records but no `BatchDone`
+    pub unfinished_batches: Vec<usize>,
+    /// ids missing between the first and the last store file;
+    ///     merge leaves these behind, they are no damage by themselves
+    pub file_id_gaps: Vec<u32>,
+    /// `merge/` of a merge that did not finish, removed on the next open
+    pub merge_dir_left: bool,
+    /// store files that could not be opened, e.g. of an invalid header
+    pub unreadable_files: Vec<(u32, Errors)>,
+    /// the store could not be read at all, e.g. for a missing encryption key
+    pub store_error: Option<Errors>,
+}
+
+impl VerifyReport {
+    /// Whether nothing was found but gaps in file ids
+    pub fn is_clean(&self) -> bool {
+        self.crc_mismatches.is_empty()
+            && self.unreadable_records.is_empty()
+            && self.truncated_tails.is_empty()
+            && self.unfinished_batches.is_empty()
+            && !self.merge_dir_left
+            && self.unreadable_files.is_empty()
+            && self.store_error.is_none()
+    }
+}
+
+impl Store {
+    /// Check the store at `dir` without modifying it, see `VerifyReport`.
+    pub fn verify(dir: PathBuf) -> VerifyReport {
+        Self::verify_with(dir, &EncryptionConfig::default())
+    }
+
+    /// Same as `verify`, for a store that may be encrypted.
+    pub fn verify_with(dir: PathBuf, encryption: &EncryptionConfig) -> VerifyReport {
+        let mut report = VerifyReport {
+            merge_dir_left: dir.join(MERGE_STORE_PATH).is_dir(),
+            ..Default::default()
+        };
+
+        let file_ids = match get_prefix_numbers(dir.clone()) {
+            Ok(file_ids) => file_ids,
+            Err(e) => {
+                report.store_error = Some(e);
+                return report;
+            }
+        };
+        // opening the cipher writes the key check of a store that has none yet
+        let cipher = if dir.join(KEY_CHECK_FILE_NAME).is_file() {
+            match encryption.open_cipher(dir.clone()) {
+                Ok(cipher) => cipher,
+                Err(e) => {
+                    report.store_error = Some(e);
+                    return report;
+                }
+            }
+        } else {
+            None
+        };
+
+        if let (Some(first), Some(last)) = (file_ids.first(), file_ids.last()) {
+            report.file_id_gaps = (*first..*last)
+                .filter(|file_id| file_ids.binary_search(file_id).is_err())
+                .collect();
+        }
+
+        // batches span files, they are matched up in file order
+        let mut open_batches = BTreeSet::new();
+        for file_id in file_ids {
+            let file = match FileHandle::open_at(
+                format_filename(dir.clone(), file_id),
+                file_id,
+                // only read from
+                FileConfig { max_file_size: 0 },
+                IoType::MemMapped,
+            ) {
+                Ok(file) => file.with_cipher(cipher.clone()),
+                Err(e) => {
+                    report.unreadable_files.push((file_id, e));
+                    continue;
+                }
+            };
+            report.files.push(file_id);
+            Self::verify_file(&file, file_id, &mut open_batches, &mut report);
+        }
+        report.unfinished_batches = open_batches.into_iter().collect();
+        report
+    }
+
+    fn verify_file(
+        file: &FileHandle,
+        file_id: u32,
+        open_batches: &mut BTreeSet<usize>,
+        report: &mut VerifyReport,
+    ) {
+        let size = file.size();
+        let mut offset = 0;
+        while offset < size {
+            let ptr = LogRecordPtr { file_id, offset };
+            let (record, record_size) = match file.read_at_offset(offset) {
+                Ok(res) => res,
+                // zeroes or a record cut short
+                Err(Errors::Eof) => {
+                    report.truncated_tails.push(TruncatedTail {
+                        file_id,
+                        offset,
+                        bytes: size - offset,
+                    });
+                    return;
+                }
+                Err(Errors::CrcMismatch { .. }) => {
+                    report.crc_mismatches.push(ptr);
+                    return;
+                }
+                Err(e) => {
+                    report.unreadable_records.push((ptr, e));
+                    return;
+                }
+            };
+            match record {
+                LogRecord::DataInBatch { batch_id, .. }
+                | LogRecord::TombInBatch { batch_id, .. }
+                | LogRecord::ExpiringInBatch { batch_id, .. } => {
+                    open_batches.insert(batch_id);
+                }
+                LogRecord::BatchDone { batch_id } => {
+                    open_batches.remove(&batch_id);
+                }
+                _ => {}
+            }
+            report.records += 1;
+            offset += record_size;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        io::Write,
+        os::unix::fs::{FileExt, PermissionsExt},
+        path::PathBuf,
+        sync::Arc,
+    };
+
+    use crate::{
+        batched::batched_write::CreateBatch,
+        config::config::{Config, FileConfig},
+        definitions::constants::{get_prefix_numbers, MERGE_STORE_PATH},
+        errors::Errors,
+        io::traits::IoType,
+        records::log_record::{LogRecord, LogRecordPtr},
+        store::{
+            file_handle::FileHandle, file_header::FileHeader, store::Store, utils::format_filename,
+        },
+    };
+
+    use super::TruncatedTail;
+
+    /// (name, content) of every file in `dir`
+    fn snapshot(dir: &str) -> Vec<(PathBuf, Vec<u8>)> {
+        let mut files: Vec<_> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.is_file())
+            .map(|path| {
+                let content = fs::read(&path).unwrap();
+                (path, content)
+            })
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn test_verify() {
+        let dir = "store/test_54";
+        // remove if exist
+        fs::remove_dir_all(dir);
+
+        let (mut store_config, file_config, batched_config) =
+            Config::from_toml("config.toml".into());
+        store_config.dir = dir.into();
+        let store = Arc::new(Store::open(store_config, file_config, batched_config).unwrap());
+        for i in 0..300 {
+            let key = format!("{}", i);
+            let val = english_numbers::convert_all_fmt(i);
+            store.put(key.into(), val.into()).unwrap();
+        }
+        let batch = store.new_batched();
+        batch.put("batched".into(), "value".into()).unwrap();
+        batch.commit().unwrap();
+        drop(batch);
+
+        // an open store can be checked
+        let report = Store::verify(dir.into());
+        assert!(report.is_clean(), "{:?}", report);
+        assert_eq!(report.records, 302);
+        assert!(report.file_id_gaps.is_empty());
+        drop(store);
+
+        let file_ids = get_prefix_numbers(dir.into()).unwrap();
+        assert!(file_ids.len() > 2, "{:?}", file_ids);
+        let active_file_id = *file_ids.last().unwrap();
+
+        // a batch cut off before its `BatchDone`, then a record cut short
+        let active =
+            FileHandle::open(dir.into(), active_file_id, file_config, IoType::File).unwrap();
+        active.set_write_offset(active.size());
+        active
+            .try_append(&mut LogRecord::DataInBatch {
+                batch_id: 42,
+                key: b"key".to_vec(),
+                value: b"value".to_vec(),
+            })
+            .unwrap();
+        let torn_offset = active.size();
+        drop(active);
+        let mut file = fs::OpenOptions::new()
+            .append(true)
+            .open(format_filename(dir.into(), active_file_id))
+            .unwrap();
+        file.write_all(&[0, 0, 0, 0, 3, 0, 0, 0, 5, b'k']).unwrap();
+
+        // a flipped bit in the second record of the first file
+        let first = format_filename(dir.into(), file_ids[0]);
+        let (_, first_size) = FileHandle::open_at(
+            first.clone(),
+            file_ids[0],
+            FileConfig { max_file_size: 0 },
+            IoType::File,
+        )
+        .unwrap()
+        .read_at_offset(0)
+        .unwrap();
+        let file = fs::OpenOptions::new().write(true).open(&first).unwrap();
+        file.write_all_at(b"~", FileHeader::LENGTH + first_size + 12)
+            .unwrap();
+
+        // a file gone, and an unfinished merge
+        fs::remove_file(format_filename(dir.into(), file_ids[1])).unwrap();
+        fs::create_dir(PathBuf::from(dir).join(MERGE_STORE_PATH)).unwrap();
+
+        let before = snapshot(dir);
+        let report = Store::verify(dir.into());
+        assert_eq!(snapshot(dir), before);
+
+        assert!(!report.is_clean());
+        assert_eq!(report.files.len(), file_ids.len() - 1);
+        assert_eq!(
+            report.crc_mismatches,
+            vec![LogRecordPtr {
+                file_id: file_ids[0],
+                offset: first_size
+            }]
+        );
+        assert_eq!(
+            report.truncated_tails,
+            vec![TruncatedTail {
+                file_id: active_file_id,
+                offset: torn_offset,
+                bytes: 10
+            }]
+        );
+        assert_eq!(report.unfinished_batches, vec![42]);
+        assert_eq!(report.file_id_gaps, vec![file_ids[1]]);
+        assert!(report.merge_dir_left);
+        assert!(report.unreadable_records.is_empty());
+        assert!(report.unreadable_files.is_empty());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_verify_invalid_record_type() {
+        let dir = "store/test_61";
+        // remove if exist
+        fs::remove_dir_all(dir);
+
+        let (mut store_config, file_config, batched_config) =
+            Config::from_toml("config.toml".into());
+        store_config.dir = dir.into();
+        let store = Store::open(store_config, file_config, batched_config).unwrap();
+        for i in 0..10 {
+            let key = format!("{}", i);
+            store.put(key.into(), "value".into()).unwrap();
+        }
+        drop(store);
+
+        // no type of record is 0x08, even with every flag cleared
+        let file_id = get_prefix_numbers(dir.into()).unwrap()[0];
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .open(format_filename(dir.into(), file_id))
+            .unwrap();
+        file.write_all_at(&[0x08], FileHeader::LENGTH).unwrap();
+        drop(file);
+
+        let before = snapshot(dir);
+        let report = Store::verify(dir.into());
+        assert_eq!(snapshot(dir), before);
+
+        assert!(!report.is_clean());
+        assert_eq!(report.records, 0);
+        assert_eq!(
+            report.unreadable_records,
+            vec![(
+                LogRecordPtr { file_id, offset: 0 },
+                Errors::InvalidRecordType { code: 0x08 }
+            )]
+        );
+        assert!(report.crc_mismatches.is_empty());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_verify_read_only() {
+        let dir = "store/test_67";
+        // remove if exist
+        fs::remove_dir_all(dir);
+
+        let (mut store_config, file_config, batched_config) =
+            Config::from_toml("config.toml".into());
+        store_config.dir = dir.into();
+        let store = Store::open(store_config, file_config, batched_config).unwrap();
+        for i in 0..300 {
+            let key = format!("{}", i);
+            store.put(key.into(), "value".into()).unwrap();
+        }
+        drop(store);
+
+        // a read-only mount, or a user who may only read
+        let set_mode = |mode: u32| {
+            for (path, _) in snapshot(dir) {
+                fs::set_permissions(path, fs::Permissions::from_mode(mode & 0o666)).unwrap();
+            }
+            fs::set_permissions(dir, fs::Permissions::from_mode(mode)).unwrap();
+        };
+        let before = snapshot(dir);
+        set_mode(0o555);
+        let report = Store::verify(dir.into());
+        set_mode(0o755);
+        assert!(report.is_clean(), "{:?}", report);
+        assert_eq!(report.records, 300);
+        assert_eq!(snapshot(dir), before);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}